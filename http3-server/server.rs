@@ -1,18 +1,52 @@
 // This thing is entirely AI-generated, and should serve only as a demo HTTP/3 server.
 
-use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{net::SocketAddr, path::{Path, PathBuf}, sync::Arc, time::Instant};
 use bytes::Bytes;
+use clap::Parser;
+use tokio::sync::Semaphore;
 use h3::quic::BidiStream;
 use h3::server::RequestStream;
 use h3_quinn::quinn;
 use http::{Request, StatusCode};
 
+/// Demo HTTP/3 server to forward QUIC traffic to
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Certificate chain (PEM or DER), such as a Let's Encrypt fullchain.pem. When not set,
+    /// cert.pem or cert.der in --cert-dir is used
+    #[arg(long, env = "HTTP3_CERT", requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// Private key (PEM or DER) for --cert
+    #[arg(long, env = "HTTP3_KEY", requires = "cert")]
+    key: Option<PathBuf>,
+
+    /// Directory to look for a certificate in, and to store a generated self-signed one in
+    #[arg(long, env = "HTTP3_CERT_DIR", default_value = "/certs")]
+    cert_dir: PathBuf,
+
+    /// Serve the files in this directory, instead of a fixed greeting for every request
+    #[arg(long, env = "HTTP3_ROOT")]
+    root: Option<PathBuf>,
+
+    /// Most requests handled at once on a single connection; further streams wait to be accepted
+    #[arg(long, env = "HTTP3_MAX_CONCURRENT_REQUESTS", default_value_t = 100)]
+    max_concurrent_requests: usize,
+
+    /// Most connections handled at once; further connections wait to be accepted
+    #[arg(long, env = "HTTP3_MAX_CONNECTIONS", default_value_t = 1000)]
+    max_connections: usize,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let addr: SocketAddr = "0.0.0.0:8443".parse()?;
 
     // Load or generate certificate
-    let (cert, key) = load_or_generate_cert()?;
+    let (certs, key) = load_or_generate_cert(&args)?;
 
     // Configure QUIC server
     let mut tls_config = rustls::ServerConfig::builder()
@@ -21,7 +55,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with_protocol_versions(&[&rustls::version::TLS13])
         .unwrap()
         .with_no_client_auth()
-        .with_single_cert(vec![cert], key)?;
+        .with_single_cert(certs, key)?;
 
     tls_config.max_early_data_size = u32::MAX;
     tls_config.alpn_protocols = vec![b"h3".to_vec()];
@@ -29,100 +63,241 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let server_config = quinn::ServerConfig::with_crypto(Arc::new(tls_config));
     let endpoint = quinn::Endpoint::server(server_config, addr)?;
 
-    println!("HTTP/3 server listening on {}", addr);
+    log::info!("HTTP/3 server listening on {}", addr);
 
-    while let Some(incoming) = endpoint.accept().await {
+    // Resolved once, so that requests can be checked against the real path of the root
+    let root: Option<Arc<Path>> = match &args.root {
+        Some(root) => Some(root.canonicalize()?.into()),
+        None => None,
+    };
+
+    let connection_permits = Arc::new(Semaphore::new(args.max_connections));
+    loop {
+        // Wait for a free slot before accepting, so excess connections queue up in quinn
+        let permit = connection_permits.clone().acquire_owned().await?;
+        let Some(incoming) = endpoint.accept().await else {
+            break;
+        };
+        let root = root.clone();
+        let max_requests = args.max_concurrent_requests;
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(incoming).await {
-                eprintln!("Connection error: {}", e);
+            if let Err(e) = handle_connection(incoming, root, max_requests).await {
+                log::error!("Connection error: {}", e);
             }
+            drop(permit);
         });
     }
 
     Ok(())
 }
 
-async fn handle_connection(incoming: quinn::Connecting) -> Result<(), Box<dyn std::error::Error>> {
+async fn handle_connection(
+    incoming: quinn::Connecting,
+    root: Option<Arc<Path>>,
+    max_requests: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
     let connection = incoming.await?;
-    println!("New connection from {}", connection.remote_address());
+    let remote = connection.remote_address();
+    let opened = Instant::now();
+    let mut requests = 0;
+    log::info!("New connection from {}", remote);
 
     let mut h3_conn = h3::server::Connection::new(h3_quinn::Connection::new(connection))
         .await?;
 
+    let request_permits = Arc::new(Semaphore::new(max_requests));
     loop {
+        // Stop accepting streams while the connection is at its limit
+        let permit = request_permits.clone().acquire_owned().await?;
         match h3_conn.accept().await {
             Ok(Some((req, stream))) => {
+                requests += 1;
+                let root = root.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = handle_request(req, stream).await {
-                        eprintln!("Request error: {}", e);
+                    if let Err(e) = handle_request(req, stream, root.as_deref(), remote).await {
+                        log::error!("Request error from {}: {}", remote, e);
                     }
+                    drop(permit);
                 });
             }
             Ok(None) => break,
             Err(e) => {
-                eprintln!("Accept error: {}", e);
+                log::error!("Accept error from {}: {}", remote, e);
                 break;
             }
         }
     }
 
+    log::info!(
+        "Connection from {} closed after {:.3?} and {} requests",
+        remote,
+        opened.elapsed(),
+        requests
+    );
     Ok(())
 }
 
 async fn handle_request<T>(
     req: Request<()>,
     mut stream: RequestStream<T, Bytes>,
+    root: Option<&Path>,
+    remote: SocketAddr,
 ) -> Result<(), Box<dyn std::error::Error>>
 where
     T: BidiStream<Bytes>,
 {
-    println!("Received request: {} {}", req.method(), req.uri());
+    let started = Instant::now();
+    log::debug!("Received request from {}: {} {}", remote, req.method(), req.uri());
 
     // Read request body if present
     while let Some(_data) = stream.recv_data().await? {}
 
     // Prepare response
-    let content = b"Hello World from HTTP/3!\n";
+    let (status, content_type, content) = match root {
+        Some(root) => match read_file(root, req.uri().path()).await {
+            Some((path, content)) => (StatusCode::OK, content_type(&path), Bytes::from(content)),
+            None => (StatusCode::NOT_FOUND, "text/plain", Bytes::from_static(b"Not found\n")),
+        },
+        None => (StatusCode::OK, "text/plain", Bytes::from_static(b"Hello World from HTTP/3!\n")),
+    };
     let response = http::Response::builder()
-        .status(StatusCode::OK)
-        .header("content-type", "text/plain")
+        .status(status)
+        .header("content-type", content_type)
         .header("content-length", content.len())
         .body(())?;
 
+    let sent = content.len();
     stream.send_response(response).await?;
-    stream.send_data(Bytes::from_static(content)).await?;
+    stream.send_data(content).await?;
     stream.finish().await?;
 
+    log::info!(
+        "{} \"{} {}\" {} {} bytes in {:.3?}",
+        remote,
+        req.method(),
+        req.uri(),
+        status.as_u16(),
+        sent,
+        started.elapsed()
+    );
+
     Ok(())
 }
 
-fn load_or_generate_cert() -> Result<(rustls::Certificate, rustls::PrivateKey), Box<dyn std::error::Error>> {
-    // Try to load existing certificate
-    let cert_path = PathBuf::from("/certs/cert.der");
-    let key_path = PathBuf::from("/certs/key.der");
-
-    if cert_path.exists() && key_path.exists() {
-        let cert_data = std::fs::read(&cert_path)?;
-        let key_data = std::fs::read(&key_path)?;
-        return Ok((
-            rustls::Certificate(cert_data),
-            rustls::PrivateKey(key_data),
-        ));
+/// Reads the file under `root` that a request path refers to, serving index.html for
+/// directories. Returns `None` for missing files and for paths that would escape the root.
+async fn read_file(root: &Path, request_path: &str) -> Option<(PathBuf, Vec<u8>)> {
+    let mut path = root.to_path_buf();
+    for segment in request_path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            segment if segment.contains('\\') || segment.contains('\0') => return None,
+            segment => path.push(segment),
+        }
+    }
+    if tokio::fs::metadata(&path).await.ok()?.is_dir() {
+        path.push("index.html");
+    }
+
+    // Symlinks could still point outside the root
+    let path = tokio::fs::canonicalize(&path).await.ok()?;
+    if !path.starts_with(root) {
+        return None;
+    }
+    let content = tokio::fs::read(&path).await.ok()?;
+    Some((path, content))
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+type Certificate = (Vec<rustls::Certificate>, rustls::PrivateKey);
+
+fn load_or_generate_cert(args: &Args) -> Result<Certificate, Box<dyn std::error::Error>> {
+    if let (Some(cert_path), Some(key_path)) = (&args.cert, &args.key) {
+        return load_cert(cert_path, key_path);
+    }
+
+    // Try to load an existing certificate, preferring PEM
+    for (cert_name, key_name) in [("cert.pem", "key.pem"), ("cert.der", "key.der")] {
+        let cert_path = args.cert_dir.join(cert_name);
+        let key_path = args.cert_dir.join(key_name);
+        if cert_path.exists() && key_path.exists() {
+            return load_cert(&cert_path, &key_path);
+        }
     }
 
     // Generate self-signed certificate
-    println!("Generating self-signed certificate...");
+    log::info!("Generating self-signed certificate...");
     let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
     let cert_der = cert.serialize_der()?;
     let key_der = cert.serialize_private_key_der();
 
     // Save certificate for reuse
-    std::fs::create_dir_all("/certs").ok();
-    std::fs::write(&cert_path, &cert_der).ok();
-    std::fs::write(&key_path, &key_der).ok();
+    std::fs::create_dir_all(&args.cert_dir).ok();
+    std::fs::write(args.cert_dir.join("cert.der"), &cert_der).ok();
+    std::fs::write(args.cert_dir.join("key.der"), &key_der).ok();
 
     Ok((
-        rustls::Certificate(cert_der),
+        vec![rustls::Certificate(cert_der)],
         rustls::PrivateKey(key_der),
     ))
 }
+
+/// Loads a certificate chain and its key, each of which may be PEM or DER encoded
+fn load_cert(cert_path: &Path, key_path: &Path) -> Result<Certificate, Box<dyn std::error::Error>> {
+    log::info!("Loading certificate from {}", cert_path.display());
+    let cert_data = std::fs::read(cert_path)?;
+    let key_data = std::fs::read(key_path)?;
+
+    let certs = if is_pem(&cert_data) {
+        let certs = rustls_pemfile::certs(&mut &cert_data[..])?;
+        if certs.is_empty() {
+            return Err(format!("no certificates in {}", cert_path.display()).into());
+        }
+        certs.into_iter().map(rustls::Certificate).collect()
+    } else {
+        vec![rustls::Certificate(cert_data)]
+    };
+
+    let key = if is_pem(&key_data) {
+        let mut reader = &key_data[..];
+        loop {
+            match rustls_pemfile::read_one(&mut reader)? {
+                Some(
+                    rustls_pemfile::Item::PKCS8Key(key)
+                    | rustls_pemfile::Item::RSAKey(key)
+                    | rustls_pemfile::Item::ECKey(key),
+                ) => break key,
+                Some(_) => continue,
+                None => return Err(format!("no private key in {}", key_path.display()).into()),
+            }
+        }
+    } else {
+        key_data
+    };
+
+    Ok((certs, rustls::PrivateKey(key)))
+}
+
+fn is_pem(data: &[u8]) -> bool {
+    data.trim_ascii_start().starts_with(b"-----BEGIN")
+}
@@ -0,0 +1,82 @@
+//! Counts the heap allocations, and measures the time, of copying forwarded packets into
+//! owned buffers that are dropped once sent, the way the main loop did with `to_vec()`,
+//! against taking them from and handing them back to the `BufferPool` behind
+//! `PACKET_BUFFERS`. A few packets stay in flight at once, as they do while queued for
+//! their backend's send task. Run with `cargo bench --bench buffer_pool`.
+
+#[path = "../src/pool.rs"]
+#[allow(dead_code)]
+mod pool;
+
+use pool::BufferPool;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::VecDeque;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const PACKETS: u64 = 1_000_000;
+/// Packets waiting to be sent at once, the depth of a backend's send queue
+const IN_FLIGHT: usize = 64;
+const PACKET_LEN: usize = 1200;
+
+/// The system allocator, counting each allocation it does
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Runs `PACKETS` packets through `copy` and `done`, with `IN_FLIGHT` of them queued, and
+/// returns the allocations made and the time taken
+fn run(copy: impl Fn(&[u8]) -> Vec<u8>, done: impl Fn(Vec<u8>)) -> (u64, Duration) {
+    let packet = [0xaa; PACKET_LEN];
+    let mut queue = VecDeque::with_capacity(IN_FLIGHT);
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..PACKETS {
+        if queue.len() == IN_FLIGHT {
+            done(black_box(queue.pop_front().unwrap()));
+        }
+        queue.push_back(copy(black_box(&packet)));
+    }
+    queue.into_iter().for_each(&done);
+    (
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        start.elapsed(),
+    )
+}
+
+fn main() {
+    let pool = BufferPool::new(4096);
+    let results = [
+        ("to_vec", run(|packet| packet.to_vec(), drop)),
+        (
+            "BufferPool",
+            run(|packet| pool.copy_of(packet), |buf| pool.recycle(buf)),
+        ),
+    ];
+    for (name, (allocations, elapsed)) in results {
+        println!(
+            "{name}: {allocations} allocations for {PACKETS} packets of {PACKET_LEN} bytes, {:.1} ns/packet",
+            elapsed.as_nanos() as f64 / PACKETS as f64
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! Measures how many WireGuard data packets per second the proxy forwards on one connection,
+//! and the CPU time it spends doing so, with every packet classified and with only the first
+//! one. Run with `cargo bench --bench classify`.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Packets sent per run, all on one connection
+const PACKETS: usize = 100_000;
+/// Packets sent between pauses, so the proxy keeps up and CPU time is spent forwarding rather
+/// than on packets it has to drop
+const BURST: usize = 50;
+/// A WireGuard data packet carrying a full sized inner packet
+const PACKET_LEN: usize = 1200;
+
+fn main() {
+    for args in [&[][..], &["--classify-first-only"][..]] {
+        // A backend that only counts what it receives, so that it keeps up with the proxy
+        let backend = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 65536];
+            while backend.recv_from(&mut buf).is_ok() {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        let quic = MockBackend::start();
+        let mut proxy_args = vec!["--queue-depth", "1024", "--batch-recv"];
+        proxy_args.extend_from_slice(args);
+        let proxy = Proxy::start_at(backend_addr, quic.addr, &proxy_args);
+
+        // The counting backend never answers, so wait for the connection to be forwarded
+        let sock = client();
+        let mut packet = vec![0x04, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef];
+        packet.resize(PACKET_LEN, 0xaa);
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        while received.load(Ordering::Relaxed) == 0 {
+            assert!(Instant::now() < deadline, "the proxy didn't start");
+            sock.send_to(&packet, proxy.addr).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let before = received.load(Ordering::Relaxed);
+        let cpu_before = cpu_time(&proxy);
+        let start = Instant::now();
+        for i in 0..PACKETS {
+            sock.send_to(&packet, proxy.addr).unwrap();
+            if i % BURST == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        // Give the forwarding task a moment to drain its queue
+        thread::sleep(Duration::from_millis(200));
+        let forwarded = received.load(Ordering::Relaxed) - before;
+        let elapsed = start.elapsed() - Duration::from_millis(200);
+        let cpu = cpu_time(&proxy) - cpu_before;
+        println!(
+            "{:<22} {forwarded} of {PACKETS} packets forwarded, {:.0} packets/s, {:.2}us CPU per packet",
+            if args.is_empty() {
+                "classifying every one"
+            } else {
+                "--classify-first-only"
+            },
+            forwarded as f64 / elapsed.as_secs_f64(),
+            cpu.as_secs_f64() * 1e6 / forwarded as f64
+        );
+    }
+}
+
+/// The user and system time the proxy has used, from /proc (so Linux only), in clock ticks
+/// of the usual 100 Hz
+fn cpu_time(proxy: &Proxy) -> Duration {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", proxy.pid())).unwrap();
+    // The command name can contain spaces, but is enclosed in parentheses
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .unwrap()
+        .1
+        .split_whitespace()
+        .collect();
+    let ticks: u64 = fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+    Duration::from_millis(ticks * 10)
+}
@@ -0,0 +1,145 @@
+//! Measures how many packets of established connections make a round trip through the proxy
+//! per second, while other clients keep opening new connections, so that lookups in the
+//! connection table contend with inserts and removals. The clients send from many addresses
+//! on the loopback network, to spread over the table's shards, so Linux only. Run with
+//! `cargo bench --bench connections`; to compare with another build, such as one from before
+//! the table was sharded, point `WGQ_BENCH_BINARY` at its binary.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::process::{Child, Command};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Threads sending on established connections
+const THREADS: usize = 8;
+/// Clients of each thread, each with an address of its own
+const CLIENTS_PER_THREAD: usize = 16;
+/// New connections opened per second alongside them
+const NEW_PER_SECOND: u32 = 1000;
+const DURATION: Duration = Duration::from_secs(3);
+
+/// The proxy binary under test, which is killed once dropped
+struct Binary(Child);
+
+impl Binary {
+    fn start(addr: SocketAddr, wireguard: SocketAddr, quic: SocketAddr) -> Self {
+        let binary = std::env::var_os("WGQ_BENCH_BINARY")
+            .unwrap_or_else(|| env!("CARGO_BIN_EXE_wg-quic-differentiator").into());
+        let child = Command::new(binary)
+            .args(["--listen", &addr.to_string()])
+            .args(["--wireguard-backend", &wireguard.to_string()])
+            .args(["--quic-backend", &quic.to_string()])
+            .env("RUST_LOG", "error")
+            .spawn()
+            .unwrap();
+        Binary(child)
+    }
+}
+
+impl Drop for Binary {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn main() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let addr = free_addr();
+    let _proxy = Binary::start(addr, wireguard.addr, quic.addr);
+    let stop = Arc::new(AtomicBool::new(false));
+    // Empty what the backend received now and then, so its channel doesn't grow all run
+    {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                wireguard.received();
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+    }
+
+    let packet = wireguard_handshake_initiation();
+    let clients: Vec<Vec<UdpSocket>> = (0..THREADS)
+        .map(|thread| {
+            (0..CLIENTS_PER_THREAD)
+                .map(|i| {
+                    let n = thread * CLIENTS_PER_THREAD + i;
+                    let ip = Ipv4Addr::new(127, 0, 1 + (n / 250) as u8, 1 + (n % 250) as u8);
+                    let sock = UdpSocket::bind((ip, 0)).unwrap();
+                    sock.set_read_timeout(Some(RECV_TIMEOUT)).unwrap();
+                    exchange_at(&sock, addr, &packet);
+                    sock
+                })
+                .collect()
+        })
+        .collect();
+
+    // New connections from ports that are never used again, with the table's locks taken for
+    // every insert, and for every removal once they time out or are evicted
+    let churn = {
+        let stop = stop.clone();
+        let packet = packet.clone();
+        thread::spawn(move || {
+            let mut opened = 0u64;
+            while !stop.load(Ordering::Relaxed) {
+                let ip = Ipv4Addr::new(127, 0, 2, 1 + (opened % 250) as u8);
+                let sock = UdpSocket::bind((ip, 0)).unwrap();
+                sock.send_to(&packet, addr).unwrap();
+                opened += 1;
+                thread::sleep(Duration::from_secs(1) / NEW_PER_SECOND);
+            }
+            opened
+        })
+    };
+
+    let start = Instant::now();
+    let senders: Vec<_> = clients
+        .into_iter()
+        .map(|socks| {
+            let stop = stop.clone();
+            let packet = packet.clone();
+            thread::spawn(move || {
+                let mut round_trips = Vec::new();
+                let mut buf = [0; 2048];
+                while !stop.load(Ordering::Relaxed) {
+                    for sock in &socks {
+                        let sent = Instant::now();
+                        sock.send_to(&packet, addr).unwrap();
+                        if sock.recv_from(&mut buf).is_ok() {
+                            round_trips.push(sent.elapsed());
+                        }
+                    }
+                }
+                round_trips
+            })
+        })
+        .collect();
+    thread::sleep(DURATION);
+    stop.store(true, Ordering::Relaxed);
+    let elapsed = start.elapsed();
+    let mut round_trips: Vec<Duration> = senders
+        .into_iter()
+        .flat_map(|sender| sender.join().unwrap())
+        .collect();
+    let opened = churn.join().unwrap();
+
+    round_trips.sort();
+    let percentile = |p: usize| round_trips[(round_trips.len() - 1) * p / 100];
+    println!(
+        "{} clients on {} threads: {:.0} round trips/s, p50 {:?}, p99 {:?}, with {:.0} new connections/s",
+        THREADS * CLIENTS_PER_THREAD,
+        THREADS,
+        round_trips.len() as f64 / elapsed.as_secs_f64(),
+        percentile(50),
+        percentile(99),
+        opened as f64 / elapsed.as_secs_f64()
+    );
+}
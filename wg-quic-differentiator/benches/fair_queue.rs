@@ -0,0 +1,135 @@
+//! Measures how a backend's `--max-bps` is shared between one greedy client and a few light
+//! ones, with packets over the limit dropped and with `--fair-queue`. Each client sends from
+//! an address of its own on the loopback network, so Linux only. Run with
+//! `cargo bench --bench fair_queue`.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The backend's limit, in bytes per second
+const MAX_BPS: u64 = 2_000_000;
+/// What each light client sends, in bytes per second
+const LIGHT_BPS: u64 = 100_000;
+const LIGHT_CLIENTS: usize = 4;
+const PACKET_LEN: usize = 1200;
+const DURATION: Duration = Duration::from_secs(3);
+
+fn main() {
+    let max_bps = MAX_BPS.to_string();
+    for args in [&[][..], &["--fair-queue"][..]] {
+        // A backend that counts the bytes from each client, which puts its number in the
+        // receiver index of its packets
+        let backend = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let received: Arc<Vec<AtomicU64>> =
+            Arc::new((0..=LIGHT_CLIENTS).map(|_| AtomicU64::new(0)).collect());
+        let counters = received.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 65536];
+            while let Ok((len, _)) = backend.recv_from(&mut buf) {
+                if let Some(counter) = counters.get(buf[4] as usize) {
+                    counter.fetch_add(len as u64, Ordering::Relaxed);
+                }
+            }
+        });
+
+        let quic = MockBackend::start();
+        let mut proxy_args = vec!["--max-bps", &max_bps];
+        proxy_args.extend_from_slice(args);
+        let proxy = Proxy::start_at(backend_addr, quic.addr, &proxy_args);
+
+        let clients: Vec<_> = (0..=LIGHT_CLIENTS)
+            .map(|id| {
+                let ip = Ipv4Addr::new(127, 0, 0, 2 + id as u8);
+                let sock = UdpSocket::bind((ip, 0)).unwrap();
+                let mut packet = vec![0x04, 0, 0, 0, id as u8, 0, 0, 0];
+                packet.resize(PACKET_LEN, 0xaa);
+                (sock, packet)
+            })
+            .collect();
+        // The counting backend never answers, so wait for every connection to be forwarded
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        while received
+            .iter()
+            .any(|bytes| bytes.load(Ordering::Relaxed) == 0)
+        {
+            assert!(Instant::now() < deadline, "the proxy didn't start");
+            for (sock, packet) in &clients {
+                sock.send_to(packet, proxy.addr).unwrap();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Let the buckets refill, so the runs start alike
+        thread::sleep(Duration::from_secs(1));
+
+        let before: Vec<u64> = received
+            .iter()
+            .map(|bytes| bytes.load(Ordering::Relaxed))
+            .collect();
+        let end = Instant::now() + DURATION;
+        let senders: Vec<_> = clients
+            .into_iter()
+            .enumerate()
+            .map(|(id, (sock, packet))| {
+                let proxy_addr = proxy.addr;
+                thread::spawn(move || {
+                    // The greedy client sends far more than the limit, the light ones pace
+                    // themselves
+                    let (burst, pause) = if id == 0 {
+                        (50, Duration::from_millis(1))
+                    } else {
+                        (
+                            1,
+                            Duration::from_secs_f64(PACKET_LEN as f64 / LIGHT_BPS as f64),
+                        )
+                    };
+                    let mut sent = 0;
+                    while Instant::now() < end {
+                        for _ in 0..burst {
+                            sock.send_to(&packet, proxy_addr).unwrap();
+                            sent += packet.len() as u64;
+                        }
+                        thread::sleep(pause);
+                    }
+                    sent
+                })
+            })
+            .collect();
+        let sent: Vec<u64> = senders
+            .into_iter()
+            .map(|sender| sender.join().unwrap())
+            .collect();
+        // Fair queueing holds packets back, so give the queues a moment to drain
+        thread::sleep(Duration::from_millis(200));
+
+        println!(
+            "{}",
+            if args.is_empty() {
+                "dropping packets over --max-bps:"
+            } else {
+                "--fair-queue:"
+            }
+        );
+        for (id, bytes) in received.iter().enumerate() {
+            let forwarded = bytes.load(Ordering::Relaxed) - before[id];
+            println!(
+                "  {:<14} sent {:>8.0} bytes/s, {:>8.0} forwarded ({:.0}%)",
+                if id == 0 {
+                    "greedy client"
+                } else {
+                    "light client"
+                },
+                sent[id] as f64 / DURATION.as_secs_f64(),
+                forwarded as f64 / DURATION.as_secs_f64(),
+                forwarded as f64 * 100.0 / sent[id] as f64
+            );
+        }
+    }
+}
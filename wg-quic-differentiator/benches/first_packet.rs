@@ -0,0 +1,47 @@
+//! Measures how long the first packet of a new connection takes to make a round trip through
+//! the proxy, with and without a pool of backend sockets. Run with
+//! `cargo bench --bench first_packet`.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// New clients per run, each of which opens a connection
+const CLIENTS: usize = 500;
+
+fn main() {
+    for pool_size in ["0", "16"] {
+        let wireguard = MockBackend::start();
+        let quic = MockBackend::start();
+        let proxy = Proxy::start(&wireguard, &quic, &["--socket-pool-size", pool_size]);
+        let packet = wireguard_handshake_initiation();
+
+        // Wait for the proxy to start, and for the pool to fill
+        exchange(&client(), &proxy, &packet);
+        thread::sleep(Duration::from_millis(100));
+
+        let mut round_trips = Vec::with_capacity(CLIENTS);
+        let mut buf = [0; 65536];
+        for _ in 0..CLIENTS {
+            let sock = client();
+            let start = Instant::now();
+            sock.send_to(&packet, proxy.addr).unwrap();
+            sock.recv_from(&mut buf).unwrap();
+            round_trips.push(start.elapsed());
+            // Leave the pool a moment to refill, as between the clients of a connection storm
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        round_trips.sort();
+        let percentile = |p: usize| round_trips[(CLIENTS - 1) * p / 100];
+        println!(
+            "socket pool size {pool_size:>2}: median {:?}, p90 {:?}, p99 {:?}",
+            percentile(50),
+            percentile(90),
+            percentile(99)
+        );
+    }
+}
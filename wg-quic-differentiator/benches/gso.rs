@@ -0,0 +1,69 @@
+//! Measures how many QUIC packets per second the proxy forwards to a backend, with and
+//! without segmented sends. Run with `cargo bench --bench gso`.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::*;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Packets sent per run, all on one connection
+const PACKETS: usize = 200_000;
+/// A typical QUIC packet, sized to fit a 1280 byte path MTU
+const PACKET_LEN: usize = 1200;
+
+fn main() {
+    for args in [&[][..], &["--gso"][..]] {
+        // A backend that only counts what it receives, so that it keeps up with the proxy
+        let backend = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 65536];
+            while backend.recv_from(&mut buf).is_ok() {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        let wireguard = MockBackend::start();
+        let mut proxy_args = vec!["--queue-depth", "1024", "--batch-recv"];
+        proxy_args.extend_from_slice(args);
+        let proxy = Proxy::start_at(wireguard.addr, backend_addr, &proxy_args);
+
+        // The counting backend never answers, so wait for the connection to be forwarded
+        let sock = client();
+        let mut packet = quic_short_header(&[0x11; 8]);
+        packet.resize(PACKET_LEN, 0xaa);
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        while received.load(Ordering::Relaxed) == 0 {
+            assert!(Instant::now() < deadline, "the proxy didn't start");
+            sock.send_to(&packet, proxy.addr).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let before = received.load(Ordering::Relaxed);
+        let start = Instant::now();
+        for _ in 0..PACKETS {
+            sock.send_to(&packet, proxy.addr).unwrap();
+        }
+        // Give the forwarding task a moment to drain its queue
+        thread::sleep(Duration::from_millis(200));
+        let forwarded = received.load(Ordering::Relaxed) - before;
+        let elapsed = start.elapsed() - Duration::from_millis(200);
+        println!(
+            "{:<12} {forwarded} of {PACKETS} packets forwarded, {:.0} packets/s",
+            if args.is_empty() {
+                "plain sends"
+            } else {
+                "--gso"
+            },
+            forwarded as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
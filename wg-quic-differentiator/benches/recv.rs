@@ -0,0 +1,70 @@
+//! Measures how many datagrams per second the listen socket's `Receiver` takes in, one
+//! `recv_from` at a time and batched with `recvmmsg` as with `--batch-recv`, while a few
+//! threads flood it over loopback. Run with `cargo bench --bench recv`.
+
+#[path = "../src/recv.rs"]
+#[allow(dead_code)]
+mod recv;
+
+use recv::Receiver;
+use std::net::UdpSocket as StdUdpSocket;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+const SENDERS: usize = 4;
+/// A typical QUIC packet, sized to fit a 1280 byte path MTU
+const PACKET_LEN: usize = 1200;
+const DURATION: Duration = Duration::from_secs(3);
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    for batched in [false, true] {
+        let sock = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_nonblocking(true).unwrap();
+        let addr = sock.local_addr().unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let senders: Vec<_> = (0..SENDERS)
+            .map(|_| {
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    let sock = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+                    let packet = [0xaa; PACKET_LEN];
+                    while !stop.load(Ordering::Relaxed) {
+                        let _ = sock.send_to(&packet, addr);
+                    }
+                })
+            })
+            .collect();
+
+        let (received, syscalls, elapsed) = runtime.block_on(async {
+            let sock = UdpSocket::from_std(sock).unwrap();
+            let mut receiver = Receiver::new(2048, batched);
+            assert_eq!(receiver.is_batched(), batched);
+            let (mut received, mut syscalls) = (0, 0);
+            let start = Instant::now();
+            while start.elapsed() < DURATION {
+                received += receiver.recv(&sock).await.unwrap();
+                syscalls += 1;
+            }
+            (received, syscalls, start.elapsed())
+        });
+        stop.store(true, Ordering::Relaxed);
+        senders
+            .into_iter()
+            .for_each(|sender| sender.join().unwrap());
+
+        println!(
+            "{}: {:.0} packets/s, {:.1} packets per call",
+            if batched { "recvmmsg" } else { "recv_from" },
+            received as f64 / elapsed.as_secs_f64(),
+            received as f64 / syscalls as f64
+        );
+    }
+}
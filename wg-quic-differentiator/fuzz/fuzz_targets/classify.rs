@@ -0,0 +1,27 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use wg_quic_differentiator::{PacketType, classify, dtls, quic, stun, wireguard_message};
+
+// The classifier must never panic, whatever a client sends
+fuzz_target!(|data: &[u8]| {
+    let packet_type = classify(data);
+
+    // Every type is decided by exactly one of the parsers, checked in this order
+    let expected = if wireguard_message(data).is_some() {
+        PacketType::Wireguard
+    } else if dtls::parse_dtls_record(data).is_some() {
+        PacketType::Dtls
+    } else if stun::parse_stun_message(data).is_some() {
+        PacketType::Stun
+    } else if quic::parse_quic_header(data).is_some() {
+        PacketType::Quic
+    } else {
+        PacketType::Unknown
+    };
+    assert_eq!(packet_type, expected);
+
+    if let Some(versions) = quic::offered_versions(data) {
+        versions.for_each(drop);
+    }
+});
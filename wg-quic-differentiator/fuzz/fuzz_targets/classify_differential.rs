@@ -0,0 +1,98 @@
+#![no_main]
+
+//! Compares the classifier against stricter parsers that decode the whole header. Anything
+//! they accept must be classified the same way, so a header that is well-formed all the way
+//! through can never be sent to the wrong backend.
+
+use libfuzzer_sys::fuzz_target;
+use wg_quic_differentiator::{PacketType, classify};
+
+fuzz_target!(|data: &[u8]| {
+    let packet_type = classify(data);
+    if strict_wireguard(data) {
+        assert_eq!(packet_type, PacketType::Wireguard, "{data:02x?}");
+    }
+    if strict_quic_v1_long_header(data) {
+        assert_eq!(packet_type, PacketType::Quic, "{data:02x?}");
+    }
+});
+
+/// A WireGuard message with every reserved byte zero and the exact size of its type
+fn strict_wireguard(data: &[u8]) -> bool {
+    let Some((&message_type, rest)) = data.split_first() else {
+        return false;
+    };
+    if rest.get(..3) != Some(&[0, 0, 0]) {
+        return false;
+    }
+    match message_type {
+        1 => data.len() == 148,
+        2 => data.len() == 92,
+        3 => data.len() == 64,
+        // A 16 byte header followed by at least the authentication tag
+        4 => data.len() >= 32 && (data.len() - 16).is_multiple_of(16),
+        _ => false,
+    }
+}
+
+/// A QUIC version 1 Initial, 0-RTT or Handshake packet whose header, including the token and
+/// length fields, decodes completely (RFC 9000, section 17.2)
+fn strict_quic_v1_long_header(data: &[u8]) -> bool {
+    let mut reader = Reader(data);
+    let Some(first) = reader.byte() else {
+        return false;
+    };
+    if first & 0xc0 != 0xc0 || reader.take(4) != Some(&[0, 0, 0, 1]) {
+        return false;
+    }
+    let long_type = (first & 0x30) >> 4;
+    if long_type == 3 {
+        // Retry packets have no length field
+        return false;
+    }
+    for _ in 0..2 {
+        match reader.byte() {
+            Some(len) if len <= 20 && reader.take(len as usize).is_some() => {}
+            _ => return false,
+        }
+    }
+    if long_type == 0 {
+        match reader.varint() {
+            Some(len) if reader.take(len as usize).is_some() => {}
+            _ => return false,
+        }
+    }
+    let packet_number_len = (first & 0x03) as u64 + 1;
+    match reader.varint() {
+        Some(len) => len >= packet_number_len && len as usize <= reader.0.len(),
+        None => false,
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    /// A variable-length integer (RFC 9000, section 16)
+    fn varint(&mut self) -> Option<u64> {
+        let first = self.byte()?;
+        let len = 1usize << (first >> 6);
+        let mut value = (first & 0x3f) as u64;
+        for &b in self.take(len - 1)? {
+            value = (value << 8) | b as u64;
+        }
+        Some(value)
+    }
+}
@@ -0,0 +1,173 @@
+//! A Unix socket for querying the live state of the proxy. Each line sent to it is a command,
+//! which is answered with one or more lines of text.
+
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::Proxy;
+use crate::metrics::{self, METRICS};
+
+/// Binds the admin socket at `path`, replacing a stale socket left by a previous run
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(path)
+}
+
+/// Answers commands on `listener` until the proxy shuts down
+pub async fn serve(listener: UnixListener, proxy: Arc<Proxy>) {
+    loop {
+        let stream = tokio::select! {
+            result = listener.accept() => match result {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::error!("Error accepting admin connection: {:?}", e);
+                    continue;
+                }
+            },
+            _ = proxy.shutdown.cancelled() => break,
+        };
+
+        let proxy = proxy.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &proxy).await {
+                log::debug!("Error handling admin connection: {:?}", e);
+            }
+        });
+    }
+
+    if let Ok(addr) = listener.local_addr()
+        && let Some(path) = addr.as_pathname()
+    {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+async fn handle_connection(stream: UnixStream, proxy: &Proxy) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let mut words = line.split_whitespace();
+        let response = match (words.next(), words.next(), words.next()) {
+            (None, _, _) => continue,
+            (Some("list"), None, _) => list(proxy).await,
+            (Some("stats"), None, _) => stats(proxy),
+            (Some("backends"), None, _) => backends(proxy).await,
+            (Some("drain"), Some(address), None) => drain(proxy, address, true),
+            (Some("undrain"), Some(address), None) => drain(proxy, address, false),
+            _ => format!(
+                "unknown command {:?}, expected list, stats, backends, drain <backend> or undrain <backend>\n",
+                line.trim()
+            ),
+        };
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// One line per active connection, ending with its smoothed round trip time to the backend in
+/// milliseconds, or `-` before the backend has answered
+async fn list(proxy: &Proxy) -> String {
+    let mut connections = proxy.connections.snapshot().await;
+    connections.sort_by_key(|(key, _)| *key);
+
+    let mut out = String::from(
+        "client type age_secs idle_secs bytes_to_backend bytes_to_client packets_to_backend packets_to_client dropped rtt_ms\n",
+    );
+    for (_, connection) in connections {
+        let stats = &connection.stats;
+        let _ = writeln!(
+            out,
+            "{} {} {} {} {} {} {} {} {} {}",
+            connection.client.load(),
+            connection.packet_type.label(),
+            stats.age().as_secs(),
+            stats.idle().as_secs(),
+            stats.bytes_to_backend.get(),
+            stats.bytes_to_client.get(),
+            stats.packets_to_backend.get(),
+            stats.packets_to_client.get(),
+            stats.dropped.get(),
+            match stats.smoothed_rtt() {
+                Some(rtt) => format!("{:.1}", rtt.as_secs_f64() * 1000.0),
+                None => "-".to_string(),
+            }
+        );
+    }
+    out
+}
+
+/// One line per backend, with the address it currently resolves to, whether it is up or
+/// draining, and how many connections it has
+async fn backends(proxy: &Proxy) -> String {
+    let connections = proxy.connections.snapshot().await;
+    let live = proxy.live.load_full();
+    let mut out = String::from("type backend address up draining connections\n");
+    for (packet_type, backend) in live.backends.all() {
+        let active = connections
+            .iter()
+            .filter(|(_, connection)| {
+                connection.packet_type == packet_type && connection.backend == backend.address
+            })
+            .count();
+        let _ = writeln!(
+            out,
+            "{} {} {} {} {} {}",
+            packet_type.label(),
+            backend.address,
+            backend.addr(),
+            backend.health.is_up(),
+            backend.is_draining(),
+            active
+        );
+    }
+    out
+}
+
+/// Stops or resumes sending new connections to the backends configured as `address`. Their
+/// existing connections are left alone, so the `connections` column of `backends` shows when
+/// they are done.
+fn drain(proxy: &Proxy, address: &str, draining: bool) -> String {
+    let mut out = String::new();
+    for (packet_type, backend) in proxy.live.load().backends.all() {
+        if *backend.address == *address {
+            backend.set_draining(draining);
+            log::info!(
+                backend = address, packet_type = packet_type.label();
+                "{} {} backend {}",
+                if draining { "Draining" } else { "No longer draining" },
+                packet_type.label(),
+                address
+            );
+            let _ = writeln!(out, "{} {} {}", packet_type.label(), address, draining);
+        }
+    }
+    if out.is_empty() {
+        out = format!("no backend {address:?}, see backends for their names\n");
+    }
+    out
+}
+
+/// The same counters as the metrics endpoint, without the Prometheus comments
+fn stats(proxy: &Proxy) -> String {
+    let mut out = format!(
+        "connections {}\ntasks {}\n",
+        proxy.connections.len(),
+        proxy.tasks.len()
+    );
+    let listeners = metrics::render_listeners(&proxy.listeners);
+    for line in METRICS.render().lines().chain(listeners.lines()) {
+        if !line.starts_with('#') {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
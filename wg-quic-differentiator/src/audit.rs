@@ -0,0 +1,102 @@
+//! An audit trail of routing decisions, with `--audit-log`: one JSON object per line for every
+//! new connection, saying when it was opened and from where, how its first packet was
+//! classified and why, and which backend it went to. Unlike the operational logs, nothing
+//! else goes in it, and it is only ever appended to. Like captures, it is written in the
+//! background.
+
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::metrics::METRICS;
+use crate::throttle::Throttle;
+
+/// Entries waiting to be written before new ones are left out
+const BACKLOG: usize = 4096;
+
+/// Queues entries for the writer thread
+pub struct AuditLog {
+    tx: mpsc::Sender<Line>,
+}
+
+/// What is recorded about a new connection
+#[derive(Serialize)]
+pub struct Entry {
+    pub client_addr: SocketAddr,
+    /// The listen address the connection's first packet arrived on
+    pub listener: SocketAddr,
+    pub packet_type: &'static str,
+    /// Why the connection went to its backend: how its first packet was classified, or that
+    /// it failed over or was restored from the state file
+    pub reason: String,
+    /// The backend as configured
+    pub backend: String,
+    /// The address it resolved to
+    pub backend_addr: SocketAddr,
+}
+
+#[derive(Serialize)]
+struct Line {
+    /// When the connection was opened, in RFC 3339 format
+    timestamp: String,
+    #[serde(flatten)]
+    entry: Entry,
+}
+
+impl AuditLog {
+    /// Opens the file at `path` for appending, creating it if needed, and starts writing to it
+    /// in the background
+    pub fn open(path: &Path) -> io::Result<AuditLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("failed to open audit log {}: {e}", path.display()),
+                )
+            })?;
+        let mut writer = BufWriter::new(file);
+
+        let (tx, mut rx) = mpsc::channel::<Line>(BACKLOG);
+        std::thread::spawn(move || {
+            while let Some(line) = rx.blocking_recv() {
+                // Flush whenever the backlog is written, so no entry waits for the next one
+                if let Err(e) = write_line(&mut writer, &line, rx.is_empty()) {
+                    log::error!("Error writing to audit log, stopping it: {}", e);
+                    return;
+                }
+            }
+        });
+        Ok(AuditLog { tx })
+    }
+
+    /// Adds an entry for a connection opened just now, unless the writer has fallen behind
+    pub fn record(&self, entry: Entry) {
+        let line = Line {
+            timestamp: jiff::Timestamp::now().to_string(),
+            entry,
+        };
+        if self.tx.try_send(line).is_err() {
+            static BACKLOG_WARNING: Throttle = Throttle::new(Duration::from_secs(10));
+            METRICS.audit_log_dropped.inc();
+            if BACKLOG_WARNING.allow() {
+                log::warn!("Audit log can't keep up, leaving connections out of it");
+            }
+        }
+    }
+}
+
+fn write_line<W: Write>(writer: &mut W, line: &Line, flush: bool) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, line)?;
+    writer.write_all(b"\n")?;
+    if flush {
+        writer.flush()?;
+    }
+    Ok(())
+}
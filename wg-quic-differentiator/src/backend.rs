@@ -0,0 +1,337 @@
+use arc_swap::ArcSwap;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use crate::PacketType;
+use crate::config::{Config, EgressMode};
+use crate::egress::SharedSocket;
+use crate::fair_queue::FairQueue;
+use crate::health::Health;
+use crate::ratelimit::RateLimiter;
+use crate::socket::{EgressOptions, SocketPool};
+use wg_quic_differentiator::BackendSelector;
+
+/// A backend given as host:port, whose hostname is periodically resolved again so that new
+/// connections follow address changes (such as a container being recreated).
+pub struct Backend {
+    name: &'static str,
+    pub address: Arc<str>,
+    current: Arc<ArcSwap<SocketAddr>>,
+    pub limiter: Option<Arc<RateLimiter>>,
+    /// Shares the rate limit out between clients instead, with `--fair-queue`
+    pub fair_queue: Option<Arc<FairQueue>>,
+    /// Sockets connected ahead of time, with `--socket-pool-size`
+    pub sockets: Option<Arc<SocketPool>>,
+    /// The socket the backend's QUIC connections share, with `--quic-egress shared`
+    pub shared: Option<Arc<SharedSocket>>,
+    pub health: Arc<Health>,
+    /// Set with the admin socket's `drain`, so no new connections go to the backend while
+    /// existing ones carry on
+    draining: AtomicBool,
+}
+
+impl Backend {
+    async fn resolve(name: &'static str, address: &str, config: &Config) -> io::Result<Backend> {
+        let resolved = lookup(address).await.map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid {name} backend address {address:?}: {e}"),
+            )
+        })?;
+        let current = Arc::new(ArcSwap::from_pointee(resolved));
+        let address: Arc<str> = address.into();
+        let limiter = RateLimiter::new(config.max_pps, config.max_bps);
+        let (limiter, fair_queue) = match limiter {
+            Some(limiter) if config.fair_queue => (None, Some(FairQueue::new(limiter))),
+            limiter => (limiter, None),
+        };
+        let pool_size = config.socket_pool_size;
+        Ok(Backend {
+            name,
+            sockets: (pool_size > 0).then(|| Arc::new(SocketPool::new(current.clone(), pool_size))),
+            shared: None,
+            health: Arc::new(Health::new(address.clone(), current.clone())),
+            address,
+            current,
+            limiter: limiter.map(Arc::new),
+            fair_queue: fair_queue.map(Arc::new),
+            draining: AtomicBool::new(false),
+        })
+    }
+
+    /// Shares a socket between the connections to the backend, instead of pooling sockets
+    fn share_socket(&mut self, options: EgressOptions<'_>) -> io::Result<()> {
+        self.sockets = None;
+        self.shared = Some(Arc::new(SharedSocket::bind(self.addr(), options)?));
+        Ok(())
+    }
+
+    /// The most recently resolved address of the backend
+    pub fn addr(&self) -> SocketAddr {
+        **self.current.load()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    async fn refresh(&self) {
+        match lookup(&self.address).await {
+            Ok(resolved) => {
+                let previous = self.current.swap(Arc::new(resolved));
+                if *previous != resolved {
+                    log::info!(
+                        "Backend {} now resolves to {} (was {})",
+                        self.address,
+                        resolved,
+                        previous
+                    );
+                }
+            }
+            Err(e) => log::warn!(
+                "Failed to resolve backend {}, keeping {}: {}",
+                self.address,
+                self.addr(),
+                e
+            ),
+        }
+    }
+}
+
+fn quic_egress_options(config: &Config) -> EgressOptions<'_> {
+    EgressOptions {
+        interface: config.egress_interface.as_deref(),
+        dscp: config.dscp(PacketType::Quic),
+        ttl: config.ttl(PacketType::Quic),
+        no_fragment: config.no_fragment,
+        buffers: config.buffer_sizes(),
+        source: None,
+    }
+}
+
+async fn lookup(address: &str) -> io::Result<SocketAddr> {
+    tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "did not resolve to any address"))
+}
+
+/// The backends for each packet type, which is empty if that type isn't forwarded anywhere
+pub struct Backends {
+    backends: [Vec<Backend>; PacketType::ALL.len()],
+    /// QUIC backends for the server names clients ask for, with `--route-by-sni`
+    by_server_name: HashMap<String, Backend>,
+    selector: Arc<dyn BackendSelector>,
+}
+
+impl Backends {
+    /// Resolves all configured backends, so that a typo fails at startup rather than on the
+    /// first forwarded packet. New connections are spread over them by `selector`.
+    pub async fn resolve(
+        config: &Config,
+        selector: Arc<dyn BackendSelector>,
+    ) -> io::Result<Backends> {
+        let mut backends = [const { Vec::new() }; PacketType::ALL.len()];
+        for packet_type in PacketType::ALL {
+            let name = match packet_type {
+                PacketType::Unknown => "unknown traffic",
+                _ => packet_type.label(),
+            };
+            let addresses = config.backends(packet_type);
+            let required = matches!(packet_type, PacketType::Wireguard | PacketType::Quic);
+            if addresses.is_empty() && required {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("at least one {name} backend is required"),
+                ));
+            }
+            for address in addresses {
+                let mut backend = Backend::resolve(name, address, config).await?;
+                if packet_type == PacketType::Quic && config.quic_egress == EgressMode::Shared {
+                    backend.share_socket(quic_egress_options(config))?;
+                }
+                backends[packet_type as usize].push(backend);
+            }
+        }
+        let mut by_server_name = HashMap::new();
+        for route in &config.route_by_sni {
+            let Some((name, address)) = route.split_once('=') else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid SNI route {route:?}, expected name=host:port"),
+                ));
+            };
+            let mut backend = Backend::resolve("quic", address, config).await?;
+            if config.quic_egress == EgressMode::Shared {
+                backend.share_socket(quic_egress_options(config))?;
+            }
+            by_server_name.insert(name.to_ascii_lowercase(), backend);
+        }
+        Ok(Backends {
+            backends,
+            by_server_name,
+            selector,
+        })
+    }
+
+    /// The selector new connections are spread over the backends by, which a reload that keeps
+    /// `--backend-selection` carries over
+    pub fn selector(&self) -> Arc<dyn BackendSelector> {
+        self.selector.clone()
+    }
+
+    /// Picks the backend for a new connection from `client`. The selector picks one of the
+    /// backends for the type that are up, or from all of them if none are, even when there is
+    /// only one, so that it can drop the packet instead.
+    pub fn get(&self, packet_type: PacketType, client: &SocketAddr) -> Option<&Backend> {
+        let backends = &self.backends[packet_type as usize];
+        if backends.is_empty() {
+            return None;
+        }
+        let open = |backend: &Backend| !backend.is_draining();
+        let up = |backend: &Backend| open(backend) && backend.health.is_up();
+        // Backends that are up are preferred. Draining ones are only picked when all of them
+        // are draining, and the caller then drops the packet.
+        let eligible: &dyn Fn(&Backend) -> bool =
+            match backends.iter().filter(|backend| up(backend)).count() {
+                0 if backends.iter().any(open) => &open,
+                0 => &|_| true,
+                _ => &up,
+            };
+        let candidates: Vec<&Backend> = backends
+            .iter()
+            .filter(|backend| eligible(backend))
+            .collect();
+        let addrs: Vec<SocketAddr> = candidates.iter().map(|backend| backend.addr()).collect();
+        let selected = self.selector.select(client, packet_type, &addrs)?;
+        let index = addrs.iter().position(|addr| *addr == selected)?;
+        Some(candidates[index])
+    }
+
+    /// The QUIC backend for clients asking for the server `name`, if it has one of its own
+    pub fn by_server_name(&self, name: &str) -> Option<&Backend> {
+        self.by_server_name.get(name)
+    }
+
+    /// Whether any QUIC clients are routed by the server name they ask for
+    pub fn routes_by_server_name(&self) -> bool {
+        !self.by_server_name.is_empty()
+    }
+
+    /// The backend of the given type configured as `address`, if it still is
+    pub fn find(&self, packet_type: PacketType, address: &str) -> Option<&Backend> {
+        self.all()
+            .find(|(backend_type, backend)| {
+                *backend_type == packet_type && *backend.address == *address
+            })
+            .map(|(_, backend)| backend)
+    }
+
+    /// The socket pool of every backend that has one, with the type of packets it is for
+    pub fn socket_pools(&self) -> impl Iterator<Item = (PacketType, &Arc<SocketPool>)> {
+        self.all()
+            .filter_map(|(packet_type, backend)| Some((packet_type, backend.sockets.as_ref()?)))
+    }
+
+    /// The shared socket of every backend that has one
+    pub fn shared_sockets(&self) -> impl Iterator<Item = &Arc<SharedSocket>> {
+        self.all()
+            .filter_map(|(_, backend)| backend.shared.as_ref())
+    }
+
+    /// The fair queue of every backend that has one
+    pub fn fair_queues(&self) -> impl Iterator<Item = &Arc<FairQueue>> {
+        self.all()
+            .filter_map(|(_, backend)| backend.fair_queue.as_ref())
+    }
+
+    /// Every backend, along with the type of packets it is for
+    pub fn all(&self) -> impl Iterator<Item = (PacketType, &Backend)> {
+        PacketType::ALL
+            .into_iter()
+            .zip(&self.backends)
+            .flat_map(|(packet_type, backends)| {
+                backends.iter().map(move |backend| (packet_type, backend))
+            })
+            .chain(
+                self.by_server_name
+                    .values()
+                    .map(|backend| (PacketType::Quic, backend)),
+            )
+    }
+
+    /// Checks that every backend still resolves, returning a description of each one that
+    /// doesn't
+    pub async fn check(&self) -> Vec<String> {
+        let mut failures = Vec::new();
+        for (_, backend) in self.all() {
+            if let Err(e) = lookup(&backend.address).await {
+                failures.push(format!(
+                    "{} backend {} is unreachable: {e}",
+                    backend.name, backend.address
+                ));
+            }
+        }
+        failures
+    }
+
+    /// Resolves every backend again each `interval`, until `shutdown` is cancelled
+    pub async fn refresh_periodically(&self, interval: Duration, shutdown: CancellationToken) {
+        let mut interval = tokio::time::interval(interval);
+        // The first tick completes immediately, and we just resolved everything
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.cancelled() => break,
+            }
+            for (_, backend) in self.all() {
+                backend.refresh().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// Drops every new connection
+    struct Closed;
+
+    impl BackendSelector for Closed {
+        fn select(&self, _: &SocketAddr, _: PacketType, _: &[SocketAddr]) -> Option<SocketAddr> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn asks_the_selector_even_for_a_single_backend() {
+        let config = Config::parse_from([
+            "wg-quic-differentiator",
+            "--wireguard-backend",
+            "127.0.0.1:51820",
+            "--quic-backend",
+            "127.0.0.1:8443",
+        ]);
+        let client = "192.0.2.1:1234".parse().unwrap();
+        let backends = Backends::resolve(&config, Arc::new(Closed)).await.unwrap();
+        assert!(backends.get(PacketType::Wireguard, &client).is_none());
+
+        let backends = Backends::resolve(&config, config.backend_selection.selector())
+            .await
+            .unwrap();
+        let backend = backends.get(PacketType::Wireguard, &client).unwrap();
+        assert_eq!(backend.addr(), "127.0.0.1:51820".parse().unwrap());
+    }
+}
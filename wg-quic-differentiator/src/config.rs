@@ -0,0 +1,965 @@
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use wg_quic_differentiator::quic::MIN_PATH_MTU;
+use wg_quic_differentiator::selector::{self, BackendSelector};
+
+use crate::PacketType;
+use crate::connections::ClientKey;
+use crate::health::Probe;
+use crate::logging::LogFormat;
+use crate::queue::DropPolicy;
+use crate::socket::BufferSizes;
+
+const SERVER_ADDR: &str = "0.0.0.0:8080";
+// const WIREGUARD_SERVER_ADDR: &str = "wireguard:51820";
+// const QUIC_SERVER_ADDR: &str = "http3-server:8443";
+const WIREGUARD_SERVER_ADDR: &str = "localhost:51820";
+const QUIC_SERVER_ADDR: &str = "localhost:8443";
+const CONNECTION_TIMEOUT_SECS: u64 = 30;
+const RESOLVE_INTERVAL_SECS: u64 = 30;
+const STATE_INTERVAL_SECS: u64 = 30;
+const FORWARD_TIMEOUT_MILLIS: u64 = 1000;
+const SHUTDOWN_GRACE_SECS: u64 = 5;
+const MAX_DATAGRAM_SIZE: usize = 65536;
+const DUMP_BYTES: usize = 32;
+const QUEUE_DEPTH: usize = 100;
+/// The settings a SIGHUP takes from the reloaded config; changes to the others need a restart
+const RELOADABLE: &[&str] = &[
+    "wireguard_backend",
+    "quic_backend",
+    "route_by_sni",
+    "forward_unknown_to",
+    "dtls_backend",
+    "stun_backend",
+    "backend_selection",
+    "max_pps",
+    "max_bps",
+    "fair_queue",
+    "connection_timeout",
+    "wireguard_timeout",
+    "quic_timeout",
+    "response_timeout",
+    "forward_timeout",
+    "max_connection_lifetime",
+    "max_response_bps",
+    "max_packet_rate_per_source",
+    "max_byte_rate_per_source",
+    "allow_cidr",
+    "deny_cidr",
+];
+/// The reloadable settings the backends are set up from, which are set up again when any of
+/// them changes
+const BACKEND_SETTINGS: &[&str] = &[
+    "wireguard_backend",
+    "quic_backend",
+    "route_by_sni",
+    "forward_unknown_to",
+    "dtls_backend",
+    "stun_backend",
+    "backend_selection",
+    "max_pps",
+    "max_bps",
+    "fair_queue",
+];
+/// The socket buffer sizes that can be asked for
+const SOCKET_BUFFER_SIZES: std::ops::RangeInclusive<usize> = 4096..=1 << 30;
+
+/// UDP proxy that forwards WireGuard and QUIC traffic arriving on a single port to separate backends.
+///
+/// Every option can also be set through its environment variable, or by its field name in the
+/// config file. Command line flags take precedence over environment variables, which take
+/// precedence over the config file.
+#[derive(Parser, Serialize, Deserialize, Debug, Clone)]
+#[command(version, about)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Path to a TOML config file
+    #[arg(long, env = "WGQ_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    /// Classify the raw packet in this file, print its type and the reason, and exit without
+    /// binding any sockets
+    #[arg(long)]
+    #[serde(skip)]
+    pub classify_file: Option<PathBuf>,
+
+    /// Address to listen on for incoming packets. Can be given multiple times (or comma
+    /// separated) to listen on several addresses or ports at once.
+    #[arg(long, env = "WGQ_LISTEN", default_value = SERVER_ADDR, value_delimiter = ',')]
+    #[serde(deserialize_with = "one_or_many")]
+    pub listen: Vec<SocketAddr>,
+
+    /// Only accept packets arriving on this network interface (Linux only, needs CAP_NET_RAW)
+    #[arg(long, env = "WGQ_LISTEN_INTERFACE")]
+    pub listen_interface: Option<String>,
+
+    /// Attach an XDP program to this network interface, the one clients arrive on. Needs a
+    /// build with the `xdp` feature, on Linux, and CAP_BPF and CAP_NET_ADMIN. Where it can't
+    /// be attached, packets are classified in userspace as without it.
+    #[arg(long, env = "WGQ_XDP_INTERFACE")]
+    pub xdp_interface: Option<String>,
+
+    /// Send packets to the backends out of this network interface, such as a specific NIC on
+    /// a multi-homed host (Linux only, needs CAP_NET_RAW)
+    #[arg(long, env = "WGQ_EGRESS_INTERFACE")]
+    pub egress_interface: Option<String>,
+
+    /// Send packets to the backends from the client's own address and port rather than the
+    /// proxy's, so backends see who they talk to. Linux only, needs CAP_NET_ADMIN and routing
+    /// that brings the backends' responses back through this host
+    #[arg(long, env = "WGQ_TRANSPARENT")]
+    pub transparent: bool,
+
+    /// DSCP value (0-63) to mark packets forwarded to WireGuard backends with, so downstream
+    /// QoS can prioritize them
+    #[arg(long, env = "WGQ_WIREGUARD_DSCP", value_parser = clap::value_parser!(u8).range(..64))]
+    pub wireguard_dscp: Option<u8>,
+
+    /// DSCP value (0-63) to mark packets forwarded to QUIC backends with
+    #[arg(long, env = "WGQ_QUIC_DSCP", value_parser = clap::value_parser!(u8).range(..64))]
+    pub quic_dscp: Option<u8>,
+
+    /// IP TTL (IPv6 hop limit) of the packets forwarded to the backends, instead of the
+    /// system default
+    #[arg(long, env = "WGQ_FORWARD_TTL", value_parser = clap::value_parser!(u8).range(1..))]
+    pub forward_ttl: Option<u8>,
+
+    /// TTL of the packets forwarded to WireGuard backends, if different from --forward-ttl
+    #[arg(long, env = "WGQ_WIREGUARD_TTL", value_parser = clap::value_parser!(u8).range(1..))]
+    pub wireguard_ttl: Option<u8>,
+
+    /// TTL of the packets forwarded to QUIC backends, if different from --forward-ttl
+    #[arg(long, env = "WGQ_QUIC_TTL", value_parser = clap::value_parser!(u8).range(1..))]
+    pub quic_ttl: Option<u8>,
+
+    /// Set the don't fragment bit on the packets forwarded to the backends, so that a packet
+    /// larger than the path MTU is refused, and logged, instead of fragmented (Linux only)
+    #[arg(long, env = "WGQ_NO_FRAGMENT")]
+    pub no_fragment: bool,
+
+    /// SOCKS5 proxy (host:port) to reach the WireGuard backends through, with a UDP
+    /// association per connection. Packets go to the backends directly when not set.
+    #[arg(long, env = "WGQ_WIREGUARD_SOCKS5")]
+    pub wireguard_socks5: Option<String>,
+
+    /// SOCKS5 proxy (host:port) to reach the QUIC backends through
+    #[arg(long, env = "WGQ_QUIC_SOCKS5")]
+    pub quic_socks5: Option<String>,
+
+    /// Address (host:port) of the WireGuard server. Can be given multiple times (or comma
+    /// separated) to spread clients over several servers by their address.
+    #[arg(long, env = "WGQ_WIREGUARD_BACKEND", default_value = WIREGUARD_SERVER_ADDR, value_delimiter = ',')]
+    #[serde(deserialize_with = "one_or_many")]
+    pub wireguard_backend: Vec<String>,
+
+    /// How new connections pick one of several backends of their type: by a hash of the
+    /// client's address, so each client sticks to one, in turn, or at random
+    #[arg(long, env = "WGQ_BACKEND_SELECTION", value_enum, default_value_t = BackendSelection::Hash)]
+    pub backend_selection: BackendSelection,
+
+    /// Address (host:port) of the QUIC/HTTP3 server. Can be given multiple times (or comma
+    /// separated) to spread clients over several servers by their address.
+    #[arg(long, env = "WGQ_QUIC_BACKEND", default_value = QUIC_SERVER_ADDR, value_delimiter = ',')]
+    #[serde(deserialize_with = "one_or_many")]
+    pub quic_backend: Vec<String>,
+
+    /// Route QUIC clients asking for a server name to a backend of its own, as
+    /// name=host:port, by decrypting their first Initial packet. Clients asking for any
+    /// other name (or none) go to --quic-backend. Can be given multiple times
+    #[arg(long, env = "WGQ_ROUTE_BY_SNI", value_delimiter = ',')]
+    pub route_by_sni: Vec<String>,
+
+    /// Address (host:port) to forward packets that are neither WireGuard nor QUIC to, such as a
+    /// honeypot; they are dropped when not set
+    #[arg(long, env = "WGQ_FORWARD_UNKNOWN_TO")]
+    pub forward_unknown_to: Option<String>,
+
+    /// Log the client and first bytes of every packet that is dropped for not being
+    /// recognised, at most once per source IP every 10 seconds
+    #[arg(long, env = "WGQ_REJECT_UNKNOWN")]
+    pub reject_unknown: bool,
+
+    /// Address (host:port) to forward TCP connections to the listen address to, for clients
+    /// that fall back from QUIC to TCP; no TCP port is opened when not set
+    #[arg(long, env = "WGQ_TCP_BACKEND")]
+    pub tcp_backend: Option<String>,
+
+    /// Address (host:port) of a collector that gets a copy of every QUIC packet forwarded to
+    /// a backend, sent on a best-effort basis
+    #[arg(long, env = "WGQ_MIRROR_QUIC")]
+    pub mirror_quic: Option<String>,
+
+    /// Like --mirror-quic, for WireGuard packets
+    #[arg(long, env = "WGQ_MIRROR_WIREGUARD")]
+    pub mirror_wireguard: Option<String>,
+
+    /// Address (host:port) to forward DTLS packets to; they are dropped when not set
+    #[arg(long, env = "WGQ_DTLS_BACKEND")]
+    pub dtls_backend: Option<String>,
+
+    /// Address (host:port) to forward STUN and TURN packets to; they are dropped when not set
+    #[arg(long, env = "WGQ_STUN_BACKEND")]
+    pub stun_backend: Option<String>,
+
+    /// Seconds of inactivity after which a connection is closed
+    #[arg(long, env = "WGQ_CONNECTION_TIMEOUT", default_value_t = CONNECTION_TIMEOUT_SECS)]
+    pub connection_timeout: u64,
+
+    /// Idle timeout in seconds for WireGuard connections, if different from --connection-timeout
+    #[arg(long, env = "WGQ_WIREGUARD_TIMEOUT")]
+    pub wireguard_timeout: Option<u64>,
+
+    /// Idle timeout in seconds for QUIC connections, if different from --connection-timeout
+    #[arg(long, env = "WGQ_QUIC_TIMEOUT")]
+    pub quic_timeout: Option<u64>,
+
+    /// Seconds a connection may go without a single response from its backend before it is
+    /// closed, however active the client is. Not set by default
+    #[arg(long, env = "WGQ_RESPONSE_TIMEOUT")]
+    pub response_timeout: Option<u64>,
+
+    /// Milliseconds sending a packet to a backend may take before the packet is dropped and
+    /// the connection closed, in case a socket gets stuck
+    #[arg(long, env = "WGQ_FORWARD_TIMEOUT", default_value_t = FORWARD_TIMEOUT_MILLIS)]
+    pub forward_timeout: u64,
+
+    /// Seconds to wait on shutdown for connections to flush what their clients sent, and for
+    /// TCP connections to finish, before the remaining ones are aborted
+    #[arg(long, env = "WGQ_SHUTDOWN_GRACE", default_value_t = SHUTDOWN_GRACE_SECS)]
+    pub shutdown_grace: u64,
+
+    /// Seconds after which a connection is closed however active it is, so that its client
+    /// picks up changes to the backend addresses and health with its next packet. Not set by
+    /// default
+    #[arg(long, env = "WGQ_MAX_CONNECTION_LIFETIME")]
+    pub max_connection_lifetime: Option<u64>,
+
+    /// Seconds between resolving the backend hostnames again; existing connections keep the
+    /// address they were created with. 0 disables this
+    #[arg(long, env = "WGQ_RESOLVE_INTERVAL", default_value_t = RESOLVE_INTERVAL_SECS)]
+    pub resolve_interval: u64,
+
+    /// Largest datagram in bytes that is forwarded in either direction; larger ones are
+    /// dropped as truncated. Lowering this saves memory per connection, down to 1200 bytes.
+    #[arg(long, env = "WGQ_MAX_DATAGRAM_SIZE", default_value_t = MAX_DATAGRAM_SIZE)]
+    pub max_datagram_size: usize,
+
+    /// Receive buffer size in bytes of the listen sockets and the sockets to the backends,
+    /// instead of the system default. The kernel may grant less (on Linux, at most
+    /// net.core.rmem_max).
+    #[arg(long, env = "WGQ_SO_RCVBUF")]
+    pub so_rcvbuf: Option<usize>,
+
+    /// Send buffer size in bytes of the listen sockets and the sockets to the backends,
+    /// instead of the system default. The kernel may grant less (on Linux, at most
+    /// net.core.wmem_max).
+    #[arg(long, env = "WGQ_SO_SNDBUF")]
+    pub so_sndbuf: Option<usize>,
+
+    /// How many bytes of each packet from a client are logged at debug level. At trace level
+    /// packets are logged in full.
+    #[arg(long, env = "WGQ_DUMP_BYTES", default_value_t = DUMP_BYTES)]
+    pub dump_bytes: usize,
+
+    /// Only classify and log incoming packets, without forwarding any of them
+    #[arg(long, env = "WGQ_CLASSIFY_ONLY")]
+    pub classify_only: bool,
+
+    /// Receive several packets per syscall using recvmmsg, where the platform supports it
+    #[arg(long, env = "WGQ_BATCH_RECV")]
+    pub batch_recv: bool,
+
+    /// Send packets of the same size queued for a backend in one syscall using UDP_SEGMENT,
+    /// where the platform supports it
+    #[arg(long, env = "WGQ_GSO")]
+    pub gso: bool,
+
+    /// Maximum number of simultaneous connections; packets from new clients are dropped beyond this
+    #[arg(long, env = "WGQ_MAX_CONNECTIONS")]
+    pub max_connections: Option<usize>,
+
+    /// Maximum number of simultaneous connections from a single source IP
+    #[arg(long, env = "WGQ_MAX_CONNECTIONS_PER_IP")]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// What to do with a new client when --max-connections is reached
+    #[arg(long, env = "WGQ_ON_CONNECTION_LIMIT", value_enum, default_value_t = ConnectionLimit::Reject)]
+    pub on_connection_limit: ConnectionLimit,
+
+    /// Only proxy clients in this network (such as 10.0.0.0/8). Can be given multiple times;
+    /// all clients are allowed when not set.
+    #[arg(long, env = "WGQ_ALLOW_CIDR", value_delimiter = ',')]
+    pub allow_cidr: Vec<IpNet>,
+
+    /// Never proxy clients in this network, even if they are allowed by --allow-cidr. Can be
+    /// given multiple times.
+    #[arg(long, env = "WGQ_DENY_CIDR", value_delimiter = ',')]
+    pub deny_cidr: Vec<IpNet>,
+
+    /// Prepend a PROXY protocol v2 header with the client's address to packets forwarded to the
+    /// backends of this protocol. Can be given multiple times.
+    #[arg(long, env = "WGQ_PROXY_PROTOCOL", value_enum, value_delimiter = ',')]
+    pub proxy_protocol: Vec<PacketType>,
+
+    /// Remove a PROXY protocol header from the start of responses from those backends, for
+    /// backends that echo it. Responses are passed through unmodified otherwise
+    #[arg(long, env = "WGQ_STRIP_PROXY_PROTOCOL")]
+    pub strip_proxy_protocol: bool,
+
+    /// Most packets per second forwarded to each backend; packets beyond it are dropped, or
+    /// held back with --fair-queue
+    #[arg(long, env = "WGQ_MAX_PPS")]
+    pub max_pps: Option<u64>,
+
+    /// Most bytes per second forwarded to each backend; packets beyond it are dropped, or held
+    /// back with --fair-queue
+    #[arg(long, env = "WGQ_MAX_BPS")]
+    pub max_bps: Option<u64>,
+
+    /// Share --max-bps and --max-pps fairly between source IPs, holding packets over the limit
+    /// back until it is their turn instead of dropping them
+    #[arg(long, env = "WGQ_FAIR_QUEUE")]
+    pub fair_queue: bool,
+
+    /// Most bytes per second sent back to the client of each connection
+    #[arg(long, env = "WGQ_MAX_RESPONSE_BPS")]
+    pub max_response_bps: Option<u64>,
+
+    /// What to do with responses beyond --max-response-bps
+    #[arg(long, env = "WGQ_ON_RESPONSE_LIMIT", value_enum, default_value_t = ResponseLimit::Drop)]
+    pub on_response_limit: ResponseLimit,
+
+    /// Most packets per second accepted from each source IP for connections whose backend
+    /// hasn't responded yet, so a spoofed source can't have the proxy flood its real owner
+    /// with handshake responses. Established connections aren't limited by this
+    #[arg(long, env = "WGQ_MAX_PACKET_RATE_PER_SOURCE")]
+    pub max_packet_rate_per_source: Option<u64>,
+
+    /// Like --max-packet-rate-per-source, but in bytes per second
+    #[arg(long, env = "WGQ_MAX_BYTE_RATE_PER_SOURCE")]
+    pub max_byte_rate_per_source: Option<u64>,
+
+    /// Packets queued per connection while its backend is busy, before packets are dropped
+    #[arg(long, env = "WGQ_QUEUE_DEPTH", default_value_t = QUEUE_DEPTH)]
+    pub queue_depth: usize,
+
+    /// Which packet to drop when a connection's queue is full
+    #[arg(long, env = "WGQ_DROP_POLICY", value_enum, default_value_t = DropPolicy::Newest)]
+    pub drop_policy: DropPolicy,
+
+    /// Sockets kept connected to each backend ahead of time, so the first packet of a new
+    /// connection doesn't wait for one to be set up. 0 disables this
+    #[arg(long, env = "WGQ_SOCKET_POOL_SIZE", default_value_t = 0)]
+    pub socket_pool_size: usize,
+
+    /// Give every QUIC connection a socket of its own, or share one socket per QUIC backend,
+    /// routing the responses by the client's connection ID
+    #[arg(long, env = "WGQ_QUIC_EGRESS", value_enum, default_value_t = EgressMode::PerClient)]
+    pub quic_egress: EgressMode,
+
+    /// Track WireGuard clients by their full address, or by IP address only so that a client
+    /// changing ports (as mobile clients behind NAT do) keeps its backend socket
+    #[arg(long, env = "WGQ_WIREGUARD_TRACK_BY", value_enum, default_value_t = TrackBy::Address)]
+    pub wireguard_track_by: TrackBy,
+
+    /// Track QUIC clients by their full address, by IP address only, or by their full address
+    /// while following a client to a new one by its connection ID. Following is unverified,
+    /// so anyone who sees a connection ID can redirect that connection's responses.
+    #[arg(long, env = "WGQ_QUIC_TRACK_BY", value_enum, default_value_t = TrackBy::Address)]
+    pub quic_track_by: TrackBy,
+
+    /// What to do when a client's packets start looking like the other protocol mid-flow
+    #[arg(long, env = "WGQ_ON_PROTOCOL_SWITCH", value_enum, default_value_t = ProtocolSwitch::Keep)]
+    pub on_protocol_switch: ProtocolSwitch,
+
+    /// Only classify the first packet of each connection, and forward the rest to its backend
+    /// without looking at them, so protocol switches go unnoticed
+    #[arg(long, env = "WGQ_CLASSIFY_FIRST_ONLY")]
+    pub classify_first_only: bool,
+
+    /// Format of the log output; the level is still set through RUST_LOG
+    #[arg(long, env = "WGQ_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Record every datagram received from clients to this pcapng file, with a comment saying
+    /// how it was classified
+    #[arg(long, env = "WGQ_PCAP_OUT")]
+    pub pcap_out: Option<PathBuf>,
+
+    /// Record the first this many bytes of the first packet of every new connection to the
+    /// pcapng file given with --capture-handshake-out
+    #[arg(long, env = "WGQ_CAPTURE_HANDSHAKE", value_name = "BYTES")]
+    pub capture_handshake: Option<usize>,
+
+    /// The pcapng file the first packets of new connections are recorded to
+    #[arg(long, env = "WGQ_CAPTURE_HANDSHAKE_OUT")]
+    pub capture_handshake_out: Option<PathBuf>,
+
+    /// Append a JSON object for every new connection to this file, with its client, how it
+    /// was classified and why, and the backend it went to
+    #[arg(long, env = "WGQ_AUDIT_LOG")]
+    pub audit_log: Option<PathBuf>,
+
+    /// Save which backend each client is pinned to in this file, and restore it on startup so
+    /// that returning clients keep their backend
+    #[arg(long, env = "WGQ_STATE_FILE")]
+    pub state_file: Option<PathBuf>,
+
+    /// Seconds between saving the state file
+    #[arg(long, env = "WGQ_STATE_INTERVAL", default_value_t = STATE_INTERVAL_SECS)]
+    pub state_interval: u64,
+
+    /// Seconds between probing every backend, marking those that fail two probes in a row
+    /// down until one succeeds again. New connections avoid backends that are down, and
+    /// existing ones move to another backend of the same type. Disabled when not set
+    #[arg(long, env = "WGQ_HEALTH_PROBE_INTERVAL")]
+    pub health_probe_interval: Option<u64>,
+
+    /// Datagram to probe backends with, in hex, which they must answer within a second. By
+    /// default an empty datagram is sent, which only detects backends refusing it
+    #[arg(long, env = "WGQ_HEALTH_PROBE")]
+    pub health_probe: Option<String>,
+
+    /// Address to serve Prometheus metrics on (at /metrics); disabled when not set
+    #[arg(long, env = "WGQ_METRICS_ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Address to serve liveness (/healthz) and readiness (/readyz) checks on; disabled when
+    /// not set
+    #[arg(long, env = "WGQ_HEALTH_ADDR")]
+    pub health_addr: Option<SocketAddr>,
+
+    /// Path of a Unix socket that answers `list`, `stats` and `backends` commands about the
+    /// live state; disabled when not set
+    #[arg(long, env = "WGQ_ADMIN_SOCKET")]
+    pub admin_socket: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProtocolSwitch {
+    /// Keep forwarding to the backend the connection was pinned to
+    Keep,
+    /// Close the connection and open a new one to the backend of the new protocol
+    Repin,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionLimit {
+    /// Drop the new client's packets, keeping the existing connections
+    Reject,
+    /// Close the connection that has been idle the longest to make room
+    Evict,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseLimit {
+    /// Drop the response, like a policer
+    Drop,
+    /// Hold the connection's responses back until they fit, like a shaper. The backend's
+    /// socket buffer fills up in the meantime, and the kernel drops what doesn't fit in it.
+    Pace,
+}
+
+/// What reloading the config changed, from [`Config::reload`]
+pub struct Reload {
+    /// The running config with the reloaded settings
+    pub config: Config,
+    /// Each reloadable setting that changed, as `name: old -> new`
+    pub changed: Vec<String>,
+    /// The settings that changed, but only take effect on a restart
+    pub needs_restart: Vec<String>,
+    /// Whether any of the settings the backends are set up from changed
+    pub backends_changed: bool,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendSelection {
+    Hash,
+    RoundRobin,
+    Random,
+}
+
+impl BackendSelection {
+    /// The built-in selector for this `--backend-selection`
+    pub fn selector(self) -> Arc<dyn BackendSelector> {
+        match self {
+            BackendSelection::Hash => Arc::new(selector::Hash::default()),
+            BackendSelection::RoundRobin => Arc::new(selector::RoundRobin::default()),
+            BackendSelection::Random => Arc::new(selector::Random),
+        }
+    }
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EgressMode {
+    /// A socket connected to the backend for every connection
+    PerClient,
+    /// One unconnected socket per backend. Clients without a connection ID still get a
+    /// socket of their own.
+    Shared,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrackBy {
+    /// Every source address and port is a separate client
+    Address,
+    /// All ports of a source IP address are the same client, and responses go to the port it
+    /// sent from last
+    Ip,
+    /// QUIC only: like address, but a client that shows up at a new address with a connection
+    /// ID its backend assigned it keeps its connection, and responses follow it there
+    ConnectionId,
+}
+
+impl Config {
+    /// Parses the command line, then fills in anything that wasn't given there (or in the
+    /// environment) from the config file, if one was passed.
+    pub fn load() -> io::Result<Config> {
+        let matches = Config::command().get_matches();
+        let config = Config::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+        let config = match config.config.clone() {
+            Some(path) => config.merge_file(&path, &matches)?,
+            None => config,
+        };
+        // The config file isn't checked by clap
+        if config.listen.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "at least one listen address is needed",
+            ));
+        }
+        if let Some(addr) = config
+            .listen
+            .iter()
+            .enumerate()
+            .find_map(|(i, addr)| config.listen[..i].contains(addr).then_some(addr))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("listen address {addr} is given more than once"),
+            ));
+        }
+        if config.xdp_interface.is_some() && !cfg!(all(feature = "xdp", target_os = "linux")) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--xdp-interface needs a build with the xdp feature, on Linux",
+            ));
+        }
+        if config.transparent && !cfg!(any(target_os = "linux", target_os = "android")) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "transparent proxying is only supported on Linux",
+            ));
+        }
+        if config.transparent
+            && (config.socket_pool_size > 0 || config.quic_egress == EgressMode::Shared)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--transparent needs a socket per connection, so it can't be combined with --socket-pool-size or --quic-egress shared",
+            ));
+        }
+        if (config.wireguard_socks5.is_some() || config.quic_socks5.is_some())
+            && (config.transparent
+                || config.socket_pool_size > 0
+                || (config.quic_socks5.is_some() && config.quic_egress == EgressMode::Shared))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a SOCKS5 association is set up per connection, so --wireguard-socks5 and --quic-socks5 can't be combined with --transparent, --socket-pool-size or --quic-egress shared",
+            ));
+        }
+        if [config.wireguard_dscp, config.quic_dscp]
+            .into_iter()
+            .flatten()
+            .any(|dscp| dscp >= 64)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DSCP values range from 0 to 63",
+            ));
+        }
+        if config.classify_first_only && config.on_protocol_switch == ProtocolSwitch::Repin {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--classify-first-only doesn't notice protocol switches, so it can't be combined with --on-protocol-switch repin",
+            ));
+        }
+        for (name, size) in [
+            ("so-rcvbuf", config.so_rcvbuf),
+            ("so-sndbuf", config.so_sndbuf),
+        ] {
+            if let Some(size) = size
+                && !SOCKET_BUFFER_SIZES.contains(&size)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "--{name} must be between {} and {} bytes, not {size}",
+                        SOCKET_BUFFER_SIZES.start(),
+                        SOCKET_BUFFER_SIZES.end()
+                    ),
+                ));
+            }
+        }
+        if config.max_packet_rate_per_source == Some(0)
+            || config.max_byte_rate_per_source == Some(0)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--max-packet-rate-per-source and --max-byte-rate-per-source must be at least 1, a limit of 0 would drop every packet",
+            ));
+        }
+        if config.max_pps == Some(0) || config.max_bps == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--max-pps and --max-bps must be at least 1, a limit of 0 would drop every packet",
+            ));
+        }
+        if config.fair_queue && config.max_pps.is_none() && config.max_bps.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--fair-queue shares out --max-bps or --max-pps, so it needs one of them",
+            ));
+        }
+        if [config.forward_ttl, config.wireguard_ttl, config.quic_ttl].contains(&Some(0)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a TTL of 0 would drop every packet, TTLs range from 1 to 255",
+            ));
+        }
+        if config.wireguard_track_by == TrackBy::ConnectionId {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "WireGuard has no connection IDs, so it can't be tracked by them",
+            ));
+        }
+        if config.state_interval == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the state interval must be at least a second",
+            ));
+        }
+        if config.max_datagram_size < MIN_PATH_MTU {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "--max-datagram-size must be at least {MIN_PATH_MTU} bytes, as QUIC clients \
+                     pad their Initial packets to that size"
+                ),
+            ));
+        }
+        if config.connection_timeout == 0
+            || config.wireguard_timeout == Some(0)
+            || config.quic_timeout == Some(0)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "connection timeouts must be at least a second, a timeout of 0 would close \
+                 every connection as it opens",
+            ));
+        }
+        if config.forward_timeout == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the forward timeout must be at least a millisecond",
+            ));
+        }
+        if config.health_probe_interval == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the health probe interval must be at least a second",
+            ));
+        }
+        if let Some(probe) = &config.health_probe
+            && decode_hex(probe).is_none()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid health probe {probe:?}, expected an even number of hex digits"),
+            ));
+        }
+        if config.max_response_bps == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the response rate limit must be at least a byte per second",
+            ));
+        }
+        if config.capture_handshake == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--capture-handshake must capture at least a byte",
+            ));
+        }
+        if config.capture_handshake.is_some() != config.capture_handshake_out.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--capture-handshake and --capture-handshake-out must be used together",
+            ));
+        }
+        Ok(config)
+    }
+
+    fn merge_file(self, path: &Path, matches: &ArgMatches) -> io::Result<Config> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to read config file {}: {e}", path.display()),
+            )
+        })?;
+        let file: toml::Table = toml::from_str(&contents).map_err(|e| invalid_file(path, e))?;
+
+        let toml::Value::Table(mut merged) =
+            toml::Value::try_from(&self).map_err(|e| invalid_file(path, e))?
+        else {
+            unreachable!("Config always serializes to a table");
+        };
+        let command = Config::command();
+        for (key, value) in file {
+            // Unknown keys are kept so that deserializing below reports them
+            let known = command
+                .get_arguments()
+                .any(|arg| arg.get_id() == key.as_str());
+            let explicit = known
+                && matches!(
+                    matches.value_source(&key),
+                    Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+                );
+            if !explicit {
+                merged.insert(key, value);
+            }
+        }
+
+        let mut config: Config = toml::Value::Table(merged)
+            .try_into()
+            .map_err(|e| invalid_file(path, e))?;
+        config.config = self.config;
+        Ok(config)
+    }
+
+    /// Takes the settings that can be reloaded from `new`, keeping the others as they are
+    pub fn reload(&self, new: &Config) -> io::Result<Reload> {
+        let table = |config: &Config| match toml::Value::try_from(config) {
+            Ok(toml::Value::Table(table)) => Ok(table),
+            Ok(_) => unreachable!("Config always serializes to a table"),
+            Err(e) => Err(io::Error::other(e)),
+        };
+        let current = table(self)?;
+        let new = table(new)?;
+        let mut merged = current.clone();
+        let mut changed = Vec::new();
+        let mut needs_restart = Vec::new();
+        let mut backends_changed = false;
+        // Options that aren't set are left out of the tables, so a key may be in either one
+        let mut keys: Vec<&String> = current.keys().chain(new.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            let (old_value, new_value) = (current.get(key), new.get(key));
+            if old_value == new_value {
+                continue;
+            }
+            if !RELOADABLE.contains(&key.as_str()) {
+                needs_restart.push(key.clone());
+                continue;
+            }
+            let show =
+                |value: Option<&toml::Value>| value.map_or("unset".into(), |v| v.to_string());
+            changed.push(format!("{key}: {} -> {}", show(old_value), show(new_value)));
+            backends_changed |= BACKEND_SETTINGS.contains(&key.as_str());
+            match new_value {
+                Some(value) => merged.insert(key.clone(), value.clone()),
+                None => merged.remove(key),
+            };
+        }
+
+        let mut config: Config = toml::Value::Table(merged)
+            .try_into()
+            .map_err(io::Error::other)?;
+        config.config = self.config.clone();
+        Ok(Reload {
+            config,
+            changed,
+            needs_restart,
+            backends_changed,
+        })
+    }
+
+    /// The backends packets of the given type may be forwarded to, if any
+    pub fn backends(&self, packet_type: PacketType) -> &[String] {
+        match packet_type {
+            PacketType::Wireguard => &self.wireguard_backend,
+            PacketType::Quic => &self.quic_backend,
+            PacketType::Dtls => self.dtls_backend.as_slice(),
+            PacketType::Stun => self.stun_backend.as_slice(),
+            PacketType::Unknown => self.forward_unknown_to.as_slice(),
+        }
+    }
+
+    /// The collector that gets copies of the packets of the given type, if any
+    pub fn mirror(&self, packet_type: PacketType) -> Option<&str> {
+        match packet_type {
+            PacketType::Wireguard => self.mirror_wireguard.as_deref(),
+            PacketType::Quic => self.mirror_quic.as_deref(),
+            PacketType::Dtls | PacketType::Stun | PacketType::Unknown => None,
+        }
+    }
+
+    /// How long a connection of the given type may be idle before it is closed
+    pub fn connection_timeout(&self, packet_type: PacketType) -> Duration {
+        let specific = match packet_type {
+            PacketType::Wireguard => self.wireguard_timeout,
+            PacketType::Quic => self.quic_timeout,
+            PacketType::Dtls | PacketType::Stun | PacketType::Unknown => None,
+        };
+        Duration::from_secs(specific.unwrap_or(self.connection_timeout))
+    }
+
+    /// How long a connection may wait for its backend to respond, if that is limited
+    pub fn response_timeout(&self) -> Option<Duration> {
+        self.response_timeout.map(Duration::from_secs)
+    }
+
+    pub fn forward_timeout(&self) -> Duration {
+        Duration::from_millis(self.forward_timeout)
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace)
+    }
+
+    /// How long a connection may live, if that is limited
+    pub fn max_connection_lifetime(&self) -> Option<Duration> {
+        self.max_connection_lifetime.map(Duration::from_secs)
+    }
+
+    /// What a new connection of the given type from `addr` is tracked by
+    pub fn client_key(&self, packet_type: PacketType, addr: SocketAddr) -> ClientKey {
+        let track_by = match packet_type {
+            PacketType::Wireguard => self.wireguard_track_by,
+            PacketType::Quic => self.quic_track_by,
+            PacketType::Dtls | PacketType::Stun | PacketType::Unknown => TrackBy::Address,
+        };
+        match track_by {
+            TrackBy::Address | TrackBy::ConnectionId => ClientKey::Address(addr),
+            TrackBy::Ip => ClientKey::Ip(addr.ip()),
+        }
+    }
+
+    /// Whether QUIC clients are followed to new addresses by their connection ID
+    pub fn tracks_by_connection_id(&self) -> bool {
+        self.quic_track_by == TrackBy::ConnectionId
+    }
+
+    /// The DSCP value packets to backends of the given type are marked with, if any
+    pub fn dscp(&self, packet_type: PacketType) -> Option<u8> {
+        match packet_type {
+            PacketType::Wireguard => self.wireguard_dscp,
+            PacketType::Quic => self.quic_dscp,
+            PacketType::Dtls | PacketType::Stun | PacketType::Unknown => None,
+        }
+    }
+
+    /// The TTL packets to backends of the given type are sent with, if not the default
+    pub fn ttl(&self, packet_type: PacketType) -> Option<u8> {
+        let specific = match packet_type {
+            PacketType::Wireguard => self.wireguard_ttl,
+            PacketType::Quic => self.quic_ttl,
+            PacketType::Dtls | PacketType::Stun | PacketType::Unknown => None,
+        };
+        specific.or(self.forward_ttl)
+    }
+
+    /// The buffer sizes to ask for on every socket, other than the system defaults
+    pub fn buffer_sizes(&self) -> BufferSizes {
+        BufferSizes {
+            recv: self.so_rcvbuf,
+            send: self.so_sndbuf,
+        }
+    }
+
+    /// The SOCKS5 proxy backends of the given type are reached through, if any
+    pub fn socks5(&self, packet_type: PacketType) -> Option<&str> {
+        match packet_type {
+            PacketType::Wireguard => self.wireguard_socks5.as_deref(),
+            PacketType::Quic => self.quic_socks5.as_deref(),
+            PacketType::Dtls | PacketType::Stun | PacketType::Unknown => None,
+        }
+    }
+
+    /// Whether any clients are tracked by IP address only
+    pub fn tracks_by_ip(&self) -> bool {
+        self.wireguard_track_by == TrackBy::Ip || self.quic_track_by == TrackBy::Ip
+    }
+
+    pub fn resolve_interval(&self) -> Duration {
+        Duration::from_secs(self.resolve_interval)
+    }
+
+    pub fn state_interval(&self) -> Duration {
+        Duration::from_secs(self.state_interval)
+    }
+
+    /// How backends are probed, if they are
+    pub fn health_probe(&self) -> Option<Probe> {
+        Some(Probe {
+            interval: Duration::from_secs(self.health_probe_interval?),
+            payload: self
+                .health_probe
+                .as_deref()
+                .and_then(decode_hex)
+                .unwrap_or_default(),
+        })
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Accepts either a single string or a list of them in the config file
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(address) => vec![address],
+        OneOrMany::Many(addresses) => addresses,
+    })
+}
+
+fn invalid_file(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid config file {}: {e}", path.display()),
+    )
+}
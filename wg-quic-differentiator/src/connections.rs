@@ -0,0 +1,451 @@
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, MutexGuard};
+use tokio_util::sync::CancellationToken;
+
+use crate::PacketType;
+use crate::health::Health;
+use crate::metrics::{Counter, METRICS};
+use crate::queue;
+use crate::quic::{self, MAX_CID_LEN};
+use crate::ratelimit::RateLimiter;
+
+/// Number of independently locked parts the connection table is split into
+const SHARDS: usize = 64;
+/// `ConnectionStats::unanswered_since` when no packet is waiting for a response
+const UNANSWERED_NONE: u64 = u64::MAX;
+
+/// The forwarding task of every active client, along with the bookkeeping needed to enforce
+/// the connection limits.
+///
+/// The table is sharded so that packets from different clients rarely contend on the same
+/// lock. Connections are sharded by IP address rather than the full socket address, which
+/// keeps the per-IP connection counts local to a single shard.
+pub struct Connections {
+    shards: Box<[Mutex<Shard>]>,
+    hasher: RandomState,
+    total: AtomicUsize,
+    connection_ids: std::sync::Mutex<ConnectionIds>,
+}
+
+/// The connection IDs backends assigned to their QUIC clients, so that a client whose
+/// address changed can be found again by the ID it keeps using
+#[derive(Default)]
+struct ConnectionIds {
+    owners: HashMap<Box<[u8]>, ClientKey>,
+    /// How many of the IDs have each length, as short headers don't include it
+    lengths: [usize; MAX_CID_LEN + 1],
+}
+
+/// What a connection is looked up by. Clients are normally told apart by their full address,
+/// but can be tracked by IP address alone so that a port change keeps their connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientKey {
+    Address(SocketAddr),
+    Ip(IpAddr),
+}
+
+impl ClientKey {
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            ClientKey::Address(addr) => addr.ip(),
+            ClientKey::Ip(ip) => *ip,
+        }
+    }
+}
+
+impl fmt::Display for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientKey::Address(addr) => addr.fmt(f),
+            ClientKey::Ip(ip) => ip.fmt(f),
+        }
+    }
+}
+
+/// A client's flow through the proxy. The packet type is pinned when the connection is
+/// created, so later packets go to the same backend without being classified again.
+#[derive(Clone)]
+pub struct Connection {
+    pub packet_type: PacketType,
+    /// The backend as configured, such as `wireguard:51820`
+    pub backend: Arc<str>,
+    /// Where responses are sent, which changes when a client tracked by IP switches ports
+    pub client: Arc<ArcSwap<SocketAddr>>,
+    pub sender: queue::Sender,
+    pub stats: Arc<ConnectionStats>,
+    /// Shared with every other connection to the same backend
+    pub limiter: Option<Arc<RateLimiter>>,
+    /// Whether the backend is up, which moves the connection elsewhere when it isn't
+    pub health: Arc<Health>,
+    /// Cancelled when the connection is removed from the table, which stops its forwarding
+    /// task even while something else still holds a copy of the connection
+    pub closed: CancellationToken,
+}
+
+/// Traffic of a single connection, updated by its forwarding task
+pub struct ConnectionStats {
+    created: Instant,
+    /// Milliseconds after `created` that a packet was last forwarded in either direction
+    last_active: AtomicU64,
+    /// Milliseconds after `created` that the backend last responded
+    last_response: AtomicU64,
+    /// Whether the backend has responded at all
+    established: AtomicBool,
+    /// Microseconds after `created` that the first packet forwarded since the last response
+    /// was sent, or `UNANSWERED_NONE` if the backend has answered everything so far
+    unanswered_since: AtomicU64,
+    /// Smoothed round trip time to the backend in microseconds, 0 until it is first measured
+    smoothed_rtt: AtomicU64,
+    pub bytes_to_backend: Counter,
+    pub bytes_to_client: Counter,
+    pub packets_to_backend: Counter,
+    pub packets_to_client: Counter,
+    /// Packets dropped because the queue to the backend was full
+    pub dropped: Counter,
+}
+
+impl ConnectionStats {
+    pub fn new() -> Self {
+        ConnectionStats {
+            created: Instant::now(),
+            last_active: AtomicU64::new(0),
+            last_response: AtomicU64::new(0),
+            established: AtomicBool::new(false),
+            unanswered_since: AtomicU64::new(UNANSWERED_NONE),
+            smoothed_rtt: AtomicU64::new(0),
+            bytes_to_backend: Counter::new(),
+            bytes_to_client: Counter::new(),
+            packets_to_backend: Counter::new(),
+            packets_to_client: Counter::new(),
+            dropped: Counter::new(),
+        }
+    }
+
+    /// Records that a packet was forwarded just now
+    pub fn touch(&self) {
+        let elapsed = self.created.elapsed().as_millis() as u64;
+        self.last_active.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// How long ago a packet was last forwarded
+    pub fn idle(&self) -> Duration {
+        let last_active = Duration::from_millis(self.last_active.load(Ordering::Relaxed));
+        self.created.elapsed().saturating_sub(last_active)
+    }
+
+    /// How long ago the connection was created
+    pub fn age(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    /// Records a response from the backend, returning how long the connection waited for it
+    /// if it is the first
+    pub fn responded(&self) -> Option<Duration> {
+        let elapsed = self.created.elapsed();
+        self.last_response
+            .store(elapsed.as_millis() as u64, Ordering::Relaxed);
+        (!self.established.swap(true, Ordering::Relaxed)).then_some(elapsed)
+    }
+
+    /// Records that a packet was forwarded to the backend just now, starting a round trip
+    /// unless one is already waiting for a response
+    pub fn sent_to_backend(&self) {
+        let now = self.created.elapsed().as_micros() as u64;
+        let _ = self.unanswered_since.compare_exchange(
+            UNANSWERED_NONE,
+            now,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Ends the round trip waiting for a response with the one that arrived just now,
+    /// returning how long it took. The smoothed RTT follows it the way TCP's does (RFC 6298),
+    /// moving an eighth of the way to each new sample. UDP responses don't say which packet
+    /// they answer, so this is only a rough measure: it runs from the first packet sent
+    /// since the last response, and includes however long the backend takes to answer.
+    pub fn round_trip(&self) -> Option<Duration> {
+        let sent = self
+            .unanswered_since
+            .swap(UNANSWERED_NONE, Ordering::Relaxed);
+        if sent == UNANSWERED_NONE {
+            return None;
+        }
+        let sample = (self.created.elapsed().as_micros() as u64).saturating_sub(sent);
+        // Only the connection's own task measures, so there is no race to update it
+        let smoothed = match self.smoothed_rtt.load(Ordering::Relaxed) {
+            0 => sample,
+            smoothed => smoothed - smoothed / 8 + sample / 8,
+        };
+        self.smoothed_rtt.store(smoothed.max(1), Ordering::Relaxed);
+        Some(Duration::from_micros(sample))
+    }
+
+    /// The smoothed round trip time to the backend, once a response has been measured
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        match self.smoothed_rtt.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    /// Whether the backend has responded since the connection was created
+    pub fn is_established(&self) -> bool {
+        self.established.load(Ordering::Relaxed)
+    }
+
+    /// How long ago the backend last responded, or the connection was created if it hasn't
+    /// yet
+    pub fn since_response(&self) -> Duration {
+        let last_response = Duration::from_millis(self.last_response.load(Ordering::Relaxed));
+        self.created.elapsed().saturating_sub(last_response)
+    }
+}
+
+#[derive(Default)]
+pub struct Shard {
+    connections: HashMap<ClientKey, Connection>,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Exclusive access to the shard a connection lives in
+pub struct ShardGuard<'a> {
+    shard: MutexGuard<'a, Shard>,
+    total: &'a AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Total,
+    PerIp,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Connections {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+            total: AtomicUsize::new(0),
+            connection_ids: std::sync::Mutex::default(),
+        }
+    }
+
+    /// Locks the shard that the connections of `ip` belong in
+    pub async fn lock(&self, ip: IpAddr) -> ShardGuard<'_> {
+        let shard = self.hasher.hash_one(ip) as usize % self.shards.len();
+        ShardGuard {
+            shard: self.shards[shard].lock().await,
+            total: &self.total,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Records that packets with destination connection ID `cid` belong to the connection of
+    /// `key`, taking the ID over from any connection that had it before
+    pub fn add_connection_id(&self, cid: &[u8], key: ClientKey) {
+        let mut ids = self.connection_ids.lock().unwrap();
+        if ids.owners.insert(cid.into(), key).is_none() {
+            ids.lengths[cid.len()] += 1;
+        }
+    }
+
+    /// Forgets connection IDs added for the connection of `key` once it has been closed, other
+    /// than those another connection has taken over since
+    pub fn remove_connection_ids(&self, cids: &[Box<[u8]>], key: ClientKey) {
+        let mut ids = self.connection_ids.lock().unwrap();
+        for cid in cids {
+            if ids.owners.get(cid) == Some(&key) {
+                ids.owners.remove(cid);
+                ids.lengths[cid.len()] -= 1;
+            }
+        }
+    }
+
+    /// The connection a QUIC packet belongs to according to its destination connection ID
+    pub fn find_by_connection_id(&self, packet: &[u8]) -> Option<ClientKey> {
+        let ids = self.connection_ids.lock().unwrap();
+        if ids.owners.is_empty() {
+            return None;
+        }
+        if let Some(header) = quic::parse_quic_header(packet)
+            && let Some(cid) = header.destination_cid
+        {
+            return ids.owners.get(cid).copied();
+        }
+        // Backends normally use a single length, so this only tries a few
+        (1..=MAX_CID_LEN)
+            .filter(|&len| ids.lengths[len] > 0)
+            .filter_map(|len| quic::short_header_cid(packet, len))
+            .find_map(|cid| ids.owners.get(cid).copied())
+    }
+
+    /// Removes the connection that has been idle the longest, which stops its forwarding task.
+    /// This goes through every connection, so it is only meant for when the table is full.
+    pub async fn evict_idlest(&self) -> Option<(ClientKey, PacketType)> {
+        let mut idlest: Option<(usize, ClientKey, Duration)> = None;
+        for (index, shard) in self.shards.iter().enumerate() {
+            let shard = shard.lock().await;
+            for (key, connection) in &shard.connections {
+                let idle = connection.stats.idle();
+                if idlest.is_none_or(|(_, _, longest)| idle > longest) {
+                    idlest = Some((index, *key, idle));
+                }
+            }
+        }
+
+        let (index, key, _) = idlest?;
+        let mut shard = ShardGuard {
+            shard: self.shards[index].lock().await,
+            total: &self.total,
+        };
+        // The connection may have closed while the other shards were searched
+        let packet_type = shard.get(&key)?.packet_type;
+        shard.remove(&key);
+        Some((key, packet_type))
+    }
+
+    /// A copy of every connection, locking only one shard at a time
+    pub async fn snapshot(&self) -> Vec<(ClientKey, Connection)> {
+        let mut connections = Vec::with_capacity(self.len());
+        for shard in &self.shards {
+            let shard = shard.lock().await;
+            connections.extend(
+                shard
+                    .connections
+                    .iter()
+                    .map(|(key, connection)| (*key, connection.clone())),
+            );
+        }
+        connections
+    }
+}
+
+impl ShardGuard<'_> {
+    pub fn get(&self, key: &ClientKey) -> Option<&Connection> {
+        self.shard.connections.get(key)
+    }
+
+    /// Adds a connection, unless that would exceed one of the given limits
+    pub fn insert(
+        &mut self,
+        key: ClientKey,
+        connection: Connection,
+        max_connections: Option<usize>,
+        max_per_ip: Option<usize>,
+    ) -> Result<(), LimitExceeded> {
+        if let Some(existing) = self.shard.connections.get_mut(&key) {
+            METRICS.active_connections.get(existing.packet_type).dec();
+            METRICS.active_connections.get(connection.packet_type).inc();
+            existing.closed.cancel();
+            METRICS
+                .connections_created
+                .get(connection.packet_type)
+                .inc();
+            *existing = connection;
+            return Ok(());
+        }
+
+        let ip = key.ip();
+        let from_ip = self.shard.per_ip.get(&ip).copied().unwrap_or(0);
+        if max_per_ip.is_some_and(|max| from_ip >= max) {
+            return Err(LimitExceeded::PerIp);
+        }
+        // Other shards may be inserting at the same time, so the total is only
+        // incremented if it is still below the limit
+        let max = max_connections.unwrap_or(usize::MAX);
+        self.total
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+                (total < max).then_some(total + 1)
+            })
+            .map_err(|_| LimitExceeded::Total)?;
+
+        METRICS.active_connections.get(connection.packet_type).inc();
+        METRICS
+            .connections_created
+            .get(connection.packet_type)
+            .inc();
+        self.shard.connections.insert(key, connection);
+        *self.shard.per_ip.entry(ip).or_default() += 1;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &ClientKey) {
+        let Some(connection) = self.shard.connections.remove(key) else {
+            return;
+        };
+        connection.closed.cancel();
+        self.total.fetch_sub(1, Ordering::Relaxed);
+        METRICS.active_connections.get(connection.packet_type).dec();
+        let ip = key.ip();
+        if let Some(count) = self.shard.per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.shard.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn short_header(cid: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x40];
+        packet.extend_from_slice(cid);
+        packet.resize(1 + cid.len() + 24, 0xaa);
+        packet
+    }
+
+    #[test]
+    fn keeps_a_connection_id_taken_over_by_another_connection() {
+        let connections = Connections::new();
+        let first = ClientKey::Address("127.0.0.1:1000".parse().unwrap());
+        let second = ClientKey::Address("127.0.0.1:2000".parse().unwrap());
+        let cid = [0x11; 8];
+        connections.add_connection_id(&cid, first);
+        connections.add_connection_id(&cid, second);
+        assert_eq!(
+            connections.find_by_connection_id(&short_header(&cid)),
+            Some(second)
+        );
+
+        // The first connection closing leaves the ID with the second
+        connections.remove_connection_ids(&[cid.into()], first);
+        assert_eq!(
+            connections.find_by_connection_id(&short_header(&cid)),
+            Some(second)
+        );
+
+        connections.remove_connection_ids(&[cid.into()], second);
+        assert_eq!(connections.find_by_connection_id(&short_header(&cid)), None);
+    }
+
+    #[test]
+    fn counts_connection_id_lengths_once_per_id() {
+        let connections = Connections::new();
+        let first = ClientKey::Address("127.0.0.1:1000".parse().unwrap());
+        let second = ClientKey::Address("127.0.0.1:2000".parse().unwrap());
+        let cid = [0x22; 4];
+        connections.add_connection_id(&cid, first);
+        connections.add_connection_id(&cid, second);
+        connections.remove_connection_ids(&[cid.into()], first);
+        connections.remove_connection_ids(&[cid.into()], second);
+        assert_eq!(
+            connections.connection_ids.lock().unwrap().lengths[cid.len()],
+            0
+        );
+    }
+}
@@ -0,0 +1,93 @@
+/// DTLS 1.0 (RFC 4347)
+const VERSION_1_0: u16 = 0xfeff;
+/// DTLS 1.2 (RFC 6347), which DTLS 1.3 also uses as the legacy record version
+const VERSION_1_2: u16 = 0xfefd;
+/// Content type, version, epoch, sequence number and length
+const RECORD_HEADER_LEN: usize = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DtlsContentType {
+    ChangeCipherSpec,
+    Alert,
+    Handshake,
+    ApplicationData,
+}
+
+/// Parses the header of the first DTLS record in a datagram, returning `None` if the packet
+/// doesn't look like DTLS.
+pub fn parse_dtls_record(buf: &[u8]) -> Option<DtlsContentType> {
+    let header = buf.get(..RECORD_HEADER_LEN)?;
+    let content_type = match header[0] {
+        20 => DtlsContentType::ChangeCipherSpec,
+        21 => DtlsContentType::Alert,
+        22 => DtlsContentType::Handshake,
+        23 => DtlsContentType::ApplicationData,
+        _ => return None,
+    };
+    let version = u16::from_be_bytes([header[1], header[2]]);
+    if version != VERSION_1_0 && version != VERSION_1_2 {
+        return None;
+    }
+    // A datagram may hold several records, but the first one must fit in it
+    let length = u16::from_be_bytes([header[11], header[12]]) as usize;
+    if buf.len() < RECORD_HEADER_LEN + length {
+        return None;
+    }
+    Some(content_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Captured from a DTLS 1.2 handshake between `openssl s_client -dtls1_2` and
+    // `openssl s_server -dtls1_2`: the client's first flight, which like most clients uses
+    // the DTLS 1.0 record version, the server's flight starting with its ServerHello, and
+    // the client's first encrypted record
+    const CLIENT_HELLO: &[u8] = include_bytes!("../tests/data/dtls_client_hello.bin");
+    const SERVER_HELLO: &[u8] = include_bytes!("../tests/data/dtls_server_hello.bin");
+    const APPLICATION_DATA: &[u8] = include_bytes!("../tests/data/dtls_application_data.bin");
+    /// The ClientHello of `openssl s_client` over TCP, which is TLS rather than DTLS
+    const TLS_CLIENT_HELLO: &[u8] = include_bytes!("../tests/data/tls_client_hello.bin");
+
+    #[test]
+    fn parses_captured_records() {
+        assert_eq!(
+            parse_dtls_record(CLIENT_HELLO),
+            Some(DtlsContentType::Handshake)
+        );
+        // The datagram holds several records after the ServerHello
+        assert_eq!(
+            parse_dtls_record(SERVER_HELLO),
+            Some(DtlsContentType::Handshake)
+        );
+        assert_eq!(
+            parse_dtls_record(APPLICATION_DATA),
+            Some(DtlsContentType::ApplicationData)
+        );
+    }
+
+    #[test]
+    fn rejects_a_tls_record() {
+        assert_eq!(parse_dtls_record(TLS_CLIENT_HELLO), None);
+    }
+
+    #[test]
+    fn rejects_a_record_longer_than_the_datagram() {
+        assert_eq!(
+            parse_dtls_record(&CLIENT_HELLO[..CLIENT_HELLO.len() - 1]),
+            None
+        );
+        assert_eq!(
+            parse_dtls_record(&CLIENT_HELLO[..RECORD_HEADER_LEN - 1]),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_content_types() {
+        let mut record = APPLICATION_DATA.to_vec();
+        record[0] = 24;
+        assert_eq!(parse_dtls_record(&record), None);
+    }
+}
@@ -0,0 +1,204 @@
+//! The sockets connections send their client's packets to the backend through, and receive
+//! the responses on. Normally each connection has a socket of its own, connected to its
+//! backend, so the kernel tells the responses of different clients apart. With
+//! `--quic-egress shared`, the QUIC connections to a backend share a single socket instead,
+//! which saves a file descriptor per client: QUIC backends address their responses to the
+//! connection ID the client picked, so that is what they are routed by.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use wg_quic_differentiator::quic::{self, MAX_CID_LEN};
+
+use crate::metrics::METRICS;
+use crate::socket::{self, EgressOptions};
+use crate::socks5::Association;
+use crate::{PACKET_BUFFERS, report_truncated};
+
+/// Responses waiting for a connection on a shared socket before further ones are dropped
+const RESPONSE_BACKLOG: usize = 256;
+
+pub enum Egress {
+    /// A socket of the connection's own, connected to its backend
+    Connected(UdpSocket),
+    /// A backend's shared socket, and the responses on it for this connection
+    Shared(Route),
+    /// An association with a SOCKS5 proxy that relays to the backend
+    Socks5(Association),
+}
+
+/// A connection's registration on a shared socket, which ends when it is dropped
+pub struct Route {
+    socket: Arc<SharedSocket>,
+    cid: Box<[u8]>,
+    backend: SocketAddr,
+    responses: mpsc::Receiver<Vec<u8>>,
+}
+
+impl Egress {
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Egress::Connected(sock) => sock.send(buf).await,
+            Egress::Shared(route) => route.socket.sock.send_to(buf, route.backend).await,
+            Egress::Socks5(association) => association.send(buf).await,
+        }
+    }
+
+    /// Receives the next response from the backend into `buf`, which has room for the largest
+    /// datagram the proxy forwards
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Egress::Connected(sock) => sock.recv(buf).await,
+            Egress::Shared(route) => {
+                let Some(response) = route.responses.recv().await else {
+                    return Err(io::Error::other("the shared socket is closed"));
+                };
+                let len = response.len();
+                buf[..len].copy_from_slice(&response);
+                PACKET_BUFFERS.recycle(response);
+                Ok(len)
+            }
+            Egress::Socks5(association) => association.recv(buf).await,
+        }
+    }
+
+    /// The connection's own socket, if it has one. Segmented sends need it.
+    pub fn connected(&self) -> Option<&UdpSocket> {
+        match self {
+            Egress::Connected(sock) => Some(sock),
+            Egress::Shared(_) | Egress::Socks5(_) => None,
+        }
+    }
+}
+
+/// A socket shared by the QUIC connections to a backend
+pub struct SharedSocket {
+    sock: UdpSocket,
+    /// Whether the socket sends over IPv4, so only backends of the same family can use it
+    ipv4: bool,
+    routes: Mutex<Routes>,
+}
+
+#[derive(Default)]
+struct Routes {
+    /// The connection each client connection ID belongs to
+    by_cid: HashMap<Box<[u8]>, Destination>,
+    /// How many of the IDs have each length, as short headers don't include it
+    lengths: [usize; MAX_CID_LEN + 1],
+}
+
+struct Destination {
+    /// The backend whose responses go to the connection
+    backend: SocketAddr,
+    responses: mpsc::Sender<Vec<u8>>,
+}
+
+impl SharedSocket {
+    /// Binds a socket for sending to backends of the same address family as `backend`
+    pub fn bind(backend: SocketAddr, options: EgressOptions<'_>) -> io::Result<SharedSocket> {
+        Ok(SharedSocket {
+            sock: socket::bind_backend_socket(backend, options)?,
+            ipv4: backend.is_ipv4(),
+            routes: Mutex::default(),
+        })
+    }
+
+    /// Routes the responses from `backend` addressed to the client connection ID `cid` to the
+    /// returned egress, until it is dropped. Returns `None` if that isn't possible, because
+    /// the client has no connection ID or another connection uses the same one, or the
+    /// backend changed address family, in which case the connection needs a socket of its own.
+    pub fn register(self: &Arc<Self>, cid: &[u8], backend: SocketAddr) -> Option<Egress> {
+        if cid.is_empty() || backend.is_ipv4() != self.ipv4 {
+            return None;
+        }
+        let mut routes = self.routes.lock().unwrap();
+        if routes.by_cid.contains_key(cid) {
+            return None;
+        }
+        let (tx, rx) = mpsc::channel(RESPONSE_BACKLOG);
+        let destination = Destination {
+            backend,
+            responses: tx,
+        };
+        routes.by_cid.insert(cid.into(), destination);
+        routes.lengths[cid.len()] += 1;
+        Some(Egress::Shared(Route {
+            socket: self.clone(),
+            cid: cid.into(),
+            backend,
+            responses: rx,
+        }))
+    }
+
+    /// Receives responses and passes each one on to its connection, until `shutdown` is
+    /// cancelled
+    pub async fn demultiplex(&self, max_datagram_size: usize, shutdown: CancellationToken) {
+        let mut buf = vec![0; max_datagram_size + 1];
+        loop {
+            let (len, source) = tokio::select! {
+                result = self.sock.recv_from(&mut buf) => match result {
+                    Ok(received) => received,
+                    Err(e) => {
+                        log::debug!("Error receiving on shared socket: {:?}", e);
+                        continue;
+                    }
+                },
+                _ = shutdown.cancelled() => break,
+            };
+            if len > max_datagram_size {
+                report_truncated(source, len, max_datagram_size);
+            } else if !self.route(&buf[..len], source) {
+                METRICS.unroutable_responses.inc();
+            }
+        }
+    }
+
+    /// Queues a response for the connection it is addressed to, returning false if there is
+    /// none or it has fallen behind
+    fn route(&self, response: &[u8], source: SocketAddr) -> bool {
+        let routes = self.routes.lock().unwrap();
+        // Backends may grease the fixed bit, so only the header form is relied on
+        let found = if let Some(cid) = quic::long_header_cid(response) {
+            routes.by_cid.get(cid)
+        } else {
+            (1..=MAX_CID_LEN)
+                .filter(|&len| routes.lengths[len] > 0)
+                .filter_map(|len| quic::short_header_cid(response, len))
+                .find_map(|cid| routes.by_cid.get(cid))
+        };
+        let Some(destination) = found.filter(|destination| destination.backend == source) else {
+            log::debug!(
+                "Dropping response from {} on a shared socket, as it isn't addressed to a connection ID a client gave during its handshake",
+                source
+            );
+            return false;
+        };
+        match destination
+            .responses
+            .try_send(PACKET_BUFFERS.copy_of(response))
+        {
+            Ok(()) => true,
+            Err(e) => {
+                log::debug!(
+                    "Dropping response from {}, as its connection is behind",
+                    source
+                );
+                PACKET_BUFFERS.recycle(e.into_inner());
+                false
+            }
+        }
+    }
+}
+
+impl Drop for Route {
+    fn drop(&mut self) {
+        let mut routes = self.socket.routes.lock().unwrap();
+        if routes.by_cid.remove(&self.cid).is_some() {
+            routes.lengths[self.cid.len()] -= 1;
+        }
+    }
+}
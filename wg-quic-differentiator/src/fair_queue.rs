@@ -0,0 +1,111 @@
+//! Sharing a backend's `--max-bps` and `--max-pps` fairly between the source IPs sending to
+//! it, with `--fair-queue`. Instead of dropping the packets over the limit, whoever they are
+//! from, connections wait for their turn to send one, and turns go to the source that has had
+//! the fewest bytes forwarded (start-time fair queueing). A client that sends more than its
+//! share only holds up its own connections, whose queues fill and drop its packets, while
+//! the others keep forwarding as if the backend weren't busy.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tokio::sync::{Notify, oneshot};
+use tokio_util::sync::CancellationToken;
+
+use crate::ratelimit::RateLimiter;
+
+pub struct FairQueue {
+    limiter: RateLimiter,
+    state: Mutex<State>,
+    /// Wakes the scheduler when a connection starts waiting
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    /// The bytes forwarded since the backend got busy, as the start tag of the last packet
+    /// let through
+    virtual_bytes: u64,
+    /// Where each source's bytes so far take it, for the sources that had packets waiting
+    /// recently
+    finish: HashMap<IpAddr, u64>,
+    /// The packets waiting for their turn, by start tag and then in arrival order
+    waiting: BTreeMap<(u64, u64), Turn>,
+    arrivals: u64,
+}
+
+struct Turn {
+    len: usize,
+    granted: oneshot::Sender<()>,
+}
+
+impl FairQueue {
+    pub fn new(limiter: RateLimiter) -> Self {
+        FairQueue {
+            limiter,
+            state: Mutex::default(),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Waits until a packet of `len` bytes from `source` may be forwarded, returning whether it
+    /// had to wait at all
+    pub async fn wait_turn(&self, source: IpAddr, len: usize) -> bool {
+        let granted = {
+            let mut state = self.state.lock().unwrap();
+            // While nobody is waiting, packets within the limit go straight through
+            if state.waiting.is_empty() && self.limiter.allow(len) {
+                return false;
+            }
+            let start = state
+                .virtual_bytes
+                .max(state.finish.get(&source).copied().unwrap_or(0));
+            state.finish.insert(source, start + len as u64);
+            let (tx, rx) = oneshot::channel();
+            let arrival = state.arrivals;
+            state.arrivals += 1;
+            state
+                .waiting
+                .insert((start, arrival), Turn { len, granted: tx });
+            rx
+        };
+        self.notify.notify_one();
+        // The scheduler dropping the turn on shutdown lets the packet through too
+        let _ = granted.await;
+        true
+    }
+
+    /// Hands out turns as fast as the backend's limits allow, until the proxy shuts down
+    pub async fn schedule(&self, shutdown: CancellationToken) {
+        loop {
+            let next = self.next_turn();
+            let Some(turn) = next else {
+                tokio::select! {
+                    _ = self.notify.notified() => continue,
+                    _ = shutdown.cancelled() => break,
+                }
+            };
+            let delay = self.limiter.reserve(turn.len);
+            if !delay.is_zero() {
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = shutdown.cancelled() => break,
+                }
+            }
+            // A connection that closed while waiting doesn't need its turn any more
+            let _ = turn.granted.send(());
+        }
+        self.state.lock().unwrap().waiting.clear();
+    }
+
+    fn next_turn(&self) -> Option<Turn> {
+        let mut state = self.state.lock().unwrap();
+        let ((start, _), turn) = state.waiting.pop_first()?;
+        state.virtual_bytes = start;
+        // Sources that have caught up start afresh from the current tag anyway
+        if state.finish.len() > 2 * state.waiting.len() + 64 {
+            let virtual_bytes = state.virtual_bytes;
+            state.finish.retain(|_, finish| *finish > virtual_bytes);
+        }
+        Some(turn)
+    }
+}
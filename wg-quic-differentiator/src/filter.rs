@@ -0,0 +1,27 @@
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Decides which client addresses may be proxied, from the configured allow and deny lists
+pub struct SourceFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl SourceFilter {
+    pub fn new(allow: &[IpNet], deny: &[IpNet]) -> Self {
+        SourceFilter {
+            allow: IpNet::aggregate(&allow.to_vec()),
+            deny: IpNet::aggregate(&deny.to_vec()),
+        }
+    }
+
+    /// A denied address is never allowed, and an empty allow list allows everything else
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack socket show up as IPv4-mapped IPv6 addresses
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
@@ -0,0 +1,80 @@
+use std::io;
+use tokio::net::UdpSocket;
+
+/// Most segments the kernel accepts in one send (`UDP_MAX_SEGMENTS`)
+pub const MAX_SEGMENTS: usize = 64;
+/// Most bytes in one send, as the segments travel through the stack as a single datagram
+pub const MAX_LEN: usize = 65507;
+
+/// Whether datagrams can be sent in batches with `UDP_SEGMENT` on this platform
+pub fn supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+/// Sends `buf` as datagrams of `segment_len` bytes each (the last one may be shorter) to the
+/// address the socket is connected to, with a single syscall
+#[cfg(target_os = "linux")]
+pub async fn send(sock: &UdpSocket, buf: &[u8], segment_len: usize) -> io::Result<()> {
+    use tokio::io::Interest;
+
+    loop {
+        sock.writable().await?;
+        match sock.try_io(Interest::WRITABLE, || sendmsg(sock, buf, segment_len)) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn send(_sock: &UdpSocket, _buf: &[u8], _segment_len: usize) -> io::Result<()> {
+    unreachable!("segmented sends are only enabled where they are supported")
+}
+
+/// Whether a segmented send failed because the socket or the device it goes out of can't
+/// split datagrams, so the packets have to be sent one by one instead
+pub fn is_unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EIO | libc::EINVAL | libc::ENOPROTOOPT | libc::EOPNOTSUPP)
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn sendmsg(sock: &UdpSocket, buf: &[u8], segment_len: usize) -> io::Result<()> {
+    use std::mem;
+    use std::os::fd::AsRawFd;
+
+    let mut iovec = libc::iovec {
+        iov_base: buf.as_ptr().cast_mut().cast(),
+        iov_len: buf.len(),
+    };
+    // SAFETY: CMSG_SPACE only computes a size
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as _) } as usize;
+    // u64s keep the control buffer aligned for the cmsghdr in it
+    let mut control = [0u64; 4];
+    assert!(space <= mem::size_of_val(&control));
+
+    // SAFETY: an all zero msghdr is valid, and is filled in below
+    let mut header: libc::msghdr = unsafe { mem::zeroed() };
+    header.msg_iov = &mut iovec;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr().cast();
+    header.msg_controllen = space as _;
+    // SAFETY: the control buffer is large enough for one cmsghdr carrying a u16
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&header);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as _) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<u16>(), segment_len as u16);
+    }
+
+    // SAFETY: the header points at a live iovec and control buffer of the advertised sizes
+    let sent = unsafe { libc::sendmsg(sock.as_raw_fd(), &header, 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
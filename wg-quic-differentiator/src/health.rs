@@ -0,0 +1,96 @@
+//! Health probes that mark backends down, so new connections avoid them and existing ones
+//! move to another backend of the same type.
+
+use arc_swap::ArcSwap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use crate::socket::{self, EgressOptions};
+
+/// How long a probe waits for the backend to answer
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Probes that fail in a row before a backend is marked down, so a single lost datagram
+/// doesn't move its clients
+const FAILURES_BEFORE_DOWN: u32 = 2;
+
+/// How backends are probed, with `--health-probe-interval`
+pub struct Probe {
+    pub interval: Duration,
+    /// Sent to the backend, which must answer it. When empty, the backend is only checked for
+    /// refusing datagrams.
+    pub payload: Vec<u8>,
+}
+
+/// Whether a backend is up. Backends are up until a probe says otherwise, and always are when
+/// probing is off.
+pub struct Health {
+    address: Arc<str>,
+    /// The backend's current address, which probes follow when it is resolved again
+    backend: Arc<ArcSwap<SocketAddr>>,
+    up: AtomicBool,
+}
+
+impl Health {
+    pub fn new(address: Arc<str>, backend: Arc<ArcSwap<SocketAddr>>) -> Self {
+        Health {
+            address,
+            backend,
+            up: AtomicBool::new(true),
+        }
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    /// Probes the backend every `probe.interval` until `shutdown` is cancelled
+    pub async fn probe_periodically(
+        &self,
+        probe: &Probe,
+        options: EgressOptions<'_>,
+        shutdown: CancellationToken,
+    ) {
+        let mut interval = tokio::time::interval(probe.interval);
+        let mut failures = 0;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.cancelled() => break,
+            }
+            match self.probe(probe, options).await {
+                Ok(()) => {
+                    failures = 0;
+                    if !self.up.swap(true, Ordering::Relaxed) {
+                        log::info!("Backend {} is up again", self.address);
+                    }
+                }
+                Err(reason) => {
+                    failures += 1;
+                    if failures >= FAILURES_BEFORE_DOWN && self.up.swap(false, Ordering::Relaxed) {
+                        log::warn!("Backend {} is down: {}", self.address, reason);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn probe(&self, probe: &Probe, options: EgressOptions<'_>) -> Result<(), String> {
+        let backend = **self.backend.load();
+        let sock = socket::connect_backend_socket(backend, options)
+            .await
+            .map_err(|e| e.to_string())?;
+        sock.send(&probe.payload).await.map_err(|e| e.to_string())?;
+        // The answer itself doesn't matter, only that there is one
+        let mut buf = [0; 2048];
+        match tokio::time::timeout(PROBE_TIMEOUT, sock.recv(&mut buf)).await {
+            Ok(Ok(_)) => Ok(()),
+            // A closed port is reported as the datagram being refused
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) if probe.payload.is_empty() => Ok(()),
+            Err(_) => Err(format!("no response within {PROBE_TIMEOUT:?}")),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! A deliberately tiny HTTP/1.1 server for the monitoring endpoints. Every request gets a
+//! single response, after which the connection is closed.
+
+use std::future::Future;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+
+const MAX_REQUEST_SIZE: usize = 8192;
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<String>) -> Self {
+        Response {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Response::new(404, "text/plain", "not found\n")
+    }
+}
+
+/// Answers GET requests on `listener` with `handler`, which is passed the request path,
+/// until `shutdown` is cancelled.
+pub async fn serve<F, Fut>(listener: TcpListener, shutdown: CancellationToken, handler: F)
+where
+    F: Fn(String) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Response> + Send,
+{
+    loop {
+        let stream = tokio::select! {
+            result = listener.accept() => match result {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::error!("Error accepting HTTP connection: {:?}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => break,
+        };
+
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, handler).await {
+                log::debug!("Error handling HTTP request: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection<F, Fut>(mut stream: TcpStream, handler: F) -> io::Result<()>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Response>,
+{
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..len]);
+        if request.len() > MAX_REQUEST_SIZE {
+            return write_response(&mut stream, Response::new(431, "text/plain", "")).await;
+        }
+    }
+
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => {
+            let path = target.split('?').next().unwrap_or(target);
+            handler(path.to_string()).await
+        }
+        (Some(_), Some(_)) => Response::new(405, "text/plain", "method not allowed\n"),
+        _ => Response::new(400, "text/plain", "bad request\n"),
+    };
+    write_response(&mut stream, response).await
+}
+
+async fn write_response(stream: &mut TcpStream, response: Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {reason}\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
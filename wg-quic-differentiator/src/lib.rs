@@ -0,0 +1,374 @@
+//! The packet classifier behind wg-quic-differentiator, which tells WireGuard, QUIC, DTLS and
+//! STUN datagrams apart by looking only at their first bytes.
+//!
+//! The proxy itself lives in the binary; this library only exposes the heuristics, so they
+//! can be reused (for example in packet capture tools) without running it.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub mod dtls;
+pub mod quic;
+pub mod selector;
+pub mod sni;
+pub mod stun;
+#[cfg(feature = "wireguard-over-quic")]
+pub mod wg_over_quic;
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum PacketType {
+    Wireguard,
+    Quic,
+    Dtls,
+    /// STUN and TURN, as used for NAT traversal
+    Stun,
+    /// Neither WireGuard, QUIC, DTLS nor STUN
+    Unknown,
+}
+
+impl PacketType {
+    pub const ALL: [PacketType; 5] = [
+        PacketType::Wireguard,
+        PacketType::Quic,
+        PacketType::Dtls,
+        PacketType::Stun,
+        PacketType::Unknown,
+    ];
+
+    /// Name of the type as used in logs, metrics and the configuration
+    pub fn label(self) -> &'static str {
+        match self {
+            PacketType::Wireguard => "wireguard",
+            PacketType::Quic => "quic",
+            PacketType::Dtls => "dtls",
+            PacketType::Stun => "stun",
+            PacketType::Unknown => "unknown",
+        }
+    }
+}
+
+/// Returns the name of the WireGuard message in `buf`, if it is one
+pub fn wireguard_message(buf: &[u8]) -> Option<&'static str> {
+    // Wireguard messages start with a type of 0x01 to 0x04 followed by 3 bytes of 0x00, and each
+    // type has a fixed length (or for transport data, a minimum one).
+    match buf {
+        [0x01, 0x00, 0x00, 0x00, ..] if buf.len() == 148 => Some("Handshake Initiation"),
+        [0x02, 0x00, 0x00, 0x00, ..] if buf.len() == 92 => Some("Handshake Response"),
+        [0x03, 0x00, 0x00, 0x00, ..] if buf.len() == 64 => Some("Cookie Reply"),
+        [0x04, 0x00, 0x00, 0x00, ..] if buf.len() >= 32 => Some("Data"),
+        _ => None,
+    }
+}
+
+/// The message type of a datagram that starts like a WireGuard message but doesn't have the
+/// length of one, which is either a bug in a client or someone probing the port
+pub fn malformed_wireguard(buf: &[u8]) -> Option<u8> {
+    match buf {
+        [message_type @ 0x01..=0x04, 0x00, 0x00, 0x00, ..] if wireguard_message(buf).is_none() => {
+            Some(*message_type)
+        }
+        _ => None,
+    }
+}
+
+/// The fields of a WireGuard message that identify the session it belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WireguardHeader {
+    pub message: &'static str,
+    /// The index the sender picked for itself, in handshake initiations and responses
+    pub sender_index: Option<u32>,
+    /// The index the receiver picked, in every message but handshake initiations
+    pub receiver_index: Option<u32>,
+}
+
+/// Parses the type and session indices of a WireGuard message, as shown by `wg show` on the
+/// backend
+pub fn parse_wireguard_header(buf: &[u8]) -> Option<WireguardHeader> {
+    let message = wireguard_message(buf)?;
+    let index = |offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+    let (sender_index, receiver_index) = match buf[0] {
+        0x01 => (Some(index(4)), None),
+        0x02 => (Some(index(4)), Some(index(8))),
+        _ => (None, Some(index(4))),
+    };
+    Some(WireguardHeader {
+        message,
+        sender_index,
+        receiver_index,
+    })
+}
+
+/// The type of a datagram, along with what gave it away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Classification {
+    pub packet_type: PacketType,
+    pub reason: Reason,
+    /// Length of the datagram
+    pub len: usize,
+}
+
+/// The header that matched. Formatting a [`Classification`] turns this into a readable
+/// explanation, so it only costs anything when it is logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// A WireGuard message type with its expected length
+    Wireguard {
+        message: &'static str,
+    },
+    Dtls {
+        content_type: dtls::DtlsContentType,
+    },
+    Quic {
+        packet_type: quic::QuicPacketType,
+        version: Option<u32>,
+    },
+    Stun {
+        message: stun::StunMessage,
+    },
+    /// Matched by a [`PacketClassifier`] other than the built-in one
+    Custom {
+        classifier: &'static str,
+    },
+    /// A WireGuard message type with the wrong length. These are dropped rather than
+    /// forwarded anywhere.
+    MalformedWireguard {
+        message_type: u8,
+    },
+    /// None of the headers matched
+    NoMatch {
+        first_byte: Option<u8>,
+    },
+}
+
+impl fmt::Display for Classification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            Reason::Wireguard { message } => write!(f, "wireguard {message}")?,
+            Reason::Dtls { content_type } => write!(f, "dtls {content_type:?} record")?,
+            Reason::Quic {
+                packet_type,
+                version: Some(version),
+            } => write!(f, "quic {packet_type:?} version={version:#010x}")?,
+            Reason::Quic {
+                packet_type,
+                version: None,
+            } => write!(f, "quic {packet_type:?} short header")?,
+            Reason::Stun { message } => {
+                write!(f, "stun {:?} method={:#05x}", message.class, message.method)?
+            }
+            Reason::Custom { classifier } => write!(f, "{classifier} matched")?,
+            Reason::MalformedWireguard { message_type } => {
+                write!(f, "malformed wireguard message type {message_type}")?
+            }
+            Reason::NoMatch {
+                first_byte: Some(first_byte),
+            } => write!(f, "no known header, first byte {first_byte:#04x}")?,
+            Reason::NoMatch { first_byte: None } => write!(f, "empty datagram")?,
+        }
+        write!(f, " len={}", self.len)
+    }
+}
+
+/// Classifies a single datagram
+pub fn classify(buf: &[u8]) -> PacketType {
+    classify_with_reason(buf).packet_type
+}
+
+/// Classifies a single datagram, explaining the decision
+pub fn classify_with_reason(buf: &[u8]) -> Classification {
+    let (packet_type, reason) = if let Some(message) = wireguard_message(buf) {
+        (PacketType::Wireguard, Reason::Wireguard { message })
+    } else if let Some(content_type) = dtls::parse_dtls_record(buf) {
+        (PacketType::Dtls, Reason::Dtls { content_type })
+    } else if let Some(message) = stun::parse_stun_message(buf) {
+        (PacketType::Stun, Reason::Stun { message })
+    } else if let Some(header) = quic::parse_quic_header(buf) {
+        let reason = Reason::Quic {
+            packet_type: header.packet_type,
+            version: header.version,
+        };
+        (PacketType::Quic, reason)
+    } else if let Some(message_type) = malformed_wireguard(buf) {
+        (
+            PacketType::Unknown,
+            Reason::MalformedWireguard { message_type },
+        )
+    } else {
+        let reason = Reason::NoMatch {
+            first_byte: buf.first().copied(),
+        };
+        (PacketType::Unknown, reason)
+    };
+    Classification {
+        packet_type,
+        reason,
+        len: buf.len(),
+    }
+}
+
+/// Recognises a protocol by the first datagram of a flow. Classifiers are tried in order
+/// until one of them matches, so a custom one can add a protocol (or take over part of an
+/// existing one) without changing the built-in heuristics.
+///
+/// There are no packet types beyond [`PacketType`], so a classifier picks which of their
+/// backends a protocol goes to. [`PacketType::Unknown`] sends it to `--forward-unknown-to`,
+/// which suits protocols like OpenVPN that the proxy has no backend option for.
+pub trait PacketClassifier: Send + Sync {
+    /// Returns the type of the datagram, or `None` to leave it to the next classifier
+    fn classify(&self, buf: &[u8]) -> Option<PacketType>;
+
+    /// Like `classify`, but explaining the decision. By default this only names the
+    /// classifier.
+    fn classify_with_reason(&self, buf: &[u8]) -> Option<Classification> {
+        let packet_type = self.classify(buf)?;
+        Some(Classification {
+            packet_type,
+            reason: Reason::Custom {
+                classifier: std::any::type_name::<Self>(),
+            },
+            len: buf.len(),
+        })
+    }
+}
+
+pub use selector::BackendSelector;
+
+/// The WireGuard, DTLS, STUN and QUIC heuristics of [`classify`]
+pub struct BuiltinClassifier;
+
+impl PacketClassifier for BuiltinClassifier {
+    fn classify(&self, buf: &[u8]) -> Option<PacketType> {
+        self.classify_with_reason(buf).map(|c| c.packet_type)
+    }
+
+    fn classify_with_reason(&self, buf: &[u8]) -> Option<Classification> {
+        // Malformed WireGuard is kept, so that it is dropped rather than left to the next
+        // classifier
+        Some(classify_with_reason(buf)).filter(|c| {
+            c.packet_type != PacketType::Unknown
+                || matches!(c.reason, Reason::MalformedWireguard { .. })
+        })
+    }
+}
+
+/// An ordered list of classifiers, by default only the built-in one. This is for library
+/// users with classifiers of their own: the proxy binary always uses the default.
+pub struct Classifiers(Vec<Box<dyn PacketClassifier>>);
+
+impl Classifiers {
+    pub fn new(classifiers: Vec<Box<dyn PacketClassifier>>) -> Self {
+        Classifiers(classifiers)
+    }
+
+    /// Classifies a datagram with the first classifier that matches it
+    pub fn classify(&self, buf: &[u8]) -> PacketType {
+        self.0
+            .iter()
+            .find_map(|classifier| classifier.classify(buf))
+            .unwrap_or(PacketType::Unknown)
+    }
+
+    /// Classifies a datagram with the first classifier that matches it, explaining the
+    /// decision
+    pub fn classify_with_reason(&self, buf: &[u8]) -> Classification {
+        self.0
+            .iter()
+            .find_map(|classifier| classifier.classify_with_reason(buf))
+            .unwrap_or(Classification {
+                packet_type: PacketType::Unknown,
+                reason: Reason::NoMatch {
+                    first_byte: buf.first().copied(),
+                },
+                len: buf.len(),
+            })
+    }
+}
+
+impl Default for Classifiers {
+    fn default() -> Self {
+        Classifiers::new(vec![Box::new(BuiltinClassifier)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A handshake between two test peers and the first messages over it, made by
+    // tests/data/wireguard_frames.py. The initiator's index is 0x1a2b3c4d, the responder's
+    // 0x5e6f7081.
+    const INITIATION: &[u8] = include_bytes!("../tests/data/wireguard_initiation.bin");
+    const RESPONSE: &[u8] = include_bytes!("../tests/data/wireguard_response.bin");
+    const COOKIE_REPLY: &[u8] = include_bytes!("../tests/data/wireguard_cookie_reply.bin");
+    const KEEPALIVE: &[u8] = include_bytes!("../tests/data/wireguard_keepalive.bin");
+    const DATA: &[u8] = include_bytes!("../tests/data/wireguard_data.bin");
+
+    const INITIATOR: u32 = 0x1a2b_3c4d;
+    const RESPONDER: u32 = 0x5e6f_7081;
+
+    #[test]
+    fn parses_each_message_type() {
+        let cases = [
+            (INITIATION, "Handshake Initiation", Some(INITIATOR), None),
+            (
+                RESPONSE,
+                "Handshake Response",
+                Some(RESPONDER),
+                Some(INITIATOR),
+            ),
+            (COOKIE_REPLY, "Cookie Reply", None, Some(INITIATOR)),
+            (KEEPALIVE, "Data", None, Some(RESPONDER)),
+            (DATA, "Data", None, Some(RESPONDER)),
+        ];
+        for (frame, message, sender_index, receiver_index) in cases {
+            assert_eq!(wireguard_message(frame), Some(message));
+            assert_eq!(
+                parse_wireguard_header(frame),
+                Some(WireguardHeader {
+                    message,
+                    sender_index,
+                    receiver_index,
+                })
+            );
+            assert_eq!(malformed_wireguard(frame), None);
+            assert_eq!(
+                Classifiers::default().classify(frame),
+                PacketType::Wireguard
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_handshake_messages_of_the_wrong_length() {
+        for frame in [INITIATION, RESPONSE, COOKIE_REPLY] {
+            let mut longer = frame.to_vec();
+            longer.push(0);
+            for wrong in [&frame[..frame.len() - 1], &longer[..]] {
+                assert_eq!(wireguard_message(wrong), None);
+                assert_eq!(parse_wireguard_header(wrong), None);
+                assert_eq!(malformed_wireguard(wrong), Some(frame[0]));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_data_messages_shorter_than_a_keepalive() {
+        let short = &KEEPALIVE[..31];
+        assert_eq!(wireguard_message(short), None);
+        assert_eq!(malformed_wireguard(short), Some(0x04));
+        // Any longer length is fine, as data is padded to 16 bytes but not otherwise fixed
+        assert_eq!(wireguard_message(&DATA[..33]), Some("Data"));
+    }
+
+    #[test]
+    fn rejects_messages_with_reserved_bits_set() {
+        for frame in [INITIATION, RESPONSE, COOKIE_REPLY, DATA] {
+            let mut reserved = frame.to_vec();
+            reserved[3] = 0x01;
+            assert_eq!(wireguard_message(&reserved), None);
+            assert_eq!(malformed_wireguard(&reserved), None);
+        }
+    }
+}
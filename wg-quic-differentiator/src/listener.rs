@@ -0,0 +1,59 @@
+//! The sockets clients send their packets to, one for every `--listen` address. They share
+//! the connections and backends, but a connection always answers its client through the
+//! listener its first packet arrived on.
+
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+use crate::metrics::Counter;
+use crate::socket::{self, BufferSizes};
+
+pub struct Listener {
+    pub addr: SocketAddr,
+    pub sock: UdpSocket,
+    pub packets_received: Counter,
+    pub bytes_received: Counter,
+    pub packets_sent: Counter,
+    pub bytes_sent: Counter,
+}
+
+impl Listener {
+    pub fn bind(
+        addr: SocketAddr,
+        interface: Option<&str>,
+        buffers: BufferSizes,
+    ) -> io::Result<Listener> {
+        Ok(Listener {
+            addr,
+            sock: socket::bind_listen_socket(addr, interface, buffers)?,
+            packets_received: Counter::new(),
+            bytes_received: Counter::new(),
+            packets_sent: Counter::new(),
+            bytes_sent: Counter::new(),
+        })
+    }
+
+    /// Takes over a socket that is already bound, such as one passed in by systemd
+    pub fn from_std(sock: std::net::UdpSocket, buffers: BufferSizes) -> io::Result<Listener> {
+        let addr = sock.local_addr()?;
+        socket::set_listen_buffer_sizes((&sock).into(), addr, buffers)?;
+        sock.set_nonblocking(true)?;
+        Ok(Listener {
+            addr,
+            sock: UdpSocket::from_std(sock)?,
+            packets_received: Counter::new(),
+            bytes_received: Counter::new(),
+            packets_sent: Counter::new(),
+            bytes_sent: Counter::new(),
+        })
+    }
+
+    /// Sends a response to a client, counting it
+    pub async fn send_to(&self, buf: &[u8], client: SocketAddr) -> io::Result<usize> {
+        let sent = self.sock.send_to(buf, client).await?;
+        self.packets_sent.inc();
+        self.bytes_sent.add(sent as u64);
+        Ok(sent)
+    }
+}
@@ -0,0 +1,45 @@
+use clap::ValueEnum;
+use log::kv::{self, VisitSource};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, including structured fields such as `client_addr`
+    Json,
+}
+
+/// Sets up `env_logger`, which is still configured through `RUST_LOG`
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    match format {
+        // The structured fields only repeat what the messages already say
+        LogFormat::Text => builder.format_key_values(|_, _| Ok(())),
+        LogFormat::Json => builder.format(|buf, record| {
+            let mut line = serde_json::Map::new();
+            line.insert("timestamp".into(), buf.timestamp().to_string().into());
+            line.insert("level".into(), record.level().as_str().into());
+            line.insert("target".into(), record.target().into());
+            line.insert("message".into(), record.args().to_string().into());
+            let _ = record.key_values().visit(&mut JsonFields(&mut line));
+            writeln!(buf, "{}", serde_json::Value::Object(line))
+        }),
+    };
+    builder.init();
+}
+
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = match value.to_u64() {
+            Some(n) => n.into(),
+            None => value.to_string().into(),
+        };
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
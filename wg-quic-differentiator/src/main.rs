@@ -1,158 +1,1879 @@
-use std::collections::HashMap;
+#[cfg(unix)]
+mod admin;
+mod audit;
+mod backend;
+mod config;
+mod connections;
+mod egress;
+mod fair_queue;
+mod filter;
+mod gso;
+mod health;
+mod http;
+mod listener;
+mod logging;
+mod metrics;
+mod mirror;
+mod pcap;
+mod pool;
+mod proxy_protocol;
+mod queue;
+mod ratelimit;
+mod recv;
+mod reload;
+mod socket;
+mod socks5;
+mod state;
+mod systemd;
+mod tcp;
+mod throttle;
+#[cfg(all(feature = "xdp", target_os = "linux"))]
+mod xdp;
+
+use arc_swap::ArcSwap;
+use audit::AuditLog;
+use backend::Backends;
+use config::{Config, ConnectionLimit, ProtocolSwitch, ResponseLimit};
+use connections::{ClientKey, Connection, ConnectionStats, Connections, LimitExceeded, ShardGuard};
+use egress::Egress;
+use fair_queue::FairQueue;
+use listener::Listener;
+use metrics::METRICS;
+use mirror::Mirror;
+use pcap::Capture;
+use pool::BufferPool;
+use ratelimit::RateLimiter;
+use recv::Receiver;
+use reload::Live;
+use socks5::Association;
+use state::Affinities;
+use std::hash::{BuildHasher, BuildHasherDefault, DefaultHasher};
 use std::io;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::net::UdpSocket;
-use tokio::sync::{Mutex, mpsc};
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-enum PacketType {
-    Wireguard,
-    Quic,
-}
+use std::time::Duration;
+use throttle::{KeyedThrottle, Throttle};
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tokio_util::task::TaskTracker;
+use wg_quic_differentiator::{
+    Classification, Classifiers, PacketType, Reason, parse_wireguard_header, quic, sni,
+};
 
-const SERVER_ADDR: &str = "0.0.0.0:8080";
-// const WIREGUARD_SERVER_ADDR: &str = "wireguard:51820";
-// const QUIC_SERVER_ADDR: &str = "http3-server:8443";
-const WIREGUARD_SERVER_ADDR: &str = "localhost:51820";
-const QUIC_SERVER_ADDR: &str = "localhost:8443";
-const CONNECTION_TIMEOUT_SECS: u64 = 30;
+/// How often sending to a backend is retried after a transient error
+const SEND_RETRIES: usize = 3;
 
-const BUFFER_SIZE: usize = 65536;
+/// Buffers for packets queued towards a backend
+static PACKET_BUFFERS: BufferPool = BufferPool::new(4096);
+/// Full size buffers used by the forwarding tasks to receive responses into
+static RESPONSE_BUFFERS: BufferPool = BufferPool::new(256);
 
-type ConnectionMap = Arc<Mutex<HashMap<(SocketAddr, PacketType), mpsc::Sender<Vec<u8>>>>>;
+/// State shared between the receive loop and the forwarding tasks
+struct Proxy {
+    /// The config the proxy started with. The settings a SIGHUP reloads are read from `live`.
+    config: Config,
+    live: ArcSwap<Live>,
+    /// Tried in order on the first packet of every connection
+    classifiers: Classifiers,
+    capture: Option<Capture>,
+    /// The start of the first packet of every new connection, with `--capture-handshake`
+    handshakes: Option<Capture>,
+    /// A record of every new connection, with `--audit-log`
+    audit: Option<AuditLog>,
+    /// Connections from before a restart, with `--state-file`
+    affinities: Option<Affinities>,
+    listeners: Vec<Arc<Listener>>,
+    // Map to maintain persistent forwarding sockets per client
+    connections: Connections,
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+    /// Limits the `--reject-unknown` log lines per source
+    rejections: KeyedThrottle<IpAddr>,
+    /// Collectors that get copies of the packets of each type, with `--mirror-quic` and
+    /// `--mirror-wireguard`
+    mirrors: [Option<Arc<Mirror>>; PacketType::ALL.len()],
+}
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    env_logger::init();
-    let client_sock = Arc::new(UdpSocket::bind(SERVER_ADDR).await?);
-    log::info!("Listening on {SERVER_ADDR}...");
+    let config = Config::load()?;
+    // The binary has no way to load custom classifiers, only library users can add them
+    let classifiers = Classifiers::default();
+    if let Some(path) = &config.classify_file {
+        return classify_file(path, &classifiers);
+    }
+    logging::init(config.log_format);
+    let backends = Backends::resolve(&config, config.backend_selection.selector()).await?;
+    let backends = Arc::new(backends);
+    let shutdown = CancellationToken::new();
+    let stop_backend_tasks = shutdown.child_token();
 
-    // Map to maintain persistent forwarding sockets per client
-    let connections: ConnectionMap = Arc::new(Mutex::new(HashMap::new()));
-    let mut buf = [0; BUFFER_SIZE];
+    let mut listeners = Vec::new();
+    let mut inherited_tcp = Vec::new();
+    if let Some(inherited) = systemd::listen_fds()? {
+        // The sockets systemd bound take the place of the --listen addresses
+        for sock in inherited.udp {
+            let listener = Listener::from_std(sock, config.buffer_sizes())?;
+            log::info!("Listening on {} (socket activated)...", listener.addr);
+            listeners.push(Arc::new(listener));
+        }
+        inherited_tcp = inherited.tcp;
+    } else {
+        for &addr in &config.listen {
+            let listener = exit_if_in_use(
+                Listener::bind(
+                    addr,
+                    config.listen_interface.as_deref(),
+                    config.buffer_sizes(),
+                ),
+                "clients",
+                addr,
+            )?;
+            log::info!("Listening on {}...", addr);
+            listeners.push(Arc::new(listener));
+        }
+    }
+
+    // Detached again when main returns
+    #[cfg(all(feature = "xdp", target_os = "linux"))]
+    let _xdp = attach_xdp(&config);
+
+    let proxy = Arc::new(
+        Proxy::new(
+            config,
+            classifiers,
+            listeners,
+            backends.clone(),
+            shutdown,
+            &stop_backend_tasks,
+        )
+        .await?,
+    );
+
+    spawn_backend_tasks(&proxy, &backends, stop_backend_tasks);
+    #[cfg(unix)]
+    proxy.tasks.spawn(reload::reload_on_sighup(proxy.clone()));
+
+    if proxy.config.state_file.is_some() {
+        let state_proxy = proxy.clone();
+        proxy.tasks.spawn(async move {
+            let path = state_proxy.config.state_file.as_deref().unwrap();
+            state::save_periodically(
+                path,
+                &state_proxy.connections,
+                state_proxy.affinities.as_ref(),
+                &state_proxy.config,
+                state_proxy.shutdown.clone(),
+            )
+            .await
+        });
+    }
+
+    if let Some(metrics_addr) = proxy.config.metrics_addr {
+        let listener = exit_if_in_use(
+            TcpListener::bind(metrics_addr).await,
+            "metrics",
+            metrics_addr,
+        )?;
+        log::info!("Serving metrics on http://{metrics_addr}/metrics");
+        let metrics_proxy = proxy.clone();
+        proxy
+            .tasks
+            .spawn(http::serve(listener, proxy.shutdown.clone(), move |path| {
+                let proxy = metrics_proxy.clone();
+                async move {
+                    match path.as_str() {
+                        "/metrics" => http::Response::new(
+                            200,
+                            "text/plain; version=0.0.4",
+                            METRICS.render()
+                                + &metrics::render_backends(&proxy.live.load().backends)
+                                + &metrics::render_listeners(&proxy.listeners),
+                        ),
+                        _ => http::Response::not_found(),
+                    }
+                }
+            }));
+    }
+
+    if let Some(health_addr) = proxy.config.health_addr {
+        let listener = exit_if_in_use(
+            TcpListener::bind(health_addr).await,
+            "health checks",
+            health_addr,
+        )?;
+        log::info!("Serving health checks on http://{health_addr}/healthz and /readyz");
+        let health_proxy = proxy.clone();
+        proxy
+            .tasks
+            .spawn(http::serve(listener, proxy.shutdown.clone(), move |path| {
+                let proxy = health_proxy.clone();
+                async move {
+                    match path.as_str() {
+                        "/healthz" => http::Response::new(200, "text/plain", "ok\n"),
+                        "/readyz" => {
+                            let backends = proxy.live.load().backends.clone();
+                            let failures = backends.check().await;
+                            if failures.is_empty() {
+                                http::Response::new(200, "text/plain", "ok\n")
+                            } else {
+                                http::Response::new(503, "text/plain", failures.join("\n") + "\n")
+                            }
+                        }
+                        _ => http::Response::not_found(),
+                    }
+                }
+            }));
+    }
+
+    if let Some(path) = &proxy.config.admin_socket {
+        #[cfg(unix)]
+        {
+            let listener = admin::bind(path)?;
+            log::info!("Serving admin commands on {}", path.display());
+            proxy.tasks.spawn(admin::serve(listener, proxy.clone()));
+        }
+        #[cfg(not(unix))]
+        log::warn!(
+            "Ignoring admin socket {}, Unix sockets are not supported on this platform",
+            path.display()
+        );
+    }
+
+    if let Some(backend) = &proxy.config.tcp_backend {
+        let mut tcp_listeners = Vec::new();
+        if inherited_tcp.is_empty() {
+            for listener in &proxy.listeners {
+                let addr = listener.addr;
+                tcp_listeners.push(exit_if_in_use(
+                    TcpListener::bind(addr).await,
+                    "TCP clients",
+                    addr,
+                )?);
+            }
+        }
+        for listener in inherited_tcp {
+            listener.set_nonblocking(true)?;
+            tcp_listeners.push(TcpListener::from_std(listener)?);
+        }
+        for listener in tcp_listeners {
+            log::info!(
+                "Forwarding TCP connections on {} to {}",
+                listener.local_addr()?,
+                backend
+            );
+            proxy
+                .tasks
+                .spawn(tcp::serve(listener, proxy.clone(), backend.clone()));
+        }
+    } else if !inherited_tcp.is_empty() {
+        log::warn!("Ignoring the TCP sockets passed in by systemd, as --tcp-backend isn't set");
+    }
+
+    let receivers: Vec<_> = proxy
+        .listeners
+        .iter()
+        .map(|_| {
+            Receiver::new(
+                receive_buffer_size(proxy.config.max_datagram_size),
+                proxy.config.batch_recv,
+            )
+        })
+        .collect();
+    if proxy.config.batch_recv && !receivers[0].is_batched() {
+        log::warn!(
+            "Batched receive is not supported on this platform, receiving one packet at a time"
+        );
+    }
+    if proxy.config.gso && !gso::supported() {
+        log::warn!("Segmented sends are not supported on this platform, sending one at a time");
+    }
+
+    // Every listener has a receive loop of its own, and any of them failing stops the proxy
+    let mut receiving = JoinSet::new();
+    for (listener, receiver) in proxy.listeners.iter().zip(receivers) {
+        receiving.spawn(receive(proxy.clone(), listener.clone(), receiver));
+    }
+    tokio::select! {
+        Some(result) = receiving.join_next() => {
+            result.map_err(io::Error::other)??;
+        }
+        result = shutdown_signal() => result?,
+    }
+    receiving.abort_all();
+
+    // Stop the forwarding tasks, letting them flush anything still queued for their backend
+    let active = proxy.connections.len();
+    log::info!("Shutting down, closing {active} active connections");
+    // Saved before the connections close and remove themselves
+    if let Some(path) = &proxy.config.state_file
+        && let Err(e) = state::save(
+            path,
+            &proxy.connections,
+            proxy.affinities.as_ref(),
+            &proxy.config,
+        )
+        .await
+    {
+        log::error!("Error saving state to {}: {}", path.display(), e);
+    }
+    proxy.shutdown.cancel();
+    proxy.tasks.close();
+    let outstanding = proxy.tasks.len();
+    let grace = proxy.config.shutdown_grace();
+    if tokio::time::timeout(grace, proxy.tasks.wait())
+        .await
+        .is_err()
+    {
+        // Returning drops the runtime, which cancels what is left and closes its sockets
+        let aborted = proxy.tasks.len();
+        log::warn!(
+            "{} of {} tasks finished within the {:?} shutdown grace period, aborting the other {}",
+            outstanding - aborted,
+            outstanding,
+            grace,
+            aborted
+        );
+    } else {
+        log::info!("Shutdown complete, all {outstanding} tasks finished");
+    }
+
+    Ok(())
+}
 
+/// Receives packets from clients on `listener` and handles them, until an error that means the
+/// socket is broken
+async fn receive(
+    proxy: Arc<Proxy>,
+    listener: Arc<Listener>,
+    mut receiver: Receiver,
+) -> io::Result<()> {
     loop {
-        let (len, addr) = client_sock.recv_from(&mut buf).await?;
-        log::info!("{:?} bytes received from {:?}", len, addr);
-        if log::log_enabled!(log::Level::Debug) {
-            log::debug!("Data: {:02x?}", &buf[..len.min(32)]);
+        let count = match receiver.recv(&listener.sock).await {
+            Ok(count) => count,
+            Err(e) if is_recoverable_recv_error(&e) => {
+                static RECV_ERROR_WARNING: Throttle = Throttle::new(Duration::from_secs(10));
+                METRICS.receive_errors.inc();
+                if RECV_ERROR_WARNING.allow() {
+                    log::warn!("Error receiving from clients, continuing: {}", e);
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        for index in 0..count {
+            let (packet_data, addr) = receiver.packet(index);
+            if packet_data.len() > proxy.config.max_datagram_size {
+                let len = receiver.datagram_len(index);
+                report_truncated(addr, len, proxy.config.max_datagram_size);
+                continue;
+            }
+            // Nothing can be classified from an empty datagram, and no backend expects one
+            if packet_data.is_empty() {
+                METRICS.empty_datagrams.inc();
+                log::debug!(client_addr:% = addr; "Dropping empty datagram from {:?}", addr);
+                continue;
+            }
+            listener.packets_received.inc();
+            listener.bytes_received.add(packet_data.len() as u64);
+            proxy.handle_packet(packet_data, addr, &listener).await?;
+        }
+    }
+}
+
+/// Errors from receiving on the listen socket that don't mean the socket is broken. Some
+/// platforms report an ICMP error caused by an earlier send (such as port unreachable) on the
+/// next receive, and the kernel may briefly run out of buffers under load.
+fn is_recoverable_recv_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::OutOfMemory
+    )
+}
+
+/// Prints how the packet in the file at `path` is classified, such as
+/// `quic: quic Initial version=0x00000001 len=1200`, for checking the classifier against
+/// captured packets
+fn classify_file(path: &std::path::Path, classifiers: &Classifiers) -> io::Result<()> {
+    let packet = std::fs::read(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("can't read {}: {e}", path.display())))?;
+    let classification = classifiers.classify_with_reason(&packet);
+    println!("{}: {}", classification.packet_type.label(), classification);
+    Ok(())
+}
+
+/// Attaches the XDP program with `--xdp-interface`, carrying on without it where it can't be
+#[cfg(all(feature = "xdp", target_os = "linux"))]
+fn attach_xdp(config: &Config) -> Option<xdp::Attached> {
+    let interface = config.xdp_interface.as_deref()?;
+    match xdp::attach(interface) {
+        Ok(attached) => {
+            log::info!(
+                "Attached the XDP program to {}, which passes every packet on to be classified here",
+                interface
+            );
+            Some(attached)
+        }
+        Err(e) => {
+            log::warn!(
+                "Can't attach the XDP program to {}: {}, classifying every packet in userspace",
+                interface,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Starts the background tasks of a set of backends, which run until `stop` is cancelled:
+/// demultiplexing shared sockets, handing out fair queue turns, filling socket pools, health
+/// probes and resolving the backends again
+fn spawn_backend_tasks(proxy: &Arc<Proxy>, backends: &Arc<Backends>, stop: CancellationToken) {
+    for shared in backends.shared_sockets() {
+        let shared = shared.clone();
+        let max_datagram_size = proxy.config.max_datagram_size;
+        let stop = stop.clone();
+        proxy
+            .tasks
+            .spawn(async move { shared.demultiplex(max_datagram_size, stop).await });
+    }
+
+    for fair_queue in backends.fair_queues() {
+        let fair_queue = fair_queue.clone();
+        let stop = stop.clone();
+        proxy
+            .tasks
+            .spawn(async move { fair_queue.schedule(stop).await });
+    }
+
+    for (packet_type, pool) in backends.socket_pools() {
+        let pool = pool.clone();
+        let fill_proxy = proxy.clone();
+        let stop = stop.clone();
+        proxy.tasks.spawn(async move {
+            pool.fill(fill_proxy.egress_options(packet_type), stop)
+                .await
+        });
+    }
+
+    if proxy.config.health_probe().is_some() {
+        for (packet_type, backend) in backends.all() {
+            let health = backend.health.clone();
+            let probe_proxy = proxy.clone();
+            let stop = stop.clone();
+            proxy.tasks.spawn(async move {
+                let probe = probe_proxy.config.health_probe().unwrap();
+                health
+                    .probe_periodically(&probe, probe_proxy.egress_options(packet_type), stop)
+                    .await
+            });
+        }
+    }
+
+    if proxy.config.resolve_interval > 0 {
+        let backends = backends.clone();
+        let interval = proxy.config.resolve_interval();
+        proxy
+            .tasks
+            .spawn(async move { backends.refresh_periodically(interval, stop).await });
+    }
+}
+
+/// Exits with a message that the address to listen for `purpose` on is taken, which usually
+/// means another instance is running, rather than returning the bare `AddrInUse` error
+fn exit_if_in_use<T>(result: io::Result<T>, purpose: &str, addr: SocketAddr) -> io::Result<T> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            eprintln!(
+                "Can't listen for {purpose} on {addr}: port {} is already in use; is another instance running?",
+                addr.port()
+            );
+            std::process::exit(1);
+        }
+        result => result,
+    }
+}
+
+/// Resolves once the process receives SIGINT or SIGTERM
+#[cfg(unix)]
+async fn shutdown_signal() -> io::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = sigterm.recv() => {}
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+/// A packet's wait for its turn in the fair queue, see [`FairQueue::wait_turn`]
+type Turn<'a> = Pin<Box<dyn Future<Output = bool> + Send + 'a>>;
+
+impl Proxy {
+    /// Sets up the proxy for `config`, taking packets from `listeners` and forwarding them to
+    /// `backends`, whose tasks are stopped by `stop_backend_tasks` once a reload replaces
+    /// them. New connections are classified by `classifiers`.
+    async fn new(
+        config: Config,
+        classifiers: Classifiers,
+        listeners: Vec<Arc<Listener>>,
+        backends: Arc<Backends>,
+        shutdown: CancellationToken,
+        stop_backend_tasks: &CancellationToken,
+    ) -> io::Result<Self> {
+        let mut mirrors = [const { None }; PacketType::ALL.len()];
+        for packet_type in PacketType::ALL {
+            if let Some(address) = config.mirror(packet_type) {
+                mirrors[packet_type as usize] = Some(Arc::new(Mirror::bind(address).await?));
+            }
+        }
+        Ok(Proxy {
+            classifiers,
+            capture: config
+                .pcap_out
+                .as_deref()
+                .map(|path| Capture::create(path, None))
+                .transpose()?,
+            handshakes: config
+                .capture_handshake_out
+                .as_deref()
+                .map(|path| Capture::create(path, config.capture_handshake))
+                .transpose()?,
+            audit: config
+                .audit_log
+                .as_deref()
+                .map(AuditLog::open)
+                .transpose()?,
+            affinities: config
+                .state_file
+                .as_deref()
+                .map(|path| Affinities::load(path, &config))
+                .transpose()?,
+            mirrors,
+            live: ArcSwap::from_pointee(Live::new(
+                config.clone(),
+                backends,
+                Arc::new(stop_backend_tasks.clone().drop_guard()),
+            )),
+            config,
+            listeners,
+            connections: Connections::new(),
+            shutdown,
+            tasks: TaskTracker::new(),
+            rejections: KeyedThrottle::new(Duration::from_secs(10), 4096),
+        })
+    }
+
+    /// Logs a packet received from a client and forwards it, if the client is allowed
+    async fn handle_packet(
+        self: &Arc<Self>,
+        packet_data: &[u8],
+        addr: SocketAddr,
+        listener: &Arc<Listener>,
+    ) -> io::Result<()> {
+        if let Some(capture) = &self.capture {
+            capture.record(
+                addr,
+                listener.addr,
+                self.classifiers.classify(packet_data),
+                packet_data,
+            );
+        }
+
+        if !self.live.load().filter.allows(addr.ip()) {
+            static DENIED_WARNING: Throttle = Throttle::new(Duration::from_secs(10));
+            METRICS.denied_packets.inc();
+            if DENIED_WARNING.allow() {
+                log::warn!(client_addr:% = addr; "Dropping packets from denied client {:?}", addr);
+            }
+            return Ok(());
+        }
+
+        let len = packet_data.len();
+        log::info!(client_addr:% = addr, bytes = len; "{:?} bytes received from {:?}", len, addr);
+        if log::log_enabled!(log::Level::Trace) {
+            log::trace!("Data: {:02x?}", packet_data);
+        } else if log::log_enabled!(log::Level::Debug) {
+            log::debug!(
+                "Data: {:02x?}",
+                &packet_data[..len.min(self.config.dump_bytes)]
+            );
         }
 
-        let packet_data = &buf[..len];
+        if self.config.classify_only {
+            self.log_classification(packet_data, addr);
+            return Ok(());
+        }
 
-        let packet_type = determine_packet_type(&packet_data, &addr);
+        self.forward_udp(packet_data, addr, listener).await
+    }
 
-        let forward_address = match packet_type {
-            PacketType::Wireguard => WIREGUARD_SERVER_ADDR,
-            PacketType::Quic => QUIC_SERVER_ADDR,
+    /// Classifies a packet and logs where it would have been forwarded, without forwarding it
+    fn log_classification(&self, packet_data: &[u8], addr: SocketAddr) {
+        let classified = determine_packet_type(&self.classifiers, packet_data, &addr)
+            .map(|classification| classification.packet_type);
+        let packet_type = classified.unwrap_or(PacketType::Unknown);
+        let digest = format!(
+            "{:016x}",
+            BuildHasherDefault::<DefaultHasher>::default().hash_one(packet_data)
+        );
+        let live = self.live.load();
+        let (backend, action) = match classified.and_then(|t| live.backends.get(t, &addr)) {
+            Some(backend) => (backend.addr().to_string(), "forward"),
+            None => ("none".to_string(), "drop"),
         };
+        log::info!(
+            client_addr:% = addr, packet_type = packet_type.label(), backend = backend.as_str(), bytes = packet_data.len(), digest = digest.as_str(), action = action;
+            "Would {} {} packet of {} bytes (digest {}) from {:?}, backend {}",
+            action,
+            packet_type.label(),
+            packet_data.len(),
+            digest,
+            addr,
+            backend
+        );
+    }
+
+    async fn forward_udp(
+        self: &Arc<Self>,
+        packet_data: &[u8],
+        addr: SocketAddr,
+        listener: &Arc<Listener>,
+    ) -> io::Result<()> {
+        // New connections go to the backends of the config at their first packet
+        let live = self.live.load_full();
+        let mut failover = None;
+        if let Some((key, connection)) = self.find_connection(packet_data, addr).await {
+            // If we already have a forwarding socket for this client, send the packet through
+            // it. The connection's type was pinned by its first packet, so this one is only
+            // checked for looking like the other protocol.
+            failover = self.failover_backend(&live.backends, &connection, addr);
+            let repin =
+                failover.is_some() || self.check_protocol_switch(&connection, packet_data, addr);
+            if !repin {
+                if !connection.stats.is_established()
+                    && !self.unestablished_allows(packet_data, addr, connection.packet_type)
+                {
+                    return Ok(());
+                }
+                if !rate_allows(
+                    connection.limiter.as_deref(),
+                    packet_data,
+                    connection.packet_type,
+                ) {
+                    return Ok(());
+                }
+                // The receive loop never waits for a connection, so a slow backend only
+                // loses its own packets
+                match connection
+                    .sender
+                    .try_send(PACKET_BUFFERS.copy_of(packet_data))
+                {
+                    Ok(()) => {}
+                    Err(queue::SendError::Full(packet)) => {
+                        PACKET_BUFFERS.recycle(packet);
+                        connection.stats.dropped.inc();
+                        report_queue_full(connection.packet_type);
+                    }
+                    Err(queue::SendError::Closed(packet)) => {
+                        PACKET_BUFFERS.recycle(packet);
+                        log::error!("Forwarding task of {} has stopped", key);
+                        self.connections.lock(key.ip()).await.remove(&key);
+                    }
+                }
+                return Ok(());
+            }
+            // Dropping the old connection's sender stops its forwarding task. A client followed
+            // by connection ID may have moved to another IP than the one whose shard it is in.
+            self.connections.lock(key.ip()).await.remove(&key);
+            // A failover is only counted once it is known whether the client moved
+            if failover.is_none() {
+                METRICS.repins.get(connection.packet_type).inc();
+            }
+        }
 
-        let sender = {
-            let lock = connections.lock().await;
-            lock.get(&(addr, packet_type)).cloned()
+        let mut classification = None;
+        let (packet_type, backend) = match failover
+            .or_else(|| self.restored_backend(&live.backends, addr))
+        {
+            Some(restored) => restored,
+            None => {
+                let Some(classified) = determine_packet_type(&self.classifiers, packet_data, &addr)
+                else {
+                    return Ok(());
+                };
+                classification = Some(classified);
+                let packet_type = classified.packet_type;
+                let Some(backend) = live.backends.get(packet_type, &addr) else {
+                    log::debug!(client_addr:% = addr, packet_type = packet_type.label(); "Dropping {} packet from {:?}, as it has no backend", packet_type.label(), addr);
+                    METRICS.no_backend_drops.get(packet_type).inc();
+                    if packet_type == PacketType::Unknown {
+                        METRICS.unknown_dropped.inc();
+                    }
+                    if packet_type == PacketType::Unknown
+                        && self.config.reject_unknown
+                        && self.rejections.allow(addr.ip())
+                    {
+                        log::info!(
+                            client_addr:% = addr, len = packet_data.len();
+                            "Rejected unrecognised packet of {} bytes from {}, starting with {}",
+                            packet_data.len(),
+                            addr,
+                            hex_prefix(packet_data)
+                        );
+                    }
+                    return Ok(());
+                };
+                #[cfg(feature = "wireguard-over-quic")]
+                if packet_type == PacketType::Quic {
+                    inspect_wireguard_over_quic(packet_data, addr);
+                }
+                let backend = self
+                    .route_by_server_name(&live.backends, packet_type, packet_data, addr)
+                    .unwrap_or(backend);
+                (packet_type, backend)
+            }
         };
 
-        if let Some(sender) = sender {
-            // If we already have a forwarding socket for this client and destination, send the packet through it
-            if let Err(e) = sender.send(packet_data.to_vec()).await {
-                log::error!("Error sending packet to forwarding task: {:?}", e);
-                let mut lock = connections.lock().await;
-                lock.remove(&(addr, packet_type));
+        if backend.is_draining() {
+            static DRAINING_WARNING: Throttle = Throttle::new(Duration::from_secs(10));
+            METRICS.draining_drops.get(packet_type).inc();
+            if DRAINING_WARNING.allow() {
+                log::warn!(
+                    client_addr:% = addr, backend = &*backend.address, packet_type = packet_type.label();
+                    "Dropping packets from new clients such as {:?}, as {} backend {} is draining",
+                    addr,
+                    packet_type.label(),
+                    backend.address
+                );
+            }
+            count_failover(failover, false);
+            return Ok(());
+        }
+        if !self.unestablished_allows(packet_data, addr, packet_type)
+            || !rate_allows(backend.limiter.as_deref(), packet_data, packet_type)
+        {
+            count_failover(failover, false);
+            return Ok(());
+        }
+
+        // Otherwise, create a new forwarding task, if the limits allow it. The task sets up
+        // its own socket, so later packets simply queue up behind the first one.
+        let (tx, rx) = queue::queue(self.config.queue_depth, self.config.drop_policy);
+        let stats = Arc::new(ConnectionStats::new());
+        let key = self.config.client_key(packet_type, addr);
+        let client = Arc::new(ArcSwap::from_pointee(addr));
+        let closed = CancellationToken::new();
+        let connection = Connection {
+            packet_type,
+            backend: backend.address.clone(),
+            client: client.clone(),
+            sender: tx.clone(),
+            stats: stats.clone(),
+            limiter: backend.limiter.clone(),
+            health: backend.health.clone(),
+            closed: closed.clone(),
+        };
+        let insert = |connection| async move {
+            self.connections.lock(addr.ip()).await.insert(
+                key,
+                connection,
+                self.config.max_connections,
+                self.config.max_connections_per_ip,
+            )
+        };
+        let mut inserted = insert(connection.clone()).await;
+        if inserted == Err(LimitExceeded::Total)
+            && self.config.on_connection_limit == ConnectionLimit::Evict
+            && self.evict_idlest().await
+        {
+            inserted = insert(connection).await;
+        }
+        if let Err(limit) = inserted {
+            static LIMIT_WARNING: Throttle = Throttle::new(Duration::from_secs(10));
+            METRICS.rejected_connections.get(packet_type).inc();
+            if LIMIT_WARNING.allow() {
+                match limit {
+                    LimitExceeded::Total => log::warn!(
+                        "Connection limit of {} reached, dropping packets from new clients such as {:?}",
+                        self.connections.len(),
+                        addr
+                    ),
+                    LimitExceeded::PerIp => log::warn!(
+                        "Per-IP connection limit reached for {}, dropping packets from new ports such as {:?}",
+                        addr.ip(),
+                        addr
+                    ),
+                }
             }
-        } else {
-            // Otherwise, create a new forwarding socket and task
-            let (tx, mut rx) = mpsc::channel::<Vec<u8>>(100);
-            let mut lock = connections.lock().await;
-            lock.insert((addr, packet_type), tx);
+            count_failover(failover, false);
+            return Ok(());
+        }
+        count_failover(failover, true);
 
-            let connections_clone = connections.clone();
+        if let Some(handshakes) = &self.handshakes {
+            handshakes.record(addr, listener.addr, packet_type, packet_data);
+        }
+        if let Some(audit) = &self.audit {
+            let reason = match classification {
+                Some(classification) => classification.to_string(),
+                None if failover.is_some() => "failed over from an unhealthy backend".to_string(),
+                None => "restored from the state file".to_string(),
+            };
+            audit.record(audit::Entry {
+                client_addr: addr,
+                listener: listener.addr,
+                packet_type: packet_type.label(),
+                reason,
+                backend: backend.address.to_string(),
+                backend_addr: backend.addr(),
+            });
+        }
 
-            let forward_sock = UdpSocket::bind("0.0.0.0:0").await?;
-            forward_sock.connect(&forward_address).await?;
+        // The channel is empty, so this can't fail
+        let _ = tx.try_send(PACKET_BUFFERS.copy_of(packet_data));
+        let target = Target {
+            key,
+            client,
+            listener: listener.clone(),
+            backend: backend.addr(),
+            packet_type,
+            stats,
+            closed,
+            proxy_header: self
+                .config
+                .proxy_protocol
+                .contains(&packet_type)
+                .then(|| proxy_protocol::header(addr, listener.addr)),
+            forward_timeout: live.config.forward_timeout(),
+            _backend_tasks: live.backend_tasks.clone(),
+            fair_queue: backend.fair_queue.clone(),
+            mirror: self.mirrors[packet_type as usize].clone(),
+            client_cid: (packet_type == PacketType::Quic)
+                .then(|| {
+                    quic::parse_quic_header(packet_data)?
+                        .source_cid
+                        .map(Box::from)
+                })
+                .flatten(),
+        };
+        let shared = backend
+            .shared
+            .as_ref()
+            .zip(target.client_cid.as_deref())
+            .and_then(|(shared, cid)| shared.register(cid, target.backend));
+        let ready = shared.or_else(|| {
+            let pool = backend.sockets.as_ref()?;
+            pool.take(target.backend).map(Egress::Connected)
+        });
+        self.tasks
+            .spawn(self.clone().run_connection(target, ready, rx));
+        Ok(())
+    }
 
-            // Send the first packet immediately
-            forward_sock.send(packet_data).await?;
-            log::debug!(
-                "--> Sent {} bytes to {}",
-                packet_data.len(),
-                forward_address
+    /// Takes a packet from `addr` for a connection that isn't established yet out of its
+    /// source's limit, returning false if it should be dropped
+    fn unestablished_allows(
+        &self,
+        packet_data: &[u8],
+        addr: SocketAddr,
+        packet_type: PacketType,
+    ) -> bool {
+        let live = self.live.load();
+        let Some(limiter) = &live.unestablished else {
+            return true;
+        };
+        if limiter.allow(addr.ip(), packet_data.len()) {
+            return true;
+        }
+        static UNESTABLISHED_WARNING: Throttle = Throttle::new(Duration::from_secs(10));
+        METRICS.unestablished_rate_limited.get(packet_type).inc();
+        if UNESTABLISHED_WARNING.allow() {
+            log::warn!(
+                client_addr:% = addr, packet_type = packet_type.label();
+                "Rate limit for unestablished connections reached by {}, dropping packets",
+                addr.ip()
             );
+        }
+        false
+    }
 
-            let client_sock_clone = client_sock.clone();
-
-            tokio::spawn(async move {
-                let mut proxy_buf = [0u8; BUFFER_SIZE];
-
-                loop {
-                    tokio::select! {
-                        // Forward responses from the server back to the client
-                        result = forward_sock.recv(&mut proxy_buf) => {
-                            match result {
-                                Ok(response_len) => {
-                                    log::info!(
-                                        "<-- Received {} bytes from {}",
-                                        response_len,
-                                        forward_address
-                                    );
-
-                                    if let Err(e) = client_sock_clone
-                                        .send_to(&proxy_buf[..response_len], addr)
-                                        .await
-                                    {
-                                        log::error!("Error sending response back to client: {:?}", e);
-                                        break;
-                                    }
-                                    log::debug!("<-- Forwarded {} bytes back to {:?}", response_len, addr);
-                                }
-                                Err(e) => {
-                                    log::error!("Error receiving from server: {:?}", e);
-                                    break;
-                                }
-                            }
+    /// Another backend for a connection whose backend is down, if one of the same type is up
+    fn failover_backend<'a>(
+        &self,
+        backends: &'a Backends,
+        connection: &Connection,
+        addr: SocketAddr,
+    ) -> Option<(PacketType, &'a backend::Backend)> {
+        if connection.health.is_up() {
+            return None;
+        }
+        let packet_type = connection.packet_type;
+        let backend = backends
+            .get(packet_type, &addr)
+            .filter(|backend| backend.health.is_up())?;
+        log::info!(
+            client_addr:% = addr, packet_type = packet_type.label();
+            "Moving {} client {:?} from {}, which is down, to {}",
+            packet_type.label(),
+            addr,
+            connection.backend,
+            backend.address
+        );
+        Some((packet_type, backend))
+    }
+
+    /// The backend for the server name a new QUIC client asks for, if it has one of its own
+    fn route_by_server_name<'a>(
+        &self,
+        backends: &'a Backends,
+        packet_type: PacketType,
+        packet_data: &[u8],
+        addr: SocketAddr,
+    ) -> Option<&'a backend::Backend> {
+        if packet_type != PacketType::Quic || !backends.routes_by_server_name() {
+            return None;
+        }
+        let name = sni::server_name(packet_data);
+        let backend = name
+            .as_deref()
+            .and_then(|name| backends.by_server_name(name));
+        log::debug!(
+            client_addr:% = addr, packet_type = "quic";
+            "QUIC client {} asks for server name {:?}, {}",
+            addr,
+            name,
+            if backend.is_some() { "routing it by name" } else { "using the default backend" }
+        );
+        backend
+    }
+
+    /// The backend a client was pinned to before the proxy restarted, if it still exists
+    fn restored_backend<'a>(
+        &self,
+        backends: &'a Backends,
+        addr: SocketAddr,
+    ) -> Option<(PacketType, &'a backend::Backend)> {
+        let affinity = self.affinities.as_ref()?.take(addr)?;
+        let backend = backends.find(affinity.packet_type, &affinity.backend)?;
+        log::info!(
+            client_addr:% = addr, backend = &*backend.address, packet_type = affinity.packet_type.label();
+            "Restored connection of {} to {} backend {}", addr, affinity.packet_type.label(), backend.address
+        );
+        Some((affinity.packet_type, backend))
+    }
+
+    /// Closes the connection that has been idle the longest to make room for a new one,
+    /// returning whether there was one
+    async fn evict_idlest(&self) -> bool {
+        let Some((key, packet_type)) = self.connections.evict_idlest().await else {
+            return false;
+        };
+        METRICS.capacity_evictions.get(packet_type).inc();
+        log::debug!(
+            client_addr:% = key, packet_type = packet_type.label();
+            "Connection limit reached, closing the connection with {} that was idle the longest", key
+        );
+        true
+    }
+
+    /// Looks up the connection a packet from `addr` belongs to. A client tracked by IP or by
+    /// connection ID that sends from a new address is moved over to it, so responses follow
+    /// the client.
+    async fn find_connection(
+        &self,
+        packet_data: &[u8],
+        addr: SocketAddr,
+    ) -> Option<(ClientKey, Connection)> {
+        {
+            let shard = self.connections.lock(addr.ip()).await;
+            let key = ClientKey::Address(addr);
+            if let Some(connection) = shard.get(&key) {
+                return Some((key, connection.clone()));
+            }
+            if self.config.tracks_by_ip()
+                && let Some(found) = self.find_by_ip(&shard, packet_data, addr)
+            {
+                return Some(found);
+            }
+        }
+        if !self.config.tracks_by_connection_id() {
+            return None;
+        }
+
+        // The connection lives in the shard of the address it started from
+        let key = self.connections.find_by_connection_id(packet_data)?;
+        let connection = self.connections.lock(key.ip()).await.get(&key)?.clone();
+        let previous = **connection.client.load();
+        if previous != addr {
+            // Nothing proves the new address is the client's: anyone who saw the connection ID
+            // could send this packet. So only what a migrating client sends, a short header
+            // packet once the handshake got an answer, moves the responses.
+            if !connection.stats.is_established() || !quic::is_short_header(packet_data) {
+                return None;
+            }
+            METRICS.quic_migrations.inc();
+            log::info!(
+                client_addr:% = addr, previous_addr:% = previous, packet_type = connection.packet_type.label();
+                "QUIC client {:?} migrated to {:?}", previous, addr
+            );
+            connection.client.store(Arc::new(addr));
+        }
+        Some((key, connection))
+    }
+
+    fn find_by_ip(
+        &self,
+        shard: &ShardGuard<'_>,
+        packet_data: &[u8],
+        addr: SocketAddr,
+    ) -> Option<(ClientKey, Connection)> {
+        let key = ClientKey::Ip(addr.ip());
+        let connection = shard.get(&key)?.clone();
+        let previous = **connection.client.load();
+        if previous != addr {
+            // Another client behind the same IP speaking a different protocol is not a port
+            // change, so it gets a connection of its own
+            let packet_type = self.classifiers.classify(packet_data);
+            if packet_type != connection.packet_type
+                && self.config.client_key(packet_type, addr) != key
+            {
+                return None;
+            }
+            log::info!(
+                client_addr:% = addr, previous_addr:% = previous, packet_type = connection.packet_type.label();
+                "Client {:?} moved to {:?}", previous, addr
+            );
+            connection.client.store(Arc::new(addr));
+        }
+        Some((key, connection))
+    }
+
+    /// Warns when a packet on an existing connection looks like a different protocol than the
+    /// connection was pinned to, returning whether the client should be re-pinned to it.
+    fn check_protocol_switch(
+        &self,
+        connection: &Connection,
+        packet_data: &[u8],
+        addr: SocketAddr,
+    ) -> bool {
+        if self.config.classify_first_only {
+            return false;
+        }
+        // Short header packets of an established QUIC connection have no version to verify,
+        // and peers may even grease the fixed bit (RFC 9287), so they are trusted to belong to it
+        if connection.packet_type == PacketType::Quic && quic::is_short_header(packet_data) {
+            return false;
+        }
+
+        let packet_type = self.classifiers.classify(packet_data);
+        // Packets that look like neither protocol are forwarded as before
+        if packet_type == connection.packet_type || packet_type == PacketType::Unknown {
+            return false;
+        }
+
+        static SWITCH_WARNING: Throttle = Throttle::new(Duration::from_secs(10));
+        METRICS.protocol_switches.get(packet_type).inc();
+        let repin = self.config.on_protocol_switch == ProtocolSwitch::Repin;
+        if SWITCH_WARNING.allow() {
+            log::warn!(
+                client_addr:% = addr, packet_type = packet_type.label();
+                "Client {:?} was classified as {} but sent a {} packet, {}",
+                addr,
+                connection.packet_type.label(),
+                packet_type.label(),
+                if repin { "re-pinning it" } else { "keeping its backend" }
+            );
+        }
+        repin
+    }
+
+    /// How the sockets to backends of the given type are set up
+    fn egress_options(&self, packet_type: PacketType) -> socket::EgressOptions<'_> {
+        socket::EgressOptions {
+            interface: self.config.egress_interface.as_deref(),
+            dscp: self.config.dscp(packet_type),
+            ttl: self.config.ttl(packet_type),
+            no_fragment: self.config.no_fragment,
+            buffers: self.config.buffer_sizes(),
+            source: None,
+        }
+    }
+
+    /// Connects to the backend, unless the connection already has a pooled or shared socket, then
+    /// forwards packets between it and the client until the connection times out
+    async fn run_connection(
+        self: Arc<Self>,
+        target: Target,
+        ready: Option<Egress>,
+        mut rx: queue::Receiver,
+    ) {
+        let egress = match ready {
+            Some(egress) => Ok(egress),
+            None => {
+                let options = socket::EgressOptions {
+                    // Only a socket of the connection's own can send from its client's address
+                    source: self.config.transparent.then(|| **target.client.load()),
+                    ..self.egress_options(target.packet_type)
+                };
+                match self.config.socks5(target.packet_type) {
+                    Some(proxy) => Association::open(proxy, target.backend, options)
+                        .await
+                        .map(Egress::Socks5),
+                    None => socket::connect_backend_socket(target.backend, options)
+                        .await
+                        .map(Egress::Connected),
+                }
+            }
+        };
+        match egress {
+            Ok(mut egress) => self.forward(&target, &mut egress, &mut rx).await,
+            // The connection is forgotten again, so the client's next packet starts over
+            Err(e) => {
+                METRICS.backend_send_failures.get(target.packet_type).inc();
+                report_unreachable(&target, &e);
+            }
+        }
+
+        // Clean up connection on exit. Once it is closed the connection was already removed,
+        // and the entry for this address (if any) belongs to its replacement.
+        let mut shard = self.connections.lock(target.key.ip()).await;
+        if !target.closed.is_cancelled() {
+            shard.remove(&target.key);
+        }
+        drop(shard);
+        log_accounting(&target);
+    }
+
+    async fn forward(&self, target: &Target, egress: &mut Egress, rx: &mut queue::Receiver) {
+        let &Target {
+            key,
+            backend: forward_address,
+            packet_type,
+            ..
+        } = target;
+        // With a fair queue, every packet waits for a turn of its own
+        let segmented = self.config.gso
+            && gso::supported()
+            && egress.connected().is_some()
+            && target.fair_queue.is_none();
+        let strip_proxy_header = target.proxy_header.is_some() && self.config.strip_proxy_protocol;
+        // The IDs the backend picks for itself during the handshake, which the client then
+        // addresses its packets to
+        let track_connection_ids =
+            packet_type == PacketType::Quic && self.config.tracks_by_connection_id();
+        let mut connection_ids: Vec<Box<[u8]>> = Vec::new();
+        let response_limiter = RateLimiter::new(None, self.live.load().config.max_response_bps);
+        let mut proxy_buf = RESPONSE_BUFFERS.take();
+        proxy_buf.resize(receive_buffer_size(self.config.max_datagram_size), 0);
+        // The client's packet waiting for its turn in the fair queue. It waits in a branch of
+        // its own, so that responses, timeouts and closing are handled in the meantime.
+        let mut queued: Option<(Vec<u8>, Turn<'_>)> = None;
+
+        loop {
+            // Read every time, so reloaded timeouts apply from the next packet on
+            let (timeout, response_timeout, max_lifetime) = {
+                let config = &self.live.load().config;
+                (
+                    config.connection_timeout(packet_type),
+                    config.response_timeout(),
+                    config.max_connection_lifetime(),
+                )
+            };
+            tokio::select! {
+                // Forward responses from the server back to the client
+                result = egress.recv(&mut proxy_buf) => {
+                    match result {
+                        Ok(response_len) if response_len > self.config.max_datagram_size => {
+                            report_truncated(forward_address, response_len, self.config.max_datagram_size);
                         }
+                        Ok(response_len) => {
+                            // A header the backend echoes is meant for the proxy, not the client
+                            let header_len = if strip_proxy_header {
+                                proxy_protocol::header_len(&proxy_buf[..response_len]).unwrap_or(0)
+                            } else {
+                                0
+                            };
+                            // Responses aren't classified, so whatever the checks on client
+                            // packets, a WireGuard cookie reply always reaches its client
+                            let response = &proxy_buf[header_len..response_len];
+                            if track_connection_ids
+                                && let Some(header) = quic::parse_quic_header(response)
+                                && let Some(cid) = header.source_cid
+                                && !cid.is_empty()
+                                && !connection_ids.iter().any(|known| **known == *cid)
+                            {
+                                self.connections.add_connection_id(cid, key);
+                                connection_ids.push(cid.into());
+                            }
+                            if packet_type == PacketType::Quic
+                                && !quic::is_short_header(response)
+                                && quic::parse_quic_header(response)
+                                    .is_some_and(|h| h.packet_type == quic::QuicPacketType::Retry)
+                            {
+                                METRICS.quic_retries_to_clients.inc();
+                                log::debug!(
+                                    client_addr:% = key, backend:% = forward_address, packet_type = "quic";
+                                    "{} asked {:?} to validate its address with a Retry",
+                                    forward_address,
+                                    key
+                                );
+                            }
+                            if let Some(client_cid) = &target.client_cid
+                                && quic::looks_like_stateless_reset(response, client_cid)
+                            {
+                                METRICS.quic_stateless_resets.inc();
+                                log::info!(
+                                    client_addr:% = **target.client.load(), backend:% = forward_address, packet_type = "quic";
+                                    "{} sent what looks like a stateless reset to {:?}, so it may have lost the connection's state",
+                                    forward_address,
+                                    key
+                                );
+                            }
 
-                        // Forward packets from the client to the server
-                        Some(packet) = rx.recv() => {
-                            if let Err(e) = forward_sock.send(&packet).await {
-                                log::error!("Error forwarding packet to server: {:?}", e);
+                            let addr = **target.client.load();
+                            log::info!(
+                                client_addr:% = addr, backend:% = forward_address, packet_type = packet_type.label(), bytes = response_len;
+                                "<-- Received {} bytes from {}",
+                                response_len,
+                                forward_address
+                            );
+
+                            target.stats.touch();
+                            if let Some(latency) = target.stats.responded() {
+                                METRICS.first_response_latency.get(packet_type).observe_duration(latency);
+                            }
+                            if let Some(rtt) = target.stats.round_trip() {
+                                METRICS.round_trip_time.get(packet_type).observe_duration(rtt);
+                            }
+                            if !self.response_allowed(response_limiter.as_ref(), packet_type, response.len()).await {
+                                continue;
+                            }
+                            if let Err(e) = target.listener.send_to(response, addr).await
+                            {
+                                log::error!("Error sending response back to client: {:?}", e);
+                                METRICS.unreachable_closes.get(packet_type).inc();
                                 break;
                             }
-                            log::debug!("--> Forwarded {} bytes to {}", packet.len(), forward_address);
+                            METRICS.bytes_to_client.get(packet_type).add(response.len() as u64);
+                            target.stats.bytes_to_client.add(response.len() as u64);
+                            target.stats.packets_to_client.inc();
+                            METRICS.packet_sizes_to_client.get(packet_type).observe(response.len() as u64);
+                            log::debug!(
+                                client_addr:% = addr, backend:% = forward_address, packet_type = packet_type.label(), bytes = response.len();
+                                "<-- Forwarded {} bytes back to {:?}", response.len(), addr
+                            );
+                        }
+                        // An ICMP "fragmentation needed" for an earlier packet. The path MTU
+                        // is lowered, so only packets larger than it are lost from now on.
+                        Err(e) if socket::is_too_large(&e) => report_too_large(egress, target, None),
+                        // The kernel reports a datagram we sent being refused on the next receive
+                        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                            METRICS.backend_send_failures.get(packet_type).inc();
+                            report_unreachable(target, &e);
+                            break;
+                        }
+                        Err(e) => {
+                            log::error!("Error receiving from server: {:?}", e);
+                            METRICS.unreachable_closes.get(packet_type).inc();
+                            break;
                         }
+                    }
+                }
+
+                // Forward packets from the client to the server, until the connection is
+                // replaced by one to a different backend or the backend becomes unreachable
+                packet = rx.recv(), if queued.is_none() => {
+                    let Some(packet) = packet else { break };
+                    if let Some(fair_queue) = &target.fair_queue {
+                        let turn = fair_queue.wait_turn(target.client.load().ip(), packet.len());
+                        queued = Some((packet, Box::pin(turn)));
+                        continue;
+                    }
+                    let sent = if segmented {
+                        forward_segments(egress, target, packet, rx).await
+                    } else {
+                        forward_packet(egress, target, packet).await
+                    };
+                    if let Err(e) = sent {
+                        report_unreachable(target, &e);
+                        break;
+                    }
+                }
+
+                // Forward the packet that waited once its turn comes. The next one is only
+                // taken from the queue after, so a client over its share fills its own queue.
+                waited = async { queued.as_mut().unwrap().1.as_mut().await }, if queued.is_some() => {
+                    let (packet, _) = queued.take().unwrap();
+                    if waited {
+                        METRICS.fair_queued.get(packet_type).inc();
+                    }
+                    if let Err(e) = forward_packet(egress, target, packet).await {
+                        report_unreachable(target, &e);
+                        break;
+                    }
+                }
+
+                // Handle connection timeout
+                _ = tokio::time::sleep(timeout) => {
+                    log::info!(
+                        client_addr:% = key, packet_type = packet_type.label();
+                        "Connection with {:?} timed out due to inactivity", (key, packet_type)
+                    );
+                    METRICS.idle_cleanups.get(packet_type).inc();
+                    break;
+                }
+
+                // Close connections whose backend stopped responding, even if the client
+                // keeps them active
+                _ = tokio::time::sleep(
+                    response_timeout.unwrap_or_default().saturating_sub(target.stats.since_response())
+                ), if response_timeout.is_some() => {
+                    log::info!(
+                        client_addr:% = key, backend:% = forward_address, packet_type = packet_type.label();
+                        "Connection with {:?} closed as {} did not respond", (key, packet_type), forward_address
+                    );
+                    METRICS.response_timeouts.get(packet_type).inc();
+                    break;
+                }
+
+                // Recycle long lived connections, however active, so the client's next packet
+                // goes to the backend's current address
+                _ = tokio::time::sleep(
+                    max_lifetime.unwrap_or_default().saturating_sub(target.stats.age())
+                ), if max_lifetime.is_some() => {
+                    log::info!(
+                        client_addr:% = key, backend:% = forward_address, packet_type = packet_type.label();
+                        "Connection with {:?} closed as it reached its maximum lifetime", (key, packet_type)
+                    );
+                    METRICS.lifetime_expirations.get(packet_type).inc();
+                    break;
+                }
+
+                // Removed from the connection table, for example to make room for another one
+                _ = target.closed.cancelled() => break,
 
-                        // Handle connection timeout
-                        _ = tokio::time::sleep(tokio::time::Duration::from_secs(CONNECTION_TIMEOUT_SECS)) => {
-                            log::info!("Connection with {:?} timed out due to inactivity", (addr, packet_type));
+                // On shutdown, flush whatever the client already sent before closing
+                _ = self.shutdown.cancelled() => {
+                    let mut queued = queued.take().map(|(packet, _)| packet);
+                    while let Some(packet) = queued.take().or_else(|| rx.try_recv()) {
+                        if let Err(e) = forward_packet(egress, target, packet).await {
+                            log::error!("Error forwarding packet to server: {:?}", e);
                             break;
                         }
                     }
+                    break;
                 }
-                // Clean up connection on exit
-                connections_clone.lock().await.remove(&(addr, packet_type));
-            });
+            }
+        }
+        RESPONSE_BUFFERS.recycle(proxy_buf);
+        self.connections.remove_connection_ids(&connection_ids, key);
+    }
+
+    /// Checks a response of `len` bytes against the connection's `--max-response-bps`, waiting
+    /// for it to fit when pacing. Returns false if it should be dropped.
+    async fn response_allowed(
+        &self,
+        limiter: Option<&RateLimiter>,
+        packet_type: PacketType,
+        len: usize,
+    ) -> bool {
+        let Some(limiter) = limiter else {
+            return true;
+        };
+        match self.config.on_response_limit {
+            ResponseLimit::Drop => {
+                if limiter.allow(len) {
+                    return true;
+                }
+                METRICS.response_rate_limited.get(packet_type).inc();
+                false
+            }
+            ResponseLimit::Pace => {
+                let delay = limiter.reserve(len);
+                if !delay.is_zero() {
+                    METRICS.response_rate_limited.get(packet_type).inc();
+                    tokio::time::sleep(delay).await;
+                }
+                true
+            }
         }
     }
 }
 
-fn determine_packet_type(buf: &[u8], _source_addr: &SocketAddr) -> PacketType {
-    // Simple heuristic: Wireguard packets start with 0x00 to 0x04 followed by 3 bytes of 0x00
-    if buf.len() >= 4
-        && buf[0] <= 0x04
-        && buf[0] > 0
-        && buf[1] == 0x00
-        && buf[2] == 0x00
-        && buf[3] == 0x00
-    {
-        match buf[0] {
-            0x01 if buf.len() == 148 => log::info!("Identified as Wireguard: Handshake Initiation"),
-            0x02 if buf.len() == 92 => log::info!("Identified as Wireguard: Handshake Response"),
-            0x03 if buf.len() == 64 => log::info!("Identified as Wireguard: Cookie Reply"),
-            0x04 if buf.len() >= 32 => log::info!("Identified as Wireguard: Data"),
-            _ => log::info!("Identified as Wireguard: Unknown type {}", buf[0]),
-        }
-        PacketType::Wireguard
+/// The first 16 bytes of a packet in hex, which is usually enough to tell its protocol
+fn hex_prefix(packet_data: &[u8]) -> String {
+    packet_data[..packet_data.len().min(16)]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Checks a packet against the rate limit of its backend, if it has one, counting and
+/// warning about the packets that exceed it
+fn rate_allows(limiter: Option<&RateLimiter>, packet_data: &[u8], packet_type: PacketType) -> bool {
+    if limiter.is_none_or(|limiter| limiter.allow(packet_data.len())) {
+        return true;
+    }
+    static RATE_WARNING: Throttle = Throttle::new(Duration::from_secs(10));
+    METRICS.rate_limited.get(packet_type).inc();
+    if RATE_WARNING.allow() {
+        log::warn!(
+            "Rate limit reached for {} backend, dropping packets",
+            packet_type.label()
+        );
+    }
+    false
+}
+
+/// Receive buffers have room for one byte more than the largest datagram we forward, so that
+/// a datagram that didn't fit can be told apart from one that exactly filled the buffer.
+fn receive_buffer_size(max_datagram_size: usize) -> usize {
+    max_datagram_size + 1
+}
+
+fn report_queue_full(packet_type: PacketType) {
+    static QUEUE_WARNING: Throttle = Throttle::new(Duration::from_secs(10));
+    METRICS.queue_full_drops.get(packet_type).inc();
+    if QUEUE_WARNING.allow() {
+        log::warn!(
+            "Dropping {} packets for a connection whose backend can't keep up",
+            packet_type.label()
+        );
+    }
+}
+
+/// Counts and logs a datagram that was dropped for being too large. `len` is the part of it that
+/// was received, which is at least one byte more than `max_datagram_size`.
+fn report_truncated(source: SocketAddr, len: usize, max_datagram_size: usize) {
+    static TRUNCATED_WARNING: Throttle = Throttle::new(Duration::from_secs(10));
+    METRICS.truncated_datagrams.inc();
+    if TRUNCATED_WARNING.allow() {
+        log::warn!(
+            source:% = source, bytes = len;
+            "Dropping datagram of at least {} bytes from {:?}, larger than --max-datagram-size of {} bytes",
+            len,
+            source,
+            max_datagram_size
+        );
+    }
+}
+
+/// Who a connection forwards between, and its statistics
+struct Target {
+    key: ClientKey,
+    client: Arc<ArcSwap<SocketAddr>>,
+    /// Where the connection's first packet arrived, which its responses are sent from
+    listener: Arc<Listener>,
+    backend: SocketAddr,
+    packet_type: PacketType,
+    stats: Arc<ConnectionStats>,
+    closed: CancellationToken,
+    /// PROXY protocol header to prepend to every packet, if the backend expects one
+    proxy_header: Option<Vec<u8>>,
+    /// How long sending a packet to the backend may take before the connection is closed
+    forward_timeout: Duration,
+    /// Keeps the background tasks of the backends the connection was made with running
+    _backend_tasks: Arc<DropGuard>,
+    /// Where the connection waits for its turn to send, with `--fair-queue`
+    fair_queue: Option<Arc<FairQueue>>,
+    /// Gets a copy of every packet forwarded to the backend
+    mirror: Option<Arc<Mirror>>,
+    /// The connection ID a QUIC client picked for itself in its first packet, which the
+    /// backend's responses are addressed to
+    client_cid: Option<Box<[u8]>>,
+}
+
+/// Sends a queued packet to the backend, then returns its buffer to the pool
+async fn forward_packet(egress: &Egress, target: &Target, packet: Vec<u8>) -> io::Result<()> {
+    let result = send_to_backend(egress, target, &packet).await;
+    PACKET_BUFFERS.recycle(packet);
+    result
+}
+
+/// Sends a packet to the backend, retrying errors that don't mean the backend is gone. An
+/// error is only returned when the connection should be closed; a packet that still couldn't
+/// be sent after retrying is dropped.
+async fn send_to_backend(egress: &Egress, target: &Target, packet: &[u8]) -> io::Result<()> {
+    let &Target {
+        backend: forward_address,
+        packet_type,
+        ..
+    } = target;
+    let with_header = target.proxy_header.as_ref().map(|header| {
+        let mut buf = PACKET_BUFFERS.take();
+        buf.extend_from_slice(header);
+        buf.extend_from_slice(packet);
+        buf
+    });
+    let datagram = with_header.as_deref().unwrap_or(packet);
+    let len = datagram.len();
+
+    let mut retries = 0;
+    let result = loop {
+        // UDP sends hardly ever block, but a socket stuck in some bad state mustn't hold up
+        // the connection for good
+        match tokio::time::timeout(target.forward_timeout, egress.send(datagram)).await {
+            Ok(Ok(_)) => break Ok(()),
+            Ok(Err(e)) if is_transient(&e) && retries < SEND_RETRIES => {
+                retries += 1;
+                tokio::task::yield_now().await;
+            }
+            Ok(Err(e)) => break Err(e),
+            Err(_) => break Err(send_timed_out(target)),
+        }
+    };
+    if let Some(buf) = with_header {
+        PACKET_BUFFERS.recycle(buf);
+    }
+    if let Err(e) = result {
+        METRICS.backend_send_failures.get(packet_type).inc();
+        // Only this packet is too large, the connection may well carry on with smaller ones
+        if socket::is_too_large(&e) {
+            report_too_large(egress, target, Some(len));
+            return Ok(());
+        }
+        if is_transient(&e) {
+            log::debug!("Dropping packet for {}: {:?}", forward_address, e);
+            return Ok(());
+        }
+        return Err(e);
+    }
+
+    record_forwarded(target, packet);
+    Ok(())
+}
+
+/// Sends a queued packet to the backend along with the packets of the same size queued
+/// behind it, as a single segmented send. A packet of another size ends the batch and is sent
+/// on its own, as are the packets of a batch the socket can't segment.
+async fn forward_segments(
+    egress: &Egress,
+    target: &Target,
+    first: Vec<u8>,
+    rx: &mut queue::Receiver,
+) -> io::Result<()> {
+    let header = target.proxy_header.as_deref().unwrap_or_default();
+    let segment_len = header.len() + first.len();
+    let mut packets = vec![first];
+    let mut next = None;
+    while packets.len() < gso::MAX_SEGMENTS && (packets.len() + 1) * segment_len <= gso::MAX_LEN {
+        match rx.try_recv() {
+            Some(packet) if header.len() + packet.len() == segment_len => packets.push(packet),
+            Some(packet) => {
+                next = Some(packet);
+                break;
+            }
+            None => break,
+        }
+    }
+
+    let mut result = Ok(());
+    if packets.len() == 1 {
+        result = send_to_backend(egress, target, &packets[0]).await;
+    } else {
+        let mut buf = PACKET_BUFFERS.take();
+        for packet in &packets {
+            buf.extend_from_slice(header);
+            buf.extend_from_slice(packet);
+        }
+        let sent = tokio::time::timeout(
+            target.forward_timeout,
+            gso::send(
+                egress.connected().expect(
+                    "segmented sends are only used by connections with a socket of their own",
+                ),
+                &buf,
+                segment_len,
+            ),
+        )
+        .await;
+        PACKET_BUFFERS.recycle(buf);
+        match sent {
+            Ok(Ok(())) => {
+                for packet in &packets {
+                    record_forwarded(target, packet);
+                }
+            }
+            Err(_) => result = Err(send_timed_out(target)),
+            Ok(Err(e)) => {
+                if gso::is_unsupported(&e) {
+                    static GSO_WARNING: Throttle = Throttle::new(Duration::from_secs(60));
+                    if GSO_WARNING.allow() {
+                        log::warn!(
+                            "Segmented send to {} failed, sending packets one by one: {}",
+                            target.backend,
+                            e
+                        );
+                    }
+                }
+                for packet in &packets {
+                    result = send_to_backend(egress, target, packet).await;
+                    if result.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    for packet in packets {
+        PACKET_BUFFERS.recycle(packet);
+    }
+    match next {
+        Some(packet) if result.is_ok() => forward_packet(egress, target, packet).await,
+        Some(packet) => {
+            PACKET_BUFFERS.recycle(packet);
+            result
+        }
+        None => result,
+    }
+}
+
+/// Logs the totals of a connection that has closed, under the `accounting` target so that
+/// they can be collected (or silenced) separately, for example with
+/// `RUST_LOG=warn,accounting=info`
+fn log_accounting(target: &Target) {
+    let stats = &target.stats;
+    let client = **target.client.load();
+    let duration = stats.age();
+    log::info!(
+        target: "accounting",
+        client_addr:% = client, backend:% = target.backend, packet_type = target.packet_type.label(),
+        duration_secs = duration.as_secs(),
+        bytes_to_backend = stats.bytes_to_backend.get(), bytes_to_client = stats.bytes_to_client.get(),
+        packets_to_backend = stats.packets_to_backend.get(), packets_to_client = stats.packets_to_client.get();
+        "Connection with {} closed after {:?}: {} packets ({} bytes) to {}, {} packets ({} bytes) back",
+        client,
+        duration,
+        stats.packets_to_backend.get(),
+        stats.bytes_to_backend.get(),
+        target.backend,
+        stats.packets_to_client.get(),
+        stats.bytes_to_client.get()
+    );
+}
+
+/// Counts a send to the backend that timed out, returning the error that closes its
+/// connection
+fn send_timed_out(target: &Target) -> io::Error {
+    METRICS.send_timeouts.get(target.packet_type).inc();
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("sending timed out after {:?}", target.forward_timeout),
+    )
+}
+
+/// Counts a packet as forwarded to the backend, and copies it to the mirror
+fn record_forwarded(target: &Target, packet: &[u8]) {
+    let len = packet.len();
+    if let Some(mirror) = &target.mirror {
+        mirror.send(packet, target.packet_type);
+    }
+    let &Target {
+        key: addr,
+        backend: forward_address,
+        packet_type,
+        ..
+    } = target;
+    METRICS.bytes_to_backend.get(packet_type).add(len as u64);
+    target.stats.bytes_to_backend.add(len as u64);
+    target.stats.packets_to_backend.inc();
+    target.stats.sent_to_backend();
+    METRICS
+        .packet_sizes_to_backend
+        .get(packet_type)
+        .observe(len as u64);
+    target.stats.touch();
+    log::debug!(
+        client_addr:% = addr, backend:% = forward_address, packet_type = packet_type.label(), bytes = len;
+        "--> Forwarded {} bytes to {}", len, forward_address
+    );
+}
+
+/// Errors after which sending to the same socket again may well succeed
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted | io::ErrorKind::OutOfMemory
+    )
+}
+
+/// Warns that a packet to the backend was larger than the path MTU, with its length if it was
+/// refused on sending rather than reported by ICMP. This matters most for QUIC, whose clients
+/// can't connect over a path too narrow for their padded Initial packets.
+fn report_too_large(egress: &Egress, target: &Target, len: Option<usize>) {
+    static MTU_WARNING: Throttle = Throttle::new(Duration::from_secs(60));
+    METRICS.mtu_errors.get(target.packet_type).inc();
+    if !MTU_WARNING.allow() {
+        return;
+    }
+    let packet = match len {
+        Some(len) => format!("packet of {len} bytes"),
+        None => "packet".to_string(),
+    };
+    let mtu = match egress.connected().and_then(socket::path_mtu) {
+        Some(mtu) => format!("path MTU of {mtu} bytes"),
+        None => "path MTU".to_string(),
+    };
+    if target.packet_type == PacketType::Quic && len.is_none_or(|len| len >= quic::MIN_PATH_MTU) {
+        log::warn!(
+            backend:% = target.backend, packet_type = "quic";
+            "QUIC {} to {} was larger than the {}. QUIC needs a path MTU of at least {} bytes, \
+             so clients may fail to connect or stall; check the MTU of the path to the backend, \
+             such as that of a tunnel on the way",
+            packet,
+            target.backend,
+            mtu,
+            quic::MIN_PATH_MTU
+        );
+    } else {
+        log::warn!(
+            backend:% = target.backend, packet_type = target.packet_type.label();
+            "{} {} to {} was larger than the {}, so it was {}",
+            target.packet_type.label(),
+            packet,
+            target.backend,
+            mtu,
+            if len.is_some() { "dropped" } else { "lost on the way" }
+        );
+    }
+}
+
+/// Counts and warns about a connection closed because its backend is unreachable. Sends
+/// that timed out were already counted by [`send_timed_out`].
+fn report_unreachable(target: &Target, e: &io::Error) {
+    static UNREACHABLE_WARNING: Throttle = Throttle::new(Duration::from_secs(10));
+    if e.kind() != io::ErrorKind::TimedOut {
+        METRICS.unreachable_closes.get(target.packet_type).inc();
+    }
+    if UNREACHABLE_WARNING.allow() {
+        log::warn!(
+            backend:% = target.backend;
+            "Closing connection, backend {} is unreachable: {}",
+            target.backend,
+            e
+        );
+    }
+}
+
+/// Counts the connection closed to move its client off a backend that is down: as failed
+/// over once the replacement is in the connection table, and as unreachable if the client's
+/// packet was dropped instead
+fn count_failover(failover: Option<(PacketType, &backend::Backend)>, moved: bool) {
+    let Some((packet_type, _)) = failover else {
+        return;
+    };
+    if moved {
+        METRICS.backend_failovers.get(packet_type).inc();
     } else {
-        PacketType::Quic
+        METRICS.unreachable_closes.get(packet_type).inc();
+    }
+}
+
+/// Logs what the Initial packet of a new QUIC client shows of WireGuard tunnelled through
+/// QUIC: DATAGRAM frames (which don't belong in Initial packets) at info level, and whether the
+/// client supports them at all at debug level
+#[cfg(feature = "wireguard-over-quic")]
+fn inspect_wireguard_over_quic(packet_data: &[u8], addr: SocketAddr) {
+    use wg_quic_differentiator::wg_over_quic;
+
+    let Some(findings) = wg_over_quic::inspect(packet_data) else {
+        return;
+    };
+    if findings.datagrams > 0 {
+        log::info!(
+            target: "wireguard_over_quic", client_addr:% = addr;
+            "QUIC client {} sent {} DATAGRAM frames in its Initial packet, of which WireGuard shaped: {:?}",
+            addr,
+            findings.datagrams,
+            findings.wireguard_datagrams
+        );
+    } else if findings.any() {
+        log::debug!(
+            target: "wireguard_over_quic", client_addr:% = addr;
+            "QUIC client {} accepts DATAGRAM frames of up to {:?} bytes",
+            addr,
+            findings.max_datagram_frame_size
+        );
+    }
+}
+
+/// Classifies the first packet of a connection, or returns `None` for packets that are
+/// dropped whichever backends there are
+fn determine_packet_type(
+    classifiers: &Classifiers,
+    buf: &[u8],
+    source_addr: &SocketAddr,
+) -> Option<Classification> {
+    let classification = classifiers.classify_with_reason(buf);
+    match classification.reason {
+        Reason::Wireguard { message } => {
+            log::info!(client_addr:% = source_addr, packet_type = "wireguard"; "Identified as Wireguard: {message}");
+            if let Some(header) = parse_wireguard_header(buf) {
+                if let Some(sender_index) = header.sender_index {
+                    log::debug!(client_addr:% = source_addr, packet_type = "wireguard", sender_index; "WireGuard sender index {sender_index}");
+                }
+                if let Some(receiver_index) = header.receiver_index {
+                    log::debug!(client_addr:% = source_addr, packet_type = "wireguard", receiver_index; "WireGuard receiver index {receiver_index}");
+                }
+            }
+        }
+        Reason::Dtls { content_type } => {
+            log::info!(client_addr:% = source_addr, packet_type = "dtls"; "Identified as DTLS: {:?}", content_type);
+        }
+        Reason::Quic { packet_type, .. } => {
+            log::info!(client_addr:% = source_addr, packet_type = "quic"; "Identified as QUIC: {:?}", packet_type);
+            if packet_type == quic::QuicPacketType::Retry {
+                METRICS.quic_retries_from_clients.inc();
+            }
+            if packet_type == quic::QuicPacketType::VersionNegotiation {
+                METRICS.quic_version_negotiations.inc();
+                if log::log_enabled!(log::Level::Debug)
+                    && let Some(versions) = quic::offered_versions(buf)
+                {
+                    let versions: Vec<_> = versions.map(|v| format!("{v:#010x}")).collect();
+                    log::debug!(client_addr:% = source_addr, packet_type = "quic"; "Version negotiation offers versions {}", versions.join(", "));
+                }
+            }
+        }
+        Reason::Stun { message } => {
+            log::info!(client_addr:% = source_addr, packet_type = "stun"; "Identified as STUN: {:?} method {:#05x}", message.class, message.method);
+        }
+        Reason::Custom { classifier } => {
+            let packet_type = classification.packet_type.label();
+            log::info!(client_addr:% = source_addr, packet_type; "Identified as {packet_type} by {classifier}");
+        }
+        Reason::MalformedWireguard { message_type } => {
+            METRICS.malformed_wireguard.inc();
+            log::debug!(
+                client_addr:% = source_addr, len = buf.len();
+                "Dropping malformed WireGuard packet from {:?}: message type {message_type:#04x} with length {}",
+                source_addr,
+                buf.len()
+            );
+        }
+        Reason::NoMatch { .. } => {
+            log::info!(client_addr:% = source_addr, packet_type = "unknown"; "Could not identify packet");
+        }
     }
+
+    let packet_type = classification.packet_type;
+    log::debug!(client_addr:% = source_addr, packet_type = packet_type.label(), reason:% = classification; "Classified as {} because of: {}", packet_type.label(), classification);
+    METRICS.packets_classified.get(packet_type).inc();
+    (!matches!(classification.reason, Reason::MalformedWireguard { .. })).then_some(classification)
 }
@@ -0,0 +1,723 @@
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::PacketType;
+use crate::backend::Backends;
+use crate::listener::Listener;
+
+pub static METRICS: Metrics = Metrics::new();
+
+/// A monotonically increasing counter
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Gauge(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Upper bounds of the packet size buckets, in bytes: from WireGuard keepalives up to jumbo
+/// frames, with the common 1500 byte MTU in between
+const SIZE_BUCKETS: [u64; 7] = [64, 128, 256, 512, 1024, 1500, 9000];
+
+/// Upper bounds of the latency buckets, in microseconds: from a backend on the same host up to
+/// one that takes a few retransmissions to answer
+const LATENCY_BUCKETS: [u64; 11] = [
+    1_000, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000,
+    5_000_000,
+];
+
+/// A distribution of packet sizes, or of latencies in microseconds. `N` is the number of
+/// buckets, one more than there are bounds.
+pub struct Histogram<const N: usize> {
+    /// Upper bounds of every bucket but the last, which holds the rest
+    bounds: &'static [u64],
+    /// Observed units per unit of the exported metric, as latencies are exported in seconds
+    per_unit: u64,
+    buckets: [Counter; N],
+    sum: Counter,
+}
+
+pub type SizeHistogram = Histogram<{ SIZE_BUCKETS.len() + 1 }>;
+pub type LatencyHistogram = Histogram<{ LATENCY_BUCKETS.len() + 1 }>;
+
+impl SizeHistogram {
+    const fn sizes() -> Self {
+        Histogram::new(&SIZE_BUCKETS, 1)
+    }
+}
+
+impl LatencyHistogram {
+    const fn latencies() -> Self {
+        Histogram::new(&LATENCY_BUCKETS, 1_000_000)
+    }
+
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_micros().try_into().unwrap_or(u64::MAX));
+    }
+}
+
+impl<const N: usize> Histogram<N> {
+    const fn new(bounds: &'static [u64], per_unit: u64) -> Self {
+        Histogram {
+            bounds,
+            per_unit,
+            buckets: [const { Counter::new() }; N],
+            sum: Counter::new(),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].inc();
+        self.sum.add(value);
+    }
+}
+
+/// One instance of a metric per packet type
+pub struct PerType<T>([T; PacketType::ALL.len()]);
+
+impl<T> PerType<T> {
+    pub fn get(&self, packet_type: PacketType) -> &T {
+        &self.0[packet_type as usize]
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (PacketType, &T)> {
+        PacketType::ALL.into_iter().zip(&self.0)
+    }
+}
+
+pub struct Metrics {
+    pub packets_classified: PerType<Counter>,
+    pub bytes_to_backend: PerType<Counter>,
+    pub bytes_to_client: PerType<Counter>,
+    pub active_connections: PerType<Gauge>,
+    pub connections_created: PerType<Counter>,
+    pub idle_cleanups: PerType<Counter>,
+    pub response_timeouts: PerType<Counter>,
+    pub lifetime_expirations: PerType<Counter>,
+    pub packet_sizes_to_backend: PerType<SizeHistogram>,
+    pub packet_sizes_to_client: PerType<SizeHistogram>,
+    /// From a connection's first packet to its backend's first response
+    pub first_response_latency: PerType<LatencyHistogram>,
+    /// From a packet forwarded to a backend to its next response, per connection
+    pub round_trip_time: PerType<LatencyHistogram>,
+    pub rejected_connections: PerType<Counter>,
+    pub protocol_switches: PerType<Counter>,
+    /// Connections closed to re-pin their client, with `--on-protocol-switch repin`
+    pub repins: PerType<Counter>,
+    pub backend_send_failures: PerType<Counter>,
+    pub send_timeouts: PerType<Counter>,
+    pub mtu_errors: PerType<Counter>,
+    pub mirrored_packets: PerType<Counter>,
+    pub mirror_failures: PerType<Counter>,
+    pub rate_limited: PerType<Counter>,
+    pub fair_queued: PerType<Counter>,
+    pub unestablished_rate_limited: PerType<Counter>,
+    pub response_rate_limited: PerType<Counter>,
+    pub queue_full_drops: PerType<Counter>,
+    pub capacity_evictions: PerType<Counter>,
+    pub backend_failovers: PerType<Counter>,
+    /// Connections closed because their backend refused packets, or a send or receive failed
+    pub unreachable_closes: PerType<Counter>,
+    pub draining_drops: PerType<Counter>,
+    pub no_backend_drops: PerType<Counter>,
+    pub unknown_dropped: Counter,
+    pub denied_packets: Counter,
+    pub truncated_datagrams: Counter,
+    pub empty_datagrams: Counter,
+    pub malformed_wireguard: Counter,
+    pub audit_log_dropped: Counter,
+    pub quic_version_negotiations: Counter,
+    pub quic_retries_from_clients: Counter,
+    pub quic_retries_to_clients: Counter,
+    pub quic_migrations: Counter,
+    pub quic_stateless_resets: Counter,
+    pub unroutable_responses: Counter,
+    pub receive_errors: Counter,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            packets_classified: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            bytes_to_backend: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            bytes_to_client: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            active_connections: PerType([const { Gauge::new() }; PacketType::ALL.len()]),
+            connections_created: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            idle_cleanups: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            response_timeouts: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            lifetime_expirations: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            packet_sizes_to_backend: PerType([const { Histogram::sizes() }; PacketType::ALL.len()]),
+            packet_sizes_to_client: PerType([const { Histogram::sizes() }; PacketType::ALL.len()]),
+            first_response_latency: PerType(
+                [const { Histogram::latencies() }; PacketType::ALL.len()],
+            ),
+            round_trip_time: PerType([const { Histogram::latencies() }; PacketType::ALL.len()]),
+            rejected_connections: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            protocol_switches: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            repins: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            backend_send_failures: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            send_timeouts: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            mtu_errors: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            mirrored_packets: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            mirror_failures: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            rate_limited: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            fair_queued: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            unestablished_rate_limited: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            response_rate_limited: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            queue_full_drops: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            capacity_evictions: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            backend_failovers: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            unreachable_closes: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            draining_drops: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            no_backend_drops: PerType([const { Counter::new() }; PacketType::ALL.len()]),
+            unknown_dropped: Counter::new(),
+            denied_packets: Counter::new(),
+            truncated_datagrams: Counter::new(),
+            empty_datagrams: Counter::new(),
+            malformed_wireguard: Counter::new(),
+            audit_log_dropped: Counter::new(),
+            quic_version_negotiations: Counter::new(),
+            quic_retries_from_clients: Counter::new(),
+            quic_retries_to_clients: Counter::new(),
+            quic_migrations: Counter::new(),
+            quic_stateless_resets: Counter::new(),
+            unroutable_responses: Counter::new(),
+            receive_errors: Counter::new(),
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_per_type(
+            &mut out,
+            "wgq_packets_classified_total",
+            "counter",
+            "Packets classified when a client opens a new connection, by type",
+            &self.packets_classified,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_forwarded_bytes_total",
+            "counter",
+            "Bytes forwarded from clients to backends",
+            &self.bytes_to_backend,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_response_bytes_total",
+            "counter",
+            "Bytes forwarded from backends back to clients",
+            &self.bytes_to_client,
+            Counter::get,
+        );
+        write_histogram(
+            &mut out,
+            "wgq_forwarded_packet_size_bytes",
+            "Sizes of the packets forwarded from clients to backends",
+            &self.packet_sizes_to_backend,
+        );
+        write_histogram(
+            &mut out,
+            "wgq_response_packet_size_bytes",
+            "Sizes of the packets forwarded from backends back to clients",
+            &self.packet_sizes_to_client,
+        );
+        write_histogram(
+            &mut out,
+            "wgq_first_response_latency_seconds",
+            "Time from the first packet of a connection to the first response from its backend",
+            &self.first_response_latency,
+        );
+        write_histogram(
+            &mut out,
+            "wgq_round_trip_time_seconds",
+            "Time from a packet forwarded to a backend to the next response on its connection",
+            &self.round_trip_time,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_active_connections",
+            "gauge",
+            "Connections currently being forwarded",
+            &self.active_connections,
+            Gauge::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_connections_created_total",
+            "counter",
+            "Connections added to the connection table",
+            &self.connections_created,
+            Counter::get,
+        );
+        self.write_evictions(&mut out);
+        write_per_type(
+            &mut out,
+            "wgq_idle_cleanups_total",
+            "counter",
+            "Connections closed because they were inactive for too long",
+            &self.idle_cleanups,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_response_timeouts_total",
+            "counter",
+            "Connections closed because their backend did not respond for too long",
+            &self.response_timeouts,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_lifetime_expirations_total",
+            "counter",
+            "Connections closed because they reached their maximum lifetime",
+            &self.lifetime_expirations,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_rejected_connections_total",
+            "counter",
+            "Packets from new clients dropped because a connection limit was reached",
+            &self.rejected_connections,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_protocol_switches_total",
+            "counter",
+            "Packets that looked like a different protocol than their connection, by the new type",
+            &self.protocol_switches,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_backend_send_failures_total",
+            "counter",
+            "Packets that could not be sent to a backend",
+            &self.backend_send_failures,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_send_timeouts_total",
+            "counter",
+            "Connections closed because sending a packet to their backend took too long",
+            &self.send_timeouts,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_mtu_errors_total",
+            "counter",
+            "Packets to a backend that were larger than the path MTU, refused on sending or reported by ICMP",
+            &self.mtu_errors,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_mirrored_packets_total",
+            "counter",
+            "Copies of forwarded packets sent to the mirror collector",
+            &self.mirrored_packets,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_mirror_failures_total",
+            "counter",
+            "Copies of forwarded packets that could not be sent to the mirror collector",
+            &self.mirror_failures,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_rate_limited_packets_total",
+            "counter",
+            "Packets dropped because their backend's rate limit was reached",
+            &self.rate_limited,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_fair_queued_packets_total",
+            "counter",
+            "Packets held back until their source's turn, because their backend's rate limit was reached with --fair-queue",
+            &self.fair_queued,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_unestablished_rate_limited_packets_total",
+            "counter",
+            "Packets dropped because their source exceeded its rate limit for connections the backend hasn't responded to",
+            &self.unestablished_rate_limited,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_response_rate_limited_packets_total",
+            "counter",
+            "Responses dropped or held back because their connection reached --max-response-bps",
+            &self.response_rate_limited,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_queue_full_drops_total",
+            "counter",
+            "Packets dropped because their connection's queue to the backend was full",
+            &self.queue_full_drops,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_capacity_evictions_total",
+            "counter",
+            "Connections closed to make room for a new client at the connection limit",
+            &self.capacity_evictions,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_backend_failovers_total",
+            "counter",
+            "Connections moved to another backend because theirs failed its health probes",
+            &self.backend_failovers,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_draining_drops_total",
+            "counter",
+            "Packets from new clients dropped because their backend is draining",
+            &self.draining_drops,
+            Counter::get,
+        );
+        write_per_type(
+            &mut out,
+            "wgq_no_backend_drops_total",
+            "counter",
+            "Packets from new clients dropped because there is no backend for their type",
+            &self.no_backend_drops,
+            Counter::get,
+        );
+        write_single(
+            &mut out,
+            "wgq_unknown_dropped_total",
+            "counter",
+            "Unclassified packets dropped because --forward-unknown-to isn't set",
+            self.unknown_dropped.get(),
+        );
+        write_single(
+            &mut out,
+            "wgq_denied_packets_total",
+            "counter",
+            "Packets dropped because their source is not allowed by the CIDR lists",
+            self.denied_packets.get(),
+        );
+        write_single(
+            &mut out,
+            "wgq_truncated_datagrams_total",
+            "counter",
+            "Datagrams dropped because they were larger than the maximum datagram size",
+            self.truncated_datagrams.get(),
+        );
+        write_single(
+            &mut out,
+            "wgq_empty_datagrams_total",
+            "counter",
+            "Empty datagrams from clients, which are dropped",
+            self.empty_datagrams.get(),
+        );
+        write_single(
+            &mut out,
+            "wgq_malformed_wireguard_total",
+            "counter",
+            "Packets from new clients that start like a WireGuard message but have the wrong length, which are dropped",
+            self.malformed_wireguard.get(),
+        );
+        write_single(
+            &mut out,
+            "wgq_audit_log_dropped_total",
+            "counter",
+            "New connections left out of --audit-log because writing it fell behind",
+            self.audit_log_dropped.get(),
+        );
+        write_single(
+            &mut out,
+            "wgq_quic_version_negotiations_total",
+            "counter",
+            "QUIC version negotiation packets seen when classifying a connection",
+            self.quic_version_negotiations.get(),
+        );
+        let name = "wgq_quic_retry_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} QUIC Retry packets, sent by backends validating a client's address, or (rarely) by clients"
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(
+            out,
+            "{name}{{direction=\"to_client\"}} {}",
+            self.quic_retries_to_clients.get()
+        );
+        let _ = writeln!(
+            out,
+            "{name}{{direction=\"from_client\"}} {}",
+            self.quic_retries_from_clients.get()
+        );
+        write_single(
+            &mut out,
+            "wgq_quic_migrations_total",
+            "counter",
+            "QUIC clients followed to a new address by their connection ID",
+            self.quic_migrations.get(),
+        );
+        write_single(
+            &mut out,
+            "wgq_quic_stateless_resets_total",
+            "counter",
+            "Responses from QUIC backends that look like stateless resets, sent when they lost a connection's state",
+            self.quic_stateless_resets.get(),
+        );
+        write_single(
+            &mut out,
+            "wgq_unroutable_responses_total",
+            "counter",
+            "Responses on shared QUIC sockets dropped because they matched no connection, or their connection had too many waiting",
+            self.unroutable_responses.get(),
+        );
+        write_single(
+            &mut out,
+            "wgq_receive_errors_total",
+            "counter",
+            "Recoverable errors while receiving packets from clients",
+            self.receive_errors.get(),
+        );
+        out
+    }
+
+    /// The counters of connections the proxy closed itself, together as one family by reason,
+    /// so the connection table's churn can be watched with a single query
+    fn write_evictions(&self, out: &mut String) {
+        let name = "wgq_connections_evicted_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Connections closed by the proxy rather than their client, by reason"
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let reasons = [
+            ("idle", &self.idle_cleanups),
+            ("response_timeout", &self.response_timeouts),
+            ("lifetime", &self.lifetime_expirations),
+            ("capacity", &self.capacity_evictions),
+            ("send_timeout", &self.send_timeouts),
+            ("failover", &self.backend_failovers),
+            ("unreachable", &self.unreachable_closes),
+            ("protocol_switch", &self.repins),
+        ];
+        for (reason, counters) in reasons {
+            for (packet_type, counter) in counters.iter() {
+                let _ = writeln!(
+                    out,
+                    "{name}{{packet_type=\"{}\",reason=\"{reason}\"}} {}",
+                    packet_type.label(),
+                    counter.get()
+                );
+            }
+        }
+    }
+}
+
+/// Renders whether each backend is up, which isn't known to the static metrics
+pub fn render_backends(backends: &Backends) -> String {
+    let mut out = String::new();
+    let name = "wgq_backend_up";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Whether the backend passes its health probes, 1 if probing is off"
+    );
+    let _ = writeln!(out, "# TYPE {name} gauge");
+    for (packet_type, backend) in backends.all() {
+        let _ = writeln!(
+            out,
+            "{name}{{packet_type=\"{}\",backend=\"{}\"}} {}",
+            packet_type.label(),
+            backend.address,
+            u8::from(backend.health.is_up())
+        );
+    }
+    let name = "wgq_backend_draining";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Whether the backend is draining, taking no new connections"
+    );
+    let _ = writeln!(out, "# TYPE {name} gauge");
+    for (packet_type, backend) in backends.all() {
+        let _ = writeln!(
+            out,
+            "{name}{{packet_type=\"{}\",backend=\"{}\"}} {}",
+            packet_type.label(),
+            backend.address,
+            u8::from(backend.is_draining())
+        );
+    }
+    out
+}
+
+/// Traffic per `--listen` address, labelled with its port as well so listeners on the same
+/// port of different addresses stay apart
+pub fn render_listeners(listeners: &[Arc<Listener>]) -> String {
+    let mut out = String::new();
+    write_per_listener(
+        &mut out,
+        "wgq_listener_packets_received_total",
+        "Packets received from clients on each listen address",
+        listeners,
+        |listener| &listener.packets_received,
+    );
+    write_per_listener(
+        &mut out,
+        "wgq_listener_bytes_received_total",
+        "Bytes received from clients on each listen address",
+        listeners,
+        |listener| &listener.bytes_received,
+    );
+    write_per_listener(
+        &mut out,
+        "wgq_listener_packets_sent_total",
+        "Responses sent back to clients from each listen address",
+        listeners,
+        |listener| &listener.packets_sent,
+    );
+    write_per_listener(
+        &mut out,
+        "wgq_listener_bytes_sent_total",
+        "Bytes sent back to clients from each listen address",
+        listeners,
+        |listener| &listener.bytes_sent,
+    );
+    out
+}
+
+fn write_per_listener(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    listeners: &[Arc<Listener>],
+    counter: impl Fn(&Listener) -> &Counter,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} counter");
+    for listener in listeners {
+        let _ = writeln!(
+            out,
+            "{name}{{listen=\"{}\",port=\"{}\"}} {}",
+            listener.addr,
+            listener.addr.port(),
+            counter(listener).get()
+        );
+    }
+}
+
+fn write_single(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn write_histogram<const N: usize>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    values: &PerType<Histogram<N>>,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} histogram");
+    for (packet_type, histogram) in values.iter() {
+        let label = packet_type.label();
+        let in_units = |value: u64| value as f64 / histogram.per_unit as f64;
+        // Prometheus buckets are cumulative
+        let mut count = 0;
+        let bounds = histogram
+            .bounds
+            .iter()
+            .map(|&bound| in_units(bound).to_string());
+        for (bound, bucket) in bounds.chain(["+Inf".to_string()]).zip(&histogram.buckets) {
+            count += bucket.get();
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{packet_type=\"{label}\",le=\"{bound}\"}} {count}"
+            );
+        }
+        let sum = in_units(histogram.sum.get());
+        let _ = writeln!(out, "{name}_sum{{packet_type=\"{label}\"}} {sum}");
+        let _ = writeln!(out, "{name}_count{{packet_type=\"{label}\"}} {count}");
+    }
+}
+
+fn write_per_type<T>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    values: &PerType<T>,
+    get: fn(&T) -> u64,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    for (packet_type, value) in values.iter() {
+        let _ = writeln!(
+            out,
+            "{name}{{packet_type=\"{}\"}} {}",
+            packet_type.label(),
+            get(value)
+        );
+    }
+}
@@ -0,0 +1,48 @@
+//! Passive copies of the traffic of a protocol, for collectors that analyse it without being
+//! in the forwarding path.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+
+use crate::PacketType;
+use crate::metrics::METRICS;
+
+pub struct Mirror {
+    sock: UdpSocket,
+    addr: SocketAddr,
+}
+
+impl Mirror {
+    /// Resolves the collector at `address` (host:port) once, and binds a socket to send to it
+    pub async fn bind(address: &str) -> io::Result<Mirror> {
+        let addr = tokio::net::lookup_host(address)
+            .await
+            .and_then(|mut addrs| {
+                addrs.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "did not resolve to any address")
+                })
+            })
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid mirror address {address:?}: {e}"),
+                )
+            })?;
+        let local_addr: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let sock = UdpSocket::bind(local_addr).await?;
+        Ok(Mirror { sock, addr })
+    }
+
+    /// Sends a copy of `packet` if that can be done without waiting. Failures are only counted,
+    /// so the mirror never holds up or breaks the forwarding to the real backend.
+    pub fn send(&self, packet: &[u8], packet_type: PacketType) {
+        match self.sock.try_send_to(packet, self.addr) {
+            Ok(_) => METRICS.mirrored_packets.get(packet_type).inc(),
+            Err(_) => METRICS.mirror_failures.get(packet_type).inc(),
+        }
+    }
+}
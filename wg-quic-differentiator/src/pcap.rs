@@ -0,0 +1,225 @@
+//! Records the datagrams clients send to a pcapng file, each with a comment saying how it was
+//! classified, so the classifier's decisions can be checked in Wireshark afterwards. The same
+//! format is used to record the first bytes of the first packet of every new connection.
+
+use pcap_file::DataLink;
+use pcap_file::pcapng::PcapNgWriter;
+use pcap_file::pcapng::blocks::enhanced_packet::{EnhancedPacketBlock, EnhancedPacketOption};
+use pcap_file::pcapng::blocks::interface_description::{
+    InterfaceDescriptionBlock, InterfaceDescriptionOption,
+};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+
+use crate::PacketType;
+use crate::throttle::Throttle;
+
+/// Datagrams waiting to be written before new ones are left out of the capture
+const BACKLOG: usize = 4096;
+
+/// Queues datagrams for the writer thread. The file is complete once every `Capture` has
+/// been dropped.
+pub struct Capture {
+    tx: mpsc::Sender<Record>,
+    /// Bytes of each datagram that are recorded, or all of them when `None`
+    snaplen: Option<usize>,
+}
+
+struct Record {
+    timestamp: Duration,
+    source: SocketAddr,
+    destination: SocketAddr,
+    packet_type: PacketType,
+    data: Vec<u8>,
+    /// The size of the datagram `data` was cut from
+    original_len: usize,
+}
+
+impl Capture {
+    /// Creates the file at `path`, and starts writing to it in the background. With a
+    /// `snaplen`, only the first that many bytes of each datagram are recorded.
+    pub fn create(path: &Path, snaplen: Option<usize>) -> io::Result<Capture> {
+        let file = File::create(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to create capture file {}: {e}", path.display()),
+            )
+        })?;
+        let mut writer = PcapNgWriter::new(BufWriter::new(file)).map_err(io::Error::other)?;
+        // Datagrams are stored with made up IP and UDP headers, so Wireshark can dissect them
+        writer
+            .write_pcapng_block(InterfaceDescriptionBlock {
+                linktype: DataLink::RAW,
+                snaplen: 0,
+                options: vec![InterfaceDescriptionOption::IfTsResol(9)],
+            })
+            .map_err(io::Error::other)?;
+
+        let (tx, mut rx) = mpsc::channel::<Record>(BACKLOG);
+        std::thread::spawn(move || {
+            while let Some(record) = rx.blocking_recv() {
+                if let Err(e) = write_record(&mut writer, &record) {
+                    log::error!("Error writing to capture file, stopping the capture: {}", e);
+                    return;
+                }
+                // Flush whenever the backlog is written, so the file is usable while running
+                if rx.is_empty() && writer.get_mut().flush().is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Capture { tx, snaplen })
+    }
+
+    /// Adds a datagram received from `source` on `destination` to the capture, unless the
+    /// writer has fallen behind
+    pub fn record(
+        &self,
+        source: SocketAddr,
+        destination: SocketAddr,
+        packet_type: PacketType,
+        data: &[u8],
+    ) {
+        let len = self
+            .snaplen
+            .map_or(data.len(), |snaplen| snaplen.min(data.len()));
+        let record = Record {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default(),
+            source,
+            destination,
+            packet_type,
+            data: data[..len].to_vec(),
+            original_len: data.len(),
+        };
+        if self.tx.try_send(record).is_err() {
+            static BACKLOG_WARNING: Throttle = Throttle::new(Duration::from_secs(10));
+            if BACKLOG_WARNING.allow() {
+                log::warn!("Capture file can't keep up, leaving packets out of it");
+            }
+        }
+    }
+}
+
+fn write_record<W: Write>(
+    writer: &mut PcapNgWriter<W>,
+    record: &Record,
+) -> Result<(), pcap_file::PcapError> {
+    let packet = ip_packet(
+        record.source,
+        record.destination,
+        &record.data,
+        record.original_len,
+    );
+    let headers_len = packet.len() - record.data.len();
+    writer.write_pcapng_block(EnhancedPacketBlock {
+        interface_id: 0,
+        timestamp: record.timestamp,
+        original_len: (headers_len + record.original_len) as u32,
+        data: Cow::Borrowed(&packet),
+        options: vec![EnhancedPacketOption::Comment(Cow::Owned(format!(
+            "classified as {}",
+            record.packet_type.label()
+        )))],
+    })?;
+    Ok(())
+}
+
+/// Wraps a datagram in IP and UDP headers. `data` may be cut short from a datagram of
+/// `original_len` bytes, in which case the headers are those of the whole datagram.
+fn ip_packet(
+    source: SocketAddr,
+    destination: SocketAddr,
+    data: &[u8],
+    original_len: usize,
+) -> Vec<u8> {
+    let source_ip = source.ip().to_canonical();
+    let destination_ip = same_family(destination.ip().to_canonical(), source_ip);
+    let udp_len = 8 + original_len;
+
+    let mut packet = Vec::with_capacity(40 + udp_len);
+    match (source_ip, destination_ip) {
+        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + udp_len) as u16).to_be_bytes());
+            // Identification, flags and fragment offset, TTL, protocol and checksum
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+            packet.extend_from_slice(&source_ip.octets());
+            packet.extend_from_slice(&destination_ip.octets());
+            let checksum = checksum(&packet, 0);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (IpAddr::V6(source_ip), IpAddr::V6(destination_ip)) => {
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            // Next header and hop limit
+            packet.extend_from_slice(&[17, 64]);
+            packet.extend_from_slice(&source_ip.octets());
+            packet.extend_from_slice(&destination_ip.octets());
+        }
+        _ => unreachable!("both addresses have the same family"),
+    }
+
+    let udp_start = packet.len();
+    packet.extend_from_slice(&source.port().to_be_bytes());
+    packet.extend_from_slice(&destination.port().to_be_bytes());
+    packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(data);
+    // The UDP checksum is optional over IPv4, but not over IPv6. It can't be computed for a
+    // datagram that was cut short, which is how Wireshark treats it anyway.
+    if let (IpAddr::V6(source_ip), IpAddr::V6(destination_ip)) = (source_ip, destination_ip)
+        && data.len() == original_len
+    {
+        let mut pseudo_header = Vec::with_capacity(40);
+        pseudo_header.extend_from_slice(&source_ip.octets());
+        pseudo_header.extend_from_slice(&destination_ip.octets());
+        pseudo_header.extend_from_slice(&(udp_len as u32).to_be_bytes());
+        pseudo_header.extend_from_slice(&[0, 0, 0, 17]);
+        let initial = sum(&pseudo_header, 0);
+        let checksum = match checksum(&packet[udp_start..], initial) {
+            0 => 0xffff,
+            checksum => checksum,
+        };
+        packet[udp_start + 6..udp_start + 8].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet
+}
+
+/// A dual-stack socket listening on `[::]` receives IPv4 clients, in which case the capture
+/// shows them arriving at the unspecified IPv4 address
+fn same_family(ip: IpAddr, like: IpAddr) -> IpAddr {
+    match (ip, like) {
+        (IpAddr::V6(_), IpAddr::V4(_)) => Ipv4Addr::UNSPECIFIED.into(),
+        (IpAddr::V4(ip), IpAddr::V6(_)) => ip.to_ipv6_mapped().into(),
+        (ip, _) => ip,
+    }
+}
+
+/// The one's complement sum (RFC 1071) of `data`, added to `initial`
+fn sum(data: &[u8], initial: u32) -> u32 {
+    let mut sum = initial;
+    for chunk in data.chunks(2) {
+        let word = match chunk {
+            [high, low] => u16::from_be_bytes([*high, *low]),
+            [high] => u16::from_be_bytes([*high, 0]),
+            _ => unreachable!(),
+        };
+        sum += u32::from(word);
+    }
+    sum
+}
+
+fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = sum(data, initial);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
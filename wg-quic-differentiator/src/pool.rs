@@ -0,0 +1,39 @@
+use std::sync::Mutex;
+
+/// A free-list of byte buffers, so that the hot path can reuse allocations instead of
+/// allocating a fresh `Vec` for every packet.
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    capacity: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool that keeps at most `capacity` unused buffers around
+    pub const fn new(capacity: usize) -> Self {
+        BufferPool {
+            free: Mutex::new(Vec::new()),
+            capacity,
+        }
+    }
+
+    /// Returns an empty buffer, reusing a previously recycled one if available
+    pub fn take(&self) -> Vec<u8> {
+        self.free.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    /// Returns a buffer filled with a copy of `data`
+    pub fn copy_of(&self, data: &[u8]) -> Vec<u8> {
+        let mut buf = self.take();
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    /// Hands a buffer back to the pool once it is no longer needed
+    pub fn recycle(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.capacity {
+            free.push(buf);
+        }
+    }
+}
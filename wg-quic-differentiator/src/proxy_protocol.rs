@@ -0,0 +1,60 @@
+//! Encoding of PROXY protocol version 2 headers, which tell a backend the address of the client
+//! a datagram was originally sent from, and recognising them in what backends send back.
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Protocol version 2, and the PROXY command (as opposed to LOCAL)
+const VERSION_COMMAND: u8 = 0x21;
+/// The address family in the high nibble, and the transport protocol (datagram) in the low one
+const UDP_OVER_IPV4: u8 = 0x12;
+const UDP_OVER_IPV6: u8 = 0x22;
+
+/// Builds the header for datagrams that `client` sent to the proxy at `local`
+pub fn header(client: SocketAddr, local: SocketAddr) -> Vec<u8> {
+    // IPv4 clients of a dual-stack socket show up as IPv4-mapped IPv6 addresses, which the
+    // backend should see as plain IPv4 where possible
+    let source = client.ip().to_canonical();
+    let destination = local.ip().to_canonical();
+
+    let mut header = Vec::with_capacity(16 + 36);
+    header.extend_from_slice(&SIGNATURE);
+    header.push(VERSION_COMMAND);
+    match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            header.push(UDP_OVER_IPV4);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&source.octets());
+            header.extend_from_slice(&destination.octets());
+        }
+        // If the families differ, both are sent as IPv6
+        (source, destination) => {
+            header.push(UDP_OVER_IPV6);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&to_ipv6(source).octets());
+            header.extend_from_slice(&to_ipv6(destination).octets());
+        }
+    }
+    header.extend_from_slice(&client.port().to_be_bytes());
+    header.extend_from_slice(&local.port().to_be_bytes());
+    header
+}
+
+/// The length of the version 2 header a datagram starts with, if it has one
+pub fn header_len(packet: &[u8]) -> Option<usize> {
+    let fixed = packet.get(..16)?;
+    if fixed[..12] != SIGNATURE || fixed[12] >> 4 != 2 {
+        return None;
+    }
+    let len = 16 + u16::from_be_bytes([fixed[14], fixed[15]]) as usize;
+    (len <= packet.len()).then_some(len)
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
@@ -0,0 +1,135 @@
+//! The bounded queue between the receive loop and a connection's forwarding task. It works
+//! like a bounded channel, except that a full queue can make room for a new packet by
+//! dropping the oldest one instead.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DropPolicy {
+    /// Drop the packet that didn't fit, keeping the ones already queued
+    Newest,
+    /// Drop the packet that has been queued the longest, to make room for the new one
+    Oldest,
+}
+
+pub enum SendError {
+    /// The queue was full. Holds the packet that was dropped, which depending on the drop
+    /// policy is either the new one or the oldest queued one.
+    Full(Vec<u8>),
+    /// The forwarding task has stopped
+    Closed(Vec<u8>),
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Wakes the receiver when a packet is queued or the last sender is dropped
+    notify: Notify,
+    capacity: usize,
+    policy: DropPolicy,
+}
+
+struct State {
+    packets: VecDeque<Vec<u8>>,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+pub struct Sender(Arc<Shared>);
+
+pub struct Receiver(Arc<Shared>);
+
+pub fn queue(capacity: usize, policy: DropPolicy) -> (Sender, Receiver) {
+    // There always has to be room for the packet that creates a connection
+    let capacity = capacity.max(1);
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            packets: VecDeque::with_capacity(capacity),
+            senders: 1,
+            receiver_alive: true,
+        }),
+        notify: Notify::new(),
+        capacity,
+        policy,
+    });
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+impl Sender {
+    /// Queues a packet without waiting
+    pub fn try_send(&self, packet: Vec<u8>) -> Result<(), SendError> {
+        let mut state = self.0.state.lock().unwrap();
+        if !state.receiver_alive {
+            return Err(SendError::Closed(packet));
+        }
+        let dropped = if state.packets.len() < self.0.capacity {
+            None
+        } else {
+            match self.0.policy {
+                DropPolicy::Newest => return Err(SendError::Full(packet)),
+                DropPolicy::Oldest => state.packets.pop_front(),
+            }
+        };
+        state.packets.push_back(packet);
+        drop(state);
+        self.0.notify.notify_one();
+        match dropped {
+            Some(dropped) => Err(SendError::Full(dropped)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Self {
+        self.0.state.lock().unwrap().senders += 1;
+        Sender(self.0.clone())
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.0.notify.notify_one();
+        }
+    }
+}
+
+impl Receiver {
+    /// Waits for the next packet, returning `None` once the queue is empty and every sender
+    /// has been dropped
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        loop {
+            {
+                let mut state = self.0.state.lock().unwrap();
+                if let Some(packet) = state.packets.pop_front() {
+                    return Some(packet);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            // A notification sent since the lock was released is kept for this
+            self.0.notify.notified().await;
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<Vec<u8>> {
+        self.0.state.lock().unwrap().packets.pop_front()
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.receiver_alive = false;
+        state.packets.clear();
+    }
+}
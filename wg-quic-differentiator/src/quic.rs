@@ -0,0 +1,300 @@
+/// QUIC version 1 (RFC 9000)
+const VERSION_1: u32 = 0x0000_0001;
+/// QUIC version 2 (RFC 9369)
+const VERSION_2: u32 = 0x6b33_43cf;
+/// Connection IDs are at most 20 bytes long in all versions we know of
+pub const MAX_CID_LEN: usize = 20;
+/// The smallest path MTU QUIC works over, which clients pad their Initial packets to (RFC 9000,
+/// section 14)
+pub const MIN_PATH_MTU: usize = 1200;
+
+/// Length of the integrity tag at the end of a Retry packet (RFC 9001, section 5.8)
+const RETRY_INTEGRITY_TAG_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuicPacketType {
+    Initial,
+    ZeroRtt,
+    Handshake,
+    /// Sent by a server to validate a client's address before accepting its connection,
+    /// asking it to repeat its Initial with the token it carries
+    Retry,
+    /// Sent by a server that doesn't support the version a client asked for, listing the ones
+    /// it does support
+    VersionNegotiation,
+    /// Short header packet, sent once the handshake has completed
+    OneRtt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuicHeader<'a> {
+    /// Only present in long header packets
+    pub version: Option<u32>,
+    pub packet_type: QuicPacketType,
+    /// Only present in long header packets, as the length of the destination connection ID
+    /// in a short header was negotiated during the handshake. See [`short_header_cid`].
+    pub destination_cid: Option<&'a [u8]>,
+    pub source_cid: Option<&'a [u8]>,
+}
+
+/// Parses the invariant parts of a QUIC packet header (RFC 8999), returning `None` if the
+/// packet doesn't look like QUIC, or uses a version we don't know.
+pub fn parse_quic_header(buf: &[u8]) -> Option<QuicHeader<'_>> {
+    let first = *buf.first()?;
+    let long_header = first & 0x80 != 0;
+    if long_header && buf.get(1..5)? == [0, 0, 0, 0] {
+        // Version negotiation packets are version independent, so even the fixed bit may not be set
+        let _versions = offered_versions(buf)?;
+        let (destination_cid, source_cid) = connection_ids(buf)?;
+        return Some(QuicHeader {
+            version: Some(0),
+            packet_type: QuicPacketType::VersionNegotiation,
+            destination_cid: Some(destination_cid),
+            source_cid: Some(source_cid),
+        });
+    }
+
+    // The fixed bit is always set in the versions we support
+    if first & 0x40 == 0 {
+        return None;
+    }
+
+    if !long_header {
+        // Short headers only contain a connection ID of a length negotiated during the
+        // handshake, so there is nothing more to check.
+        return Some(QuicHeader {
+            version: None,
+            packet_type: QuicPacketType::OneRtt,
+            destination_cid: None,
+            source_cid: None,
+        });
+    }
+
+    let version = u32::from_be_bytes(buf.get(1..5)?.try_into().unwrap());
+    let long_type = (first & 0x30) >> 4;
+    let packet_type = match version {
+        VERSION_1 | 0xff00_001d..=0xff00_0020 => match long_type {
+            0 => QuicPacketType::Initial,
+            1 => QuicPacketType::ZeroRtt,
+            2 => QuicPacketType::Handshake,
+            _ => QuicPacketType::Retry,
+        },
+        // Version 2 shuffles the long packet types around
+        VERSION_2 => match long_type {
+            0 => QuicPacketType::Retry,
+            1 => QuicPacketType::Initial,
+            2 => QuicPacketType::ZeroRtt,
+            _ => QuicPacketType::Handshake,
+        },
+        _ => return None,
+    };
+
+    let (destination_cid, source_cid) = connection_ids(buf)?;
+    // A Retry carries a token, which clients discard the packet without, then its tag
+    if packet_type == QuicPacketType::Retry
+        && buf.len() < connection_ids_end(buf)? + 1 + RETRY_INTEGRITY_TAG_LEN
+    {
+        return None;
+    }
+    Some(QuicHeader {
+        version: Some(version),
+        packet_type,
+        destination_cid: Some(destination_cid),
+        source_cid: Some(source_cid),
+    })
+}
+
+/// Whether a packet has a short header, as used by established connections. These carry no
+/// version, so apart from the header form there is nothing to check.
+pub fn is_short_header(buf: &[u8]) -> bool {
+    buf.first().is_some_and(|first| first & 0x80 == 0)
+}
+
+/// The destination connection ID of a long header packet, from the parts of the header all
+/// versions share (RFC 8999), so even packets with the fixed bit greased (RFC 9287) have one
+pub fn long_header_cid(buf: &[u8]) -> Option<&[u8]> {
+    if is_short_header(buf) {
+        return None;
+    }
+    connection_ids(buf).map(|(destination_cid, _)| destination_cid)
+}
+
+/// The destination connection ID of a short header packet, given the length the connection
+/// uses for it
+pub fn short_header_cid(buf: &[u8], len: usize) -> Option<&[u8]> {
+    if !is_short_header(buf) {
+        return None;
+    }
+    buf.get(1..1 + len)
+}
+
+/// Stateless resets are at least a byte of header, 4 unpredictable bytes and a 16 byte token
+/// long (RFC 9000, section 10.3)
+const MIN_STATELESS_RESET_LEN: usize = 21;
+
+/// Whether a packet sent to a client whose connection ID is `client_cid` looks like a
+/// stateless reset (RFC 9000, section 10.3). A reset is made to look like a short header
+/// packet, but as its sender lost the connection's state, the bytes where the connection ID
+/// goes are random. Its token could only be checked against the one the server gave the
+/// client encrypted, so a server switching to another of the client's connection IDs makes
+/// its packets look like resets too. Clients without a connection ID can't be told apart.
+pub fn looks_like_stateless_reset(buf: &[u8], client_cid: &[u8]) -> bool {
+    !client_cid.is_empty()
+        && buf.len() >= MIN_STATELESS_RESET_LEN
+        && short_header_cid(buf, client_cid.len()).is_some_and(|cid| cid != client_cid)
+}
+
+/// The versions offered by a version negotiation packet, or `None` if the list is malformed
+pub fn offered_versions(buf: &[u8]) -> Option<impl Iterator<Item = u32> + '_> {
+    let versions = buf.get(connection_ids_end(buf)?..)?;
+    if versions.is_empty() || versions.len() % 4 != 0 {
+        return None;
+    }
+    Some(
+        versions
+            .chunks_exact(4)
+            .map(|version| u32::from_be_bytes(version.try_into().unwrap())),
+    )
+}
+
+/// Checks that both connection IDs of a long header fit in the packet, returning the offset
+/// of the first byte after them
+fn connection_ids_end(buf: &[u8]) -> Option<usize> {
+    let dcid_len = *buf.get(5)? as usize;
+    if dcid_len > MAX_CID_LEN {
+        return None;
+    }
+    let scid_len_offset = 6 + dcid_len;
+    let scid_len = *buf.get(scid_len_offset)? as usize;
+    let end = scid_len_offset + 1 + scid_len;
+    if scid_len > MAX_CID_LEN || buf.len() < end {
+        return None;
+    }
+    Some(end)
+}
+
+/// The destination and source connection IDs of a long header
+fn connection_ids(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = connection_ids_end(buf)?;
+    let dcid_len = buf[5] as usize;
+    Some((&buf[6..6 + dcid_len], &buf[6 + dcid_len + 1..end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// The connection IDs of the example connection in RFC 9001, appendix A
+    const CLIENT_CID: &str = "8394c8f03e515708";
+    const SERVER_CID: &str = "f067a5502a4262b5";
+
+    fn parse(packet: &[u8]) -> QuicHeader<'_> {
+        parse_quic_header(packet).expect("a QUIC packet")
+    }
+
+    #[test]
+    fn parses_a_client_initial() {
+        // The start of the protected client Initial of RFC 9001, appendix A.2. Header
+        // protection only hides the packet number bits of the first byte.
+        let packet = hex("c000000001088394c8f03e5157080000449e7b9aec34");
+        let header = parse(&packet);
+        assert_eq!(header.packet_type, QuicPacketType::Initial);
+        assert_eq!(header.version, Some(VERSION_1));
+        assert_eq!(header.destination_cid, Some(&hex(CLIENT_CID)[..]));
+        assert_eq!(header.source_cid, Some(&[][..]));
+        assert_eq!(long_header_cid(&packet), Some(&hex(CLIENT_CID)[..]));
+
+        // A whole one, asking for example.com
+        let packet = include_bytes!("../tests/data/quic_initial_example_com.bin");
+        let header = parse_quic_header(packet).unwrap();
+        assert_eq!(header.packet_type, QuicPacketType::Initial);
+        assert_eq!(header.destination_cid.map(<[u8]>::len), Some(20));
+    }
+
+    #[test]
+    fn parses_a_server_initial() {
+        // The start of the server Initial of RFC 9001, appendix A.3
+        let packet = hex("cf000000010008f067a5502a4262b5004075c0d9");
+        let header = parse(&packet);
+        assert_eq!(header.packet_type, QuicPacketType::Initial);
+        assert_eq!(header.destination_cid, Some(&[][..]));
+        assert_eq!(header.source_cid, Some(&hex(SERVER_CID)[..]));
+    }
+
+    #[test]
+    fn parses_handshake_and_0rtt_packets() {
+        // The RFCs have no examples of these, so they are laid out as in RFC 9000, section
+        // 17.2, for the connection of RFC 9001, appendix A: type, version, connection IDs,
+        // length and a one byte packet number
+        let handshake = hex(&format!("e00000000108{SERVER_CID}0040160027"));
+        let header = parse(&handshake);
+        assert_eq!(header.packet_type, QuicPacketType::Handshake);
+        assert_eq!(header.destination_cid, Some(&hex(SERVER_CID)[..]));
+        assert_eq!(header.source_cid, Some(&[][..]));
+
+        let zero_rtt = hex(&format!("d00000000108{CLIENT_CID}0040250001"));
+        let header = parse(&zero_rtt);
+        assert_eq!(header.packet_type, QuicPacketType::ZeroRtt);
+        assert_eq!(header.version, Some(VERSION_1));
+        assert_eq!(header.destination_cid, Some(&hex(CLIENT_CID)[..]));
+    }
+
+    #[test]
+    fn parses_a_retry_with_its_integrity_tag() {
+        // RFC 9001, appendix A.4, with the token "token"
+        let packet =
+            hex("ff000000010008f067a5502a4262b5746f6b656e04a265ba2eff4d829058fb3f0f2496ba");
+        let header = parse(&packet);
+        assert_eq!(header.packet_type, QuicPacketType::Retry);
+        assert_eq!(header.source_cid, Some(&hex(SERVER_CID)[..]));
+        // Without a token and tag it can't be a Retry
+        assert_eq!(parse_quic_header(&packet[..packet.len() - 6]), None);
+    }
+
+    #[test]
+    fn parses_version_2_packet_types() {
+        // RFC 9369, appendix A: the client Initial and the Retry
+        let initial = hex("d36b3343cf088394c8f03e5157080000449ea0c95e82ffe6");
+        assert_eq!(parse(&initial).packet_type, QuicPacketType::Initial);
+        assert_eq!(parse(&initial).version, Some(VERSION_2));
+        let retry = hex("cf6b3343cf0008f067a5502a4262b5746f6b656ec8646ce8bfe33952d955543665dcc7b6");
+        assert_eq!(parse(&retry).packet_type, QuicPacketType::Retry);
+    }
+
+    #[test]
+    fn parses_a_short_header() {
+        // RFC 9001, appendix A.5, a protected packet to a zero length connection ID
+        let packet = hex("4cfe4189655e5cd55c41f69080575d7999c25a5bfb");
+        let header = parse(&packet);
+        assert_eq!(header.packet_type, QuicPacketType::OneRtt);
+        assert_eq!(header.version, None);
+        assert_eq!(header.destination_cid, None);
+        assert!(is_short_header(&packet));
+        assert_eq!(short_header_cid(&packet, 0), Some(&[][..]));
+        assert_eq!(long_header_cid(&packet), None);
+    }
+
+    #[test]
+    fn rejects_what_isnt_quic() {
+        // An unknown version
+        assert_eq!(
+            parse_quic_header(&hex("c0abcdef01088394c8f03e51570800")),
+            None
+        );
+        // The fixed bit clear
+        assert_eq!(
+            parse_quic_header(&hex("8000000001088394c8f03e51570800")),
+            None
+        );
+        // A connection ID longer than any version allows, or than the packet
+        assert_eq!(parse_quic_header(&hex("c00000000115")), None);
+        assert_eq!(parse_quic_header(&hex("c000000001088394c8")), None);
+    }
+}
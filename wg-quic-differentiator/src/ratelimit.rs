@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A token bucket limiting the packets and bytes per second forwarded to a backend. Each
+/// bucket holds up to one second worth of tokens, which allows short bursts. A packet larger
+/// than that still passes once the byte bucket is full, leaving it in debt.
+pub struct RateLimiter {
+    max_pps: Option<f64>,
+    max_bps: Option<f64>,
+    state: Mutex<Buckets>,
+}
+
+struct Buckets {
+    packets: f64,
+    bytes: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Returns `None` when neither limit is set, so unlimited backends skip the check entirely
+    pub fn new(max_pps: Option<u64>, max_bps: Option<u64>) -> Option<Self> {
+        if max_pps.is_none() && max_bps.is_none() {
+            return None;
+        }
+        let max_pps = max_pps.map(|n| n as f64);
+        let max_bps = max_bps.map(|n| n as f64);
+        Some(RateLimiter {
+            max_pps,
+            max_bps,
+            state: Mutex::new(Buckets::full(max_pps, max_bps, Instant::now())),
+        })
+    }
+
+    /// Takes the tokens for a packet of `len` bytes, returning false if there aren't enough
+    pub fn allow(&self, len: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.take(self.max_pps, self.max_bps, len, Instant::now())
+    }
+
+    /// Takes the tokens for a packet of `len` bytes even if there aren't enough, and returns
+    /// how long to wait before sending it to stay within the limits
+    pub fn reserve(&self, len: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        state.reserve(self.max_pps, self.max_bps, len, Instant::now())
+    }
+}
+
+/// Like `RateLimiter`, but with buckets for each source IP address. Only a bounded number of
+/// sources is remembered, so a peer spoofing many of them can't grow it without limit; beyond
+/// that, packets from new sources are refused until others have been quiet for a second.
+pub struct SourceRateLimiter {
+    max_pps: Option<f64>,
+    max_bps: Option<f64>,
+    capacity: usize,
+    sources: Mutex<HashMap<IpAddr, Buckets>>,
+}
+
+impl SourceRateLimiter {
+    /// Returns `None` when neither limit is set, so that the check can be skipped entirely
+    pub fn new(max_pps: Option<u64>, max_bps: Option<u64>, capacity: usize) -> Option<Self> {
+        if max_pps.is_none() && max_bps.is_none() {
+            return None;
+        }
+        Some(SourceRateLimiter {
+            max_pps: max_pps.map(|n| n as f64),
+            max_bps: max_bps.map(|n| n as f64),
+            capacity,
+            sources: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Takes the tokens for a packet of `len` bytes from `source`, returning false if there
+    /// aren't enough
+    pub fn allow(&self, source: IpAddr, len: usize) -> bool {
+        let now = Instant::now();
+        let mut sources = self.sources.lock().unwrap();
+        if sources.len() >= self.capacity && !sources.contains_key(&source) {
+            // A bucket left alone for a second has refilled, so forgetting it changes nothing
+            sources
+                .retain(|_, buckets| now.duration_since(buckets.updated) < Duration::from_secs(1));
+            if sources.len() >= self.capacity {
+                return false;
+            }
+        }
+        sources
+            .entry(source)
+            .or_insert_with(|| Buckets::full(self.max_pps, self.max_bps, now))
+            .take(self.max_pps, self.max_bps, len, now)
+    }
+}
+
+impl Buckets {
+    fn full(max_pps: Option<f64>, max_bps: Option<f64>, now: Instant) -> Self {
+        Buckets {
+            packets: max_pps.unwrap_or(0.0),
+            bytes: max_bps.unwrap_or(0.0),
+            updated: now,
+        }
+    }
+
+    /// Refills the buckets for the time since they were last updated
+    fn refill(&mut self, max_pps: Option<f64>, max_bps: Option<f64>, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+
+        if let Some(max) = max_pps {
+            self.packets = (self.packets + elapsed * max).min(max);
+        }
+        if let Some(max) = max_bps {
+            self.bytes = (self.bytes + elapsed * max).min(max);
+        }
+    }
+
+    /// Refills the buckets, then takes the tokens for a packet of `len` bytes if there are
+    /// enough
+    fn take(
+        &mut self,
+        max_pps: Option<f64>,
+        max_bps: Option<f64>,
+        len: usize,
+        now: Instant,
+    ) -> bool {
+        self.refill(max_pps, max_bps, now);
+        let len = len as f64;
+        let packets_ok = max_pps.is_none() || self.packets >= 1.0;
+        // Otherwise a packet longer than a second's worth of bytes could never pass
+        let bytes_ok = max_bps.is_none_or(|max| self.bytes >= len.min(max));
+        if !(packets_ok && bytes_ok) {
+            return false;
+        }
+        if max_pps.is_some() {
+            self.packets -= 1.0;
+        }
+        if max_bps.is_some() {
+            self.bytes -= len;
+        }
+        true
+    }
+    /// Refills the buckets, then takes the tokens for a packet of `len` bytes, going into debt
+    /// if there aren't enough. Returns how long it takes for the debt to be paid off.
+    fn reserve(
+        &mut self,
+        max_pps: Option<f64>,
+        max_bps: Option<f64>,
+        len: usize,
+        now: Instant,
+    ) -> Duration {
+        self.refill(max_pps, max_bps, now);
+        let mut wait: f64 = 0.0;
+        if let Some(max) = max_pps {
+            self.packets -= 1.0;
+            wait = wait.max(-self.packets / max);
+        }
+        if let Some(max) = max_bps {
+            self.bytes -= len as f64;
+            wait = wait.max(-self.bytes / max);
+        }
+        // A limit of 0 is rejected by the config, but would make the wait infinite, which
+        // mustn't bring down the fair queue's scheduler
+        Duration::try_from_secs_f64(wait).unwrap_or(Duration::from_secs(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn allows_a_burst_of_one_second_then_refuses() {
+        let start = Instant::now();
+        let mut buckets = Buckets::full(Some(10.0), None, start);
+        for _ in 0..10 {
+            assert!(buckets.take(Some(10.0), None, 100, start));
+        }
+        assert!(!buckets.take(Some(10.0), None, 100, start));
+    }
+
+    #[test]
+    fn refills_at_the_rate_up_to_one_second_worth() {
+        let start = Instant::now();
+        let mut buckets = Buckets::full(None, Some(1000.0), start);
+        assert!(buckets.take(None, Some(1000.0), 1000, start));
+        assert!(!buckets.take(None, Some(1000.0), 1, start));
+
+        // Half a second refills half the bucket
+        assert!(buckets.take(None, Some(1000.0), 500, at(start, 500)));
+        assert!(!buckets.take(None, Some(1000.0), 1, at(start, 500)));
+
+        // However long it is left alone, it holds no more than a second's worth
+        assert!(buckets.take(None, Some(1000.0), 1000, at(start, 10_000)));
+        assert!(!buckets.take(None, Some(1000.0), 1, at(start, 10_000)));
+    }
+
+    #[test]
+    fn needs_both_limits_to_allow_a_packet() {
+        let start = Instant::now();
+        let mut buckets = Buckets::full(Some(100.0), Some(1000.0), start);
+        assert!(buckets.take(Some(100.0), Some(1000.0), 1000, start));
+        // Plenty of packets left, but no bytes, and the refused packet takes neither
+        assert!(!buckets.take(Some(100.0), Some(1000.0), 1, start));
+        assert_eq!(buckets.packets, 99.0);
+    }
+
+    #[test]
+    fn lets_a_packet_larger_than_the_bucket_through_when_it_is_full() {
+        let start = Instant::now();
+        let mut buckets = Buckets::full(None, Some(1000.0), start);
+        assert!(buckets.take(None, Some(1000.0), 1500, start));
+        // The debt is paid off before anything else passes
+        assert!(!buckets.take(None, Some(1000.0), 1, at(start, 400)));
+        assert!(buckets.take(None, Some(1000.0), 1, at(start, 600)));
+        assert!(!buckets.take(None, Some(1000.0), 1500, at(start, 600)));
+    }
+
+    #[test]
+    fn reserves_beyond_the_limit_with_a_wait() {
+        let start = Instant::now();
+        let mut buckets = Buckets::full(Some(10.0), None, start);
+        for _ in 0..10 {
+            assert_eq!(
+                buckets.reserve(Some(10.0), None, 100, start),
+                Duration::ZERO
+            );
+        }
+        let wait = buckets.reserve(Some(10.0), None, 100, start);
+        assert!(wait.abs_diff(Duration::from_millis(100)) < Duration::from_micros(1));
+    }
+
+    #[test]
+    fn is_skipped_without_limits() {
+        assert!(RateLimiter::new(None, None).is_none());
+        assert!(SourceRateLimiter::new(None, None, 1).is_none());
+    }
+
+    #[test]
+    fn keeps_buckets_per_source() {
+        let limiter = SourceRateLimiter::new(Some(1), None, 2).unwrap();
+        let (a, b, c) = (
+            IpAddr::from([10, 0, 0, 1]),
+            IpAddr::from([10, 0, 0, 2]),
+            IpAddr::from([10, 0, 0, 3]),
+        );
+        assert!(limiter.allow(a, 100));
+        assert!(!limiter.allow(a, 100));
+        assert!(limiter.allow(b, 100));
+        // Both remembered sources were active within the second, so there is no room
+        assert!(!limiter.allow(c, 100));
+    }
+}
@@ -0,0 +1,215 @@
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// Number of datagrams pulled from the socket per `recvmmsg` call
+#[cfg(target_os = "linux")]
+const BATCH_SIZE: usize = 32;
+
+/// Receives datagrams from the listen socket, either one at a time or, where `recvmmsg` is
+/// available, in batches to save syscalls under heavy load.
+pub struct Receiver {
+    bufs: Vec<Vec<u8>>,
+    /// Index into `bufs`, length and sender of each received datagram. The length can exceed
+    /// the buffer's where the kernel reports the full size of a datagram that didn't fit.
+    received: Vec<(usize, usize, SocketAddr)>,
+    batched: bool,
+    #[cfg(target_os = "linux")]
+    batch: Batch,
+}
+
+impl Receiver {
+    pub fn new(buffer_size: usize, batched: bool) -> Self {
+        let batched = batched && batch_supported();
+        let count = if batched { batch_size() } else { 1 };
+        Receiver {
+            bufs: vec![vec![0; buffer_size]; count],
+            received: Vec::with_capacity(count),
+            batched,
+            #[cfg(target_os = "linux")]
+            batch: Batch::new(count),
+        }
+    }
+
+    pub fn is_batched(&self) -> bool {
+        self.batched
+    }
+
+    /// Waits for at least one datagram, then returns how many were received
+    pub async fn recv(&mut self, sock: &UdpSocket) -> io::Result<usize> {
+        self.received.clear();
+        if self.batched {
+            self.recv_batch(sock).await?;
+        } else {
+            let (len, addr) = sock.recv_from(&mut self.bufs[0]).await?;
+            self.received.push((0, len, addr));
+        }
+        Ok(self.received.len())
+    }
+
+    /// The `index`th datagram of the last call to `recv`, and who sent it. A datagram larger
+    /// than the buffers is cut short, and fills the buffer.
+    pub fn packet(&self, index: usize) -> (&[u8], SocketAddr) {
+        let (buf, len, addr) = self.received[index];
+        let buf = &self.bufs[buf];
+        (&buf[..len.min(buf.len())], addr)
+    }
+
+    /// The size of the `index`th datagram of the last call to `recv`. For a datagram that was
+    /// cut short, this is only its full size when received in a batch; otherwise it is the
+    /// size of the buffer.
+    pub fn datagram_len(&self, index: usize) -> usize {
+        self.received[index].1
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn recv_batch(&mut self, sock: &UdpSocket) -> io::Result<()> {
+        use tokio::io::Interest;
+
+        loop {
+            sock.readable().await?;
+            match sock.try_io(Interest::READABLE, || {
+                recvmmsg(sock, &mut self.bufs, &mut self.batch, &mut self.received)
+            }) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn recv_batch(&mut self, _sock: &UdpSocket) -> io::Result<()> {
+        unreachable!("batched receive is only enabled where it is supported")
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn batch_supported() -> bool {
+    true
+}
+
+#[cfg(not(target_os = "linux"))]
+fn batch_supported() -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn batch_size() -> usize {
+    BATCH_SIZE
+}
+
+#[cfg(not(target_os = "linux"))]
+fn batch_size() -> usize {
+    1
+}
+
+/// The sender addresses, iovecs and message headers for `recvmmsg`, allocated once with the
+/// receiver rather than on every call
+#[cfg(target_os = "linux")]
+struct Batch {
+    addrs: Vec<libc::sockaddr_storage>,
+    iovecs: Vec<libc::iovec>,
+    headers: Vec<libc::mmsghdr>,
+}
+
+// SAFETY: the pointers in the iovecs and headers are only followed by `recvmmsg`, which sets
+// them first to the buffers passed to it along with the batch
+#[cfg(target_os = "linux")]
+unsafe impl Send for Batch {}
+
+#[cfg(target_os = "linux")]
+impl Batch {
+    fn new(count: usize) -> Self {
+        use std::mem;
+
+        // SAFETY: these are plain C structs, for which all zeroes is a valid value
+        Batch {
+            addrs: vec![unsafe { mem::zeroed() }; count],
+            iovecs: vec![unsafe { mem::zeroed() }; count],
+            headers: vec![unsafe { mem::zeroed() }; count],
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn recvmmsg(
+    sock: &UdpSocket,
+    bufs: &mut [Vec<u8>],
+    batch: &mut Batch,
+    received: &mut Vec<(usize, usize, SocketAddr)>,
+) -> io::Result<()> {
+    use std::mem;
+    use std::os::fd::AsRawFd;
+
+    let Batch {
+        addrs,
+        iovecs,
+        headers,
+    } = batch;
+    // Point the headers at the buffers again, as the kernel overwrites the address lengths
+    for (((buf, iovec), addr), header) in bufs
+        .iter_mut()
+        .zip(&mut *iovecs)
+        .zip(&mut *addrs)
+        .zip(&mut *headers)
+    {
+        *iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        header.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+        header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+        header.msg_hdr.msg_iov = iovec;
+        header.msg_hdr.msg_iovlen = 1;
+    }
+
+    // SAFETY: every header points at a live iovec and address buffer of the advertised size
+    let count = unsafe {
+        libc::recvmmsg(
+            sock.as_raw_fd(),
+            headers.as_mut_ptr(),
+            headers.len() as _,
+            // With MSG_TRUNC, the length of a datagram that didn't fit is its full size
+            libc::MSG_DONTWAIT | libc::MSG_TRUNC,
+            std::ptr::null_mut(),
+        )
+    };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    for (index, (header, addr)) in headers.iter().zip(&*addrs).take(count as usize).enumerate() {
+        if let Some(addr) = sockaddr_to_std(addr) {
+            received.push((index, header.msg_len as usize, addr));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn sockaddr_to_std(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the kernel filled in an IPv4 address
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the kernel filled in an IPv6 address
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
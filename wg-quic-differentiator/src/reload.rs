@@ -0,0 +1,135 @@
+//! Reloading the config on SIGHUP. The config file is read again, along with the command line
+//! and the environment, which still take precedence over it, and the settings that can change
+//! while running are taken from it: the backends, the timeouts, the rate limits and the allow
+//! and deny lists. New connections go to the reloaded backends; existing ones carry on with
+//! the backends they were made with, whose health probes, socket pools and so on keep running
+//! until the last of those connections closes. Reloaded timeouts apply to existing
+//! connections too, from their next packet on.
+
+use std::sync::Arc;
+use tokio_util::sync::DropGuard;
+
+use crate::Proxy;
+use crate::backend::Backends;
+use crate::config::Config;
+use crate::filter::SourceFilter;
+use crate::ratelimit::SourceRateLimiter;
+
+/// The sources remembered by the rate limit for unestablished connections
+const UNESTABLISHED_SOURCES: usize = 65536;
+
+/// What a SIGHUP can change
+pub struct Live {
+    /// The running config, which has the reloaded settings
+    pub config: Config,
+    pub backends: Arc<Backends>,
+    /// Stops the background tasks of `backends` once dropped here and by the connections made
+    /// with them
+    pub backend_tasks: Arc<DropGuard>,
+    pub filter: SourceFilter,
+    /// Limits the packets per source for connections that aren't established yet
+    pub unestablished: Option<Arc<SourceRateLimiter>>,
+}
+
+impl Live {
+    pub fn new(config: Config, backends: Arc<Backends>, backend_tasks: Arc<DropGuard>) -> Live {
+        Live {
+            filter: SourceFilter::new(&config.allow_cidr, &config.deny_cidr),
+            unestablished: unestablished_limiter(&config),
+            config,
+            backends,
+            backend_tasks,
+        }
+    }
+}
+
+fn unestablished_limiter(config: &Config) -> Option<Arc<SourceRateLimiter>> {
+    SourceRateLimiter::new(
+        config.max_packet_rate_per_source,
+        config.max_byte_rate_per_source,
+        UNESTABLISHED_SOURCES,
+    )
+    .map(Arc::new)
+}
+
+/// Reloads the config on every SIGHUP, until the proxy shuts down. The handler is installed
+/// right away, rather than once the future is first polled, as a SIGHUP before then would end
+/// the process.
+#[cfg(unix)]
+pub fn reload_on_sighup(proxy: Arc<Proxy>) -> impl Future<Output = ()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let sighup = signal(SignalKind::hangup());
+    async move {
+        let mut sighup = match sighup {
+            Ok(sighup) => sighup,
+            Err(e) => {
+                log::warn!("Can't reload the config on SIGHUP: {}", e);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = sighup.recv() => reload(&proxy).await,
+                _ = proxy.shutdown.cancelled() => break,
+            }
+        }
+    }
+}
+
+/// Reads the config again and applies the settings that can change while running. A config
+/// that fails to load, or whose backends can't be set up, changes nothing.
+async fn reload(proxy: &Arc<Proxy>) {
+    log::info!("Reloading the config");
+    let reload = match Config::load().and_then(|new| proxy.live.load().config.reload(&new)) {
+        Ok(reload) => reload,
+        Err(e) => {
+            log::error!("Not reloading the config, as it is invalid: {}", e);
+            return;
+        }
+    };
+    for key in &reload.needs_restart {
+        log::warn!("Ignoring the change to {}, which needs a restart", key);
+    }
+    if reload.changed.is_empty() {
+        log::info!("The reloaded config changes nothing that can be reloaded");
+        return;
+    }
+
+    let config = reload.config;
+    let current = proxy.live.load_full();
+    let (backends, backend_tasks) = if reload.backends_changed {
+        // The selector is kept, along with its state, unless a different one was asked for
+        let selector = if config.backend_selection == current.config.backend_selection {
+            current.backends.selector()
+        } else {
+            config.backend_selection.selector()
+        };
+        let backends = match Backends::resolve(&config, selector).await {
+            Ok(backends) => Arc::new(backends),
+            Err(e) => {
+                log::error!(
+                    "Not reloading the config, as its backends can't be set up: {}",
+                    e
+                );
+                return;
+            }
+        };
+        let stop = proxy.shutdown.child_token();
+        crate::spawn_backend_tasks(proxy, &backends, stop.clone());
+        (backends, Arc::new(stop.drop_guard()))
+    } else {
+        (current.backends.clone(), current.backend_tasks.clone())
+    };
+    let mut live = Live::new(config, backends, backend_tasks);
+    // The limiter's buckets are kept unless its limits changed
+    if live.config.max_packet_rate_per_source == current.config.max_packet_rate_per_source
+        && live.config.max_byte_rate_per_source == current.config.max_byte_rate_per_source
+    {
+        live.unestablished = current.unestablished.clone();
+    }
+    proxy.live.store(Arc::new(live));
+    for change in &reload.changed {
+        log::info!("Reloaded {}", change);
+    }
+}
@@ -0,0 +1,70 @@
+//! How a new connection picks one of the backends of its type. The proxy uses one of the
+//! built-in policies here, chosen with `--backend-selection`. Others (weighted, by the
+//! client's location, ...) can be written by implementing [`BackendSelector`], for the
+//! proxy's `Backends::resolve` in `main` or for tools of their own.
+
+use std::hash::{BuildHasher, BuildHasherDefault, DefaultHasher, RandomState};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::PacketType;
+
+pub trait BackendSelector: Send + Sync {
+    /// Picks the backend for a new connection from `client`, out of the current addresses of
+    /// the backends of type `kind` that can take it (those up and not draining, if there are
+    /// any). `None` drops the packet, as if there were no backend.
+    fn select(
+        &self,
+        client: &SocketAddr,
+        kind: PacketType,
+        backends: &[SocketAddr],
+    ) -> Option<SocketAddr>;
+}
+
+/// Hashes the client's address, so a client keeps landing on the same backend
+#[derive(Default)]
+pub struct Hash {
+    // A fixed hasher, so clients keep landing on the same backend when the proxy restarts
+    hasher: BuildHasherDefault<DefaultHasher>,
+}
+
+impl BackendSelector for Hash {
+    fn select(
+        &self,
+        client: &SocketAddr,
+        _: PacketType,
+        backends: &[SocketAddr],
+    ) -> Option<SocketAddr> {
+        let hash = self.hasher.hash_one(client) as usize;
+        backends.get(hash % backends.len().max(1)).copied()
+    }
+}
+
+/// Takes the backends in turn, which spreads connections evenly whoever they are from
+#[derive(Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl BackendSelector for RoundRobin {
+    fn select(&self, _: &SocketAddr, _: PacketType, backends: &[SocketAddr]) -> Option<SocketAddr> {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        backends.get(next % backends.len().max(1)).copied()
+    }
+}
+
+/// Picks any backend
+pub struct Random;
+
+impl BackendSelector for Random {
+    fn select(
+        &self,
+        client: &SocketAddr,
+        _: PacketType,
+        backends: &[SocketAddr],
+    ) -> Option<SocketAddr> {
+        // Every RandomState is seeded differently, which is random enough to spread load
+        let random = RandomState::new().hash_one(client) as usize;
+        backends.get(random % backends.len().max(1)).copied()
+    }
+}
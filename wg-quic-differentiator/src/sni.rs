@@ -0,0 +1,286 @@
+//! Reads the server name a QUIC client asks for from its first Initial packet. Initial packets
+//! are encrypted, but with keys derived from the destination connection ID in the packet
+//! itself (RFC 9001, section 5), so anyone on the path can decrypt them and read the TLS
+//! ClientHello inside.
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, quic::HeaderProtectionKey};
+use ring::hkdf::{self, HKDF_SHA256, KeyType, Prk, Salt};
+
+use crate::quic::{self, QuicPacketType};
+
+/// QUIC version 1 (RFC 9001, section 5.2)
+const VERSION_1_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+/// QUIC version 2 (RFC 9369, section 3.3.1)
+const VERSION_2_SALT: [u8; 20] = [
+    0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26, 0x9d, 0xcb,
+    0xf9, 0xbd, 0x2e, 0xd9,
+];
+/// Header protection samples 16 bytes, starting 4 bytes after the packet number offset
+const SAMPLE_LEN: usize = 16;
+
+/// The server name in the ClientHello of a QUIC Initial packet, in lowercase. Returns `None`
+/// if the packet isn't a client Initial of a version we know, can't be decrypted, or the
+/// part of the ClientHello it carries doesn't include a server name.
+pub fn server_name(packet: &[u8]) -> Option<String> {
+    let crypto = crypto_stream(&initial_frames(packet)?)?;
+    client_hello_server_name(&crypto)
+}
+
+/// The decrypted frames of the first packet in a datagram, if it is a client Initial of a
+/// version we know
+pub(crate) fn initial_frames(packet: &[u8]) -> Option<Vec<u8>> {
+    let header = quic::parse_quic_header(packet)?;
+    if header.packet_type != QuicPacketType::Initial {
+        return None;
+    }
+    let (salt, labels) = match header.version? {
+        0x0000_0001 => (VERSION_1_SALT, ["quic key", "quic iv", "quic hp"]),
+        0x6b33_43cf => (VERSION_2_SALT, ["quicv2 key", "quicv2 iv", "quicv2 hp"]),
+        _ => return None,
+    };
+    let keys = InitialKeys::new(&salt, labels, header.destination_cid?)?;
+    keys.decrypt(packet)
+}
+
+struct InitialKeys {
+    key: LessSafeKey,
+    iv: [u8; 12],
+    header_protection: HeaderProtectionKey,
+}
+
+impl InitialKeys {
+    fn new(salt: &[u8], [key, iv, hp]: [&str; 3], destination_cid: &[u8]) -> Option<Self> {
+        let initial = Salt::new(HKDF_SHA256, salt).extract(destination_cid);
+        let client: Prk = expand_label(&initial, "client in", HKDF_SHA256)?;
+        let key: UnboundKey = expand_label(&client, key, &aead::AES_128_GCM)?;
+        let Iv(iv) = expand_label(&client, iv, IvLen)?;
+        Some(InitialKeys {
+            key: LessSafeKey::new(key),
+            iv,
+            header_protection: expand_label(&client, hp, &aead::quic::AES_128)?,
+        })
+    }
+
+    /// Removes the header protection and decrypts the first packet in the datagram,
+    /// returning its frames
+    fn decrypt(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        // Skip the connection IDs and the token to get to the length of the packet
+        let dcid_len = *packet.get(5)? as usize;
+        let scid_len = *packet.get(6 + dcid_len)? as usize;
+        let mut pos = 7 + dcid_len + scid_len;
+        let token_len = varint(packet, &mut pos)? as usize;
+        pos = pos.checked_add(token_len)?;
+        let len = varint(packet, &mut pos)? as usize;
+        let pn_offset = pos;
+        let end = pn_offset.checked_add(len)?;
+        let packet = packet.get(..end)?;
+
+        let sample = packet.get(pn_offset + 4..pn_offset + 4 + SAMPLE_LEN)?;
+        let mask = self.header_protection.new_mask(sample).ok()?;
+        let mut header = packet[..pn_offset + 4].to_vec();
+        header[0] ^= mask[0] & 0x0f;
+        let pn_len = (header[0] & 0x03) as usize + 1;
+        header.truncate(pn_offset + pn_len);
+        let mut packet_number = 0u64;
+        for (i, byte) in header[pn_offset..].iter_mut().enumerate() {
+            *byte ^= mask[1 + i];
+            packet_number = packet_number << 8 | u64::from(*byte);
+        }
+
+        let mut nonce = self.iv;
+        for (nonce, pn) in nonce[4..].iter_mut().zip(packet_number.to_be_bytes()) {
+            *nonce ^= pn;
+        }
+        let mut payload = packet[pn_offset + pn_len..].to_vec();
+        let plaintext_len = self
+            .key
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(&header),
+                &mut payload,
+            )
+            .ok()?
+            .len();
+        payload.truncate(plaintext_len);
+        Some(payload)
+    }
+}
+
+/// HKDF-Expand-Label from TLS 1.3 (RFC 8446, section 7.1), with an empty context
+fn expand_label<L, T>(prk: &Prk, label: &str, len: L) -> Option<T>
+where
+    L: KeyType,
+    T: for<'a> From<hkdf::Okm<'a, L>>,
+{
+    let out_len = u16::try_from(len.len()).ok()?.to_be_bytes();
+    let label_len = [6 + label.len() as u8];
+    let info = [&out_len[..], &label_len, b"tls13 ", label.as_bytes(), &[0]];
+    prk.expand(&info, len).ok().map(T::from)
+}
+
+/// The IV packet numbers are combined with to get the nonce of a packet
+struct Iv([u8; 12]);
+
+struct IvLen;
+
+impl KeyType for IvLen {
+    fn len(&self) -> usize {
+        12
+    }
+}
+
+impl From<hkdf::Okm<'_, IvLen>> for Iv {
+    fn from(okm: hkdf::Okm<'_, IvLen>) -> Self {
+        let mut iv = [0; 12];
+        okm.fill(&mut iv).expect("the IV is as long as requested");
+        Iv(iv)
+    }
+}
+
+/// Reads a variable-length integer (RFC 9000, section 16)
+pub(crate) fn varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let first = *buf.get(*pos)?;
+    let len = 1 << (first >> 6);
+    let bytes = buf.get(*pos..*pos + len)?;
+    *pos += len;
+    Some(
+        bytes[1..]
+            .iter()
+            .fold(u64::from(first & 0x3f), |value, byte| {
+                value << 8 | u64::from(*byte)
+            }),
+    )
+}
+
+/// The frames of a decrypted packet that are of interest here
+pub(crate) enum Frame<'a> {
+    Crypto {
+        offset: usize,
+        data: &'a [u8],
+    },
+    /// An unreliable datagram (RFC 9221)
+    #[cfg_attr(not(feature = "wireguard-over-quic"), allow(dead_code))]
+    Datagram(&'a [u8]),
+}
+
+/// Iterates over the CRYPTO and DATAGRAM frames in `payload`, skipping PADDING, PING and ACK
+/// frames. Stops at the first other frame, or one that is cut short.
+pub(crate) fn frames(payload: &[u8]) -> impl Iterator<Item = Frame<'_>> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        while pos < payload.len() {
+            match varint(payload, &mut pos)? {
+                // PADDING and PING
+                0x00 | 0x01 => {}
+                // ACK, with or without ECN counts
+                frame_type @ (0x02 | 0x03) => {
+                    let _largest = varint(payload, &mut pos)?;
+                    let _delay = varint(payload, &mut pos)?;
+                    let ranges = varint(payload, &mut pos)?;
+                    let _first_range = varint(payload, &mut pos)?;
+                    for _ in 0..ranges.saturating_mul(2) {
+                        varint(payload, &mut pos)?;
+                    }
+                    if frame_type == 0x03 {
+                        for _ in 0..3 {
+                            varint(payload, &mut pos)?;
+                        }
+                    }
+                }
+                0x06 => {
+                    let offset = varint(payload, &mut pos)? as usize;
+                    let len = varint(payload, &mut pos)? as usize;
+                    let data = payload.get(pos..pos.checked_add(len)?)?;
+                    pos += len;
+                    return Some(Frame::Crypto { offset, data });
+                }
+                // Without a length, the datagram runs to the end of the packet
+                0x30 => {
+                    let data = &payload[pos..];
+                    pos = payload.len();
+                    return Some(Frame::Datagram(data));
+                }
+                0x31 => {
+                    let len = varint(payload, &mut pos)? as usize;
+                    let data = payload.get(pos..pos.checked_add(len)?)?;
+                    pos += len;
+                    return Some(Frame::Datagram(data));
+                }
+                // Nothing else may appear in an Initial packet before the handshake, apart
+                // from CONNECTION_CLOSE, which ends it
+                _ => return None,
+            }
+        }
+        None
+    })
+}
+
+/// The start of the crypto stream, put together from the CRYPTO frames in a packet. Clients
+/// may split the ClientHello over frames in any order, so only the bytes from offset 0 up
+/// to the first gap are returned.
+pub(crate) fn crypto_stream(payload: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks: Vec<_> = frames(payload)
+        .filter_map(|frame| match frame {
+            Frame::Crypto { offset, data } => Some((offset, data)),
+            Frame::Datagram(_) => None,
+        })
+        .collect();
+
+    chunks.sort_by_key(|(offset, _)| *offset);
+    let mut stream = Vec::new();
+    for (offset, data) in chunks {
+        if offset > stream.len() {
+            break;
+        }
+        let overlap = stream.len() - offset;
+        stream.extend_from_slice(data.get(overlap..).unwrap_or_default());
+    }
+    (!stream.is_empty()).then_some(stream)
+}
+
+/// The host name in the server_name extension (RFC 6066) of a ClientHello, which may be
+/// cut short
+fn client_hello_server_name(hello: &[u8]) -> Option<String> {
+    // A list of names, of which only host names (type 0) are defined
+    let data = client_hello_extension(hello, 0)?;
+    let name_len = u16_at(data, 3)? as usize;
+    if data.get(2)? != &0 {
+        return None;
+    }
+    let name = std::str::from_utf8(data.get(5..5 + name_len)?).ok()?;
+    Some(name.to_ascii_lowercase())
+}
+
+/// The data of the first extension of `extension_type` in a ClientHello, if the part of it
+/// in `hello` includes all of it
+pub(crate) fn client_hello_extension(hello: &[u8], extension_type: u16) -> Option<&[u8]> {
+    // Handshake type 1 is ClientHello; skip its length, legacy version and random
+    if *hello.first()? != 1 {
+        return None;
+    }
+    let mut pos = 4 + 2 + 32;
+    let session_id_len = *hello.get(pos)? as usize;
+    pos += 1 + session_id_len;
+    let cipher_suites_len = u16_at(hello, pos)? as usize;
+    pos += 2 + cipher_suites_len;
+    let compression_len = *hello.get(pos)? as usize;
+    pos += 1 + compression_len + 2;
+
+    while let (Some(found), Some(len)) = (u16_at(hello, pos), u16_at(hello, pos + 2)) {
+        let data_start = pos + 4;
+        pos = data_start + len as usize;
+        if found == extension_type {
+            return hello.get(data_start..pos);
+        }
+    }
+    None
+}
+
+fn u16_at(buf: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        buf.get(pos..pos + 2)?.try_into().unwrap(),
+    ))
+}
@@ -0,0 +1,394 @@
+use arc_swap::ArcSwap;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::throttle::Throttle;
+
+/// Binds the socket clients send their packets to. IPv6 addresses are bound dual-stack, so
+/// listening on `[::]` also accepts IPv4 clients.
+pub fn bind_listen_socket(
+    addr: SocketAddr,
+    interface: Option<&str>,
+    buffers: BufferSizes,
+) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    if let Some(interface) = interface {
+        bind_to_interface(&socket, interface)?;
+    }
+    set_listen_buffer_sizes(SockRef::from(&socket), addr, buffers)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// How the sockets to a backend are set up
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EgressOptions<'a> {
+    /// Network interface to send through
+    pub interface: Option<&'a str>,
+    /// DSCP value to mark every packet with
+    pub dscp: Option<u8>,
+    /// IP TTL or IPv6 hop limit to send with
+    pub ttl: Option<u8>,
+    /// Set the don't fragment bit, with `--no-fragment`
+    pub no_fragment: bool,
+    pub buffers: BufferSizes,
+    /// Address to send from instead of an ephemeral port, which with `--transparent` is the
+    /// client's own
+    pub source: Option<SocketAddr>,
+}
+
+/// Creates a socket connected to `backend`, bound to an ephemeral port of the same address
+/// family.
+pub async fn connect_backend_socket(
+    backend: SocketAddr,
+    options: EgressOptions<'_>,
+) -> io::Result<UdpSocket> {
+    let socket = bind_backend_socket(backend, options)?;
+    socket.connect(backend).await?;
+    Ok(socket)
+}
+
+/// Creates a socket for sending to `backend` without connecting it, bound to an ephemeral
+/// port of the same address family, or to the source address in the options
+pub fn bind_backend_socket(
+    backend: SocketAddr,
+    options: EgressOptions<'_>,
+) -> io::Result<UdpSocket> {
+    let local_addr: SocketAddr = match (options.source, backend) {
+        // Dual-stack listeners see IPv4 clients as mapped IPv6 addresses
+        (Some(source), _) => SocketAddr::new(source.ip().to_canonical(), source.port()),
+        (None, SocketAddr::V4(_)) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        (None, SocketAddr::V6(_)) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    if local_addr.is_ipv4() != backend.is_ipv4() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("can't send from {local_addr} to {backend}, as their address families differ"),
+        ));
+    }
+
+    let socket = Socket::new(
+        Domain::for_address(backend),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    if let Some(interface) = options.interface {
+        bind_to_interface(&socket, interface)?;
+    }
+    if let Some(dscp) = options.dscp {
+        set_dscp(&socket, backend, dscp);
+    }
+    if let Some(ttl) = options.ttl {
+        set_ttl(&socket, backend, ttl);
+    }
+    if options.no_fragment {
+        set_no_fragment(&socket, backend);
+    }
+    let granted = set_buffer_sizes(SockRef::from(&socket), options.buffers)?;
+    // Every backend socket gets the same, so once is enough to know
+    static REPORTED: std::sync::Once = std::sync::Once::new();
+    REPORTED.call_once(|| report_buffer_sizes("backend sockets", options.buffers, granted));
+    if options.source.is_some() {
+        set_transparent(&socket, backend)?;
+        // The socket of a connection that was just replaced may still be bound to the address
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&local_addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// The socket buffer sizes asked for with `--so-rcvbuf` and `--so-sndbuf`, where `None` leaves
+/// the system default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferSizes {
+    pub recv: Option<usize>,
+    pub send: Option<usize>,
+}
+
+/// Sets the buffer sizes of a listen socket, logging what the kernel granted
+pub fn set_listen_buffer_sizes(
+    socket: SockRef<'_>,
+    addr: SocketAddr,
+    buffers: BufferSizes,
+) -> io::Result<()> {
+    let granted = set_buffer_sizes(socket, buffers)?;
+    report_buffer_sizes(&format!("listen socket {addr}"), buffers, granted);
+    Ok(())
+}
+
+/// Asks for the buffer sizes that are set, returning the sizes the kernel granted instead
+fn set_buffer_sizes(socket: SockRef<'_>, buffers: BufferSizes) -> io::Result<BufferSizes> {
+    let mut granted = BufferSizes::default();
+    if let Some(size) = buffers.recv {
+        socket.set_recv_buffer_size(size)?;
+        granted.recv = Some(socket.recv_buffer_size()?);
+    }
+    if let Some(size) = buffers.send {
+        socket.set_send_buffer_size(size)?;
+        granted.send = Some(socket.send_buffer_size()?);
+    }
+    Ok(granted)
+}
+
+/// Logs the buffer sizes the kernel granted, warning about those it capped. Linux reports
+/// twice the size it grants, as it counts its own bookkeeping in it, so that is halved to
+/// compare with the size asked for.
+fn report_buffer_sizes(what: &str, requested: BufferSizes, granted: BufferSizes) {
+    for (name, limit, requested, granted) in [
+        ("receive", "net.core.rmem_max", requested.recv, granted.recv),
+        ("send", "net.core.wmem_max", requested.send, granted.send),
+    ] {
+        let (Some(requested), Some(granted)) = (requested, granted) else {
+            continue;
+        };
+        let granted = if cfg!(any(target_os = "linux", target_os = "android")) {
+            granted / 2
+        } else {
+            granted
+        };
+        if granted < requested {
+            log::warn!(
+                "Asked for a {name} buffer of {requested} bytes on {what}, but the kernel capped it at {granted} (raise {limit} to allow more)"
+            );
+        } else {
+            log::info!(
+                "Asked for a {name} buffer of {requested} bytes on {what}, the kernel granted {granted}"
+            );
+        }
+    }
+}
+
+/// Lets a socket bind to an address that isn't local, such as a client's, which needs
+/// `CAP_NET_ADMIN`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_transparent(socket: &Socket, backend: SocketAddr) -> io::Result<()> {
+    match backend {
+        SocketAddr::V4(_) => socket.set_ip_transparent_v4(true),
+        SocketAddr::V6(_) => socket.set_ip_transparent_v6(true),
+    }
+    .map_err(|e| io::Error::new(e.kind(), format!("failed to make socket transparent: {e}")))
+}
+
+/// Transparent proxying is only supported on Linux, which the config checks up front
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_transparent(_socket: &Socket, _backend: SocketAddr) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent proxying is not supported on this platform",
+    ))
+}
+
+/// Sockets connected to a backend ahead of time, so a new connection can start forwarding
+/// without waiting for one to be set up
+pub struct SocketPool {
+    /// The backend's current address, which the pool follows when it is resolved again
+    backend: Arc<ArcSwap<SocketAddr>>,
+    /// Each socket with the address it is connected to
+    sockets: Mutex<Vec<(SocketAddr, UdpSocket)>>,
+    size: usize,
+    /// Wakes the refill task when a socket is taken
+    refill: Notify,
+}
+
+impl SocketPool {
+    pub fn new(backend: Arc<ArcSwap<SocketAddr>>, size: usize) -> Self {
+        SocketPool {
+            backend,
+            sockets: Mutex::new(Vec::with_capacity(size)),
+            size,
+            refill: Notify::new(),
+        }
+    }
+
+    /// Takes a socket connected to `backend`, if one is ready. Sockets connected to an
+    /// address the backend no longer resolves to are thrown away.
+    pub fn take(&self, backend: SocketAddr) -> Option<UdpSocket> {
+        let mut sockets = self.sockets.lock().unwrap();
+        let socket = loop {
+            match sockets.pop() {
+                Some((addr, socket)) if addr == backend => break Some(socket),
+                Some(_) => continue,
+                None => break None,
+            }
+        };
+        drop(sockets);
+        self.refill.notify_one();
+        socket
+    }
+
+    /// Keeps the pool full until `shutdown` is cancelled
+    pub async fn fill(&self, options: EgressOptions<'_>, shutdown: CancellationToken) {
+        loop {
+            while self.sockets.lock().unwrap().len() < self.size {
+                let backend = **self.backend.load();
+                match connect_backend_socket(backend, options).await {
+                    Ok(socket) => self.sockets.lock().unwrap().push((backend, socket)),
+                    Err(e) => {
+                        static FILL_WARNING: Throttle = Throttle::new(Duration::from_secs(10));
+                        if FILL_WARNING.allow() {
+                            log::warn!("Could not create a pooled socket to {}: {}", backend, e);
+                        }
+                        // Connections still create their own sockets in the meantime
+                        tokio::select! {
+                            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+                            _ = shutdown.cancelled() => return,
+                        }
+                    }
+                }
+            }
+            tokio::select! {
+                _ = self.refill.notified() => {}
+                _ = shutdown.cancelled() => return,
+            }
+        }
+    }
+}
+
+/// Makes a socket send and receive only through the given interface, which needs
+/// `CAP_NET_RAW`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_to_interface(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes())).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("failed to bind to interface {interface}: {e}"),
+        )
+    })
+}
+
+/// Binding to an interface is only supported on Linux, so elsewhere the socket is left as is
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_to_interface(_socket: &Socket, interface: &str) -> io::Result<()> {
+    static WARNING: std::sync::Once = std::sync::Once::new();
+    WARNING.call_once(|| {
+        log::warn!(
+            "Binding sockets to interface {interface} is not supported on this platform, ignoring it"
+        );
+    });
+    Ok(())
+}
+
+/// Marks the packets sent from a socket for QoS. The DSCP value makes up the upper six bits
+/// of the IPv4 type of service or IPv6 traffic class. Failing to set it only loses the
+/// marking, so it is warned about once rather than failing the connection.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_dscp(socket: &Socket, backend: SocketAddr, dscp: u8) {
+    let tos = u32::from(dscp) << 2;
+    let result = match backend {
+        SocketAddr::V4(_) => socket.set_tos_v4(tos),
+        SocketAddr::V6(_) => socket.set_tclass_v6(tos),
+    };
+    if let Err(e) = result {
+        static WARNING: std::sync::Once = std::sync::Once::new();
+        WARNING.call_once(|| log::warn!("Could not set DSCP {dscp} on backend sockets: {e}"));
+    }
+}
+
+/// Sets the TTL (or hop limit) of the packets sent on `socket`. Like DSCP, a platform that
+/// doesn't allow it is warned about once, and the packets go out with the default.
+fn set_ttl(socket: &Socket, backend: SocketAddr, ttl: u8) {
+    let result = match backend {
+        SocketAddr::V4(_) => socket.set_ttl_v4(ttl.into()),
+        SocketAddr::V6(_) => socket.set_unicast_hops_v6(ttl.into()),
+    };
+    if let Err(e) = result {
+        static WARNING: std::sync::Once = std::sync::Once::new();
+        WARNING.call_once(|| log::warn!("Could not set TTL {ttl} on backend sockets: {e}"));
+    }
+}
+
+/// Makes the packets sent on `socket` go out with the don't fragment bit, and never be
+/// fragmented locally: a send larger than the path MTU the kernel knows of fails with
+/// `EMSGSIZE` instead, as do later sends once an ICMP "fragmentation needed" message lowers it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_no_fragment(socket: &Socket, backend: SocketAddr) {
+    use std::os::fd::AsRawFd;
+
+    let (level, name, value) = match backend {
+        SocketAddr::V4(_) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        ),
+        SocketAddr::V6(_) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        ),
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        let e = io::Error::last_os_error();
+        static WARNING: std::sync::Once = std::sync::Once::new();
+        WARNING.call_once(|| log::warn!("Could not set don't fragment on backend sockets: {e}"));
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_no_fragment(_socket: &Socket, _backend: SocketAddr) {
+    static WARNING: std::sync::Once = std::sync::Once::new();
+    WARNING.call_once(|| {
+        log::warn!("--no-fragment is not supported on this platform, ignoring it");
+    });
+}
+
+/// Whether a send failed because the packet was larger than the path MTU, or, on a receive,
+/// an ICMP error says an earlier one was
+pub fn is_too_large(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+/// The path MTU the kernel knows of for the address a socket is connected to
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn path_mtu(socket: &UdpSocket) -> Option<u32> {
+    use std::os::fd::AsRawFd;
+
+    let (level, name) = match socket.peer_addr().ok()? {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+    };
+    let mut mtu: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&mut mtu as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    (result == 0).then_some(mtu as u32)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn path_mtu(_socket: &UdpSocket) -> Option<u32> {
+    None
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_dscp(_socket: &Socket, _backend: SocketAddr, dscp: u8) {
+    static WARNING: std::sync::Once = std::sync::Once::new();
+    WARNING.call_once(|| {
+        log::warn!("Setting DSCP {dscp} is not supported on this platform, ignoring it");
+    });
+}
@@ -0,0 +1,202 @@
+//! Forwarding through a SOCKS5 proxy's UDP relay (RFC 1928, UDP ASSOCIATE), for backends that
+//! are only reachable through one. Every connection sets up an association of its own over
+//! a TCP connection to the proxy, which has to stay open for as long as the association is
+//! used, and sends its datagrams to the relay the proxy names, each behind a header saying
+//! which backend it is for.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::PACKET_BUFFERS;
+use crate::socket::{self, EgressOptions};
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const UDP_ASSOCIATE: u8 = 3;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+/// How long the proxy gets to set up an association
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A UDP association with a SOCKS5 proxy, for sending to a single backend
+pub struct Association {
+    /// Connected to the relay
+    sock: UdpSocket,
+    /// Ends the association when closed, by either side
+    control: TcpStream,
+    /// Put in front of every datagram to the backend
+    header: Vec<u8>,
+}
+
+impl Association {
+    /// Asks the SOCKS5 proxy at `proxy` (host:port) to relay datagrams to `backend`
+    pub async fn open(
+        proxy: &str,
+        backend: SocketAddr,
+        options: EgressOptions<'_>,
+    ) -> io::Result<Association> {
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, Self::handshake(proxy, backend, options))
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("SOCKS5 proxy {proxy} didn't set up an association in time"),
+                )
+            })?
+    }
+
+    async fn handshake(
+        proxy: &str,
+        backend: SocketAddr,
+        options: EgressOptions<'_>,
+    ) -> io::Result<Association> {
+        let mut control = TcpStream::connect(proxy).await?;
+        control.write_all(&[VERSION, 1, NO_AUTHENTICATION]).await?;
+        let mut choice = [0; 2];
+        control.read_exact(&mut choice).await?;
+        if choice != [VERSION, NO_AUTHENTICATION] {
+            return Err(refused(proxy, "requires authentication"));
+        }
+
+        // The address datagrams will come from isn't known before the socket is bound, and
+        // all zeroes tells the proxy to accept them from anywhere
+        let mut request = vec![VERSION, UDP_ASSOCIATE, 0];
+        let unspecified = match backend {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        write_address(&mut request, unspecified);
+        control.write_all(&request).await?;
+        let mut reply = [0; 3];
+        control.read_exact(&mut reply).await?;
+        if reply[0] != VERSION || reply[1] != 0 {
+            return Err(refused(proxy, &format!("failed with code {}", reply[1])));
+        }
+        let mut relay = read_address(&mut control).await?;
+        // Proxies answer with an unspecified address to mean their own
+        if relay.ip().is_unspecified() {
+            relay.set_ip(control.peer_addr()?.ip());
+        }
+
+        let sock = socket::connect_backend_socket(relay, options).await?;
+        let mut header = vec![0, 0, 0];
+        write_address(&mut header, backend);
+        Ok(Association {
+            sock,
+            control,
+            header,
+        })
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut datagram = PACKET_BUFFERS.take();
+        datagram.extend_from_slice(&self.header);
+        datagram.extend_from_slice(buf);
+        let result = self.sock.send(&datagram).await;
+        PACKET_BUFFERS.recycle(datagram);
+        result.map(|sent| sent.saturating_sub(self.header.len()))
+    }
+
+    /// Receives the next datagram from the backend into the start of `buf`. Fails once the
+    /// proxy closes the association.
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut unexpected = [0; 64];
+        loop {
+            let len = tokio::select! {
+                result = self.sock.recv(buf) => result?,
+                result = self.control.read(&mut unexpected) => match result {
+                    Ok(0) | Err(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "the SOCKS5 proxy closed the association",
+                        ));
+                    }
+                    Ok(_) => continue,
+                },
+            };
+            // A datagram that filled the buffer was cut short, which the caller reports
+            if len == buf.len() {
+                return Ok(len);
+            }
+            // Fragments aren't reassembled, as hardly any proxy sends them
+            if let Some(header_len) = udp_header_len(&buf[..len]) {
+                buf.copy_within(header_len..len, 0);
+                return Ok(len - header_len);
+            }
+        }
+    }
+}
+
+fn refused(proxy: &str, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionRefused,
+        format!("SOCKS5 proxy {proxy} {reason}"),
+    )
+}
+
+fn write_address(out: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(IPV4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+async fn read_address(stream: &mut TcpStream) -> io::Result<SocketAddr> {
+    let ip = match stream.read_u8().await? {
+        IPV4 => {
+            let mut octets = [0; 4];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        }
+        IPV6 => {
+            let mut octets = [0; 16];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::from(octets)
+        }
+        DOMAIN_NAME => {
+            let mut name = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            let port = stream.read_u16().await?;
+            let name = String::from_utf8_lossy(&name);
+            return tokio::net::lookup_host((&*name, port))
+                .await?
+                .next()
+                .ok_or_else(|| io::Error::other(format!("relay {name} doesn't resolve")));
+        }
+        atyp => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown SOCKS5 address type {atyp}"),
+            ));
+        }
+    };
+    Ok(SocketAddr::new(ip, stream.read_u16().await?))
+}
+
+/// The length of the header of a datagram from the relay, or `None` if it is malformed or a
+/// fragment
+fn udp_header_len(datagram: &[u8]) -> Option<usize> {
+    if datagram.get(..2)? != [0, 0] || *datagram.get(2)? != 0 {
+        return None;
+    }
+    let address_len = match *datagram.get(3)? {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN_NAME => 1 + *datagram.get(4)? as usize,
+        _ => return None,
+    };
+    let len = 4 + address_len + 2;
+    (datagram.len() >= len).then_some(len)
+}
@@ -0,0 +1,189 @@
+//! Saves which backend each client is pinned to, so that after a restart returning clients
+//! are sent to the same backend (as the same protocol) without being classified again. The
+//! sockets to the backends are not kept, and are only created again once a client sends.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tokio_util::sync::CancellationToken;
+
+use crate::PacketType;
+use crate::config::Config;
+use crate::connections::{ClientKey, Connections};
+
+#[derive(Serialize, Deserialize)]
+struct StateFile {
+    /// Seconds since the Unix epoch
+    saved_at: u64,
+    connections: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    client: ClientKey,
+    packet_type: PacketType,
+    backend: String,
+    /// Seconds the connection had been idle when the state was saved
+    idle: u64,
+}
+
+/// A client's connection from before the restart
+pub struct Affinity {
+    pub packet_type: PacketType,
+    pub backend: String,
+    /// When the connection would have timed out, had the proxy kept running
+    expires: Instant,
+}
+
+/// The connections restored from the state file, each removed once its client returns
+pub struct Affinities(Mutex<HashMap<ClientKey, Affinity>>);
+
+impl Affinities {
+    /// Reads the connections saved at `path`, leaving out those that have timed out since.
+    /// There are none if the file doesn't exist yet.
+    pub fn load(path: &Path, config: &Config) -> io::Result<Affinities> {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("failed to read state file {}: {e}", path.display()),
+                ));
+            }
+        };
+        let mut affinities = HashMap::new();
+        if !contents.is_empty() {
+            let state: StateFile = serde_json::from_slice(&contents).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid state file {}: {e}", path.display()),
+                )
+            })?;
+            let down = unix_time().saturating_sub(state.saved_at);
+            let now = Instant::now();
+            for entry in state.connections {
+                let idle = Duration::from_secs(entry.idle + down);
+                let Some(left) = config
+                    .connection_timeout(entry.packet_type)
+                    .checked_sub(idle)
+                else {
+                    continue;
+                };
+                affinities.insert(
+                    entry.client,
+                    Affinity {
+                        packet_type: entry.packet_type,
+                        backend: entry.backend,
+                        expires: now + left,
+                    },
+                );
+            }
+            log::info!(
+                "Restored {} connections from {}",
+                affinities.len(),
+                path.display()
+            );
+        }
+        Ok(Affinities(Mutex::new(affinities)))
+    }
+
+    /// Takes the connection a packet from `addr` belonged to before the restart, if it
+    /// hasn't timed out yet
+    pub fn take(&self, addr: SocketAddr) -> Option<Affinity> {
+        let mut affinities = self.0.lock().unwrap();
+        if affinities.is_empty() {
+            return None;
+        }
+        let affinity = affinities
+            .remove(&ClientKey::Address(addr))
+            .or_else(|| affinities.remove(&ClientKey::Ip(addr.ip())))?;
+        (affinity.expires > Instant::now()).then_some(affinity)
+    }
+
+    /// The connections whose client hasn't returned yet, so that they survive another
+    /// restart too
+    fn pending(&self, config: &Config) -> Vec<Entry> {
+        let now = Instant::now();
+        let mut affinities = self.0.lock().unwrap();
+        affinities.retain(|_, affinity| affinity.expires > now);
+        affinities
+            .iter()
+            .map(|(client, affinity)| {
+                let timeout = config.connection_timeout(affinity.packet_type);
+                Entry {
+                    client: *client,
+                    packet_type: affinity.packet_type,
+                    backend: affinity.backend.clone(),
+                    idle: timeout.saturating_sub(affinity.expires - now).as_secs(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Writes every connection to `path`, along with the restored ones that are still waiting for
+/// their client. The previous state is only replaced once the new one is complete.
+pub async fn save(
+    path: &Path,
+    connections: &Connections,
+    restored: Option<&Affinities>,
+    config: &Config,
+) -> io::Result<()> {
+    let mut entries: Vec<Entry> = connections
+        .snapshot()
+        .await
+        .into_iter()
+        .map(|(client, connection)| Entry {
+            client,
+            packet_type: connection.packet_type,
+            backend: connection.backend.to_string(),
+            idle: connection.stats.idle().as_secs(),
+        })
+        .collect();
+    if let Some(restored) = restored {
+        entries.extend(restored.pending(config));
+    }
+    let state = StateFile {
+        saved_at: unix_time(),
+        connections: entries,
+    };
+    let contents = serde_json::to_vec(&state).map_err(io::Error::other)?;
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    tokio::fs::write(&temporary, contents).await?;
+    tokio::fs::rename(&temporary, path).await
+}
+
+/// Saves the connections every `interval`, until `shutdown` is cancelled
+pub async fn save_periodically(
+    path: &Path,
+    connections: &Connections,
+    restored: Option<&Affinities>,
+    config: &Config,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(config.state_interval());
+    // The first tick completes immediately, when there is nothing new to save
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => break,
+        }
+        if let Err(e) = save(path, connections, restored, config).await {
+            log::error!("Error saving state to {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
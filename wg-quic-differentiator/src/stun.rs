@@ -0,0 +1,45 @@
+/// In every STUN (RFC 5389) message, including those of TURN, which is STUN with more methods
+const MAGIC_COOKIE: [u8; 4] = [0x21, 0x12, 0xa4, 0x42];
+/// Message type, length, magic cookie and transaction ID
+const HEADER_LEN: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StunClass {
+    Request,
+    Indication,
+    SuccessResponse,
+    ErrorResponse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StunMessage {
+    pub class: StunClass,
+    /// Such as 0x001 for a binding, or 0x003 for a TURN allocation
+    pub method: u16,
+}
+
+/// Parses the header of a STUN message, returning `None` if the packet doesn't look like
+/// STUN.
+pub fn parse_stun_message(buf: &[u8]) -> Option<StunMessage> {
+    let header = buf.get(..HEADER_LEN)?;
+    let message_type = u16::from_be_bytes([header[0], header[1]]);
+    // The top two bits are zero, which also keeps STUN apart from QUIC's fixed bit
+    if message_type & 0xc000 != 0 || header[4..8] != MAGIC_COOKIE {
+        return None;
+    }
+    // Attributes are padded to four bytes, and a datagram holds exactly one message
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    if !length.is_multiple_of(4) || buf.len() != HEADER_LEN + length {
+        return None;
+    }
+    // The class bits are interleaved with the method bits
+    let class = match (message_type >> 7) & 0b10 | (message_type >> 4) & 0b1 {
+        0b00 => StunClass::Request,
+        0b01 => StunClass::Indication,
+        0b10 => StunClass::SuccessResponse,
+        _ => StunClass::ErrorResponse,
+    };
+    let method =
+        (message_type & 0x000f) | (message_type & 0x00e0) >> 1 | (message_type & 0x3e00) >> 2;
+    Some(StunMessage { class, method })
+}
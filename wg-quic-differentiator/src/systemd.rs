@@ -0,0 +1,70 @@
+//! Sockets passed in by systemd socket activation (`sd_listen_fds(3)`), so that systemd keeps
+//! the listen port bound, and queues what clients send, while the proxy restarts.
+
+use std::io;
+use std::net::{TcpListener, UdpSocket};
+
+/// The sockets the service was started with
+pub struct Inherited {
+    pub udp: Vec<UdpSocket>,
+    /// Used for `--tcp-backend`, instead of binding TCP on the UDP sockets' addresses
+    pub tcp: Vec<TcpListener>,
+}
+
+/// Takes the sockets systemd passed to this process, if it was socket activated
+#[cfg(unix)]
+pub fn listen_fds() -> io::Result<Option<Inherited>> {
+    use socket2::{Socket, Type};
+    use std::os::fd::{FromRawFd, RawFd};
+
+    /// The first passed descriptor, after stdin, stdout and stderr
+    const LISTEN_FDS_START: RawFd = 3;
+
+    // The variables are meant for the process systemd started, not one it started in turn
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let Some(count) = std::env::var("LISTEN_FDS").ok().filter(|_| for_us) else {
+        return Ok(None);
+    };
+    let count: RawFd = count.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("LISTEN_FDS must be a number of descriptors, not {count:?}"),
+        )
+    })?;
+
+    let mut inherited = Inherited {
+        udp: Vec::new(),
+        tcp: Vec::new(),
+    };
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        // Safety: systemd passes this process ownership of the descriptors from 3 onwards, and
+        // nothing else in it uses them
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        socket.set_cloexec(true)?;
+        match socket.r#type()? {
+            Type::DGRAM => inherited.udp.push(socket.into()),
+            Type::STREAM => inherited.tcp.push(socket.into()),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("passed descriptor {fd} is neither a UDP nor a TCP socket"),
+                ));
+            }
+        }
+    }
+    if inherited.udp.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "socket activated without a UDP socket to listen on",
+        ));
+    }
+    Ok(Some(inherited))
+}
+
+#[cfg(not(unix))]
+pub fn listen_fds() -> io::Result<Option<Inherited>> {
+    Ok(None)
+}
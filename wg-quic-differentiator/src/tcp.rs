@@ -0,0 +1,76 @@
+//! Forwards TCP connections on the listen port to a single backend, for clients that fall
+//! back from QUIC to HTTP/2 or HTTP/1.1 over TCP on the same address.
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::Proxy;
+use crate::throttle::Throttle;
+
+/// Accepts connections on `listener` until the proxy shuts down. The ones still open get the
+/// shutdown grace period to finish.
+pub async fn serve(listener: TcpListener, proxy: Arc<Proxy>, backend: String) {
+    let backend: Arc<str> = backend.into();
+    loop {
+        let (stream, addr) = tokio::select! {
+            result = listener.accept() => match result {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::error!("Error accepting TCP connection: {:?}", e);
+                    continue;
+                }
+            },
+            _ = proxy.shutdown.cancelled() => break,
+        };
+
+        if !proxy.live.load().filter.allows(addr.ip()) {
+            log::debug!(client_addr:% = addr; "Refusing TCP connection from denied client {:?}", addr);
+            continue;
+        }
+
+        let backend = backend.clone();
+        proxy
+            .tasks
+            .spawn(async move { forward(stream, &backend, addr).await });
+    }
+}
+
+async fn forward(mut client: TcpStream, backend: &str, addr: std::net::SocketAddr) {
+    let opened = Instant::now();
+    let mut upstream = match TcpStream::connect(backend).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            static UNREACHABLE_WARNING: Throttle = Throttle::new(Duration::from_secs(10));
+            if UNREACHABLE_WARNING.allow() {
+                log::warn!(
+                    "Closing TCP connection, backend {} is unreachable: {}",
+                    backend,
+                    e
+                );
+            }
+            return;
+        }
+    };
+    let _ = client.set_nodelay(true);
+    let _ = upstream.set_nodelay(true);
+    log::info!(client_addr:% = addr; "TCP connection from {:?} forwarded to {}", addr, backend);
+
+    match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+        Ok((to_backend, to_client)) => log::info!(
+            client_addr:% = addr;
+            "TCP connection from {:?} closed after {:.3?}, {} bytes to backend and {} to client",
+            addr,
+            opened.elapsed(),
+            to_backend,
+            to_client
+        ),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionReset => {
+            log::debug!(client_addr:% = addr; "TCP connection from {:?} reset", addr);
+        }
+        Err(e) => {
+            log::debug!(client_addr:% = addr; "Error forwarding TCP connection from {:?}: {:?}", addr, e)
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Lets an event through at most once per interval, to keep warnings that can be triggered by
+/// remote peers from flooding the logs.
+pub struct Throttle {
+    interval: Duration,
+    last: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    pub const fn new(interval: Duration) -> Self {
+        Throttle {
+            interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Returns true if the event should be logged
+    pub fn allow(&self) -> bool {
+        let now = Instant::now();
+        let mut last = self.last.lock().unwrap();
+        match *last {
+            Some(time) if now.duration_since(time) < self.interval => false,
+            _ => {
+                *last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+/// Like `Throttle`, but lets an event through once per interval for each key, such as a
+/// source address. Only a bounded number of keys is remembered, so a peer spoofing many
+/// sources can't grow it without limit; beyond that, events are held back until older keys
+/// expire.
+pub struct KeyedThrottle<K> {
+    interval: Duration,
+    capacity: usize,
+    last: Mutex<HashMap<K, Instant>>,
+}
+
+impl<K: Hash + Eq> KeyedThrottle<K> {
+    pub fn new(interval: Duration, capacity: usize) -> Self {
+        KeyedThrottle {
+            interval,
+            capacity,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true if the event for `key` should be logged
+    pub fn allow(&self, key: K) -> bool {
+        let now = Instant::now();
+        let mut last = self.last.lock().unwrap();
+        if let Some(time) = last.get(&key) {
+            if now.duration_since(*time) < self.interval {
+                return false;
+            }
+        } else if last.len() >= self.capacity {
+            last.retain(|_, time| now.duration_since(*time) < self.interval);
+            if last.len() >= self.capacity {
+                return false;
+            }
+        }
+        last.insert(key, now);
+        true
+    }
+}
@@ -0,0 +1,71 @@
+//! Looks for signs of WireGuard tunnelled through QUIC, for research rather than routing.
+//! Tunnels like that carry WireGuard messages in unreliable DATAGRAM frames (RFC 9221), which
+//! are encrypted with keys only the endpoints have, except in Initial packets. DATAGRAM frames
+//! aren't allowed in Initial packets either, so one found there is a client misbehaving or
+//! probing. Everything else that can be seen is whether the client announces support for
+//! DATAGRAM frames in its transport parameters.
+//!
+//! Only built with the `wireguard-over-quic` feature, as it decrypts every new Initial packet.
+
+use crate::sni::{self, Frame};
+use crate::wireguard_message;
+
+/// The quic_transport_parameters TLS extension (RFC 9001, section 8.2)
+const TRANSPORT_PARAMETERS: u16 = 0x39;
+/// The max_datagram_frame_size transport parameter (RFC 9221, section 3)
+const MAX_DATAGRAM_FRAME_SIZE: u64 = 0x20;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Findings {
+    /// The largest DATAGRAM frame the client accepts, if it supports them
+    pub max_datagram_frame_size: Option<u64>,
+    /// DATAGRAM frames in the packet
+    pub datagrams: usize,
+    /// DATAGRAM frames whose payload is shaped like a WireGuard message, with its name
+    pub wireguard_datagrams: Vec<&'static str>,
+}
+
+impl Findings {
+    /// Whether there is anything worth logging
+    pub fn any(&self) -> bool {
+        self.max_datagram_frame_size.is_some() || self.datagrams > 0
+    }
+}
+
+/// Inspects a QUIC Initial packet. Returns `None` if it isn't a client Initial of a version
+/// we know, or can't be decrypted.
+pub fn inspect(packet: &[u8]) -> Option<Findings> {
+    Some(inspect_frames(&sni::initial_frames(packet)?))
+}
+
+/// Inspects the decrypted frames of an Initial packet
+pub fn inspect_frames(payload: &[u8]) -> Findings {
+    let mut findings = Findings {
+        max_datagram_frame_size: sni::crypto_stream(payload)
+            .and_then(|hello| max_datagram_frame_size(&hello)),
+        ..Findings::default()
+    };
+    for frame in sni::frames(payload) {
+        if let Frame::Datagram(data) = frame {
+            findings.datagrams += 1;
+            findings.wireguard_datagrams.extend(wireguard_message(data));
+        }
+    }
+    findings
+}
+
+/// The max_datagram_frame_size in the transport parameters of a ClientHello
+fn max_datagram_frame_size(hello: &[u8]) -> Option<u64> {
+    let parameters = sni::client_hello_extension(hello, TRANSPORT_PARAMETERS)?;
+    let mut pos = 0;
+    while pos < parameters.len() {
+        let id = sni::varint(parameters, &mut pos)?;
+        let len = sni::varint(parameters, &mut pos)? as usize;
+        let value = parameters.get(pos..pos.checked_add(len)?)?;
+        pos += len;
+        if id == MAX_DATAGRAM_FRAME_SIZE {
+            return sni::varint(value, &mut 0);
+        }
+    }
+    None
+}
@@ -0,0 +1,175 @@
+//! Attaching an XDP program to the interface clients arrive on, with `--xdp-interface`.
+//!
+//! Only built with the `xdp` feature, on Linux. The program is loaded with the `bpf` syscall
+//! directly, so building it needs no BPF toolchain. So far it is a stub that passes every
+//! packet on to the network stack: this is the loader and its fallback, not yet a kernel
+//! fast path. Classifying and redirecting packets in the kernel would also need the
+//! connection table's address rewriting there, and is not implemented.
+
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{FromRawFd, OwnedFd};
+
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_LINK_CREATE: libc::c_long = 28;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const XDP_PASS: i32 = 2;
+
+/// A BPF instruction
+#[repr(C)]
+struct Insn {
+    code: u8,
+    /// The destination register in the low nibble, the source one in the high nibble
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+/// `return XDP_PASS;`
+const STUB_PROGRAM: [Insn; 2] = [
+    // BPF_ALU64 | BPF_MOV | BPF_K: r0 = XDP_PASS
+    Insn {
+        code: 0xb7,
+        regs: 0,
+        off: 0,
+        imm: XDP_PASS,
+    },
+    // BPF_JMP | BPF_EXIT
+    Insn {
+        code: 0x95,
+        regs: 0,
+        off: 0,
+        imm: 0,
+    },
+];
+
+/// The leading fields of `union bpf_attr` for `BPF_PROG_LOAD`. The kernel takes the fields
+/// it doesn't get as zero.
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+/// The leading fields of `union bpf_attr` for `BPF_LINK_CREATE`
+#[repr(C)]
+#[derive(Default)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+/// An XDP program attached to an interface, which is detached once this is dropped
+pub struct Attached {
+    // The link keeps the program attached, and closing it detaches it
+    _link: OwnedFd,
+    _program: OwnedFd,
+}
+
+/// Loads the stub program and attaches it to `interface`, in native mode where the driver
+/// supports it and generic mode otherwise. Needs CAP_BPF and CAP_NET_ADMIN.
+pub fn attach(interface: &str) -> io::Result<Attached> {
+    let name = CString::new(interface)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "interface name has a NUL"))?;
+    // SAFETY: `name` is a valid C string
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let license = c"GPL";
+    let mut prog_name = [0; 16];
+    prog_name[..4].copy_from_slice(b"wgqd");
+    let load = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_XDP,
+        insn_cnt: STUB_PROGRAM.len() as u32,
+        insns: STUB_PROGRAM.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        prog_name,
+        expected_attach_type: BPF_XDP,
+        ..Default::default()
+    };
+    let program = bpf(BPF_PROG_LOAD, &load)?;
+
+    let create = LinkCreateAttr {
+        prog_fd: fd_number(&program),
+        target_ifindex: ifindex,
+        attach_type: BPF_XDP,
+        flags: 0,
+    };
+    let link = bpf(BPF_LINK_CREATE, &create)?;
+    Ok(Attached {
+        _link: link,
+        _program: program,
+    })
+}
+
+/// Runs a `bpf` command that returns a new file descriptor
+fn bpf<T>(cmd: libc::c_long, attr: &T) -> io::Result<OwnedFd> {
+    // SAFETY: `attr` is a `bpf_attr` prefix of the size passed, and every pointer in it is
+    // valid for the duration of the call
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *const T,
+            std::mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the kernel returned a new file descriptor that nothing else owns
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) })
+}
+
+fn fd_number(fd: &OwnedFd) -> u32 {
+    use std::os::fd::AsRawFd;
+
+    fd.as_raw_fd() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_an_unknown_interface() {
+        let Err(e) = attach("wgq-no-such-if") else {
+            panic!("attached to an interface that doesn't exist");
+        };
+        assert_eq!(e.raw_os_error(), Some(libc::ENODEV));
+    }
+
+    #[test]
+    fn attaches_to_loopback_until_dropped() {
+        // Loading BPF programs needs privileges that test runs usually lack
+        let attached = match attach("lo") {
+            Ok(attached) => attached,
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => return,
+            Err(e) => panic!("can't attach to lo: {e}"),
+        };
+        // Only one XDP link fits on an interface, so a second attach fails until the first is
+        // dropped
+        assert_eq!(
+            attach("lo").err().and_then(|e| e.raw_os_error()),
+            Some(libc::EBUSY)
+        );
+        drop(attached);
+        attach("lo").unwrap();
+    }
+}
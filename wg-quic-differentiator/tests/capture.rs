@@ -0,0 +1,127 @@
+//! Checks the pcapng files the proxy records packets from clients to, and its audit log of new
+//! connections
+
+mod common;
+
+use common::*;
+use pcap_file::pcapng::blocks::enhanced_packet::EnhancedPacketOption;
+use pcap_file::pcapng::{Block, PcapNgReader};
+use std::fs::File;
+use std::thread;
+
+/// IPv4 and UDP headers, which the capture wraps datagrams in
+const HEADERS_LEN: usize = 28;
+
+#[test]
+fn captures_the_start_of_the_first_packet_of_each_connection() {
+    let path = std::env::temp_dir().join(format!("wgq-handshakes-{}.pcapng", std::process::id()));
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &[
+            "--capture-handshake",
+            "16",
+            "--capture-handshake-out",
+            path.to_str().unwrap(),
+        ],
+    );
+
+    let sock = client();
+    let initiation = wireguard_handshake_initiation();
+    exchange(&sock, &proxy, &initiation);
+    exchange(&sock, &proxy, &initiation);
+    exchange(&client(), &proxy, &quic_initial(&[]));
+    // The capture is written in the background
+    thread::sleep(RECV_TIMEOUT);
+
+    let mut reader = PcapNgReader::new(File::open(&path).unwrap()).unwrap();
+    let mut packets = Vec::new();
+    while let Some(block) = reader.next_block() {
+        if let Block::EnhancedPacket(packet) = block.unwrap() {
+            let comment = packet.options.iter().find_map(|option| match option {
+                EnhancedPacketOption::Comment(comment) => Some(comment.to_string()),
+                _ => None,
+            });
+            packets.push((
+                packet.data[HEADERS_LEN..].to_vec(),
+                packet.original_len as usize - HEADERS_LEN,
+                comment.unwrap(),
+            ));
+        }
+    }
+    let _ = std::fs::remove_file(&path);
+
+    // Only the first packet of each connection, cut short
+    assert_eq!(
+        packets,
+        [
+            (
+                initiation[..16].to_vec(),
+                initiation.len(),
+                "classified as wireguard".to_string()
+            ),
+            (
+                quic_initial(&[])[..16].to_vec(),
+                1200,
+                "classified as quic".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn appends_every_new_connection_to_the_audit_log() {
+    let path = std::env::temp_dir().join(format!("wgq-audit-{}.jsonl", std::process::id()));
+    std::fs::write(&path, "{\"earlier\":true}\n").unwrap();
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(&wireguard, &quic, &["--audit-log", path.to_str().unwrap()]);
+
+    let sock = client();
+    exchange(&sock, &proxy, &wireguard_handshake_initiation());
+    exchange(&sock, &proxy, &wireguard_handshake_initiation());
+    let quic_client = client();
+    exchange(&quic_client, &proxy, &quic_initial(&[]));
+    // The log is written in the background
+    thread::sleep(RECV_TIMEOUT);
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let lines: Vec<serde_json::Value> = contents
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    // What was there is kept, and the second packet on the WireGuard connection isn't a new one
+    assert_eq!(lines.len(), 3, "{contents}");
+    assert_eq!(lines[0]["earlier"], true);
+
+    let wireguard_entry = &lines[1];
+    assert_eq!(
+        wireguard_entry["client_addr"],
+        sock.local_addr().unwrap().to_string()
+    );
+    assert_eq!(wireguard_entry["listener"], proxy.addr.to_string());
+    assert_eq!(wireguard_entry["packet_type"], "wireguard");
+    assert_eq!(
+        wireguard_entry["reason"],
+        "wireguard Handshake Initiation len=148"
+    );
+    assert_eq!(wireguard_entry["backend_addr"], wireguard.addr.to_string());
+    assert!(
+        wireguard_entry["timestamp"]
+            .as_str()
+            .unwrap()
+            .ends_with('Z'),
+        "{wireguard_entry}"
+    );
+
+    let quic_entry = &lines[2];
+    assert_eq!(
+        quic_entry["client_addr"],
+        quic_client.local_addr().unwrap().to_string()
+    );
+    assert_eq!(quic_entry["packet_type"], "quic");
+    assert_eq!(quic_entry["backend_addr"], quic.addr.to_string());
+}
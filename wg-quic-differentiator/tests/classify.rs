@@ -0,0 +1,307 @@
+//! Checks the reasons the classifier gives for its decisions, and that custom classifiers
+//! are tried in front of it
+
+use wg_quic_differentiator::dtls::DtlsContentType;
+use wg_quic_differentiator::quic::QuicPacketType;
+use wg_quic_differentiator::stun::{StunClass, StunMessage};
+use wg_quic_differentiator::{
+    BuiltinClassifier, Classifiers, PacketClassifier, PacketType, Reason, classify_with_reason,
+};
+
+#[test]
+fn explains_wireguard_handshake_initiation() {
+    let mut packet = vec![0; 148];
+    packet[0] = 0x01;
+    let classification = classify_with_reason(&packet);
+    assert_eq!(classification.packet_type, PacketType::Wireguard);
+    assert_eq!(
+        classification.reason,
+        Reason::Wireguard {
+            message: "Handshake Initiation"
+        }
+    );
+    assert_eq!(
+        classification.to_string(),
+        "wireguard Handshake Initiation len=148"
+    );
+}
+
+#[test]
+fn recognises_wireguard_cookie_reply_by_its_exact_length() {
+    let mut packet = vec![0; 64];
+    packet[0] = 0x03;
+    assert_eq!(
+        classify_with_reason(&packet).reason,
+        Reason::Wireguard {
+            message: "Cookie Reply"
+        }
+    );
+
+    for len in [63, 65] {
+        packet.resize(len, 0);
+        assert_eq!(
+            classify_with_reason(&packet).packet_type,
+            PacketType::Unknown
+        );
+    }
+}
+
+#[test]
+fn explains_quic_initial() {
+    let mut packet = vec![0xc0, 0x00, 0x00, 0x00, 0x01, 0x08];
+    packet.extend_from_slice(&[0x11; 8]);
+    packet.push(0x00);
+    packet.resize(1200, 0);
+    let classification = classify_with_reason(&packet);
+    assert_eq!(classification.packet_type, PacketType::Quic);
+    assert_eq!(
+        classification.reason,
+        Reason::Quic {
+            packet_type: QuicPacketType::Initial,
+            version: Some(1)
+        }
+    );
+    assert_eq!(
+        classification.to_string(),
+        "quic Initial version=0x00000001 len=1200"
+    );
+}
+
+#[test]
+fn recognises_quic_retry_by_its_token_and_integrity_tag() {
+    // Retry is long packet type 3 in version 1, with empty connection IDs here
+    let mut packet = vec![0xf0, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00];
+    packet.extend_from_slice(b"token");
+    packet.extend_from_slice(&[0xaa; 16]);
+    assert_eq!(
+        classify_with_reason(&packet).reason,
+        Reason::Quic {
+            packet_type: QuicPacketType::Retry,
+            version: Some(1)
+        }
+    );
+
+    // Without a token there is only the tag, which clients would discard
+    packet.drain(7..12);
+    assert_eq!(
+        classify_with_reason(&packet).packet_type,
+        PacketType::Unknown
+    );
+}
+
+#[test]
+fn explains_quic_version_negotiation() {
+    // Version 0, with the fixed bit clear as servers may send it, and the client's connection
+    // IDs echoed back, offering versions 1 and 2
+    let mut packet = vec![0x80, 0x00, 0x00, 0x00, 0x00, 0x08];
+    packet.extend_from_slice(&[0x11; 8]);
+    packet.push(0x08);
+    packet.extend_from_slice(&[0x22; 8]);
+    packet.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x6b, 0x33, 0x43, 0xcf]);
+    let classification = classify_with_reason(&packet);
+    assert_eq!(classification.packet_type, PacketType::Quic);
+    assert_eq!(
+        classification.reason,
+        Reason::Quic {
+            packet_type: QuicPacketType::VersionNegotiation,
+            version: Some(0)
+        }
+    );
+    assert_eq!(
+        classification.to_string(),
+        "quic VersionNegotiation version=0x00000000 len=31"
+    );
+
+    // A version list that doesn't end on a whole version, or is empty, is not QUIC
+    for len in [29, 23] {
+        packet.truncate(len);
+        assert_eq!(
+            classify_with_reason(&packet).packet_type,
+            PacketType::Unknown
+        );
+    }
+}
+
+#[test]
+fn explains_wireguard_with_wrong_length() {
+    // A handshake initiation must be exactly 148 bytes long, and with the fixed bit clear
+    // this isn't QUIC either
+    let mut packet = vec![0; 100];
+    packet[0] = 0x01;
+    let classification = classify_with_reason(&packet);
+    assert_eq!(classification.packet_type, PacketType::Unknown);
+    assert_eq!(
+        classification.reason,
+        Reason::MalformedWireguard { message_type: 1 }
+    );
+    assert_eq!(
+        classification.to_string(),
+        "malformed wireguard message type 1 len=100"
+    );
+    // The built-in classifier claims it, so that it isn't left to a later one
+    assert!(BuiltinClassifier.classify_with_reason(&packet).is_some());
+
+    // Data messages only have a minimum length
+    packet[0] = 0x04;
+    packet.truncate(31);
+    assert_eq!(
+        classify_with_reason(&packet).reason,
+        Reason::MalformedWireguard { message_type: 4 }
+    );
+    // Without the zero bytes it doesn't look like WireGuard at all
+    packet[1] = 0x01;
+    assert_eq!(
+        classify_with_reason(&packet).to_string(),
+        "no known header, first byte 0x04 len=31"
+    );
+}
+
+/// The sample request of RFC 5769, section 2.1, as sent by an ICE agent
+const STUN_BINDING_REQUEST: [u8; 108] = [
+    0x00, 0x01, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86,
+    0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x10, 0x53, 0x54, 0x55, 0x4e, 0x20, 0x74, 0x65, 0x73,
+    0x74, 0x20, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x00, 0x24, 0x00, 0x04, 0x6e, 0x00, 0x01, 0xff,
+    0x80, 0x29, 0x00, 0x08, 0x93, 0x2f, 0xf9, 0xb1, 0x51, 0x26, 0x3b, 0x36, 0x00, 0x06, 0x00, 0x09,
+    0x65, 0x76, 0x74, 0x6a, 0x3a, 0x68, 0x36, 0x76, 0x59, 0x20, 0x20, 0x20, 0x00, 0x08, 0x00, 0x14,
+    0x9a, 0xea, 0xa7, 0x0c, 0xbf, 0xd8, 0xcb, 0x56, 0x78, 0x1e, 0xf2, 0xb5, 0xb2, 0xd3, 0xf2, 0x49,
+    0xc1, 0xb5, 0x71, 0xa2, 0x80, 0x28, 0x00, 0x04, 0xe5, 0x7a, 0x3b, 0xcf,
+];
+
+#[test]
+fn explains_captured_dtls_records() {
+    let cases: [(&[u8], DtlsContentType, &str); 3] = [
+        (
+            include_bytes!("data/dtls_client_hello.bin"),
+            DtlsContentType::Handshake,
+            "dtls Handshake record len=208",
+        ),
+        (
+            include_bytes!("data/dtls_server_hello.bin"),
+            DtlsContentType::Handshake,
+            "dtls Handshake record len=228",
+        ),
+        (
+            include_bytes!("data/dtls_application_data.bin"),
+            DtlsContentType::ApplicationData,
+            "dtls ApplicationData record len=48",
+        ),
+    ];
+    for (packet, content_type, explanation) in cases {
+        let classification = classify_with_reason(packet);
+        assert_eq!(classification.packet_type, PacketType::Dtls);
+        assert_eq!(classification.reason, Reason::Dtls { content_type });
+        assert_eq!(classification.to_string(), explanation);
+    }
+}
+
+#[test]
+fn does_not_take_tls_for_dtls() {
+    let packet = include_bytes!("data/tls_client_hello.bin");
+    assert_eq!(
+        classify_with_reason(packet).packet_type,
+        PacketType::Unknown
+    );
+}
+
+#[test]
+fn explains_stun_binding_request() {
+    let classification = classify_with_reason(&STUN_BINDING_REQUEST);
+    assert_eq!(classification.packet_type, PacketType::Stun);
+    assert_eq!(
+        classification.reason,
+        Reason::Stun {
+            message: StunMessage {
+                class: StunClass::Request,
+                method: 0x001
+            }
+        }
+    );
+    assert_eq!(
+        classification.to_string(),
+        "stun Request method=0x001 len=108"
+    );
+
+    // A plain binding request without attributes, as sent by browsers to learn their public
+    // address
+    let mut packet = vec![0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42];
+    packet.extend_from_slice(&[0x5a; 12]);
+    assert_eq!(classify_with_reason(&packet).packet_type, PacketType::Stun);
+}
+
+#[test]
+fn explains_stun_success_response() {
+    // A binding success response with an XOR-MAPPED-ADDRESS attribute
+    let mut packet = vec![0x01, 0x01, 0x00, 0x0c, 0x21, 0x12, 0xa4, 0x42];
+    packet.extend_from_slice(&[0x5a; 12]);
+    packet.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47]);
+    packet.extend_from_slice(&[0xe1, 0x12, 0xa6, 0x43]);
+    let classification = classify_with_reason(&packet);
+    assert_eq!(
+        classification.reason,
+        Reason::Stun {
+            message: StunMessage {
+                class: StunClass::SuccessResponse,
+                method: 0x001
+            }
+        }
+    );
+}
+
+#[test]
+fn rejects_stun_with_wrong_length() {
+    // The length field must cover the whole datagram, so trailing bytes give it away
+    let mut packet = STUN_BINDING_REQUEST.to_vec();
+    packet.extend_from_slice(&[0; 4]);
+    assert_eq!(
+        classify_with_reason(&packet).packet_type,
+        PacketType::Unknown
+    );
+}
+
+/// Recognises the hard reset an OpenVPN client opens its session with
+struct OpenVpn;
+
+impl PacketClassifier for OpenVpn {
+    fn classify(&self, buf: &[u8]) -> Option<PacketType> {
+        (buf.len() >= 14 && buf[0] == 0x38).then_some(PacketType::Unknown)
+    }
+}
+
+#[test]
+fn tries_custom_classifiers_before_the_builtin_one() {
+    let classifiers = Classifiers::new(vec![Box::new(OpenVpn), Box::new(BuiltinClassifier)]);
+
+    let mut hard_reset = vec![0x38];
+    hard_reset.resize(14, 0x33);
+    let classification = classifiers.classify_with_reason(&hard_reset);
+    assert_eq!(classification.packet_type, PacketType::Unknown);
+    assert!(matches!(
+        classification.reason,
+        Reason::Custom { classifier } if classifier.ends_with("OpenVpn")
+    ));
+
+    let mut handshake = vec![0; 148];
+    handshake[0] = 0x01;
+    assert_eq!(classifiers.classify(&handshake), PacketType::Wireguard);
+    assert_eq!(
+        classifiers.classify_with_reason(&[0xff]).reason,
+        Reason::NoMatch {
+            first_byte: Some(0xff)
+        }
+    );
+}
+
+#[test]
+fn classifies_a_packet_file_without_starting_the_proxy() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+        .arg("--classify-file")
+        .arg("tests/data/quic_initial_example_com.bin")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "quic: quic Initial version=0x00000001 len=1200\n"
+    );
+}
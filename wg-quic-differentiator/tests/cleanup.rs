@@ -0,0 +1,121 @@
+//! Checks that connections don't leave tasks behind once they are closed
+
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn timed_out_connection_stops_its_task() {
+    let admin_socket =
+        std::env::temp_dir().join(format!("wgq-cleanup-{}.sock", std::process::id()));
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &[
+            "--connection-timeout",
+            "1",
+            "--admin-socket",
+            admin_socket.to_str().unwrap(),
+        ],
+    );
+
+    let sock = client();
+    exchange(&sock, &proxy, &wireguard_handshake_initiation());
+    assert_eq!(stat(&admin_socket, "connections"), 1);
+    let with_connection = stat(&admin_socket, "tasks");
+
+    thread::sleep(Duration::from_secs(2));
+    assert_eq!(stat(&admin_socket, "connections"), 0);
+    assert_eq!(stat(&admin_socket, "tasks"), with_connection - 1);
+}
+
+#[test]
+fn response_at_the_timeout_boundary_leaves_consistent_state() {
+    let admin_socket =
+        std::env::temp_dir().join(format!("wgq-boundary-{}.sock", std::process::id()));
+    // Answers just before the connection would time out
+    let wireguard = MockBackend::answering(|packet| {
+        thread::sleep(Duration::from_millis(900));
+        packet.to_vec()
+    });
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &[
+            "--connection-timeout",
+            "1",
+            "--admin-socket",
+            admin_socket.to_str().unwrap(),
+        ],
+    );
+    exchange(&client(), &proxy, &quic_initial(&[]));
+    thread::sleep(Duration::from_secs(2));
+    let idle_tasks = stat(&admin_socket, "tasks");
+
+    let sock = client();
+    sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let packet = wireguard_handshake_initiation();
+    let mut buf = [0; 65536];
+    for _ in 0..2 {
+        // The late response is forwarded, and counts as activity
+        sock.send_to(&packet, proxy.addr).unwrap();
+        let (len, _) = sock.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &packet[..]);
+        assert_eq!(stat(&admin_socket, "connections"), 1);
+
+        // Then the connection times out for good, and the next packet opens a new one
+        thread::sleep(Duration::from_millis(1500));
+        assert_eq!(stat(&admin_socket, "connections"), 0);
+        assert_eq!(stat(&admin_socket, "tasks"), idle_tasks);
+    }
+}
+
+#[test]
+fn aborts_connections_still_open_after_the_shutdown_grace_period() {
+    // A TCP backend that accepts connections and never closes them
+    let tcp_backend = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let tcp_addr = tcp_backend.local_addr().unwrap();
+    thread::spawn(move || {
+        let open: Vec<_> = tcp_backend.incoming().collect();
+        drop(open);
+    });
+    let addr = free_addr();
+    let child = std::process::Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+        .args(["--listen", &addr.to_string()])
+        .args(["--tcp-backend", &tcp_addr.to_string()])
+        .args(["--shutdown-grace", "1"])
+        .env("RUST_LOG", "info")
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    let _client = loop {
+        if let Ok(stream) = std::net::TcpStream::connect(addr) {
+            break stream;
+        }
+        assert!(Instant::now() < deadline, "the proxy didn't start");
+        thread::sleep(Duration::from_millis(10));
+    };
+    // Give the connection time to reach the backend
+    thread::sleep(Duration::from_millis(200));
+
+    let terminated = Instant::now();
+    std::process::Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert!(terminated.elapsed() >= Duration::from_secs(1));
+    assert!(terminated.elapsed() < STARTUP_TIMEOUT);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("aborting the other 1"), "{stderr}");
+}
@@ -0,0 +1,339 @@
+//! Helpers for running the proxy binary against mock backends
+
+// Each test file uses its own subset of these
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::process::{Child, Command};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the proxy gets to start before a test gives up on it
+pub const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+pub const RECV_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// A backend that echoes every datagram back (or answers it some other way), and reports
+/// what it received from where
+pub struct MockBackend {
+    pub addr: SocketAddr,
+    received: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+}
+
+impl MockBackend {
+    pub fn start() -> Self {
+        Self::answering(|packet| packet.to_vec())
+    }
+
+    /// A backend that sends back whatever `answer` makes of each datagram it receives, unless
+    /// that is empty
+    pub fn answering(answer: fn(&[u8]) -> Vec<u8>) -> Self {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let (tx, received) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 65536];
+            while let Ok((len, peer)) = sock.recv_from(&mut buf) {
+                if tx.send((buf[..len].to_vec(), peer)).is_err() {
+                    break;
+                }
+                let answer = answer(&buf[..len]);
+                if !answer.is_empty() {
+                    let _ = sock.send_to(&answer, peer);
+                }
+            }
+        });
+        MockBackend { addr, received }
+    }
+
+    pub fn received(&self) -> Vec<Vec<u8>> {
+        self.received.try_iter().map(|(packet, _)| packet).collect()
+    }
+
+    /// The addresses the proxy sent from, one per upstream socket
+    pub fn senders(&self) -> Vec<SocketAddr> {
+        let mut senders: Vec<_> = self.received.try_iter().map(|(_, from)| from).collect();
+        senders.dedup();
+        senders
+    }
+}
+
+/// A SOCKS5 proxy that relays UDP for every association it is asked for, and reports the
+/// backends datagrams were relayed to
+pub struct MockSocks5 {
+    pub addr: SocketAddr,
+    relayed: mpsc::Receiver<SocketAddr>,
+}
+
+impl MockSocks5 {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, relayed) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { break };
+                let tx = tx.clone();
+                thread::spawn(move || Self::associate(stream, tx));
+            }
+        });
+        MockSocks5 { addr, relayed }
+    }
+
+    /// Handles an IPv4 UDP ASSOCIATE request without authentication, then relays until the
+    /// control connection closes
+    fn associate(mut control: TcpStream, tx: mpsc::Sender<SocketAddr>) {
+        let mut greeting = [0; 3];
+        control.read_exact(&mut greeting).unwrap();
+        control.write_all(&[5, 0]).unwrap();
+        let mut request = [0; 10];
+        control.read_exact(&mut request).unwrap();
+        assert_eq!(request[..4], [5, 3, 0, 1]);
+
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut reply = vec![5, 0, 0];
+        reply.extend(socks5_address(relay.local_addr().unwrap()));
+        control.write_all(&reply).unwrap();
+
+        thread::spawn(move || {
+            let mut client = None;
+            let mut buf = [0; 65536];
+            while let Ok((len, from)) = relay.recv_from(&mut buf) {
+                if len >= 10 && buf[..4] == [0, 0, 0, 1] {
+                    let ip = <[u8; 4]>::try_from(&buf[4..8]).unwrap();
+                    let backend = SocketAddr::from((ip, u16::from_be_bytes([buf[8], buf[9]])));
+                    client = Some(from);
+                    let _ = tx.send(backend);
+                    let _ = relay.send_to(&buf[10..len], backend);
+                } else if let Some(client) = client {
+                    let mut datagram = vec![0, 0, 0];
+                    datagram.extend(socks5_address(from));
+                    datagram.extend_from_slice(&buf[..len]);
+                    let _ = relay.send_to(&datagram, client);
+                }
+            }
+        });
+        // The association lasts as long as the control connection
+        let _ = control.read(&mut [0; 1]);
+    }
+
+    pub fn relayed(&self) -> Vec<SocketAddr> {
+        self.relayed.try_iter().collect()
+    }
+}
+
+fn socks5_address(addr: SocketAddr) -> Vec<u8> {
+    let SocketAddr::V4(addr) = addr else {
+        panic!("the mock SOCKS5 proxy only relays IPv4");
+    };
+    let mut out = vec![1];
+    out.extend(addr.ip().octets());
+    out.extend(addr.port().to_be_bytes());
+    out
+}
+
+/// The proxy process, killed when dropped
+pub struct Proxy {
+    child: Child,
+    pub addr: SocketAddr,
+}
+
+impl Proxy {
+    pub fn start(wireguard: &MockBackend, quic: &MockBackend, args: &[&str]) -> Self {
+        Self::start_at(wireguard.addr, quic.addr, args)
+    }
+
+    /// Starts the proxy with backends that aren't mocks
+    pub fn start_at(wireguard: SocketAddr, quic: SocketAddr, args: &[&str]) -> Self {
+        Self::start_listening(free_addr(), wireguard, quic, args)
+    }
+
+    /// Starts the proxy listening on `addr`, which may be a wildcard address
+    pub fn start_listening(
+        addr: SocketAddr,
+        wireguard: SocketAddr,
+        quic: SocketAddr,
+        args: &[&str],
+    ) -> Self {
+        let child = Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+            .arg("--listen")
+            .arg(addr.to_string())
+            .arg("--wireguard-backend")
+            .arg(wireguard.to_string())
+            .arg("--quic-backend")
+            .arg(quic.to_string())
+            .args(args)
+            .spawn()
+            .unwrap();
+        Proxy { child, addr }
+    }
+
+    /// Starts the proxy with everything but its listen address in the config file at `config`
+    pub fn start_with_config(config: &std::path::Path) -> Self {
+        let addr = free_addr();
+        let child = Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+            .arg("--listen")
+            .arg(addr.to_string())
+            .arg("--config")
+            .arg(config)
+            .spawn()
+            .unwrap();
+        Proxy { child, addr }
+    }
+
+    /// Asks the proxy to reload its config, with a SIGHUP
+    #[cfg(unix)]
+    pub fn reload(&self) {
+        let status = Command::new("kill")
+            .arg("-HUP")
+            .arg(self.pid().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    /// The process ID of the proxy, to measure the CPU time it used
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Starts the proxy with its addresses set through environment variables only, the way
+    /// containers are usually configured
+    pub fn start_from_env(wireguard: &MockBackend, quic: &MockBackend) -> Self {
+        let addr = free_addr();
+        let child = Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+            .env("WGQ_LISTEN", addr.to_string())
+            .env("WGQ_WIREGUARD_BACKEND", wireguard.addr.to_string())
+            .env("WGQ_QUIC_BACKEND", quic.addr.to_string())
+            .spawn()
+            .unwrap();
+        Proxy { child, addr }
+    }
+}
+
+/// The proxy needs a fixed address to listen on, so a free port is found up front
+pub fn free_addr() -> SocketAddr {
+    UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub fn client() -> UdpSocket {
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.set_read_timeout(Some(RECV_TIMEOUT)).unwrap();
+    sock
+}
+
+/// Sends `packet` through the proxy until a response arrives, as the proxy may still be
+/// starting up. Returns the response and the address it came from.
+pub fn exchange(sock: &UdpSocket, proxy: &Proxy, packet: &[u8]) -> (Vec<u8>, SocketAddr) {
+    exchange_at(sock, proxy.addr, packet)
+}
+
+/// An exchange with another address the proxy listens on
+pub fn exchange_at(sock: &UdpSocket, addr: SocketAddr, packet: &[u8]) -> (Vec<u8>, SocketAddr) {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    let mut buf = [0; 65536];
+    while Instant::now() < deadline {
+        sock.send_to(packet, addr).unwrap();
+        if let Ok((len, from)) = sock.recv_from(&mut buf) {
+            return (buf[..len].to_vec(), from);
+        }
+    }
+    panic!("no response from the proxy within {STARTUP_TIMEOUT:?}");
+}
+
+/// Discards responses to retries of an exchange
+pub fn drain(sock: &UdpSocket) {
+    let mut buf = [0; 65536];
+    while sock.recv_from(&mut buf).is_ok() {}
+}
+
+pub fn wireguard_handshake_initiation() -> Vec<u8> {
+    let mut packet = vec![0; 148];
+    packet[0] = 0x01;
+    packet[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+    packet
+}
+
+/// The cookie reply a WireGuard server under load answers a handshake initiation with,
+/// addressed to the index the initiation's sender picked
+pub fn wireguard_cookie_reply(initiation: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0; 64];
+    packet[0] = 0x03;
+    packet[4..8].copy_from_slice(&initiation[4..8]);
+    // The nonce and the encrypted cookie
+    packet[8..].fill(0x5c);
+    packet
+}
+
+pub fn quic_initial(source_cid: &[u8]) -> Vec<u8> {
+    // Long header with the fixed bit set, version 1 and an 8 byte destination connection ID,
+    // padded to the minimum size of a client Initial
+    let mut packet = vec![0xc0, 0x00, 0x00, 0x00, 0x01, 0x08];
+    packet.extend_from_slice(&[0x11; 8]);
+    packet.push(source_cid.len() as u8);
+    packet.extend_from_slice(source_cid);
+    packet.resize(1200, 0);
+    packet
+}
+
+pub fn quic_short_header(destination_cid: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x40];
+    packet.extend_from_slice(destination_cid);
+    packet.resize(100, 0xaa);
+    packet
+}
+
+pub fn stun_binding_request() -> Vec<u8> {
+    let mut packet = vec![0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42];
+    packet.extend_from_slice(&[0x5a; 12]);
+    packet
+}
+
+#[cfg(unix)]
+/// Sends a command to the admin socket, returning its answer
+pub fn admin(admin_socket: &std::path::Path, command: &str) -> String {
+    use std::io::{Read, Write};
+
+    let mut stream = std::os::unix::net::UnixStream::connect(admin_socket).unwrap();
+    writeln!(stream, "{command}").unwrap();
+    // The proxy answers every command before it notices the end of the input
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).unwrap();
+    answer
+}
+
+#[cfg(unix)]
+/// Reads a value from the admin socket's `stats` output
+pub fn stat(admin_socket: &std::path::Path, name: &str) -> u64 {
+    use std::io::{BufRead, BufReader, Write};
+
+    let mut stream = std::os::unix::net::UnixStream::connect(admin_socket).unwrap();
+    stream.write_all(b"stats\n").unwrap();
+    let mut line = String::new();
+    let mut reader = BufReader::new(stream);
+    loop {
+        line.clear();
+        assert!(
+            reader.read_line(&mut line).unwrap() > 0,
+            "no {name} in stats"
+        );
+        if let Some(value) = line
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix(' '))
+        {
+            return value.trim().parse().unwrap();
+        }
+    }
+}
@@ -0,0 +1,147 @@
+"""Makes the WireGuard frames in this directory, which the unit tests of the classifier parse.
+
+Runs a real WireGuard handshake (Noise_IKpsk2, as in the WireGuard paper) between two fixed
+test key pairs, checks each message from the peer's side, and writes the initiation, response,
+cookie reply, a keepalive and a data message carrying an ICMP echo request. Needs the
+`cryptography` package: python3 tests/data/wireguard_frames.py
+"""
+
+import hashlib, hmac, struct
+from cryptography.hazmat.primitives.asymmetric.x25519 import X25519PrivateKey, X25519PublicKey
+from cryptography.hazmat.primitives.ciphers.aead import ChaCha20Poly1305
+from cryptography.hazmat.primitives import serialization
+
+RAW = serialization.Encoding.Raw
+def pub(k): return k.public_key().public_bytes(RAW, serialization.PublicFormat.Raw)
+def priv_from(b): return X25519PrivateKey.from_private_bytes(b)
+def dh(k, p): return k.exchange(X25519PublicKey.from_public_bytes(p))
+
+def HASH(x): return hashlib.blake2s(x, digest_size=32).digest()
+def MAC(k, x): return hashlib.blake2s(x, digest_size=16, key=k).digest()
+def HMAC(k, x): return hmac.new(k, x, lambda d=b'': hashlib.blake2s(d)).digest()
+def KDF(n, key, inp):
+    t0 = HMAC(key, inp)
+    out, prev = [], b''
+    for i in range(1, n + 1):
+        prev = HMAC(t0, prev + bytes([i]))
+        out.append(prev)
+    return out
+def nonce(c): return b'\0' * 4 + struct.pack('<Q', c)
+def AEAD(k, c, p, a): return ChaCha20Poly1305(k).encrypt(nonce(c), p, a)
+def UNAEAD(k, c, x, a): return ChaCha20Poly1305(k).decrypt(nonce(c), x, a)
+
+def rotl(v, n): return ((v << n) & 0xffffffff) | (v >> (32 - n))
+def hchacha20(key, n16):
+    s = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574] + list(struct.unpack('<8I', key)) + list(struct.unpack('<4I', n16))
+    def qr(a, b, c, d):
+        s[a] = (s[a] + s[b]) & 0xffffffff; s[d] = rotl(s[d] ^ s[a], 16)
+        s[c] = (s[c] + s[d]) & 0xffffffff; s[b] = rotl(s[b] ^ s[c], 12)
+        s[a] = (s[a] + s[b]) & 0xffffffff; s[d] = rotl(s[d] ^ s[a], 8)
+        s[c] = (s[c] + s[d]) & 0xffffffff; s[b] = rotl(s[b] ^ s[c], 7)
+    for _ in range(10):
+        qr(0, 4, 8, 12); qr(1, 5, 9, 13); qr(2, 6, 10, 14); qr(3, 7, 11, 15)
+        qr(0, 5, 10, 15); qr(1, 6, 11, 12); qr(2, 7, 8, 13); qr(3, 4, 9, 14)
+    return struct.pack('<8I', *(s[0:4] + s[12:16]))
+def XAEAD(k, n24, p, a):
+    return ChaCha20Poly1305(hchacha20(k, n24[:16])).encrypt(b'\0' * 4 + n24[16:], p, a)
+def UNXAEAD(k, n24, x, a):
+    return ChaCha20Poly1305(hchacha20(k, n24[:16])).decrypt(b'\0' * 4 + n24[16:], x, a)
+
+# RFC draft-irtf-cfrg-xchacha test vector for HChaCha20
+assert hchacha20(bytes(range(32)), bytes.fromhex('000000090000004a0000000031415927')).hex() == \
+    '82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc'
+
+CONSTRUCTION = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s"
+IDENTIFIER = b"WireGuard v1 zx2c4 Jason@zx2c4.com"
+LABEL_MAC1 = b"mac1----"
+LABEL_COOKIE = b"cookie--"
+
+# Fixed test keys, so the frames are reproducible
+Si = priv_from(bytes([0x11] * 32)); Si_pub = pub(Si)
+Sr = priv_from(bytes([0x22] * 32)); Sr_pub = pub(Sr)
+Ei = priv_from(bytes([0x33] * 32)); Ei_pub = pub(Ei)
+Er = priv_from(bytes([0x44] * 32)); Er_pub = pub(Er)
+I_i = 0x1a2b3c4d   # initiator's sender index
+I_r = 0x5e6f7081   # responder's sender index
+TIMESTAMP = bytes.fromhex('400000006a0b2c80') + struct.pack('>I', 123456789)  # TAI64N
+Q = b'\0' * 32
+
+# Initiation
+C = HASH(CONSTRUCTION); H = HASH(C + IDENTIFIER); H = HASH(H + Sr_pub)
+C, = KDF(1, C, Ei_pub); H = HASH(H + Ei_pub)
+C, k = KDF(2, C, dh(Ei, Sr_pub)); enc_static = AEAD(k, 0, Si_pub, H); H = HASH(H + enc_static)
+C, k = KDF(2, C, dh(Si, Sr_pub)); enc_ts = AEAD(k, 0, TIMESTAMP, H); H = HASH(H + enc_ts)
+init = struct.pack('<B3xI', 1, I_i) + Ei_pub + enc_static + enc_ts
+init += MAC(HASH(LABEL_MAC1 + Sr_pub), init)
+init += b'\0' * 16
+assert len(init) == 148
+
+# Responder checks the initiation
+assert MAC(HASH(LABEL_MAC1 + Sr_pub), init[:116]) == init[116:132]
+c = HASH(CONSTRUCTION); h = HASH(c + IDENTIFIER); h = HASH(h + Sr_pub)
+e = init[8:40]; c, = KDF(1, c, e); h = HASH(h + e)
+c, k = KDF(2, c, dh(Sr, e)); s = UNAEAD(k, 0, init[40:88], h); assert s == Si_pub; h = HASH(h + init[40:88])
+c, k = KDF(2, c, dh(Sr, s)); assert UNAEAD(k, 0, init[88:116], h) == TIMESTAMP; h = HASH(h + init[88:116])
+assert (c, h) == (C, H)
+
+# Response
+C, = KDF(1, C, Er_pub); H = HASH(H + Er_pub)
+C, = KDF(1, C, dh(Er, Ei_pub))
+C, = KDF(1, C, dh(Er, Si_pub))
+C, t, k = KDF(3, C, Q); H = HASH(H + t)
+enc_empty = AEAD(k, 0, b'', H); H = HASH(H + enc_empty)
+resp = struct.pack('<B3xII', 2, I_r, I_i) + Er_pub + enc_empty
+resp += MAC(HASH(LABEL_MAC1 + Si_pub), resp)
+resp += b'\0' * 16
+assert len(resp) == 92
+
+# Initiator checks the response
+assert MAC(HASH(LABEL_MAC1 + Si_pub), resp[:60]) == resp[60:76]
+c2, h2 = c, h
+er = resp[12:44]; c2, = KDF(1, c2, er); h2 = HASH(h2 + er)
+c2, = KDF(1, c2, dh(Ei, er)); c2, = KDF(1, c2, dh(Si, er))
+c2, t2, k2 = KDF(3, c2, Q); h2 = HASH(h2 + t2)
+assert UNAEAD(k2, 0, resp[44:60], h2) == b''
+assert c2 == C
+
+# Transport keys
+T_i_send, T_r_send = KDF(2, C, b'')
+
+def data(key, receiver, counter, packet):
+    padded = packet + b'\0' * (-len(packet) % 16)
+    return struct.pack('<B3xIQ', 4, receiver, counter) + AEAD(key, counter, padded, b'')
+
+keepalive = data(T_i_send, I_r, 0, b'')
+assert len(keepalive) == 32
+# An ICMP echo request from 10.0.0.2 to 10.0.0.1 inside the tunnel
+icmp = bytes.fromhex('0800') + b'\0\0' + struct.pack('>HH', 0x1234, 1) + bytes(range(56))
+csum = sum(struct.unpack('>%dH' % (len(icmp) // 2), icmp)); csum = (csum & 0xffff) + (csum >> 16); csum = ~csum & 0xffff
+icmp = icmp[:2] + struct.pack('>H', csum) + icmp[4:]
+ip = struct.pack('>BBHHHBBH4s4s', 0x45, 0, 20 + len(icmp), 0x4242, 0x4000, 64, 1, 0, bytes([10, 0, 0, 2]), bytes([10, 0, 0, 1]))
+ipc = sum(struct.unpack('>10H', ip)); ipc = (ipc & 0xffff) + (ipc >> 16); ipc = ~ipc & 0xffff
+ip = ip[:10] + struct.pack('>H', ipc) + ip[12:] + icmp
+ping = data(T_i_send, I_r, 1, ip)
+assert UNAEAD(T_i_send, 1, ping[16:], b'')[:len(ip)] == ip
+assert len(ping) == 16 + 96 + 16
+
+# Cookie reply from the responder to the initiation, as if under load
+Rm = bytes([0x55] * 32)
+cookie = MAC(Rm, bytes([192, 0, 2, 1]) + struct.pack('>H', 51820))
+cookie_nonce = bytes(range(0x60, 0x60 + 24))
+mac1 = init[116:132]
+cookie_reply = struct.pack('<B3xI', 3, I_i) + cookie_nonce + XAEAD(HASH(LABEL_COOKIE + Sr_pub), cookie_nonce, cookie, mac1)
+assert len(cookie_reply) == 64
+assert UNXAEAD(HASH(LABEL_COOKIE + Sr_pub), cookie_nonce, cookie_reply[32:], mac1) == cookie
+
+import os
+
+here = os.path.dirname(os.path.abspath(__file__))
+for name, frame in [
+    ('initiation', init),
+    ('response', resp),
+    ('cookie_reply', cookie_reply),
+    ('keepalive', keepalive),
+    ('data', ping),
+]:
+    with open(os.path.join(here, 'wireguard_%s.bin' % name), 'wb') as f:
+        f.write(frame)
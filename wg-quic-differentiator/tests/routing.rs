@@ -0,0 +1,1440 @@
+//! Runs the proxy binary against mock backends, and checks that each protocol reaches its own
+//! backend and that responses find their way back to the right client.
+
+mod common;
+
+use common::*;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+#[test]
+fn routes_each_protocol_to_its_backend() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(&wireguard, &quic, &[]);
+
+    let wireguard_client = client();
+    let quic_client = client();
+    let wireguard_packet = wireguard_handshake_initiation();
+    let quic_packet = quic_initial(&[]);
+
+    let (response, from) = exchange(&wireguard_client, &proxy, &wireguard_packet);
+    assert_eq!(response, wireguard_packet);
+    assert_eq!(from, proxy.addr);
+
+    let (response, from) = exchange(&quic_client, &proxy, &quic_packet);
+    assert_eq!(response, quic_packet);
+    assert_eq!(from, proxy.addr);
+
+    let to_wireguard = wireguard.received();
+    let to_quic = quic.received();
+    assert!(!to_wireguard.is_empty());
+    assert!(
+        to_wireguard
+            .iter()
+            .all(|packet| *packet == wireguard_packet)
+    );
+    assert!(!to_quic.is_empty());
+    assert!(to_quic.iter().all(|packet| *packet == quic_packet));
+
+    // Neither client may see the other's response
+    let mut buf = [0; 65536];
+    for (sock, expected) in [
+        (&wireguard_client, &wireguard_packet),
+        (&quic_client, &quic_packet),
+    ] {
+        while let Ok((len, _)) = sock.recv_from(&mut buf) {
+            assert_eq!(&buf[..len], &expected[..]);
+        }
+    }
+}
+
+/// A port that is free for both IPv4 and IPv6, for the proxy to listen on `[::]`
+fn free_dual_stack_port() -> u16 {
+    UdpSocket::bind("[::]:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+#[test]
+fn forwards_ipv6_clients_when_listening_on_ipv6() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let port = free_dual_stack_port();
+    let _proxy = Proxy::start_listening(
+        SocketAddr::from(([0u16; 8], port)),
+        wireguard.addr,
+        quic.addr,
+        &[],
+    );
+    let proxy_addr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port));
+
+    // From a client each, as a client's connection sticks with the backend it was sent to
+    for packet in [wireguard_handshake_initiation(), quic_initial(&[])] {
+        let sock = UdpSocket::bind("[::1]:0").unwrap();
+        sock.set_read_timeout(Some(RECV_TIMEOUT)).unwrap();
+        let (response, from) = exchange_at(&sock, proxy_addr, &packet);
+        assert_eq!(response, packet);
+        assert_eq!(from, proxy_addr);
+    }
+    // The backends are IPv4, so are reached from IPv4 sockets of the proxy's own
+    let senders: Vec<_> = [wireguard.senders(), quic.senders()].concat();
+    assert_eq!(senders.len(), 2);
+    assert!(senders.iter().all(|sender| sender.is_ipv4()));
+}
+
+#[test]
+fn forwards_ipv4_clients_when_listening_on_ipv6() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let port = free_dual_stack_port();
+    let _proxy = Proxy::start_listening(
+        SocketAddr::from(([0u16; 8], port)),
+        wireguard.addr,
+        quic.addr,
+        &[],
+    );
+
+    // The dual-stack socket sees this client as ::ffff:127.0.0.1, and has to answer it from
+    // the plain IPv4 address it was sent to
+    let proxy_addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let packet = wireguard_handshake_initiation();
+    let (response, from) = exchange_at(&client(), proxy_addr, &packet);
+    assert_eq!(response, packet);
+    assert_eq!(from, proxy_addr);
+
+    // Also when the client itself sends to the IPv4-mapped address from an IPv6 socket
+    let sock = UdpSocket::bind("[::]:0").unwrap();
+    sock.set_read_timeout(Some(RECV_TIMEOUT)).unwrap();
+    let mapped = SocketAddr::from(([0, 0, 0, 0, 0, 0xffff, 0x7f00, 1], port));
+    let packet = quic_initial(&[]);
+    let (response, from) = exchange_at(&sock, mapped, &packet);
+    assert_eq!(response, packet);
+    assert_eq!(from, mapped);
+    assert!(!wireguard.received().is_empty());
+    assert!(!quic.received().is_empty());
+}
+
+#[test]
+fn routes_datagrams_received_in_a_batch() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(&wireguard, &quic, &["--batch-recv"]);
+    let wireguard_packet = wireguard_handshake_initiation();
+    let quic_packet = quic_initial(&[]);
+    exchange(&client(), &proxy, &wireguard_packet);
+    wireguard.received();
+
+    // Sent back to back, so that one recvmmsg call can pick up several
+    let clients: Vec<_> = (0..8).map(|_| (client(), client())).collect();
+    for (wireguard_client, quic_client) in &clients {
+        wireguard_client
+            .send_to(&wireguard_packet, proxy.addr)
+            .unwrap();
+        quic_client.send_to(&quic_packet, proxy.addr).unwrap();
+    }
+    let mut buf = [0; 2048];
+    for (wireguard_client, quic_client) in &clients {
+        let (len, from) = wireguard_client.recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..len], from), (&wireguard_packet[..], proxy.addr));
+        let (len, from) = quic_client.recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..len], from), (&quic_packet[..], proxy.addr));
+    }
+    assert_eq!(wireguard.received().len(), clients.len());
+    assert_eq!(quic.received().len(), clients.len());
+}
+
+/// The stub XDP program passes everything on, and an interface it can't be attached to leaves
+/// the proxy classifying in userspace, so either way packets are routed as without it
+#[cfg(feature = "xdp")]
+#[test]
+fn routes_with_or_without_the_xdp_program_attached() {
+    for interface in ["lo", "wgq-no-such-if"] {
+        let wireguard = MockBackend::start();
+        let quic = MockBackend::start();
+        let proxy = Proxy::start(&wireguard, &quic, &["--xdp-interface", interface]);
+        let packet = wireguard_handshake_initiation();
+        let (response, _) = exchange(&client(), &proxy, &packet);
+        assert_eq!(response, packet, "{interface}");
+        let packet = quic_initial(&[]);
+        let (response, _) = exchange(&client(), &proxy, &packet);
+        assert_eq!(response, packet, "{interface}");
+    }
+}
+
+#[test]
+fn passes_wireguard_cookie_reply_back_to_client() {
+    // A server under load answers initiations with a cookie reply rather than a handshake
+    // response, which the client needs to retry its handshake
+    let wireguard = MockBackend::answering(wireguard_cookie_reply);
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(&wireguard, &quic, &[]);
+
+    let sock = client();
+    let initiation = wireguard_handshake_initiation();
+    let (response, from) = exchange(&sock, &proxy, &initiation);
+    assert_eq!(response, wireguard_cookie_reply(&initiation));
+    assert_eq!(from, proxy.addr);
+    assert!(
+        wireguard
+            .received()
+            .iter()
+            .all(|packet| *packet == initiation)
+    );
+    assert!(quic.received().is_empty());
+}
+
+#[test]
+fn follows_migrated_quic_client_by_connection_id() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(&wireguard, &quic, &["--quic-track-by", "connection-id"]);
+
+    // The mock echoes the Initial, so its source connection ID is what "the server" picked,
+    // and what the client uses as destination connection ID from then on
+    let cid = [0x22; 8];
+    let before = client();
+    exchange(&before, &proxy, &quic_initial(&cid));
+    drain(&before);
+
+    let after = client();
+    let packet = quic_short_header(&cid);
+    let (response, _) = exchange(&after, &proxy, &packet);
+    assert_eq!(response, packet);
+
+    // The backend saw both addresses as the same client
+    assert_eq!(quic.senders().len(), 1);
+
+    // And responses now go to the new address only
+    let mut buf = [0; 65536];
+    assert!(before.recv_from(&mut buf).is_err());
+}
+
+#[cfg(unix)]
+/// Set once the first backend of `fails_over_a_client_that_migrated_to_another_ip` should stop
+/// answering, its probes included
+static FIRST_BACKEND_DOWN: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+#[test]
+fn fails_over_a_client_that_migrated_to_another_ip() {
+    let admin_socket =
+        std::env::temp_dir().join(format!("wgq-migrated-failover-{}.sock", std::process::id()));
+    let wireguard = MockBackend::start();
+    let first = MockBackend::answering(|packet| {
+        if FIRST_BACKEND_DOWN.load(Ordering::Relaxed) {
+            Vec::new()
+        } else {
+            packet.to_vec()
+        }
+    });
+    let second = MockBackend::start();
+    let second_addr = second.addr.to_string();
+    let proxy = Proxy::start(
+        &wireguard,
+        &first,
+        &[
+            "--quic-backend",
+            &second_addr,
+            "--backend-selection",
+            "round-robin",
+            "--quic-track-by",
+            "connection-id",
+            "--health-probe-interval",
+            "1",
+            // Probes that expect an answer, so a backend that stops answering goes down
+            "--health-probe",
+            "00",
+            "--admin-socket",
+            admin_socket.to_str().unwrap(),
+        ],
+    );
+
+    // The connection starts on the first backend, in the shard of 127.0.0.1
+    let cid = [0x25; 8];
+    let before = client();
+    exchange(&before, &proxy, &quic_initial(&cid));
+    drain(&before);
+    assert!(!first.received().is_empty());
+
+    // Once the first backend is down, the client shows up from another IP, and is moved to
+    // the second backend
+    FIRST_BACKEND_DOWN.store(true, Ordering::Relaxed);
+    let after = UdpSocket::bind("127.0.0.2:0").unwrap();
+    after.set_read_timeout(Some(RECV_TIMEOUT)).unwrap();
+    let packet = quic_short_header(&cid);
+    let (response, _) = exchange(&after, &proxy, &packet);
+    assert_eq!(response, packet);
+    assert!(second.received().contains(&packet));
+
+    // The old connection was removed from the shard it lives in, rather than left running
+    // next to its replacement. The two IPs share a shard only in one run out of 64.
+    assert_eq!(stat(&admin_socket, "connections"), 1);
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_active_connections{packet_type=\"quic\"}"
+        ),
+        1
+    );
+    // It is counted as evicted by the failover, so created less evicted is what is open
+    let evicted = |reason: &str| {
+        stat(
+            &admin_socket,
+            &format!("wgq_connections_evicted_total{{packet_type=\"quic\",reason=\"{reason}\"}}"),
+        )
+    };
+    assert_eq!(evicted("failover"), 1);
+    assert_eq!(evicted("unreachable"), 0);
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_connections_created_total{packet_type=\"quic\"}"
+        ),
+        2
+    );
+}
+
+#[test]
+fn only_follows_established_quic_clients_by_short_header_packets() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(&wireguard, &quic, &["--quic-track-by", "connection-id"]);
+
+    let cid = [0x26; 8];
+    let before = client();
+    exchange(&before, &proxy, &quic_initial(&cid));
+    drain(&before);
+
+    // A long header packet to the same connection ID from another address, as anyone who saw
+    // the ID could send, opens a connection of its own
+    let mut initial = vec![0xc0, 0x00, 0x00, 0x00, 0x01, 0x08];
+    initial.extend_from_slice(&cid);
+    initial.push(0x00);
+    initial.resize(1200, 0);
+    let (response, _) = exchange(&client(), &proxy, &initial);
+    assert_eq!(response, initial);
+    assert_eq!(quic.senders().len(), 2);
+
+    // And responses for the original connection still go to its client
+    let packet = quic_short_header(&cid);
+    let (response, _) = exchange(&before, &proxy, &packet);
+    assert_eq!(response, packet);
+}
+
+#[test]
+fn counts_quic_migrations() {
+    let admin_socket =
+        std::env::temp_dir().join(format!("wgq-migrations-{}.sock", std::process::id()));
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &[
+            "--quic-track-by",
+            "connection-id",
+            "--admin-socket",
+            admin_socket.to_str().unwrap(),
+        ],
+    );
+
+    let cid = [0x23; 8];
+    let before = client();
+    exchange(&before, &proxy, &quic_initial(&cid));
+    // Packets from the address the connection is already on aren't a migration
+    exchange(&before, &proxy, &quic_short_header(&cid));
+    assert_eq!(stat(&admin_socket, "wgq_quic_migrations_total"), 0);
+
+    exchange(&client(), &proxy, &quic_short_header(&cid));
+    exchange(&client(), &proxy, &quic_short_header(&cid));
+    assert_eq!(stat(&admin_socket, "wgq_quic_migrations_total"), 2);
+}
+
+#[test]
+fn holds_packets_over_the_rate_limit_back_with_fair_queueing() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(&wireguard, &quic, &["--max-pps", "10", "--fair-queue"]);
+
+    let sock = client();
+    exchange(&sock, &proxy, &wireguard_handshake_initiation());
+    wireguard.received();
+    // Twice what the bucket holds, so half of these have to wait for it to refill
+    for _ in 0..20 {
+        sock.send_to(&wireguard_handshake_initiation(), proxy.addr)
+            .unwrap();
+    }
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let mut forwarded = 0;
+    while forwarded < 20 {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "only {forwarded} packets forwarded"
+        );
+        thread::sleep(std::time::Duration::from_millis(50));
+        forwarded += wireguard.received().len();
+    }
+}
+
+#[test]
+fn forwards_responses_while_a_packet_waits_for_its_turn() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(&wireguard, &quic, &["--max-pps", "1", "--fair-queue"]);
+
+    let sock = client();
+    exchange(&sock, &proxy, &wireguard_handshake_initiation());
+    // Let the bucket refill, so that only the first of the packets below goes straight through
+    thread::sleep(std::time::Duration::from_millis(1100));
+    drain(&sock);
+    let sent = std::time::Instant::now();
+    for _ in 0..3 {
+        sock.send_to(&wireguard_handshake_initiation(), proxy.addr)
+            .unwrap();
+    }
+    // The first packet's response arrives while the second waits a second for its turn
+    let mut buf = [0; 2048];
+    sock.recv_from(&mut buf).unwrap();
+    assert!(
+        sent.elapsed() < std::time::Duration::from_millis(500),
+        "the response took {:?}",
+        sent.elapsed()
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn counts_classified_packets_without_a_backend_apart_from_unknown_ones() {
+    let admin_socket =
+        std::env::temp_dir().join(format!("wgq-no-backend-{}.sock", std::process::id()));
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &["--admin-socket", admin_socket.to_str().unwrap()],
+    );
+    // Once QUIC gets through, the proxy is up
+    exchange(&client(), &proxy, &quic_initial(&[]));
+
+    let wait_for = |name: &str| {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while stat(&admin_socket, name) == 0 {
+            assert!(std::time::Instant::now() < deadline, "{name} stayed 0");
+            thread::sleep(std::time::Duration::from_millis(50));
+        }
+    };
+    // STUN has no backend unless --stun-backend is set
+    client()
+        .send_to(&stun_binding_request(), proxy.addr)
+        .unwrap();
+    wait_for("wgq_no_backend_drops_total{packet_type=\"stun\"}");
+    assert_eq!(stat(&admin_socket, "wgq_unknown_dropped_total"), 0);
+
+    client().send_to(&[0xff; 32], proxy.addr).unwrap();
+    wait_for("wgq_unknown_dropped_total");
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_no_backend_drops_total{packet_type=\"unknown\"}"
+        ),
+        1
+    );
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_no_backend_drops_total{packet_type=\"stun\"}"
+        ),
+        1
+    );
+}
+
+#[test]
+fn routes_stun_to_its_own_backend() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let stun = MockBackend::start();
+    let stun_addr = stun.addr.to_string();
+    let proxy = Proxy::start(&wireguard, &quic, &["--stun-backend", &stun_addr]);
+
+    let packet = stun_binding_request();
+    let (response, _) = exchange(&client(), &proxy, &packet);
+    assert_eq!(response, packet);
+    assert!(stun.received().iter().all(|received| *received == packet));
+    assert!(quic.received().is_empty());
+}
+
+#[test]
+fn strips_proxy_protocol_header_echoed_by_backend() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &["--proxy-protocol", "quic", "--strip-proxy-protocol"],
+    );
+
+    // The mock echoes what it receives, header included, but the client only gets its own
+    // packet back
+    let packet = quic_initial(&[]);
+    let (response, _) = exchange(&client(), &proxy, &packet);
+    assert_eq!(response, packet);
+    assert!(
+        quic.received()
+            .iter()
+            .all(|received| received.len() == 16 + 12 + packet.len())
+    );
+}
+
+#[test]
+fn routes_quic_by_server_name() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let example = MockBackend::start();
+    let route = format!("example.com={}", example.addr);
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &[
+            "--route-by-sni",
+            &route,
+            "--route-by-sni",
+            "other.test=127.0.0.1:9",
+        ],
+    );
+
+    let initial = std::fs::read("tests/data/quic_initial_example_com.bin").unwrap();
+    let (response, _) = exchange(&client(), &proxy, &initial);
+    assert_eq!(response, initial);
+    assert_eq!(example.received(), vec![initial]);
+
+    // An Initial that can't be decrypted goes to the default backend
+    let packet = quic_initial(&[]);
+    exchange(&client(), &proxy, &packet);
+    assert_eq!(quic.received(), vec![packet]);
+}
+
+#[test]
+fn moves_clients_off_a_backend_that_fails_its_probes() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    // Nothing listens on the second backend, so the probes are refused
+    let closed = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &[
+            "--wireguard-backend",
+            &closed,
+            "--health-probe-interval",
+            "1",
+        ],
+    );
+
+    // Some of these hash to the closed backend at first, and are moved once it is down
+    let packet = wireguard_handshake_initiation();
+    for _ in 0..8 {
+        let (response, _) = exchange(&client(), &proxy, &packet);
+        assert_eq!(response, packet);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn counts_connections_closed_as_their_backend_is_unreachable() {
+    let admin_socket =
+        std::env::temp_dir().join(format!("wgq-unreachable-{}.sock", std::process::id()));
+    // Nothing listens on the backend, so the packet forwarded to it is refused
+    let closed = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start_at(
+        closed,
+        quic.addr,
+        &["--admin-socket", admin_socket.to_str().unwrap()],
+    );
+    // Once QUIC gets through, the proxy is up
+    exchange(&client(), &proxy, &quic_initial(&[]));
+
+    client()
+        .send_to(&wireguard_handshake_initiation(), proxy.addr)
+        .unwrap();
+    let evicted = "wgq_connections_evicted_total{packet_type=\"wireguard\",reason=\"unreachable\"}";
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while stat(&admin_socket, evicted) == 0 {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "the close wasn't counted"
+        );
+        thread::sleep(std::time::Duration::from_millis(50));
+    }
+    assert_eq!(stat(&admin_socket, evicted), 1);
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_connections_created_total{packet_type=\"wireguard\"}"
+        ),
+        1
+    );
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_active_connections{packet_type=\"wireguard\"}"
+        ),
+        0
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn counts_connections_closed_to_re_pin_their_client() {
+    let admin_socket = std::env::temp_dir().join(format!("wgq-repin-{}.sock", std::process::id()));
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &[
+            "--on-protocol-switch",
+            "repin",
+            "--admin-socket",
+            admin_socket.to_str().unwrap(),
+        ],
+    );
+
+    let sock = client();
+    exchange(&sock, &proxy, &wireguard_handshake_initiation());
+    drain(&sock);
+    let packet = quic_initial(&[0x27; 8]);
+    let (response, _) = exchange(&sock, &proxy, &packet);
+    assert_eq!(response, packet);
+    assert!(quic.received().contains(&packet));
+
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_connections_evicted_total{packet_type=\"wireguard\",reason=\"protocol_switch\"}"
+        ),
+        1
+    );
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_active_connections{packet_type=\"wireguard\"}"
+        ),
+        0
+    );
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_active_connections{packet_type=\"quic\"}"
+        ),
+        1
+    );
+}
+
+#[test]
+fn segmented_sends_arrive_as_separate_datagrams() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(&wireguard, &quic, &["--gso"]);
+
+    let sock = client();
+    let initial = quic_initial(&[]);
+    exchange(&sock, &proxy, &initial);
+    drain(&sock);
+
+    // A burst of equal sized packets queues up behind each other, and goes out in batches
+    let burst: Vec<Vec<u8>> = (0..50u8)
+        .map(|i| {
+            let mut packet = quic_short_header(&[0x11; 8]);
+            packet[9] = i;
+            packet
+        })
+        .collect();
+    for packet in &burst {
+        sock.send_to(packet, proxy.addr).unwrap();
+    }
+    drain(&sock);
+    let received: Vec<_> = quic
+        .received()
+        .into_iter()
+        .filter(|packet| *packet != initial)
+        .collect();
+    assert_eq!(received, burst);
+}
+
+#[test]
+fn limits_sources_until_the_backend_responds() {
+    let wireguard = MockBackend::answering(|_| Vec::new());
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(&wireguard, &quic, &["--max-packet-rate-per-source", "5"]);
+
+    // QUIC is answered, which establishes the connection and lifts the limit for it
+    let sock = client();
+    let initial = quic_initial(&[]);
+    exchange(&sock, &proxy, &initial);
+    drain(&sock);
+    quic.received();
+    for _ in 0..20 {
+        sock.send_to(&initial, proxy.addr).unwrap();
+    }
+    drain(&sock);
+    assert_eq!(quic.received().len(), 20);
+
+    // The WireGuard backend never answers, so handshakes from new ports of the same address
+    // share its budget
+    let initiation = wireguard_handshake_initiation();
+    for _ in 0..20 {
+        client().send_to(&initiation, proxy.addr).unwrap();
+    }
+    thread::sleep(RECV_TIMEOUT);
+    let forwarded = wireguard.received().len();
+    assert!((5..10).contains(&forwarded), "{forwarded} forwarded");
+}
+
+#[test]
+fn mirrors_quic_packets_to_the_collector() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let collector = MockBackend::answering(|_| Vec::new());
+    let collector_addr = collector.addr.to_string();
+    let proxy = Proxy::start(&wireguard, &quic, &["--mirror-quic", &collector_addr]);
+
+    let packet = quic_initial(&[]);
+    let (response, _) = exchange(&client(), &proxy, &packet);
+    assert_eq!(response, packet);
+    exchange(&client(), &proxy, &wireguard_handshake_initiation());
+
+    // Only QUIC is mirrored, and the responses aren't
+    let mirrored = collector.received();
+    assert!(!mirrored.is_empty());
+    assert!(mirrored.iter().all(|mirrored| *mirrored == packet));
+}
+
+#[test]
+fn drops_datagrams_larger_than_the_maximum_size() {
+    let wireguard = MockBackend::answering(|packet| match packet[0] {
+        0x01 => vec![0x42; 1300],
+        _ => packet.to_vec(),
+    });
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(&wireguard, &quic, &["--max-datagram-size", "1250"]);
+    // Wait for the proxy to start with a transport data message that is answered in full
+    let mut data = vec![0x04, 0x00, 0x00, 0x00];
+    data.resize(32, 0);
+    exchange(&client(), &proxy, &data);
+    wireguard.received();
+
+    // An Initial padded to 1300 bytes is too large to forward, while the handshake isn't, but
+    // the backend's answer to it is too large to pass back
+    let sock = client();
+    let mut initial = quic_initial(&[]);
+    initial.resize(1300, 0);
+    sock.send_to(&initial, proxy.addr).unwrap();
+    sock.send_to(&wireguard_handshake_initiation(), proxy.addr)
+        .unwrap();
+    thread::sleep(RECV_TIMEOUT);
+    assert!(quic.received().is_empty());
+    assert_eq!(wireguard.received().len(), 1);
+    let mut buf = [0; 2048];
+    assert!(sock.recv_from(&mut buf).is_err());
+}
+
+/// Waits for the proxy to answer a client of its own, whose connection has its own limits
+fn wait_for_start(proxy: &Proxy) {
+    let mut data = vec![0x04, 0x00, 0x00, 0x00];
+    data.resize(32, 0);
+    exchange(&client(), proxy, &data);
+}
+
+/// Sends `count` WireGuard transport data messages of 200 bytes from `sock`, and returns how
+/// many come back from the echoing backend
+fn echoed_transport_data(sock: &UdpSocket, proxy: &Proxy, count: usize) -> usize {
+    let mut data = vec![0x04, 0x00, 0x00, 0x00];
+    data.resize(200, 0);
+    for _ in 0..count {
+        sock.send_to(&data, proxy.addr).unwrap();
+    }
+    let mut buf = [0; 2048];
+    let mut echoed = 0;
+    while sock.recv_from(&mut buf).is_ok() {
+        echoed += 1;
+    }
+    echoed
+}
+
+#[test]
+fn drops_responses_beyond_the_response_rate_limit() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(&wireguard, &quic, &["--max-response-bps", "1000"]);
+
+    wait_for_start(&proxy);
+    wireguard.received();
+    // Each connection's bucket holds a second worth of bytes
+    let echoed = echoed_transport_data(&client(), &proxy, 10);
+    assert!((5..7).contains(&echoed), "{echoed} echoed");
+    assert_eq!(wireguard.received().len(), 10);
+}
+
+#[test]
+fn paces_responses_to_the_response_rate_limit() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &["--max-response-bps", "2000", "--on-response-limit", "pace"],
+    );
+
+    wait_for_start(&proxy);
+    // 4000 bytes take at least a second beyond the bucket's second worth of bytes
+    let start = std::time::Instant::now();
+    assert_eq!(echoed_transport_data(&client(), &proxy, 20), 20);
+    assert!(start.elapsed() >= std::time::Duration::from_millis(900));
+}
+
+#[cfg(unix)]
+#[test]
+fn counts_stateless_resets_from_the_backend() {
+    let admin_socket = std::env::temp_dir().join(format!("wgq-resets-{}.sock", std::process::id()));
+    let wireguard = MockBackend::start();
+    // Answers the Initial as the connection would, and the next packet as if it had lost the
+    // connection: with a token at the end of random looking bytes
+    let quic = MockBackend::answering(|packet| {
+        if packet[0] & 0x80 != 0 {
+            return quic_short_header(&[0x07; 8]);
+        }
+        let mut reset = vec![0x5c, 0x91, 0x2e, 0xb3, 0x08, 0x6d, 0xf4, 0x13, 0xc7];
+        reset.resize(43, 0x3b);
+        reset
+    });
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &["--admin-socket", admin_socket.to_str().unwrap()],
+    );
+
+    let sock = client();
+    exchange(&sock, &proxy, &quic_initial(&[0x07; 8]));
+    drain(&sock);
+    let resets = stat(&admin_socket, "wgq_quic_stateless_resets_total");
+    let (response, _) = exchange(&sock, &proxy, &quic_short_header(&[0x11; 8]));
+    // It is still passed on, so the client learns the connection is gone
+    assert_eq!(response.len(), 43);
+    assert_eq!(
+        stat(&admin_socket, "wgq_quic_stateless_resets_total"),
+        resets + 1
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn drains_a_backend_of_new_connections_only() {
+    let admin_socket = std::env::temp_dir().join(format!("wgq-drain-{}.sock", std::process::id()));
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &["--admin-socket", admin_socket.to_str().unwrap()],
+    );
+    let backend = wireguard.addr.to_string();
+    let packet = wireguard_handshake_initiation();
+    let existing = client();
+    exchange(&existing, &proxy, &packet);
+    drain(&existing);
+
+    assert_eq!(
+        admin(&admin_socket, &format!("drain {backend}")),
+        format!("wireguard {backend} true\n")
+    );
+    let backends = admin(&admin_socket, "backends");
+    assert!(
+        backends.contains(&format!("wireguard {backend} {backend} true true 1\n")),
+        "{backends}"
+    );
+
+    // The existing connection carries on, but a new client gets nowhere
+    let (response, _) = exchange(&existing, &proxy, &packet);
+    assert_eq!(response, packet);
+    let new = client();
+    new.send_to(&packet, proxy.addr).unwrap();
+    assert!(new.recv_from(&mut [0; 2048]).is_err());
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_draining_drops_total{packet_type=\"wireguard\"}"
+        ),
+        1
+    );
+
+    admin(&admin_socket, &format!("undrain {backend}"));
+    let (response, _) = exchange(&new, &proxy, &packet);
+    assert_eq!(response, packet);
+    assert!(admin(&admin_socket, "drain nowhere:1").starts_with("no backend"));
+}
+
+#[cfg(unix)]
+#[test]
+fn reloads_backends_for_new_connections_only() {
+    let config = std::env::temp_dir().join(format!("wgq-reload-{}.toml", std::process::id()));
+    let old = MockBackend::start();
+    let new = MockBackend::start();
+    let quic = MockBackend::start();
+    let write_config = |wireguard: &MockBackend| {
+        std::fs::write(
+            &config,
+            format!(
+                "wireguard_backend = \"{}\"\nquic_backend = \"{}\"\n",
+                wireguard.addr, quic.addr
+            ),
+        )
+        .unwrap();
+    };
+    write_config(&old);
+    let proxy = Proxy::start_with_config(&config);
+    let packet = wireguard_handshake_initiation();
+    let existing = client();
+    exchange(&existing, &proxy, &packet);
+    drain(&existing);
+
+    write_config(&new);
+    proxy.reload();
+    let deadline = std::time::Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "new clients weren't sent to the reloaded backend"
+        );
+        let (response, _) = exchange(&client(), &proxy, &packet);
+        assert_eq!(response, packet);
+        if !new.received().is_empty() {
+            break;
+        }
+    }
+
+    // The existing connection keeps the backend it was made with
+    old.received();
+    let (response, _) = exchange(&existing, &proxy, &packet);
+    assert_eq!(response, packet);
+    assert_eq!(old.received(), [packet]);
+    assert!(new.received().is_empty());
+    std::fs::remove_file(&config).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn keeps_the_backend_selector_across_a_reload() {
+    let config = std::env::temp_dir().join(format!("wgq-selector-{}.toml", std::process::id()));
+    let admin_socket =
+        std::env::temp_dir().join(format!("wgq-selector-{}.sock", std::process::id()));
+    let backends = [
+        MockBackend::start(),
+        MockBackend::start(),
+        MockBackend::start(),
+    ];
+    let quic = MockBackend::start();
+    let write_config = |wireguard: &[MockBackend]| {
+        let wireguard: Vec<String> = wireguard
+            .iter()
+            .map(|backend| format!("\"{}\"", backend.addr))
+            .collect();
+        std::fs::write(
+            &config,
+            format!(
+                "wireguard_backend = [{}]\nquic_backend = \"{}\"\nbackend_selection = \"round-robin\"\nadmin_socket = \"{}\"\n",
+                wireguard.join(", "),
+                quic.addr,
+                admin_socket.display()
+            ),
+        )
+        .unwrap();
+    };
+    write_config(&backends[..2]);
+    let proxy = Proxy::start_with_config(&config);
+    let packet = wireguard_handshake_initiation();
+    exchange(&client(), &proxy, &packet);
+    assert!(!backends[0].received().is_empty());
+
+    // A third backend changes the backends, but not how they are picked
+    write_config(&backends);
+    proxy.reload();
+    let third = backends[2].addr.to_string();
+    let deadline = std::time::Instant::now() + STARTUP_TIMEOUT;
+    while !admin(&admin_socket, "backends").contains(&third) {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "the third backend wasn't reloaded"
+        );
+        thread::sleep(RECV_TIMEOUT);
+    }
+
+    // So the round robin carries on with the second backend, rather than starting over
+    exchange(&client(), &proxy, &packet);
+    assert!(!backends[1].received().is_empty());
+    assert!(backends[0].received().is_empty());
+    std::fs::remove_file(&config).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn drops_packets_too_large_for_the_path_but_keeps_the_connection() {
+    let admin_socket = std::env::temp_dir().join(format!("wgq-mtu-{}.sock", std::process::id()));
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &[
+            "--proxy-protocol",
+            "wireguard",
+            "--no-fragment",
+            "--admin-socket",
+            admin_socket.to_str().unwrap(),
+        ],
+    );
+    let sock = client();
+    exchange(&sock, &proxy, &wireguard_handshake_initiation());
+    drain(&sock);
+    wireguard.received();
+
+    // The largest datagram there is, which the PROXY protocol header makes too large to send
+    let mut packet = vec![0x04, 0, 0, 0];
+    packet.resize(65507, 0xaa);
+    sock.send_to(&packet, proxy.addr).unwrap();
+    let small = [
+        0x04, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xaa, 0xaa,
+    ];
+    exchange(&sock, &proxy, &small);
+    let received = wireguard.received();
+    assert_eq!(received.len(), 1);
+    assert!(received[0].ends_with(&small));
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_mtu_errors_total{packet_type=\"wireguard\"}"
+        ),
+        1
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn drops_empty_datagrams() {
+    let admin_socket = std::env::temp_dir().join(format!("wgq-empty-{}.sock", std::process::id()));
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let unknown = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &[
+            "--forward-unknown-to",
+            &unknown.addr.to_string(),
+            "--admin-socket",
+            admin_socket.to_str().unwrap(),
+        ],
+    );
+
+    let sock = client();
+    let packet = wireguard_handshake_initiation();
+    exchange(&sock, &proxy, &packet);
+    drain(&sock);
+    // Neither a new connection for the unknown backend nor a packet on the existing one
+    client().send_to(&[], proxy.addr).unwrap();
+    sock.send_to(&[], proxy.addr).unwrap();
+    let (response, _) = exchange(&sock, &proxy, &packet);
+    assert_eq!(response, packet);
+
+    assert_eq!(stat(&admin_socket, "wgq_empty_datagrams_total"), 2);
+    assert_eq!(stat(&admin_socket, "connections"), 1);
+    assert!(unknown.received().is_empty());
+    assert!(
+        wireguard
+            .received()
+            .iter()
+            .all(|received| *received == packet)
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn listens_on_several_addresses() {
+    let admin_socket =
+        std::env::temp_dir().join(format!("wgq-listeners-{}.sock", std::process::id()));
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let second = free_addr();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &[
+            "--listen",
+            &second.to_string(),
+            "--admin-socket",
+            admin_socket.to_str().unwrap(),
+        ],
+    );
+
+    // Responses come from the address the client sent to
+    let packet = wireguard_handshake_initiation();
+    let (_, from) = exchange(&client(), &proxy, &packet);
+    assert_eq!(from, proxy.addr);
+    let (_, from) = exchange_at(&client(), second, &quic_initial(&[]));
+    assert_eq!(from, second);
+
+    for addr in [proxy.addr, second] {
+        let labels = format!("{{listen=\"{addr}\",port=\"{}\"}}", addr.port());
+        let received = stat(
+            &admin_socket,
+            &format!("wgq_listener_packets_received_total{labels}"),
+        );
+        let sent = stat(
+            &admin_socket,
+            &format!("wgq_listener_packets_sent_total{labels}"),
+        );
+        assert!(received >= 1 && sent >= 1, "{addr}: {received} {sent}");
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn measures_the_time_to_the_first_response_of_each_connection() {
+    let admin_socket =
+        std::env::temp_dir().join(format!("wgq-latency-{}.sock", std::process::id()));
+    let wireguard = MockBackend::answering(|packet| {
+        thread::sleep(std::time::Duration::from_millis(30));
+        packet.to_vec()
+    });
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &["--admin-socket", admin_socket.to_str().unwrap()],
+    );
+
+    let sock = client();
+    let packet = wireguard_handshake_initiation();
+    exchange(&sock, &proxy, &packet);
+    exchange(&sock, &proxy, &packet);
+    let bucket = |le: &str| {
+        stat(
+            &admin_socket,
+            &format!(
+                "wgq_first_response_latency_seconds_bucket{{packet_type=\"wireguard\",le=\"{le}\"}}"
+            ),
+        )
+    };
+    // Only the first of the responses counts
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_first_response_latency_seconds_count{packet_type=\"wireguard\"}"
+        ),
+        1
+    );
+    assert_eq!(bucket("0.025"), 0);
+    assert_eq!(bucket("0.05"), 1);
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_first_response_latency_seconds_count{packet_type=\"quic\"}"
+        ),
+        0
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn measures_the_round_trip_time_of_each_connection() {
+    let admin_socket = std::env::temp_dir().join(format!("wgq-rtt-{}.sock", std::process::id()));
+    let wireguard = MockBackend::answering(|packet| {
+        thread::sleep(std::time::Duration::from_millis(30));
+        packet.to_vec()
+    });
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &["--admin-socket", admin_socket.to_str().unwrap()],
+    );
+
+    let sock = client();
+    let packet = wireguard_handshake_initiation();
+    for _ in 0..3 {
+        exchange(&sock, &proxy, &packet);
+    }
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_round_trip_time_seconds_count{packet_type=\"wireguard\"}"
+        ),
+        3
+    );
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_round_trip_time_seconds_bucket{packet_type=\"wireguard\",le=\"0.025\"}"
+        ),
+        0
+    );
+
+    let list = admin(&admin_socket, "list");
+    let mut lines = list.lines();
+    assert!(lines.next().unwrap().ends_with(" rtt_ms"), "{list}");
+    let rtt: f64 = lines
+        .next()
+        .and_then(|line| line.split(' ').next_back())
+        .and_then(|rtt| rtt.parse().ok())
+        .unwrap_or_else(|| panic!("no RTT in {list}"));
+    assert!((30.0..1000.0).contains(&rtt), "{list}");
+}
+
+#[test]
+fn shares_one_socket_between_quic_clients() {
+    let wireguard = MockBackend::start();
+    // Answers with a short header packet to the source connection ID of each Initial, the way
+    // QUIC servers address their clients
+    let quic = MockBackend::answering(|packet| {
+        let scid_len_offset = 6 + packet[5] as usize;
+        let scid_len = packet[scid_len_offset] as usize;
+        quic_short_header(&packet[scid_len_offset + 1..scid_len_offset + 1 + scid_len])
+    });
+    let proxy = Proxy::start(&wireguard, &quic, &["--quic-egress", "shared"]);
+
+    let clients = [(client(), [0x01; 8]), (client(), [0x02; 8])];
+    for (sock, cid) in &clients {
+        let (response, _) = exchange(sock, &proxy, &quic_initial(cid));
+        assert_eq!(response, quic_short_header(cid));
+    }
+    // Neither client got the other's responses, though they came through the same socket
+    let mut buf = [0; 2048];
+    for (sock, cid) in &clients {
+        while let Ok((len, _)) = sock.recv_from(&mut buf) {
+            assert_eq!(buf[..len], quic_short_header(cid));
+        }
+    }
+    let shared = quic.senders();
+    assert_eq!(shared.len(), 1);
+
+    // A client without a connection ID can't be told apart, so it gets a socket of its own
+    exchange(&client(), &proxy, &quic_initial(&[]));
+    assert!(!quic.senders().contains(&shared[0]));
+}
+
+#[test]
+fn routes_long_headers_with_the_fixed_bit_greased_on_a_shared_socket() {
+    let wireguard = MockBackend::start();
+    // An Initial back to the client with the fixed bit cleared (RFC 9287), as quinn sends them
+    let quic = MockBackend::answering(|packet| {
+        let scid_len_offset = 6 + packet[5] as usize;
+        let scid = &packet[scid_len_offset..=scid_len_offset + packet[scid_len_offset] as usize];
+        let mut response = vec![0x80, 0x00, 0x00, 0x00, 0x01];
+        response.extend_from_slice(scid);
+        response.push(8);
+        response.extend_from_slice(&[0x33; 8]);
+        response.resize(100, 0);
+        response
+    });
+    let proxy = Proxy::start(&wireguard, &quic, &["--quic-egress", "shared"]);
+
+    let (response, _) = exchange(&client(), &proxy, &quic_initial(&[0x01; 8]));
+    assert_eq!(response[5..14], [8, 1, 1, 1, 1, 1, 1, 1, 1]);
+}
+
+#[test]
+fn forwards_quic_through_a_socks5_proxy() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let socks5 = MockSocks5::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &["--quic-socks5", &socks5.addr.to_string()],
+    );
+
+    let initial = quic_initial(&[0x01; 8]);
+    let (response, _) = exchange(&client(), &proxy, &initial);
+    assert_eq!(response, initial);
+    assert!(quic.received().contains(&initial));
+    assert!(socks5.relayed().contains(&quic.addr));
+
+    // WireGuard has no proxy configured, so it still goes straight to its backend
+    let handshake = wireguard_handshake_initiation();
+    let (response, _) = exchange(&client(), &proxy, &handshake);
+    assert_eq!(response, handshake);
+    assert!(!socks5.relayed().contains(&wireguard.addr));
+}
+
+#[cfg(unix)]
+#[test]
+fn drops_malformed_wireguard_instead_of_forwarding_it_as_unknown() {
+    let admin_socket =
+        std::env::temp_dir().join(format!("wgq-malformed-{}.sock", std::process::id()));
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let unknown = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &[
+            "--forward-unknown-to",
+            &unknown.addr.to_string(),
+            "--admin-socket",
+            admin_socket.to_str().unwrap(),
+        ],
+    );
+
+    // Something plainly unknown still goes to its backend, but a handshake initiation one
+    // byte short doesn't
+    let (response, _) = exchange(&client(), &proxy, b"hello");
+    assert_eq!(response, b"hello");
+    let mut malformed = wireguard_handshake_initiation();
+    malformed.pop();
+    let sock = client();
+    sock.send_to(&malformed, proxy.addr).unwrap();
+    let mut buf = [0; 2048];
+    assert!(sock.recv_from(&mut buf).is_err());
+
+    assert_eq!(stat(&admin_socket, "wgq_malformed_wireguard_total"), 1);
+    assert_eq!(stat(&admin_socket, "connections"), 1);
+    assert!(!unknown.received().contains(&malformed));
+    assert!(wireguard.received().is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn counts_connections_created_and_evicted() {
+    let admin_socket = std::env::temp_dir().join(format!("wgq-churn-{}.sock", std::process::id()));
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &[
+            "--wireguard-timeout",
+            "1",
+            "--admin-socket",
+            admin_socket.to_str().unwrap(),
+        ],
+    );
+
+    exchange(&client(), &proxy, &wireguard_handshake_initiation());
+    exchange(&client(), &proxy, &quic_initial(&[0x01; 8]));
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_connections_created_total{packet_type=\"wireguard\"}"
+        ),
+        1
+    );
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_connections_created_total{packet_type=\"quic\"}"
+        ),
+        1
+    );
+
+    // Only the WireGuard connection times out this soon
+    thread::sleep(std::time::Duration::from_millis(1500));
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_connections_evicted_total{packet_type=\"wireguard\",reason=\"idle\"}"
+        ),
+        1
+    );
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_connections_evicted_total{packet_type=\"quic\",reason=\"idle\"}"
+        ),
+        0
+    );
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_active_connections{packet_type=\"wireguard\"}"
+        ),
+        0
+    );
+    assert_eq!(stat(&admin_socket, "connections"), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn passes_quic_retries_to_the_client_intact() {
+    let admin_socket = std::env::temp_dir().join(format!("wgq-retry-{}.sock", std::process::id()));
+    let wireguard = MockBackend::start();
+    // A server validating addresses answers the first Initial with a Retry, from a new
+    // connection ID and carrying a token
+    let quic = MockBackend::answering(|_| {
+        let mut retry = vec![0xf0, 0x00, 0x00, 0x00, 0x01, 0x08];
+        retry.extend_from_slice(&[0x01; 8]);
+        retry.push(8);
+        retry.extend_from_slice(&[0x22; 8]);
+        retry.extend_from_slice(b"token");
+        retry.extend_from_slice(&[0xaa; 16]);
+        retry
+    });
+    let proxy = Proxy::start(
+        &wireguard,
+        &quic,
+        &["--admin-socket", admin_socket.to_str().unwrap()],
+    );
+
+    let (response, _) = exchange(&client(), &proxy, &quic_initial(&[0x01; 8]));
+    assert_eq!(response[0], 0xf0);
+    assert!(response.ends_with(&[0xaa; 16]));
+    // The exchange may have taken more than one Initial
+    assert!(
+        stat(
+            &admin_socket,
+            "wgq_quic_retry_total{direction=\"to_client\"}"
+        ) >= 1
+    );
+    assert_eq!(
+        stat(
+            &admin_socket,
+            "wgq_quic_retry_total{direction=\"from_client\"}"
+        ),
+        0
+    );
+}
+
+#[test]
+fn takes_backends_in_turn_with_round_robin_selection() {
+    let wireguard = MockBackend::start();
+    let first = MockBackend::start();
+    let second = MockBackend::start();
+    let proxy = Proxy::start(
+        &wireguard,
+        &first,
+        &[
+            "--quic-backend",
+            &second.addr.to_string(),
+            "--backend-selection",
+            "round-robin",
+        ],
+    );
+
+    for cid in 1..=4 {
+        exchange(&client(), &proxy, &quic_initial(&[cid; 8]));
+    }
+    // Each got every other client, whatever their addresses hash to
+    assert_eq!(first.senders().len(), 2);
+    assert_eq!(second.senders().len(), 2);
+}
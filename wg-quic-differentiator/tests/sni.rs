@@ -0,0 +1,29 @@
+//! Checks reading the server name from the Initial packets of QUIC clients
+
+use wg_quic_differentiator::sni::server_name;
+
+/// The first datagram of a quinn client connecting to example.com
+const INITIAL: &[u8] = include_bytes!("data/quic_initial_example_com.bin");
+
+#[test]
+fn reads_server_name_from_client_initial() {
+    assert_eq!(server_name(INITIAL).as_deref(), Some("example.com"));
+}
+
+#[test]
+fn ignores_tampered_initial() {
+    // The payload is authenticated, so a flipped bit means it can't be decrypted
+    let mut packet = INITIAL.to_vec();
+    let last = packet.len() - 1;
+    packet[last] ^= 0x01;
+    assert_eq!(server_name(&packet), None);
+}
+
+#[test]
+fn ignores_packets_other_than_initials() {
+    // A handshake packet of QUIC version 1
+    let mut packet = vec![0xe0, 0x00, 0x00, 0x00, 0x01, 0x04, 1, 2, 3, 4, 0x00];
+    packet.resize(1200, 0);
+    assert_eq!(server_name(&packet), None);
+    assert_eq!(server_name(&[0x40; 100]), None);
+}
@@ -0,0 +1,225 @@
+//! Checks how the proxy is configured when it starts, and how it fails to
+
+mod common;
+
+use common::*;
+use std::net::UdpSocket;
+use std::process::Command;
+
+#[test]
+fn takes_its_addresses_from_the_environment() {
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    let proxy = Proxy::start_from_env(&wireguard, &quic);
+
+    let packet = wireguard_handshake_initiation();
+    let (response, _) = exchange(&client(), &proxy, &packet);
+    assert_eq!(response, packet);
+    assert!(quic.received().is_empty());
+}
+
+#[test]
+fn rejects_transparent_mode_with_shared_sockets() {
+    let output = Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+        .args(["--transparent", "--quic-egress", "shared"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("needs a socket per connection"), "{stderr}");
+}
+
+#[test]
+fn rejects_repinning_when_only_first_packets_are_classified() {
+    let output = Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+        .args(["--classify-first-only", "--on-protocol-switch", "repin"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("doesn't notice protocol switches"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn rejects_fair_queueing_without_a_rate_limit() {
+    let output = Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+        .arg("--fair-queue")
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("needs one of them"), "{stderr}");
+}
+
+#[test]
+fn rejects_a_fair_queue_rate_limit_of_zero() {
+    let output = Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+        .args(["--fair-queue", "--max-pps", "0"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("a limit of 0 would drop every packet"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn rejects_a_connection_timeout_of_zero() {
+    for flag in [
+        "--connection-timeout",
+        "--wireguard-timeout",
+        "--quic-timeout",
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+            .args([flag, "0"])
+            .output()
+            .unwrap();
+
+        assert!(!output.status.success(), "{flag}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("timeouts must be at least a second"),
+            "{flag}: {stderr}"
+        );
+    }
+}
+
+#[test]
+fn rejects_a_maximum_datagram_size_below_quics_minimum() {
+    let output = Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+        .args(["--max-datagram-size", "1199"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--max-datagram-size must be at least 1200 bytes"),
+        "{stderr}"
+    );
+}
+
+#[cfg(not(feature = "xdp"))]
+#[test]
+fn rejects_xdp_without_the_feature() {
+    let output = Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+        .args(["--xdp-interface", "lo"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("needs a build with the xdp feature"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn rejects_a_tiny_socket_buffer() {
+    let output = Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+        .args(["--so-rcvbuf", "100"])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--so-rcvbuf must be between"), "{stderr}");
+}
+
+#[test]
+fn fails_fast_on_a_bad_address_in_the_environment() {
+    let output = Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+        .env("WGQ_QUIC_BACKEND", "nonsense")
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("invalid quic backend address") && stderr.contains("nonsense"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn explains_that_the_listen_port_is_taken() {
+    let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = taken.local_addr().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+        .arg("--listen")
+        .arg(addr.to_string())
+        .args([
+            "--wireguard-backend",
+            "127.0.0.1:9",
+            "--quic-backend",
+            "127.0.0.1:9",
+        ])
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!(
+            "port {} is already in use; is another instance running?",
+            addr.port()
+        )),
+        "{stderr}"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn listens_on_a_socket_passed_in_by_systemd() {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let wireguard = MockBackend::start();
+    let quic = MockBackend::start();
+    // Bound by the test, the way systemd binds a ListenDatagram= socket, and passed as the
+    // first descriptor. The shell sets LISTEN_PID to its own process ID, which the proxy
+    // keeps by replacing it.
+    let activated = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = activated.local_addr().unwrap();
+    let fd = activated.as_raw_fd();
+    let mut command = Command::new("sh");
+    command
+        .args(["-c", "LISTEN_PID=$$ exec \"$0\" \"$@\""])
+        .arg(env!("CARGO_BIN_EXE_wg-quic-differentiator"))
+        .args(["--listen", &free_addr().to_string()])
+        .args(["--wireguard-backend", &wireguard.addr.to_string()])
+        .args(["--quic-backend", &quic.addr.to_string()])
+        .env("LISTEN_FDS", "1");
+    // Safety: dup2 and fcntl are async-signal-safe. The copy is inherited, unlike the
+    // original, which is only left as it is if it already is descriptor 3.
+    unsafe {
+        command.pre_exec(move || {
+            let result = match fd {
+                3 => libc::fcntl(3, libc::F_SETFD, 0),
+                _ => libc::dup2(fd, 3),
+            };
+            match result {
+                -1 => Err(std::io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        });
+    }
+    let mut child = command.spawn().unwrap();
+    drop(activated);
+
+    let packet = wireguard_handshake_initiation();
+    let (response, _) = exchange_at(&client(), addr, &packet);
+    assert_eq!(response, packet);
+    let _ = child.kill();
+    let _ = child.wait();
+}
@@ -0,0 +1,47 @@
+//! Checks looking for WireGuard tunnelled through QUIC in decrypted Initial packets
+#![cfg(feature = "wireguard-over-quic")]
+
+mod common;
+
+use common::*;
+use wg_quic_differentiator::wg_over_quic::{inspect, inspect_frames};
+
+/// The first datagram of a quinn client connecting to example.com
+const INITIAL: &[u8] = include_bytes!("data/quic_initial_example_com.bin");
+
+#[test]
+fn reads_datagram_support_from_transport_parameters() {
+    let findings = inspect(INITIAL).expect("the Initial can be decrypted");
+    assert!(findings.max_datagram_frame_size.is_some());
+    assert_eq!(findings.datagrams, 0);
+    assert!(findings.wireguard_datagrams.is_empty());
+}
+
+#[test]
+fn finds_wireguard_in_datagram_frames() {
+    let initiation = wireguard_handshake_initiation();
+    let mut frames = vec![0x01, 0x31, 0x40 | (initiation.len() >> 8) as u8];
+    frames.push(initiation.len() as u8);
+    frames.extend_from_slice(&initiation);
+    // One that isn't WireGuard, followed by WireGuard data running to the end of the packet
+    frames.extend_from_slice(&[0x31, 0x03, 1, 2, 3, 0x30]);
+    frames.extend_from_slice(&[0x04, 0x00, 0x00, 0x00]);
+    frames.resize(frames.len() + 28, 0);
+
+    let findings = inspect_frames(&frames);
+    assert_eq!(findings.max_datagram_frame_size, None);
+    assert_eq!(findings.datagrams, 3);
+    assert_eq!(
+        findings.wireguard_datagrams,
+        ["Handshake Initiation", "Data"]
+    );
+}
+
+#[test]
+fn ignores_packets_that_cant_be_decrypted() {
+    let mut packet = INITIAL.to_vec();
+    let last = packet.len() - 1;
+    packet[last] ^= 0x01;
+    assert_eq!(inspect(&packet), None);
+    assert_eq!(inspect(&wireguard_handshake_initiation()), None);
+}